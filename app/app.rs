@@ -1,4 +1,7 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use fallible_iterator::FallibleIterator as _;
 use futures::{StreamExt, TryFutureExt};
@@ -6,13 +9,13 @@ use thunder_orchard::{
     miner::{self, Miner},
     node::{self, Node},
     types::{
-        self, InPoint, OutPoint, Transaction, TransparentAddress,
+        self, Address, InPoint, OutPoint, PointedOutput, Transaction,
         proto::mainchain::{
             self,
             generated::{validator_service_server, wallet_service_server},
         },
     },
-    wallet::{self, Wallet},
+    wallet::{self, Wallet, WalletDeposit, WalletWithdrawal},
 };
 use tokio::{spawn, sync::RwLock as TokioRwLock, task::JoinHandle};
 use tokio_util::task::LocalPoolHandle;
@@ -90,8 +93,14 @@ fn update_wallet<'a>(
         );
         let header = node.archive().get_header(&node_rotxn, block_hash)?;
         let body = node.archive().get_body(&node_rotxn, block_hash)?;
-        wallet_rwtxn =
-            wallet.disconnect_orchard_block(wallet_rwtxn, &header, &body)?;
+        let shielded_deposits =
+            node.get_shielded_deposits(&node_rotxn, &header)?;
+        wallet_rwtxn = wallet.disconnect_orchard_block(
+            wallet_rwtxn,
+            &header,
+            &body,
+            &shielded_deposits,
+        )?;
         wallet_tip = header.prev_side_hash;
     }
 
@@ -107,8 +116,14 @@ fn update_wallet<'a>(
     for block_hash in blocks_to_connect.into_iter().rev() {
         let header = node.archive().get_header(&node_rotxn, block_hash)?;
         let body = node.archive().get_body(&node_rotxn, block_hash)?;
-        wallet_rwtxn =
-            wallet.connect_orchard_block(wallet_rwtxn, &header, &body)?;
+        let shielded_deposits =
+            node.get_shielded_deposits(&node_rotxn, &header)?;
+        wallet_rwtxn = wallet.connect_orchard_block(
+            wallet_rwtxn,
+            &header,
+            &body,
+            &shielded_deposits,
+        )?;
     }
 
     let addresses = wallet.get_transparent_addresses(&wallet_rwtxn)?;
@@ -154,11 +169,73 @@ pub struct App {
     pub wallet: Wallet,
     pub miner: Option<Arc<TokioRwLock<Miner>>>,
     task: Arc<JoinHandle<()>>,
+    audit_treasury_task: Option<Arc<JoinHandle<()>>>,
     pub runtime: Arc<tokio::runtime::Runtime>,
     pub local_pool: LocalPoolHandle,
 }
 
 impl App {
+    /// Time for which the tip must not change before the treasury is audited
+    const TREASURY_AUDIT_DEBOUNCE: std::time::Duration =
+        std::time::Duration::from_secs(10);
+
+    /// Reconcile the sidechain treasury with the mainchain, and log any
+    /// discrepancy
+    async fn audit_treasury(node: &Node) {
+        let report = match node.audit_treasury().await {
+            Ok(report) => report,
+            Err(node::Error::TreasuryAudit(
+                err @ node::treasury_audit::Error::NotSynced { .. },
+            )) => {
+                tracing::debug!("Skipping treasury audit: {err:#}");
+                return;
+            }
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                tracing::warn!("Failed to audit treasury: {err:#}");
+                return;
+            }
+        };
+        if report.is_consistent() {
+            tracing::debug!(
+                tip = %report.tip,
+                ctip_sats = report.ctip.to_sat(),
+                "Treasury audit passed"
+            );
+            return;
+        }
+        for inconsistency in &report.inconsistencies {
+            tracing::error!(
+                tip = %report.tip,
+                mainchain_tip = %report.mainchain_tip,
+                "TREASURY AUDIT FAILED: {inconsistency}"
+            );
+        }
+        tracing::error!(?report, "Treasury audit report");
+    }
+
+    /// Audit the treasury once the tip has not changed for
+    /// [`Self::TREASURY_AUDIT_DEBOUNCE`], so that audits do not run for
+    /// every block while syncing
+    async fn audit_treasury_task(node: Arc<Node>) {
+        let mut state_changes = node.watch_state();
+        while let Some(()) = state_changes.next().await {
+            loop {
+                match tokio::time::timeout(
+                    Self::TREASURY_AUDIT_DEBOUNCE,
+                    state_changes.next(),
+                )
+                .await
+                {
+                    Ok(Some(())) => continue,
+                    Ok(None) => return,
+                    Err(_elapsed) => break,
+                }
+            }
+            Self::audit_treasury(&node).await;
+        }
+    }
+
     async fn task(node: Arc<Node>, wallet: Wallet) -> Result<(), Error> {
         let mut state_changes = node.watch_state();
         while let Some(()) = state_changes.next().await {
@@ -241,6 +318,39 @@ impl App {
         Ok(has_wallet_service)
     }
 
+    /// Log pending database migrations for the node and wallet, without
+    /// modifying either
+    pub fn migration_dry_run(config: &Config) -> Result<(), Error> {
+        let reports = [
+            ("node", node::migration_dry_run(&config.datadir)?),
+            (
+                "wallet",
+                Wallet::migration_dry_run(&config.datadir.join("wallet.mdb"))?,
+            ),
+        ];
+        for (name, report) in reports {
+            tracing::info!(
+                "{} pending {name} migration step(s)",
+                report.n_steps()
+            );
+            for schema in report.schemas {
+                for step in schema.steps {
+                    tracing::info!(
+                        "{name} `{}` (stored version {}): v{}: {}",
+                        schema.version_db,
+                        schema.stored_version.map_or_else(
+                            || "none".to_owned(),
+                            |v| v.to_string()
+                        ),
+                        step.version,
+                        step.description
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn new(config: &Config) -> Result<Self, Error> {
         // Node launches some tokio tasks for p2p networking, that is why we need a tokio runtime
         // here.
@@ -253,7 +363,8 @@ impl App {
             config.datadir.display()
         );
 
-        let wallet = Wallet::new(&config.datadir.join("wallet.mdb"))?;
+        let wallet =
+            Wallet::new(&config.datadir.join("wallet.mdb"), config.network)?;
         if let Some(seed_phrase_path) = &config.mnemonic_seed_phrase_path {
             let mnemonic = std::fs::read_to_string(seed_phrase_path)?;
             let () = wallet.set_seed_from_mnemonic(mnemonic.as_str())?;
@@ -292,20 +403,38 @@ impl App {
         let node = Node::new(
             &config.datadir,
             config.net_addr,
+            config.net_config.clone(),
             cusf_mainchain,
             cusf_mainchain_wallet,
+            config.compact_state,
             config.network,
+            config.prune,
+            config.reindex,
+            config.snapshot.clone(),
+            config.verify_chain,
             &runtime,
         )?;
         let node = Arc::new(node);
+        if let Some(path) = &config.import_blocks {
+            tracing::info!("Importing blocks from {}", path.display());
+            let _n_blocks: u32 = runtime.block_on(node.import_blocks(path))?;
+        }
+        if let Some(path) = &config.export_blocks {
+            tracing::info!("Exporting blocks to {}", path.display());
+            let _n_blocks: u32 = node.export_blocks(path, 0, u32::MAX)?;
+        }
         let miner = miner.map(|miner| Arc::new(TokioRwLock::new(miner)));
         let task = Self::spawn_task(node.clone(), wallet.clone());
+        let audit_treasury_task = config
+            .audit_treasury
+            .then(|| spawn(Self::audit_treasury_task(node.clone())));
         drop(rt_guard);
         Ok(Self {
             node,
             wallet,
             miner,
             task: Arc::new(task),
+            audit_treasury_task: audit_treasury_task.map(Arc::new),
             runtime: Arc::new(runtime),
             local_pool,
         })
@@ -369,6 +498,87 @@ impl App {
         Ok(())
     }
 
+    /// List withdrawal outputs created by the wallet, along with the
+    /// withdrawal bundles that have included them
+    pub fn list_withdrawals(&self) -> Result<Vec<WalletWithdrawal>, Error> {
+        let outputs: Vec<_> = {
+            let rotxn =
+                self.wallet.env().read_txn().map_err(wallet::Error::from)?;
+            let confirmed = self
+                .wallet
+                .get_utxos(&rotxn)?
+                .into_iter()
+                .chain(
+                    self.wallet
+                        .get_stxos(&rotxn)?
+                        .into_iter()
+                        .map(|(outpoint, spent)| (outpoint, spent.output)),
+                )
+                .map(|(outpoint, output)| (outpoint, output, true));
+            let unconfirmed = self
+                .wallet
+                .get_utxos_unconfirmed(&rotxn)?
+                .into_iter()
+                .map(|(outpoint, output)| (outpoint, output, false));
+            confirmed
+                .chain(unconfirmed)
+                .filter(|(_, output, _)| output.content.is_withdrawal())
+                .collect()
+        };
+        let outpoints: HashSet<_> =
+            outputs.iter().map(|(outpoint, _, _)| *outpoint).collect();
+        let mut bundle_inclusions =
+            self.node.get_withdrawal_bundle_inclusions(&outpoints)?;
+        let mut withdrawals: Vec<_> = outputs
+            .into_iter()
+            .map(|(outpoint, output, confirmed)| WalletWithdrawal {
+                bundles: bundle_inclusions
+                    .remove(&outpoint)
+                    .unwrap_or_default(),
+                output: PointedOutput { outpoint, output },
+                confirmed,
+            })
+            .collect();
+        // Unconfirmed and unbundled withdrawals first, then most recently
+        // updated first
+        withdrawals.sort_by_key(|withdrawal| {
+            let latest_update_height = withdrawal
+                .bundles
+                .last()
+                .and_then(|bundle| bundle.status_history.last())
+                .map_or(u32::MAX, |status| status.height);
+            (
+                withdrawal.confirmed,
+                std::cmp::Reverse(latest_update_height),
+            )
+        });
+        Ok(withdrawals)
+    }
+
+    /// Deposits created by the wallet that have not yet been credited on
+    /// the sidechain
+    pub fn list_pending_deposits(&self) -> Result<Vec<WalletDeposit>, Error> {
+        let deposits = {
+            let rotxn =
+                self.wallet.env().read_txn().map_err(wallet::Error::from)?;
+            self.wallet.get_deposits(&rotxn)?
+        };
+        if deposits.is_empty() {
+            return Ok(deposits);
+        }
+        let credited_txids: HashSet<_> = self
+            .node
+            .get_deposit_outpoints()?
+            .into_iter()
+            .map(|outpoint| outpoint.txid)
+            .collect();
+        let pending = deposits
+            .into_iter()
+            .filter(|deposit| !credited_txids.contains(&deposit.txid))
+            .collect();
+        Ok(pending)
+    }
+
     pub async fn get_new_main_address(
         &self,
     ) -> Result<bitcoin::Address<bitcoin::address::NetworkChecked>, Error> {
@@ -609,9 +819,10 @@ impl App {
         Ok(())
     }
 
+    /// Create a deposit to a transparent or shielded address
     pub async fn deposit(
         &self,
-        address: TransparentAddress,
+        address: Address,
         amount: bitcoin::Amount,
         fee: bitcoin::Amount,
     ) -> Result<bitcoin::Txid, Error> {
@@ -624,12 +835,18 @@ impl App {
             .create_deposit_tx(address, amount.to_sat(), fee.to_sat())
             .await?;
         drop(miner_write);
+        let () = self.wallet.put_deposit(&WalletDeposit {
+            txid,
+            address,
+            value: amount,
+            fee,
+        })?;
         Ok(txid)
     }
 
     pub fn deposit_blocking(
         &self,
-        address: TransparentAddress,
+        address: Address,
         amount: bitcoin::Amount,
         fee: bitcoin::Amount,
     ) -> Result<bitcoin::Txid, Error> {
@@ -639,6 +856,9 @@ impl App {
 
 impl Drop for App {
     fn drop(&mut self) {
-        self.task.abort()
+        self.task.abort();
+        if let Some(audit_treasury_task) = &self.audit_treasury_task {
+            audit_treasury_task.abort()
+        }
     }
 }
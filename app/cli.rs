@@ -1,12 +1,16 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroU32,
     ops::Deref,
     path::PathBuf,
     sync::LazyLock,
 };
 
 use clap::{Arg, Parser};
-use thunder_orchard::types::{Network, THIS_SIDECHAIN};
+use thunder_orchard::{
+    archive, net, node, state,
+    types::{Network, SnapshotHash, THIS_SIDECHAIN},
+};
 
 use crate::util::saturating_pred_level;
 
@@ -98,12 +102,54 @@ impl clap::Args for DatadirArg {
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 pub(super) struct Cli {
+    /// Only accept connections with peers that have this public key.
+    /// May be specified multiple times.
+    /// If not specified, connections with any peer are accepted.
+    #[arg(long = "allow-peer", value_name = "PUBLIC_KEY")]
+    allow_peers: Vec<net::NodePublicKey>,
+    /// Connect to this peer on startup. May be specified multiple times.
+    #[arg(long = "addnode", value_name = "ADDR")]
+    add_nodes: Vec<SocketAddr>,
+    /// Reconcile the sidechain treasury UTXO on the mainchain with the
+    /// sidechain state after the tip changes, and log an error on any
+    /// discrepancy. Audits are skipped while the sidechain is not synced.
+    #[arg(long)]
+    audit_treasury: bool,
+    /// Do not store the full UTXO set. Only the Utreexo accumulator, Orchard
+    /// state, and deposit and withdrawal data are stored, and blocks are
+    /// validated using spent outputs relayed by peers.
+    /// A compact node cannot validate unconfirmed transactions.
+    /// Must be set when the data directory is created, and on every start.
+    #[arg(long)]
+    compact_state: bool,
+    /// Only connect to peers specified with `--addnode` on startup, and
+    /// refuse inbound connections.
+    /// Known peers and seed nodes are not connected to.
+    #[arg(long)]
+    connect_only: bool,
     /// Data directory for storing blockchain and wallet data
     #[command(flatten)]
     datadir: DatadirArg,
+    /// On startup, write all blocks in the current chain to a block file.
+    /// If `--import-blocks` is also set, blocks are exported after they
+    /// have been imported.
+    #[arg(long, value_name = "PATH")]
+    export_blocks: Option<PathBuf>,
     /// If specified, the gui will not launch.
     #[arg(long)]
     headless: bool,
+    /// On startup, read blocks from a block file, and validate and connect
+    /// them as if they were received from a peer
+    #[arg(long, value_name = "PATH")]
+    import_blocks: Option<PathBuf>,
+    /// Start from a state snapshot file, instead of syncing the full
+    /// history. Ignored if the state is not empty.
+    /// The snapshot is applied once its tip header has been synced. If the
+    /// node is restarted before then, this must be set again.
+    /// History below the snapshot is trusted, and is not validated in the
+    /// background.
+    #[arg(long, value_name = "PATH")]
+    load_snapshot: Option<PathBuf>,
     /// Directory in which to store log files.
     /// Defaults to `<DATADIR>/logs/v<VERSION>`, where `<DATADIR>` is
     /// thunder-orchard's data directory, and `<VERSION>` is the
@@ -125,6 +171,43 @@ pub(super) struct Cli {
     #[arg(default_value = "http://localhost:50051", long)]
     mainchain_grpc_url: url::Url,
 
+    /// Maximum P2P download rate from all peers, in bytes per second
+    #[arg(long)]
+    max_download_rate: Option<NonZeroU32>,
+    /// Maximum P2P download rate from a single peer, in bytes per second
+    #[arg(long)]
+    max_download_rate_per_peer: Option<NonZeroU32>,
+    /// Maximum number of inbound P2P connections.
+    /// When full, the least useful inbound peer is evicted to make room for
+    /// a new connection.
+    #[arg(default_value_t = net::Config::DEFAULT_MAX_INBOUND, long)]
+    max_inbound: usize,
+    /// Maximum number of inbound P2P connections from a single IP address.
+    /// Loopback addresses are exempt.
+    #[arg(default_value_t = net::Config::DEFAULT_MAX_INBOUND_PER_IP, long)]
+    max_inbound_per_ip: usize,
+    /// Maximum number of inbound P2P connections from a single subnet
+    /// (/16 for IPv4, /32 for IPv6).
+    /// Loopback addresses are exempt.
+    #[arg(default_value_t = net::Config::DEFAULT_MAX_INBOUND_PER_SUBNET, long)]
+    max_inbound_per_subnet: usize,
+    /// Maximum number of outbound P2P connections
+    #[arg(default_value_t = net::Config::DEFAULT_MAX_OUTBOUND, long)]
+    max_outbound: usize,
+    /// Maximum P2P upload rate to all peers, in bytes per second
+    #[arg(long)]
+    max_upload_rate: Option<NonZeroU32>,
+    /// Maximum P2P upload rate to a single peer, in bytes per second
+    #[arg(long)]
+    max_upload_rate_per_peer: Option<NonZeroU32>,
+    /// Try to keep P2P uploads under this many bytes, over a rolling 24 hour
+    /// window. Once reached, only recent blocks are served to peers.
+    #[arg(long)]
+    max_upload_target: Option<u64>,
+    /// Report any pending database migrations for the node and wallet, and
+    /// exit without modifying either
+    #[arg(long)]
+    migrate_dry_run: bool,
     /// Path to a mnemonic seed phrase
     #[arg(long)]
     mnemonic_seed_phrase_path: Option<PathBuf>,
@@ -134,24 +217,60 @@ pub(super) struct Cli {
     /// Set the network. Setting this may affect other defaults.
     #[arg(default_value_t, long, value_enum)]
     network: Network,
+    /// Delete old block bodies, keeping either this many blocks below the
+    /// tip (eg. `1000`), or approximately this many megabytes of recent
+    /// blocks (eg. `550MB`).
+    /// At least 288 blocks are always kept.
+    /// A pruned node cannot re-org deeper than the kept blocks, or reindex.
+    #[arg(long, value_name = "TARGET")]
+    prune: Option<archive::PruneTarget>,
+    /// When pruning, also delete transaction index entries for pruned
+    /// blocks
+    #[arg(long, requires = "prune")]
+    prune_tx_index: bool,
+    /// Wipe the state and mempool on startup, and rebuild them by
+    /// re-applying the current chain from stored blocks
+    #[arg(long)]
+    reindex: bool,
     /// Socket address to host the RPC server
     #[arg(default_value_t = DEFAULT_RPC_ADDR, long, short)]
     rpc_addr: SocketAddr,
+    /// Trust the snapshot set with `--load-snapshot` if it has this hash,
+    /// even if it is not trusted for the network
+    #[arg(long, requires = "load_snapshot")]
+    snapshot_hash: Option<SnapshotHash>,
+    /// Number of recent blocks to verify against stored blocks on startup.
+    /// At least 288 blocks are verified after an unclean shutdown.
+    #[arg(default_value_t = node::verify::DEFAULT_DEPTH, long)]
+    verify_chain_depth: u32,
+    /// Action to take if the startup chain verification fails
+    #[arg(default_value_t, long, value_enum)]
+    verify_chain_recovery: node::verify::Recovery,
 }
 
 #[derive(Clone, Debug)]
 pub struct Config {
+    pub audit_treasury: bool,
+    pub compact_state: bool,
     pub datadir: PathBuf,
+    pub export_blocks: Option<PathBuf>,
     pub headless: bool,
+    pub import_blocks: Option<PathBuf>,
     /// If None, logging to file should be disabled.
     pub log_dir: Option<PathBuf>,
     pub log_level: tracing::Level,
     pub log_level_file: tracing::Level, // Level for logs that get written to file
     pub mainchain_grpc_url: url::Url,
+    pub migrate_dry_run: bool,
     pub mnemonic_seed_phrase_path: Option<PathBuf>,
     pub net_addr: SocketAddr,
+    pub net_config: net::Config,
     pub network: Network,
+    pub prune: Option<archive::PruneConfig>,
+    pub reindex: bool,
     pub rpc_addr: SocketAddr,
+    pub snapshot: Option<state::snapshot::LoadConfig>,
+    pub verify_chain: node::verify::Config,
 }
 
 impl Cli {
@@ -178,16 +297,54 @@ impl Cli {
             saturating_pred_level(self.log_level)
         };
         Ok(Config {
+            audit_treasury: self.audit_treasury,
+            compact_state: self.compact_state,
             datadir: self.datadir.0,
+            export_blocks: self.export_blocks,
             headless: self.headless,
+            import_blocks: self.import_blocks,
             log_dir,
             log_level,
             log_level_file: self.log_level_file,
             mainchain_grpc_url: self.mainchain_grpc_url,
+            migrate_dry_run: self.migrate_dry_run,
             mnemonic_seed_phrase_path: self.mnemonic_seed_phrase_path,
             net_addr: self.net_addr,
+            net_config: net::Config {
+                max_inbound: self.max_inbound,
+                max_outbound: self.max_outbound,
+                max_inbound_per_ip: self.max_inbound_per_ip,
+                max_inbound_per_subnet: self.max_inbound_per_subnet,
+                add_nodes: self.add_nodes,
+                connect_only: self.connect_only,
+                allowlist: if self.allow_peers.is_empty() {
+                    None
+                } else {
+                    Some(self.allow_peers.into_iter().collect())
+                },
+                max_upload_rate: self.max_upload_rate,
+                max_upload_rate_per_peer: self.max_upload_rate_per_peer,
+                max_download_rate: self.max_download_rate,
+                max_download_rate_per_peer: self.max_download_rate_per_peer,
+                max_upload_target: self.max_upload_target,
+            },
             network: self.network,
+            prune: self.prune.map(|target| archive::PruneConfig {
+                target,
+                prune_tx_inclusions: self.prune_tx_index,
+            }),
+            reindex: self.reindex,
             rpc_addr: self.rpc_addr,
+            snapshot: self.load_snapshot.map(|path| {
+                state::snapshot::LoadConfig {
+                    path,
+                    trusted_hash: self.snapshot_hash,
+                }
+            }),
+            verify_chain: node::verify::Config {
+                depth: self.verify_chain_depth,
+                recovery: self.verify_chain_recovery,
+            },
         })
     }
 }
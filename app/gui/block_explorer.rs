@@ -3,7 +3,7 @@ use eframe::egui;
 use human_size::{Byte, Kibibyte, Mebibyte, SpecificSize};
 use thunder_orchard::{
     state::State,
-    types::{Body, GetValue, Header},
+    types::{Body, ChainTip, GetValue, Header},
 };
 
 use crate::app::App;
@@ -14,6 +14,36 @@ pub struct BlockExplorer {
     height: u32,
 }
 
+fn show_chain_tips(chain_tips: &[ChainTip], ui: &mut egui::Ui) {
+    egui::Grid::new("chain_tips").striped(true).show(ui, |ui| {
+        ui.monospace("block hash");
+        ui.monospace("height");
+        ui.monospace("branch length");
+        ui.monospace("status");
+        ui.monospace("BMM verification");
+        ui.monospace("total work");
+        ui.end_row();
+        for chain_tip in chain_tips {
+            ui.monospace_selectable_singleline(
+                false,
+                format!("{}", chain_tip.block_hash),
+            );
+            ui.monospace(format!("{}", chain_tip.height));
+            ui.monospace(format!("{}", chain_tip.branch_len));
+            ui.monospace(format!("{:?}", chain_tip.status));
+            ui.monospace(match chain_tip.main_block_hash {
+                Some(main_block_hash) => format!("{main_block_hash}"),
+                None => "-".to_owned(),
+            });
+            ui.monospace(match chain_tip.total_work {
+                Some(total_work) => format!("{total_work:x}"),
+                None => "-".to_owned(),
+            });
+            ui.end_row();
+        }
+    });
+}
+
 impl BlockExplorer {
     pub fn new(height: u32) -> Self {
         Self { height }
@@ -110,6 +140,15 @@ impl BlockExplorer {
                     ));
                 }
             }
+            if let Some(app) = app
+                && let Ok(chain_tips) = app.node.get_chain_tips()
+            {
+                ui.separator();
+                ui.heading("Chain tips");
+                egui::ScrollArea::horizontal()
+                    .id_salt("chain_tips")
+                    .show(ui, |ui| show_chain_tips(&chain_tips, ui));
+            }
         });
     }
 }
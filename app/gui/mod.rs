@@ -14,6 +14,7 @@ mod fonts;
 mod mempool_explorer;
 mod miner;
 mod parent_chain;
+mod peers;
 mod seed;
 mod util;
 mod withdrawals;
@@ -25,6 +26,7 @@ use fonts::FONT_DEFINITIONS;
 use mempool_explorer::MemPoolExplorer;
 use miner::Miner;
 use parent_chain::ParentChain;
+use peers::Peers;
 use seed::SetSeed;
 use withdrawals::Withdrawals;
 
@@ -39,6 +41,7 @@ pub struct EguiApp {
     mempool_explorer: MemPoolExplorer,
     miner: Miner,
     parent_chain: ParentChain,
+    peers: Peers,
     set_seed: SetSeed,
     tab: Tab,
     withdrawals: Withdrawals,
@@ -57,6 +60,8 @@ enum Tab {
     BlockExplorer,
     #[strum(to_string = "Withdrawals")]
     Withdrawals,
+    #[strum(to_string = "Peers")]
+    Peers,
     #[strum(to_string = "Console / Logs")]
     ConsoleLogs,
 }
@@ -213,6 +218,7 @@ impl EguiApp {
             mempool_explorer: MemPoolExplorer::default(),
             miner: Miner::default(),
             parent_chain,
+            peers: Peers::default(),
             set_seed: SetSeed::default(),
             tab: Tab::default(),
             withdrawals: Withdrawals::default(),
@@ -264,6 +270,9 @@ impl eframe::App for EguiApp {
                     Tab::Withdrawals => {
                         self.withdrawals.show(self.app.as_ref(), ui);
                     }
+                    Tab::Peers => {
+                        self.peers.show(self.app.as_ref(), ui);
+                    }
                     Tab::ConsoleLogs => {
                         self.console_logs.show(self.app.as_ref(), ui);
                     }
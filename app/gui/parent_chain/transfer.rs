@@ -1,4 +1,5 @@
 use eframe::egui::{self, Button};
+use thunder_orchard::{types::Address, wallet::WalletDeposit};
 
 use crate::app::App;
 
@@ -6,7 +7,11 @@ use crate::app::App;
 pub struct Deposit {
     amount: String,
     fee: String,
+    /// Deposit to a new shielded address
+    shielded: bool,
     promise: Option<poll_promise::Promise<Result<bitcoin::Txid, String>>>,
+    /// Cached pending deposits, refreshed on demand
+    pending_deposits: Option<Result<Vec<WalletDeposit>, String>>,
 }
 
 impl std::fmt::Debug for Deposit {
@@ -14,12 +19,63 @@ impl std::fmt::Debug for Deposit {
         f.debug_struct("Deposit")
             .field("amount", &self.amount)
             .field("fee", &self.fee)
+            .field("shielded", &self.shielded)
             .field("promise_active", &self.promise.is_some())
+            .field("pending_deposits", &self.pending_deposits)
             .finish()
     }
 }
 
 impl Deposit {
+    fn show_pending_deposits(&mut self, app: Option<&App>, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.heading("Pending deposits");
+            if ui
+                .add_enabled(app.is_some(), Button::new("refresh"))
+                .clicked()
+            {
+                self.pending_deposits = None;
+            }
+        });
+        let Some(app) = app else {
+            ui.label("Unavailable");
+            return;
+        };
+        let pending_deposits = self.pending_deposits.get_or_insert_with(|| {
+            app.list_pending_deposits().map_err(|err| {
+                let err = anyhow::Error::from(err);
+                format!("{err:#}")
+            })
+        });
+        match pending_deposits {
+            Ok(deposits) if deposits.is_empty() => {
+                ui.label("No pending deposits");
+            }
+            Ok(deposits) => {
+                egui::Grid::new("pending_deposits").striped(true).show(
+                    ui,
+                    |ui| {
+                        ui.monospace("txid");
+                        ui.monospace("address");
+                        ui.monospace("value");
+                        ui.monospace("fee");
+                        ui.end_row();
+                        for deposit in deposits.iter() {
+                            ui.monospace(format!("{}", deposit.txid));
+                            ui.monospace(format!("{}", deposit.address));
+                            ui.monospace(format!("{}", deposit.value));
+                            ui.monospace(format!("{}", deposit.fee));
+                            ui.end_row();
+                        }
+                    },
+                );
+            }
+            Err(err) => {
+                ui.label(format!("Failed to list pending deposits: {err}"));
+            }
+        }
+    }
+
     pub fn show(&mut self, app: Option<&App>, ui: &mut egui::Ui) {
         if let Some(promise) = &self.promise {
             match promise.ready() {
@@ -71,6 +127,7 @@ impl Deposit {
             })
             .response
         });
+        ui.checkbox(&mut self.shielded, "shielded");
         let amount = bitcoin::Amount::from_str_in(
             &self.amount,
             bitcoin::Denomination::Bitcoin,
@@ -91,14 +148,22 @@ impl Deposit {
             let amount = amount.expect("should not happen");
             let fee = fee.expect("should not happen");
 
-            let get_new_transparent_address = || {
+            let get_new_address = || {
                 let mut rwtxn = app.wallet.env().write_txn()?;
-                let res = app.wallet.get_new_transparent_address(&mut rwtxn)?;
+                let res = if self.shielded {
+                    Address::Shielded(
+                        app.wallet.get_new_orchard_address(&mut rwtxn)?,
+                    )
+                } else {
+                    Address::Transparent(
+                        app.wallet.get_new_transparent_address(&mut rwtxn)?,
+                    )
+                };
                 rwtxn.commit()?;
                 Ok::<_, thunder_orchard::wallet::Error>(res)
             };
 
-            match get_new_transparent_address() {
+            match get_new_address() {
                 Ok(address) => {
                     self.promise =
                         Some(poll_promise::Promise::spawn_async(async move {
@@ -112,6 +177,8 @@ impl Deposit {
                 }
             }
         }
+        ui.separator();
+        self.show_pending_deposits(app, ui);
     }
 }
 
@@ -121,6 +188,8 @@ pub struct Withdrawal {
     amount: String,
     fee: String,
     mainchain_fee: String,
+    /// Pay the withdrawal from shielded funds
+    shielded: bool,
     generate_promise: Option<
         poll_promise::Promise<
             Result<bitcoin::Address<bitcoin::address::NetworkChecked>, String>,
@@ -135,6 +204,7 @@ impl std::fmt::Debug for Withdrawal {
             .field("amount", &self.amount)
             .field("fee", &self.fee)
             .field("mainchain_fee", &self.mainchain_fee)
+            .field("shielded", &self.shielded)
             .field("generate_active", &self.generate_promise.is_some())
             .finish()
     }
@@ -146,15 +216,26 @@ fn create_withdrawal(
     amount: bitcoin::Amount,
     fee: bitcoin::Amount,
     mainchain_fee: bitcoin::Amount,
+    shielded: bool,
 ) -> anyhow::Result<()> {
     let accumulator = app.node.get_tip_accumulator()?;
-    let tx = app.wallet.create_withdrawal(
-        &accumulator,
-        mainchain_address,
-        amount,
-        mainchain_fee,
-        fee,
-    )?;
+    let tx = if shielded {
+        app.wallet.create_shielded_withdrawal(
+            &accumulator,
+            mainchain_address,
+            amount,
+            mainchain_fee,
+            fee,
+        )?
+    } else {
+        app.wallet.create_withdrawal(
+            &accumulator,
+            mainchain_address,
+            amount,
+            mainchain_fee,
+            fee,
+        )?
+    };
     app.sign_and_send(tx)?;
     Ok(())
 }
@@ -237,6 +318,7 @@ impl Withdrawal {
             })
             .response
         });
+        ui.checkbox(&mut self.shielded, "pay from shielded funds");
         let mainchain_address: Option<
             bitcoin::Address<bitcoin::address::NetworkUnchecked>,
         > = self.mainchain_address.parse().ok();
@@ -270,6 +352,7 @@ impl Withdrawal {
                 amount.expect("should not happen"),
                 fee.expect("should not happen"),
                 mainchain_fee.expect("should not happen"),
+                self.shielded,
             ) {
                 tracing::error!("{err:#}");
             } else {
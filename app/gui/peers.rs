@@ -0,0 +1,157 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eframe::egui;
+use human_size::{Byte, Gibibyte, Kibibyte, Mebibyte, SpecificSize};
+use thunder_orchard::net::Peer;
+
+use crate::app::App;
+
+fn show_bytes(bytes: u64) -> String {
+    let Ok(size) = SpecificSize::new(bytes as f64, Byte) else {
+        return String::new();
+    };
+    if bytes < 1024 {
+        format!("{size}")
+    } else if bytes < 1024 * 1024 {
+        let size: SpecificSize<Kibibyte> = size.into();
+        format!("{size}")
+    } else if bytes < 1024 * 1024 * 1024 {
+        let size: SpecificSize<Mebibyte> = size.into();
+        format!("{size}")
+    } else {
+        let size: SpecificSize<Gibibyte> = size.into();
+        format!("{size}")
+    }
+}
+
+fn show_connected_for(connected_at: Option<u64>) -> String {
+    let Some(connected_at) = connected_at else {
+        return "-".to_owned();
+    };
+    let connected_at = UNIX_EPOCH + Duration::from_secs(connected_at);
+    let secs = SystemTime::now()
+        .duration_since(connected_at)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{}h {}m {}s", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+#[derive(Default)]
+pub struct Peers {
+    selected: Option<SocketAddr>,
+}
+
+impl Peers {
+    fn show_peers(&mut self, peers: &[Peer], ui: &mut egui::Ui) {
+        egui::Grid::new("peers").striped(true).show(ui, |ui| {
+            ui.monospace("address");
+            ui.monospace("status");
+            ui.monospace("direction");
+            ui.monospace("connected for");
+            ui.monospace("heartbeat rtt");
+            ui.monospace("version");
+            ui.monospace("height");
+            ui.monospace("tip");
+            ui.monospace("sent");
+            ui.monospace("received");
+            ui.monospace("queued requests");
+            ui.monospace("in-flight requests");
+            ui.end_row();
+            for peer in peers {
+                ui.selectable_value(
+                    &mut self.selected,
+                    Some(peer.address),
+                    format!("{}", peer.address),
+                );
+                ui.monospace(format!("{}", peer.status));
+                ui.monospace(format!("{}", peer.direction));
+                ui.monospace(show_connected_for(peer.connected_at));
+                ui.monospace(match peer.heartbeat_rtt_ms {
+                    Some(rtt_ms) => format!("{rtt_ms:.1}ms"),
+                    None => "-".to_owned(),
+                });
+                ui.monospace(match peer.version {
+                    Some(version) => format!("{version}"),
+                    None => "-".to_owned(),
+                });
+                ui.monospace(match peer.height {
+                    Some(height) => format!("{height}"),
+                    None => "-".to_owned(),
+                });
+                ui.monospace(match peer.tip {
+                    Some(tip) => format!("{tip}")[0..8].to_owned(),
+                    None => "-".to_owned(),
+                });
+                let bytes_sent =
+                    peer.messages.values().map(|stats| stats.bytes_sent).sum();
+                ui.monospace(show_bytes(bytes_sent));
+                let bytes_received = peer
+                    .messages
+                    .values()
+                    .map(|stats| stats.bytes_received)
+                    .sum();
+                ui.monospace(show_bytes(bytes_received));
+                ui.monospace(format!("{}", peer.queued_requests));
+                ui.monospace(format!("{}", peer.in_flight_requests));
+                ui.end_row();
+            }
+        });
+    }
+
+    fn show_peer(peer: &Peer, ui: &mut egui::Ui) {
+        ui.heading(format!("{}", peer.address));
+        if let Some(tip) = peer.tip {
+            ui.monospace(format!("Tip:            {tip}"));
+        }
+        if let Some(main_tip) = peer.main_tip {
+            ui.monospace(format!("Mainchain tip:  {main_tip}"));
+        }
+        if let Some(total_work) = peer.total_work {
+            ui.monospace(format!("Total work:     {total_work}"));
+        }
+        ui.separator();
+        egui::Grid::new("peer_messages")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.monospace("message type");
+                ui.monospace("messages sent");
+                ui.monospace("bytes sent");
+                ui.monospace("messages received");
+                ui.monospace("bytes received");
+                ui.end_row();
+                for (message_type, stats) in &peer.messages {
+                    ui.monospace(message_type);
+                    ui.monospace(format!("{}", stats.messages_sent));
+                    ui.monospace(show_bytes(stats.bytes_sent));
+                    ui.monospace(format!("{}", stats.messages_received));
+                    ui.monospace(show_bytes(stats.bytes_received));
+                    ui.end_row();
+                }
+            });
+    }
+
+    pub fn show(&mut self, app: Option<&App>, ui: &mut egui::Ui) {
+        let mut peers = app
+            .map(|app| app.node.get_active_peers())
+            .unwrap_or_default();
+        peers.sort_by_key(|peer| peer.address);
+        egui::ScrollArea::both().show(ui, |ui| {
+            ui.heading("Peers");
+            ui.separator();
+            if peers.is_empty() {
+                ui.label("No peers");
+                return;
+            }
+            self.show_peers(&peers, ui);
+            if let Some(peer) = self.selected.and_then(|selected| {
+                peers.iter().find(|p| p.address == selected)
+            }) {
+                ui.separator();
+                Self::show_peer(peer, ui);
+            }
+        });
+    }
+}
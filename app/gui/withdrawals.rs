@@ -1,13 +1,26 @@
 use eframe::egui;
-use thunder_orchard::types::GetValue;
+use thunder_orchard::types::{
+    GetValue, OutputContent, WithdrawalBundleInclusion,
+};
 
 use crate::app::App;
 
 #[derive(Default)]
 pub struct Withdrawals {}
 
+/// Format the status history of a withdrawal bundle, eg.
+/// `Pending (10) -> Submitted (12)`
+fn format_status_history(inclusion: &WithdrawalBundleInclusion) -> String {
+    inclusion
+        .status_history
+        .iter()
+        .map(|update| format!("{:?} ({})", update.status, update.height))
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
 impl Withdrawals {
-    pub fn show(&mut self, app: Option<&App>, ui: &mut egui::Ui) {
+    fn show_pending_bundle(&mut self, app: Option<&App>, ui: &mut egui::Ui) {
         ui.heading("Pending withdrawals");
         let bundle = app.and_then(|app| {
             app.node.try_get_pending_withdrawal_bundle().ok().flatten()
@@ -28,4 +41,88 @@ impl Withdrawals {
             ui.label("No pending bundle");
         }
     }
+
+    fn show_wallet_withdrawals(
+        &mut self,
+        app: Option<&App>,
+        ui: &mut egui::Ui,
+    ) {
+        ui.heading("Wallet withdrawals");
+        let Some(app) = app else {
+            ui.label("Unavailable");
+            return;
+        };
+        let withdrawals = match app.list_withdrawals() {
+            Ok(withdrawals) => withdrawals,
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                ui.label(format!("Failed to list withdrawals: {err:#}"));
+                return;
+            }
+        };
+        if withdrawals.is_empty() {
+            ui.label("No withdrawals");
+            return;
+        }
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("wallet_withdrawals").striped(true).show(
+                ui,
+                |ui| {
+                    ui.monospace("outpoint");
+                    ui.monospace("mainchain address");
+                    ui.monospace("value");
+                    ui.monospace("mainchain fee");
+                    ui.monospace("bundle M6id");
+                    ui.monospace("status history");
+                    ui.monospace("funds reinstated");
+                    ui.end_row();
+                    for withdrawal in &withdrawals {
+                        let OutputContent::Withdrawal {
+                            value,
+                            main_fee,
+                            ref main_address,
+                        } = withdrawal.output.output.content
+                        else {
+                            continue;
+                        };
+                        ui.monospace(format!("{}", withdrawal.output.outpoint));
+                        ui.monospace(
+                            main_address.assume_checked_ref().to_string(),
+                        );
+                        ui.monospace(format!("{value}"));
+                        ui.monospace(format!("{main_fee}"));
+                        match withdrawal.bundles.last() {
+                            Some(inclusion) => {
+                                ui.monospace(format!("{}", inclusion.m6id));
+                                ui.monospace(format_status_history(inclusion));
+                                ui.monospace(
+                                    match inclusion.funds_reinstated {
+                                        Some(true) => "Yes",
+                                        Some(false) => "No",
+                                        None => "-",
+                                    },
+                                );
+                            }
+                            None => {
+                                ui.monospace("-");
+                                ui.monospace(if withdrawal.confirmed {
+                                    "Awaiting bundle"
+                                } else {
+                                    "Unconfirmed"
+                                });
+                                ui.monospace("-");
+                            }
+                        }
+                        ui.end_row();
+                    }
+                },
+            );
+        });
+    }
+
+    pub fn show(&mut self, app: Option<&App>, ui: &mut egui::Ui) {
+        self.show_pending_bundle(app, ui);
+        ui.separator();
+        self.show_wallet_withdrawals(app, ui);
+    }
 }
@@ -211,6 +211,11 @@ fn main() -> anyhow::Result<()> {
         config.log_level_file,
     )?;
 
+    if config.migrate_dry_run {
+        let () = app::App::migration_dry_run(&config)?;
+        return Ok(());
+    }
+
     let (app_tx, app_rx) = oneshot::channel::<anyhow::Error>();
 
     let app = app::App::new(&config).inspect(|app| {
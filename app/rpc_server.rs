@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
 use bitcoin::Amount;
 use jsonrpsee::{
@@ -7,14 +7,24 @@ use jsonrpsee::{
     types::ErrorObject,
 };
 use thunder_orchard::{
-    net::Peer,
+    net::{NodePublicKey, Peer},
+    node::{
+        treasury_audit::Report as TreasuryAuditReport,
+        verify::Report as VerifyChainReport,
+    },
+    state::snapshot::LoadConfig as LoadSnapshotConfig,
     types::{
-        PointedOutput, ShieldedAddress, SpentOutput, TransparentAddress, Txid,
-        WithdrawalBundle,
+        Address, BlockHash, BlockInfo, ChainTip, DepositInfo, HeaderInfo, M6id,
+        OutPoint, PointedOutput, ShieldedAddress, SnapshotHash, SpentOutput,
+        TransparentAddress, Txid, WithdrawalBundle, WithdrawalBundleHistory,
+        WithdrawalBundleStatus,
     },
-    wallet::Balance,
+    wallet::{Balance, WalletDeposit, WalletWithdrawal},
+};
+use thunder_orchard_app_rpc_api::{
+    GetTransactionResponse, MAX_GET_BLOCKS_COUNT, MAX_GET_HEADERS_COUNT,
+    RpcServer,
 };
-use thunder_orchard_app_rpc_api::{GetTransactionResponse, RpcServer};
 use tower_http::{
     cors::CorsLayer,
     request_id::{
@@ -42,10 +52,48 @@ where
 }
 #[async_trait]
 impl RpcServer for RpcServerImpl {
+    async fn audit_treasury(&self) -> RpcResult<TreasuryAuditReport> {
+        self.app.node.audit_treasury().await.map_err(custom_err)
+    }
+
     async fn balance(&self) -> RpcResult<Balance> {
         self.app.wallet.get_balance().map_err(custom_err)
     }
 
+    async fn cancel_withdrawal(
+        &self,
+        outpoint: OutPoint,
+        fee_sats: u64,
+    ) -> RpcResult<Txid> {
+        if let Some(bundle) = self
+            .app
+            .node
+            .try_get_pending_withdrawal_bundle()
+            .map_err(custom_err)?
+            && bundle.spend_utxos().contains_key(&outpoint)
+        {
+            return Err(custom_err_msg(format!(
+                "withdrawal {outpoint} is already bundled in pending \
+                 withdrawal bundle {}, and cannot be cancelled",
+                bundle.compute_m6id()
+            )));
+        }
+        let accumulator =
+            self.app.node.get_tip_accumulator().map_err(custom_err)?;
+        let tx = self
+            .app
+            .wallet
+            .create_withdrawal_cancellation(
+                &accumulator,
+                outpoint,
+                Amount::from_sat(fee_sats),
+            )
+            .map_err(custom_err)?;
+        let txid = tx.txid();
+        self.app.sign_and_send(tx).map_err(custom_err)?;
+        Ok(txid)
+    }
+
     async fn create_deposit(
         &self,
         address: TransparentAddress,
@@ -55,7 +103,26 @@ impl RpcServer for RpcServerImpl {
         let app = self.app.clone();
         tokio::task::spawn_blocking(move || {
             app.deposit_blocking(
-                address,
+                Address::Transparent(address),
+                bitcoin::Amount::from_sat(value_sats),
+                bitcoin::Amount::from_sat(fee_sats),
+            )
+            .map_err(custom_err)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn create_shielded_deposit(
+        &self,
+        address: ShieldedAddress,
+        value_sats: u64,
+        fee_sats: u64,
+    ) -> RpcResult<bitcoin::Txid> {
+        let app = self.app.clone();
+        tokio::task::spawn_blocking(move || {
+            app.deposit_blocking(
+                Address::Shielded(address),
                 bitcoin::Amount::from_sat(value_sats),
                 bitcoin::Amount::from_sat(fee_sats),
             )
@@ -69,6 +136,32 @@ impl RpcServer for RpcServerImpl {
         self.app.node.connect_peer(addr).map_err(custom_err)
     }
 
+    async fn dump_state_snapshot(
+        &self,
+        path: PathBuf,
+    ) -> RpcResult<SnapshotHash> {
+        let node = self.app.node.clone();
+        tokio::task::spawn_blocking(move || {
+            node.dump_state_snapshot(&path).map_err(custom_err)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn export_blocks(
+        &self,
+        path: PathBuf,
+        from: u32,
+        to: u32,
+    ) -> RpcResult<u32> {
+        let node = self.app.node.clone();
+        tokio::task::spawn_blocking(move || {
+            node.export_blocks(&path, from, to).map_err(custom_err)
+        })
+        .await
+        .unwrap()
+    }
+
     async fn forget_peer(&self, addr: SocketAddr) -> RpcResult<()> {
         match self.app.node.forget_peer(&addr) {
             Ok(_) => Ok(()),
@@ -84,6 +177,14 @@ impl RpcServer for RpcServerImpl {
         Ok(deposit_address)
     }
 
+    async fn format_shielded_deposit_address(
+        &self,
+        address: ShieldedAddress,
+    ) -> RpcResult<String> {
+        let deposit_address = address.format_for_deposit();
+        Ok(deposit_address)
+    }
+
     async fn generate_mnemonic(&self) -> RpcResult<String> {
         let mnemonic = bip39::Mnemonic::new(
             bip39::MnemonicType::Words12,
@@ -109,6 +210,29 @@ impl RpcServer for RpcServerImpl {
         Ok(Some(block))
     }
 
+    async fn get_block_hash(
+        &self,
+        height: u32,
+    ) -> RpcResult<Option<thunder_orchard::types::BlockHash>> {
+        self.app.node.try_get_block_hash(height).map_err(custom_err)
+    }
+
+    async fn get_blocks(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> RpcResult<Vec<BlockInfo>> {
+        if count > MAX_GET_BLOCKS_COUNT {
+            return Err(custom_err_msg(format!(
+                "count must not exceed {MAX_GET_BLOCKS_COUNT}"
+            )));
+        }
+        self.app
+            .node
+            .get_block_infos(start_height, count)
+            .map_err(custom_err)
+    }
+
     async fn get_best_sidechain_block_hash(
         &self,
     ) -> RpcResult<Option<thunder_orchard::types::BlockHash>> {
@@ -148,6 +272,36 @@ impl RpcServer for RpcServerImpl {
             .map_err(custom_err)
     }
 
+    async fn get_chain_tips(&self) -> RpcResult<Vec<ChainTip>> {
+        self.app.node.get_chain_tips().map_err(custom_err)
+    }
+
+    async fn get_header(
+        &self,
+        block_hash: thunder_orchard::types::BlockHash,
+    ) -> RpcResult<Option<HeaderInfo>> {
+        self.app
+            .node
+            .try_get_header_info(block_hash)
+            .map_err(custom_err)
+    }
+
+    async fn get_headers(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> RpcResult<Vec<HeaderInfo>> {
+        if count > MAX_GET_HEADERS_COUNT {
+            return Err(custom_err_msg(format!(
+                "count must not exceed {MAX_GET_HEADERS_COUNT}"
+            )));
+        }
+        self.app
+            .node
+            .get_header_infos(start_height, count)
+            .map_err(custom_err)
+    }
+
     async fn get_new_shielded_address(&self) -> RpcResult<ShieldedAddress> {
         (|| {
             let mut rwtxn = self.app.wallet.env().write_txn()?;
@@ -298,12 +452,37 @@ impl RpcServer for RpcServerImpl {
         Ok(utxos)
     }
 
+    async fn get_withdrawal_bundle(
+        &self,
+        m6id: M6id,
+    ) -> RpcResult<Option<WithdrawalBundleHistory>> {
+        self.app
+            .node
+            .try_get_withdrawal_bundle(m6id)
+            .map_err(custom_err)
+    }
+
     async fn getblockcount(&self) -> RpcResult<u32> {
         let height = self.app.node.try_get_height().map_err(custom_err)?;
         let block_count = height.map_or(0, |height| height + 1);
         Ok(block_count)
     }
 
+    async fn import_blocks(&self, path: PathBuf) -> RpcResult<u32> {
+        self.app.node.import_blocks(&path).await.map_err(custom_err)
+    }
+
+    async fn invalidate_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> RpcResult<Option<BlockHash>> {
+        self.app
+            .node
+            .invalidate_block(block_hash)
+            .await
+            .map_err(custom_err)
+    }
+
     async fn latest_failed_withdrawal_bundle_height(
         &self,
     ) -> RpcResult<Option<u32>> {
@@ -315,6 +494,36 @@ impl RpcServer for RpcServerImpl {
         Ok(height)
     }
 
+    async fn load_state_snapshot(
+        &self,
+        path: PathBuf,
+        trusted_hash: Option<SnapshotHash>,
+    ) -> RpcResult<SnapshotHash> {
+        let node = self.app.node.clone();
+        let config = LoadSnapshotConfig { path, trusted_hash };
+        tokio::task::spawn_blocking(move || {
+            node.load_state_snapshot(&config).map_err(custom_err)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn list_deposits(
+        &self,
+        address: Option<TransparentAddress>,
+        start_main_height: Option<u32>,
+        end_main_height: Option<u32>,
+    ) -> RpcResult<Vec<DepositInfo>> {
+        self.app
+            .node
+            .get_deposits(address, start_main_height, end_main_height)
+            .map_err(custom_err)
+    }
+
+    async fn list_pending_deposits(&self) -> RpcResult<Vec<WalletDeposit>> {
+        self.app.list_pending_deposits().map_err(custom_err)
+    }
+
     async fn list_peers(&self) -> RpcResult<Vec<Peer>> {
         let peers = self.app.node.get_active_peers();
         Ok(peers)
@@ -329,6 +538,22 @@ impl RpcServer for RpcServerImpl {
         Ok(res)
     }
 
+    async fn list_withdrawal_bundles(
+        &self,
+        status: Option<WithdrawalBundleStatus>,
+        start_height: Option<u32>,
+        end_height: Option<u32>,
+    ) -> RpcResult<Vec<WithdrawalBundleHistory>> {
+        self.app
+            .node
+            .get_withdrawal_bundles(status, start_height, end_height)
+            .map_err(custom_err)
+    }
+
+    async fn list_withdrawals(&self) -> RpcResult<Vec<WalletWithdrawal>> {
+        self.app.list_withdrawals().map_err(custom_err)
+    }
+
     async fn mine(&self, fee: Option<u64>) -> RpcResult<()> {
         let fee = fee.map(bitcoin::Amount::from_sat);
         self.app
@@ -350,12 +575,31 @@ impl RpcServer for RpcServerImpl {
             .map_err(custom_err)
     }
 
+    async fn node_public_key(&self) -> RpcResult<NodePublicKey> {
+        Ok(self.app.node.public_key())
+    }
+
     async fn openapi_schema(&self) -> RpcResult<utoipa::openapi::OpenApi> {
         let res =
             <thunder_orchard_app_rpc_api::RpcDoc as utoipa::OpenApi>::openapi();
         Ok(res)
     }
 
+    async fn reconsider_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> RpcResult<Option<BlockHash>> {
+        self.app
+            .node
+            .reconsider_block(block_hash)
+            .await
+            .map_err(custom_err)
+    }
+
+    async fn reindex(&self) -> RpcResult<Option<BlockHash>> {
+        self.app.node.reindex().await.map_err(custom_err)
+    }
+
     async fn remove_from_mempool(&self, txid: Txid) -> RpcResult<()> {
         self.app.node.remove_from_mempool(txid).map_err(custom_err)
     }
@@ -387,6 +631,15 @@ impl RpcServer for RpcServerImpl {
         Ok(txid)
     }
 
+    async fn shielded_pool_value_sats(&self) -> RpcResult<u64> {
+        let shielded_pool_value = self
+            .app
+            .node
+            .get_shielded_pool_value()
+            .map_err(custom_err)?;
+        Ok(shielded_pool_value.to_sat())
+    }
+
     async fn shielded_transfer(
         &self,
         dest: ShieldedAddress,
@@ -410,6 +663,30 @@ impl RpcServer for RpcServerImpl {
         Ok(txid)
     }
 
+    async fn shielded_withdraw(
+        &self,
+        mainchain_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
+        amount_sats: u64,
+        fee_sats: u64,
+        mainchain_fee_sats: u64,
+    ) -> RpcResult<Txid> {
+        let accumulator =
+            self.app.node.get_tip_accumulator().map_err(custom_err)?;
+        let tx = tokio::task::block_in_place(|| {
+            self.app.wallet.create_shielded_withdrawal(
+                &accumulator,
+                mainchain_address,
+                Amount::from_sat(amount_sats),
+                Amount::from_sat(mainchain_fee_sats),
+                Amount::from_sat(fee_sats),
+            )
+        })
+        .map_err(custom_err)?;
+        let txid = tx.txid();
+        self.app.sign_and_send(tx).map_err(custom_err)?;
+        Ok(txid)
+    }
+
     async fn sidechain_wealth_sats(&self) -> RpcResult<u64> {
         let sidechain_wealth =
             self.app.node.get_sidechain_wealth().map_err(custom_err)?;
@@ -463,6 +740,15 @@ impl RpcServer for RpcServerImpl {
         Ok(txid)
     }
 
+    async fn verify_chain(&self, depth: u32) -> RpcResult<VerifyChainReport> {
+        let node = self.app.node.clone();
+        tokio::task::spawn_blocking(move || {
+            node.verify_chain(depth).map_err(custom_err)
+        })
+        .await
+        .unwrap()
+    }
+
     async fn withdraw(
         &self,
         mainchain_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
@@ -1,18 +1,36 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use clap::{Parser, Subcommand};
 use http::HeaderMap;
 use jsonrpsee::{core::client::ClientT, http_client::HttpClientBuilder};
 
-use thunder_orchard::types::{ShieldedAddress, TransparentAddress, Txid};
+use thunder_orchard::types::{
+    M6id, OutPoint, ShieldedAddress, SnapshotHash, TransparentAddress, Txid,
+    WithdrawalBundleStatus,
+};
 use thunder_orchard_app_rpc_api::RpcClient;
 use tracing_subscriber::layer::SubscriberExt as _;
 
 #[derive(Clone, Debug, Subcommand)]
 #[command(arg_required_else_help(true))]
 pub enum Command {
+    /// Reconcile the sidechain treasury UTXO on the mainchain with the
+    /// sidechain state, and print the report
+    AuditTreasury,
     /// Get balance in sats
     Balance,
+    /// Cancel a withdrawal that has not been included in a withdrawal
+    /// bundle, returning the funds to a transparent address.
+    /// The fee is paid from the withdrawal.
+    CancelWithdrawal {
+        /// Txid of the transaction that created the withdrawal
+        txid: Txid,
+        /// Output index of the withdrawal
+        #[arg(long)]
+        vout: u32,
+        #[arg(long)]
+        fee_sats: u64,
+    },
     /// Connect to a peer
     ConnectPeer { addr: SocketAddr },
     /// Deposit to address
@@ -23,11 +41,34 @@ pub enum Command {
         #[arg(long)]
         fee_sats: u64,
     },
+    /// Deposit to a shielded address.
+    /// The deposit is minted into a shielded note.
+    CreateShieldedDeposit {
+        address: ShieldedAddress,
+        #[arg(long)]
+        value_sats: u64,
+        #[arg(long)]
+        fee_sats: u64,
+    },
+    /// Write a snapshot of the state at the current tip to a file, and print
+    /// the snapshot hash
+    DumpStateSnapshot { path: PathBuf },
+    /// Write blocks in the current chain, from height `from` to height `to`
+    /// inclusive, to a block file, and print the number of blocks written
+    ExportBlocks {
+        path: PathBuf,
+        #[arg(long)]
+        from: u32,
+        #[arg(long)]
+        to: u32,
+    },
     /// Delete peer from known_peers DB.
     /// Connections to the peer are not terminated.
     ForgetPeer { addr: SocketAddr },
     /// Format a deposit address
     FormatDepositAddress { address: TransparentAddress },
+    /// Format a shielded deposit address
+    FormatShieldedDepositAddress { address: ShieldedAddress },
     /// Generate a mnemonic seed phrase
     GenerateMnemonic,
     /// Get the best mainchain block hash
@@ -38,10 +79,31 @@ pub enum Command {
     GetBlock {
         block_hash: thunder_orchard::types::BlockHash,
     },
+    /// Get the hash of the block at the specified height in the current
+    /// chain, if it exists
+    GetBlockHash { height: u32 },
+    /// Get blocks in the current chain, starting at the specified height
+    GetBlocks {
+        start_height: u32,
+        #[arg(long)]
+        count: u32,
+    },
     /// Get mainchain blocks that commit to a specified block hash
     GetBmmInclusions {
         block_hash: thunder_orchard::types::BlockHash,
     },
+    /// List all known chain tips, including the tip of the active chain
+    GetChainTips,
+    /// Get the header with the specified block hash, if it exists
+    GetHeader {
+        block_hash: thunder_orchard::types::BlockHash,
+    },
+    /// Get headers in the current chain, starting at the specified height
+    GetHeaders {
+        start_height: u32,
+        #[arg(long)]
+        count: u32,
+    },
     /// Get a new shielded address
     GetNewShieldedAddress,
     /// Get a new transparent address
@@ -60,24 +122,91 @@ pub enum Command {
     GetWalletUtxos,
     /// Get unconfirmed wallet UTXOs
     GetWalletUtxosUnconfirmed,
+    /// Get a withdrawal bundle, along with the sidechain outpoints that it
+    /// spends, its total value and fee, and its status history
+    GetWithdrawalBundle { m6id: bitcoin::Txid },
     /// Get the current block count
     GetBlockcount,
+    /// Read blocks from a block file, and validate and connect them as if
+    /// they were received from a peer. Prints the number of blocks imported.
+    ImportBlocks { path: PathBuf },
+    /// Mark a block and its descendants as invalid, disconnecting them if
+    /// they are in the current chain
+    InvalidateBlock {
+        block_hash: thunder_orchard::types::BlockHash,
+    },
     /// Get the height of the latest failed withdrawal bundle
     LatestFailedWithdrawalBundleHeight,
-    /// List peers
+    /// Start from a state snapshot, instead of syncing the full history.
+    /// The state must be empty.
+    LoadStateSnapshot {
+        path: PathBuf,
+        /// Trust the snapshot if it has this hash, even if it is not trusted
+        /// for the network
+        #[arg(long)]
+        trusted_hash: Option<SnapshotHash>,
+    },
+    /// List deposits that have been credited on the sidechain, in the order
+    /// that they were credited
+    ListDeposits {
+        /// Only list deposits to this address
+        #[arg(long)]
+        address: Option<TransparentAddress>,
+        /// Only list deposits included in mainchain blocks at or above this
+        /// height
+        #[arg(long)]
+        start_main_height: Option<u32>,
+        /// Only list deposits included in mainchain blocks at or below this
+        /// height
+        #[arg(long)]
+        end_main_height: Option<u32>,
+    },
+    /// List deposits created by the wallet that have not yet been credited
+    /// on the sidechain
+    ListPendingDeposits,
+    /// List peers, along with their advertised tip and version,
+    /// latency, traffic, and pending requests
     ListPeers,
     /// List all UTXOs
     ListUtxos,
+    /// List withdrawal bundles, ordered by the height at which they were
+    /// first seen
+    ListWithdrawalBundles {
+        /// Only list bundles with this latest status
+        #[arg(long, value_enum)]
+        status: Option<WithdrawalBundleStatus>,
+        /// Only list bundles with a status transition at or above this
+        /// height
+        #[arg(long)]
+        start_height: Option<u32>,
+        /// Only list bundles with a status transition at or below this
+        /// height
+        #[arg(long)]
+        end_height: Option<u32>,
+    },
+    /// List withdrawals created by the wallet, along with the withdrawal
+    /// bundles that have included them
+    ListWithdrawals,
     /// Attempt to mine a sidechain block
     Mine {
         #[arg(long)]
         fee_sats: Option<u64>,
     },
+    /// Get the public key identifying this node to peers
+    NodePublicKey,
     /// Get pending withdrawal bundle
     PendingWithdrawalBundle,
     /// Show OpenAPI schema
     #[command(name = "openapi-schema")]
     OpenApiSchema,
+    /// Remove invalidity from a block, and from its ancestors and
+    /// descendants
+    ReconsiderBlock {
+        block_hash: thunder_orchard::types::BlockHash,
+    },
+    /// Wipe the state and mempool, and rebuild them by re-applying the
+    /// current chain from stored blocks
+    Reindex,
     /// Remove a tx from the mempool
     RemoveFromMempool { txid: Txid },
     /// Set the wallet seed from a mnemonic seed phrase
@@ -89,6 +218,8 @@ pub enum Command {
         #[arg(long)]
         fee_sats: u64,
     },
+    /// Get the total value of notes in the shielded pool
+    ShieldedPoolValue,
     /// Transfer shielded funds to the specified address
     ShieldedTransfer {
         dest: ShieldedAddress,
@@ -97,6 +228,17 @@ pub enum Command {
         #[arg(long)]
         fee_sats: u64,
     },
+    /// Initiate a withdrawal to the specified mainchain address, paid from
+    /// shielded funds in a single transaction
+    ShieldedWithdraw {
+        mainchain_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
+        #[arg(long)]
+        amount_sats: u64,
+        #[arg(long)]
+        fee_sats: u64,
+        #[arg(long)]
+        mainchain_fee_sats: u64,
+    },
     /// Get total sidechain wealth
     SidechainWealth,
     /// Stop the node
@@ -116,6 +258,13 @@ pub enum Command {
         #[arg(long)]
         fee_sats: u64,
     },
+    /// Verify recent blocks against stored blocks, and check UTXO set
+    /// invariants
+    VerifyChain {
+        /// Number of blocks to verify
+        #[arg(default_value_t = 6, long)]
+        depth: u32,
+    },
     /// Initiate a withdrawal to the specified mainchain address
     Withdraw {
         mainchain_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
@@ -161,10 +310,23 @@ where
     RpcClient: ClientT + Sync,
 {
     Ok(match command {
+        Command::AuditTreasury => {
+            let report = rpc_client.audit_treasury().await?;
+            serde_json::to_string_pretty(&report)?
+        }
         Command::Balance => {
             let balance = rpc_client.balance().await?;
             serde_json::to_string_pretty(&balance)?
         }
+        Command::CancelWithdrawal {
+            txid,
+            vout,
+            fee_sats,
+        } => {
+            let outpoint = OutPoint::Regular { txid, vout };
+            let txid = rpc_client.cancel_withdrawal(outpoint, fee_sats).await?;
+            format!("{txid}")
+        }
         Command::ConnectPeer { addr } => {
             let () = rpc_client.connect_peer(addr).await?;
             String::default()
@@ -179,6 +341,24 @@ where
                 .await?;
             format!("{txid}")
         }
+        Command::CreateShieldedDeposit {
+            address,
+            value_sats,
+            fee_sats,
+        } => {
+            let txid = rpc_client
+                .create_shielded_deposit(address, value_sats, fee_sats)
+                .await?;
+            format!("{txid}")
+        }
+        Command::DumpStateSnapshot { path } => {
+            let hash = rpc_client.dump_state_snapshot(path).await?;
+            format!("{hash}")
+        }
+        Command::ExportBlocks { path, from, to } => {
+            let n_blocks = rpc_client.export_blocks(path, from, to).await?;
+            format!("{n_blocks}")
+        }
         Command::ForgetPeer { addr } => {
             rpc_client.forget_peer(addr).await?;
             String::default()
@@ -186,11 +366,25 @@ where
         Command::FormatDepositAddress { address } => {
             rpc_client.format_deposit_address(address).await?
         }
+        Command::FormatShieldedDepositAddress { address } => {
+            rpc_client.format_shielded_deposit_address(address).await?
+        }
         Command::GenerateMnemonic => rpc_client.generate_mnemonic().await?,
         Command::GetBlock { block_hash } => {
             let block = rpc_client.get_block(block_hash).await?;
             serde_json::to_string_pretty(&block)?
         }
+        Command::GetBlockHash { height } => {
+            let block_hash = rpc_client.get_block_hash(height).await?;
+            serde_json::to_string_pretty(&block_hash)?
+        }
+        Command::GetBlocks {
+            start_height,
+            count,
+        } => {
+            let blocks = rpc_client.get_blocks(start_height, count).await?;
+            serde_json::to_string_pretty(&blocks)?
+        }
         Command::GetBestMainchainBlockHash => {
             let block_hash = rpc_client.get_best_mainchain_block_hash().await?;
             serde_json::to_string_pretty(&block_hash)?
@@ -204,6 +398,21 @@ where
                 rpc_client.get_bmm_inclusions(block_hash).await?;
             serde_json::to_string_pretty(&bmm_inclusions)?
         }
+        Command::GetChainTips => {
+            let chain_tips = rpc_client.get_chain_tips().await?;
+            serde_json::to_string_pretty(&chain_tips)?
+        }
+        Command::GetHeader { block_hash } => {
+            let header = rpc_client.get_header(block_hash).await?;
+            serde_json::to_string_pretty(&header)?
+        }
+        Command::GetHeaders {
+            start_height,
+            count,
+        } => {
+            let headers = rpc_client.get_headers(start_height, count).await?;
+            serde_json::to_string_pretty(&headers)?
+        }
         Command::GetNewShieldedAddress => {
             let address = rpc_client.get_new_shielded_address().await?;
             format!("{address}")
@@ -241,15 +450,46 @@ where
             let utxos = rpc_client.get_wallet_utxos_unconfirmed().await?;
             serde_json::to_string_pretty(&utxos)?
         }
+        Command::GetWithdrawalBundle { m6id } => {
+            let bundle = rpc_client.get_withdrawal_bundle(M6id(m6id)).await?;
+            serde_json::to_string_pretty(&bundle)?
+        }
         Command::GetBlockcount => {
             let blockcount = rpc_client.getblockcount().await?;
             format!("{blockcount}")
         }
+        Command::ImportBlocks { path } => {
+            let n_blocks = rpc_client.import_blocks(path).await?;
+            format!("{n_blocks}")
+        }
+        Command::InvalidateBlock { block_hash } => {
+            let tip = rpc_client.invalidate_block(block_hash).await?;
+            serde_json::to_string_pretty(&tip)?
+        }
         Command::LatestFailedWithdrawalBundleHeight => {
             let height =
                 rpc_client.latest_failed_withdrawal_bundle_height().await?;
             serde_json::to_string_pretty(&height)?
         }
+        Command::LoadStateSnapshot { path, trusted_hash } => {
+            let hash =
+                rpc_client.load_state_snapshot(path, trusted_hash).await?;
+            format!("{hash}")
+        }
+        Command::ListDeposits {
+            address,
+            start_main_height,
+            end_main_height,
+        } => {
+            let deposits = rpc_client
+                .list_deposits(address, start_main_height, end_main_height)
+                .await?;
+            serde_json::to_string_pretty(&deposits)?
+        }
+        Command::ListPendingDeposits => {
+            let deposits = rpc_client.list_pending_deposits().await?;
+            serde_json::to_string_pretty(&deposits)?
+        }
         Command::ListPeers => {
             let peers = rpc_client.list_peers().await?;
             serde_json::to_string_pretty(&peers)?
@@ -258,10 +498,28 @@ where
             let utxos = rpc_client.list_utxos().await?;
             serde_json::to_string_pretty(&utxos)?
         }
+        Command::ListWithdrawalBundles {
+            status,
+            start_height,
+            end_height,
+        } => {
+            let bundles = rpc_client
+                .list_withdrawal_bundles(status, start_height, end_height)
+                .await?;
+            serde_json::to_string_pretty(&bundles)?
+        }
+        Command::ListWithdrawals => {
+            let withdrawals = rpc_client.list_withdrawals().await?;
+            serde_json::to_string_pretty(&withdrawals)?
+        }
         Command::Mine { fee_sats } => {
             let () = rpc_client.mine(fee_sats).await?;
             String::default()
         }
+        Command::NodePublicKey => {
+            let public_key = rpc_client.node_public_key().await?;
+            format!("{public_key}")
+        }
         Command::PendingWithdrawalBundle => {
             let withdrawal_bundle =
                 rpc_client.pending_withdrawal_bundle().await?;
@@ -272,6 +530,14 @@ where
                 <thunder_orchard_app_rpc_api::RpcDoc as utoipa::OpenApi>::openapi();
             openapi.to_pretty_json()?
         }
+        Command::ReconsiderBlock { block_hash } => {
+            let tip = rpc_client.reconsider_block(block_hash).await?;
+            serde_json::to_string_pretty(&tip)?
+        }
+        Command::Reindex => {
+            let tip = rpc_client.reindex().await?;
+            serde_json::to_string_pretty(&tip)?
+        }
         Command::RemoveFromMempool { txid } => {
             let () = rpc_client.remove_from_mempool(txid).await?;
             String::default()
@@ -287,6 +553,11 @@ where
             let txid = rpc_client.shield(value_sats, fee_sats).await?;
             format!("{txid}")
         }
+        Command::ShieldedPoolValue => {
+            let shielded_pool_value =
+                rpc_client.shielded_pool_value_sats().await?;
+            format!("{shielded_pool_value}")
+        }
         Command::ShieldedTransfer {
             dest,
             value_sats,
@@ -297,6 +568,22 @@ where
                 .await?;
             format!("{txid}")
         }
+        Command::ShieldedWithdraw {
+            mainchain_address,
+            amount_sats,
+            fee_sats,
+            mainchain_fee_sats,
+        } => {
+            let txid = rpc_client
+                .shielded_withdraw(
+                    mainchain_address,
+                    amount_sats,
+                    fee_sats,
+                    mainchain_fee_sats,
+                )
+                .await?;
+            format!("{txid}")
+        }
         Command::SidechainWealth => {
             let sidechain_wealth = rpc_client.sidechain_wealth_sats().await?;
             format!("{sidechain_wealth}")
@@ -322,6 +609,10 @@ where
             let txid = rpc_client.unshield(value_sats, fee_sats).await?;
             format!("{txid}")
         }
+        Command::VerifyChain { depth } => {
+            let report = rpc_client.verify_chain(depth).await?;
+            serde_json::to_string_pretty(&report)?
+        }
         Command::Withdraw {
             mainchain_address,
             amount_sats,
@@ -2,6 +2,7 @@ use std::{
     cmp::Ordering,
     collections::{BTreeMap, HashMap, HashSet},
     path::PathBuf,
+    str::FromStr,
 };
 
 use bitcoin::{self, hashes::Hash as _};
@@ -12,14 +13,18 @@ use sneed::{
     RwTxnError, UnitKey, db, env,
 };
 
-use crate::types::{
-    Accumulator, BlockHash, BmmResult, Body, Header, Tip, Txid, VERSION,
-    Version, orchard, proto::mainchain,
+use crate::{
+    migration,
+    types::{
+        Accumulator, BlockHash, BmmResult, Body, ChainTip, ChainTipStatus,
+        Header, Output, Tip, Txid, Version, orchard, proto::mainchain,
+    },
 };
 
 #[allow(clippy::duplicated_attributes)]
 #[derive(Debug, thiserror::Error, transitive::Transitive)]
 #[transitive(
+    from(db::error::Delete, DbError),
     from(db::error::Get, DbError),
     from(db::error::Put, DbError),
     from(db::error::TryGet, DbError),
@@ -27,6 +32,8 @@ use crate::types::{
     from(env::error::WriteTxn, EnvError)
 )]
 pub enum Error {
+    #[error(transparent)]
+    BorshSerialize(borsh::io::Error),
     #[error(transparent)]
     Db(#[from] DbError),
     #[error("Database env error")]
@@ -74,6 +81,58 @@ pub enum Error {
     NoMainHeight(bitcoin::BlockHash),
 }
 
+/// Minimum number of blocks below the tip for which bodies are kept when
+/// pruning. Re-orgs deeper than this cannot be applied by a pruned node.
+pub const MIN_PRUNE_DEPTH: u32 = 288;
+
+/// Amount of block history to keep when pruning
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PruneTarget {
+    /// Keep bodies for this many blocks below the tip
+    Depth(u32),
+    /// Keep approximately this many megabytes of recent block bodies
+    Megabytes(u64),
+}
+
+impl std::fmt::Display for PruneTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Depth(depth) => write!(f, "{depth}"),
+            Self::Megabytes(megabytes) => write!(f, "{megabytes}MB"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "invalid prune target `{0}`; expected a depth (eg. `1000`) or a size in \
+     megabytes (eg. `550MB`)"
+)]
+pub struct ParsePruneTargetError(String);
+
+impl FromStr for PruneTarget {
+    type Err = ParsePruneTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParsePruneTargetError(s.to_owned());
+        match s.strip_suffix("MB").or_else(|| s.strip_suffix("mb")) {
+            Some(megabytes) => megabytes
+                .trim()
+                .parse()
+                .map(Self::Megabytes)
+                .map_err(|_| err()),
+            None => s.parse().map(Self::Depth).map_err(|_| err()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PruneConfig {
+    pub target: PruneTarget,
+    /// Also delete `txid_to_inclusions` entries for pruned blocks
+    pub prune_tx_inclusions: bool,
+}
+
 #[derive(Clone)]
 pub struct Archive {
     /// Utreexo accumulators for blocks that have been successfully applied.
@@ -113,6 +172,9 @@ pub struct Archive {
     >,
     /// Sidechain headers. All ancestors of any header should always be present.
     headers: DatabaseUnique<SerdeBincode<BlockHash>, SerdeBincode<Header>>,
+    /// Blocks that have been manually invalidated.
+    /// Descendants of these blocks are also treated as invalid.
+    invalid_blocks: DatabaseUnique<SerdeBincode<BlockHash>, SerdeBincode<()>>,
     main_block_hash_to_height:
         DatabaseUnique<SerdeBincode<bitcoin::BlockHash>, SerdeBincode<u32>>,
     /// Mainchain block infos.
@@ -143,6 +205,11 @@ pub struct Archive {
         SerdeBincode<Option<BlockHash>>,
         SerdeBincode<orchard::Frontier>,
     >,
+    /// Outputs spent by each block's transactions, in input order.
+    /// Only stored by compact-state nodes, which relay them with block
+    /// bodies.
+    spent_outputs:
+        DatabaseUnique<SerdeBincode<BlockHash>, SerdeBincode<Vec<Output>>>,
     /// Successor blocks. ALL known block hashes MUST be present.
     successors: DatabaseUnique<
         SerdeBincode<Option<BlockHash>>,
@@ -154,6 +221,13 @@ pub struct Archive {
         SerdeBincode<bitcoin::BlockHash>,
         SerdeBincode<bitcoin::Work>,
     >,
+    /// Bodies have been deleted for ancestors of the tip below this height.
+    /// Absent if the archive has never been pruned.
+    prune_height: DatabaseUnique<UnitKey, SerdeBincode<u32>>,
+    /// Highest pruned block. Blocks are pruned only if they are ancestors of
+    /// this block, so that bodies for forks are still requested.
+    /// Its height is `prune_height - 1`.
+    prune_tip: DatabaseUnique<UnitKey, SerdeBincode<BlockHash>>,
     /// Blocks in which a tx has been included, and index within the block
     txid_to_inclusions: DatabaseUnique<
         SerdeBincode<Txid>,
@@ -163,26 +237,48 @@ pub struct Archive {
 }
 
 impl Archive {
-    pub const NUM_DBS: u32 = 16;
+    pub const NUM_DBS: u32 = 20;
+
+    /// Schema version of the archive DBs
+    pub const SCHEMA_VERSION: Version = Version {
+        major: 0,
+        minor: 16,
+        patch: 0,
+    };
+
+    pub const SCHEMA: migration::Schema = migration::Schema {
+        version_db: "archive_version",
+        version: Self::SCHEMA_VERSION,
+        // `txid_to_inclusions` added in 0.16.0
+        // Merkle root structure changed in 0.13.0
+        // `deposits` and `main_bmm_commitments` were removed in
+        // 0.12.0, and `main_block_infos` was added
+        min_version: Version {
+            major: 0,
+            minor: 16,
+            patch: 0,
+        },
+        steps: &[],
+    };
 
     pub fn new(env: &sneed::Env) -> Result<Self, Error> {
         let mut rwtxn = env.write_txn()?;
+        let archive = Self::open(env, &mut rwtxn)?;
+        rwtxn.commit().map_err(RwTxnError::from)?;
+        Ok(archive)
+    }
+
+    /// Create or open the archive DBs in an existing write txn, eg. to read
+    /// the archive during a migration step
+    pub(crate) fn open(
+        env: &sneed::Env,
+        rwtxn: &mut RwTxn,
+    ) -> Result<Self, Error> {
         let version =
-            DatabaseUnique::create(env, &mut rwtxn, "archive_version")
+            DatabaseUnique::create(env, rwtxn, Self::SCHEMA.version_db)
                 .map_err(EnvError::from)?;
-        match version.try_get(&rwtxn, &()).map_err(DbError::from)? {
-            Some(db_version)
-                if db_version
-                    < Version {
-                        major: 0,
-                        minor: 16,
-                        patch: 0,
-                    } =>
-            {
-                // `txid_to_inclusions` added in 0.16.0
-                // Merkle root structure changed in 0.13.0
-                // `deposits` and `main_bmm_commitments` were removed in
-                // 0.12.0, and `main_block_infos` was added
+        match version.try_get(rwtxn, &()).map_err(DbError::from)? {
+            Some(db_version) if db_version < Self::SCHEMA.min_version => {
                 return Err(Error::IncompatibleVersion {
                     version: db_version,
                     db_path: env.path().to_path_buf(),
@@ -190,62 +286,58 @@ impl Archive {
             }
             Some(_) => (),
             None => version
-                .put(&mut rwtxn, &(), &*VERSION)
+                .put(rwtxn, &(), &Self::SCHEMA_VERSION)
                 .map_err(DbError::from)?,
         }
-        let accumulators =
-            DatabaseUnique::create(env, &mut rwtxn, "accumulators")?;
-        if !accumulators.contains_key(&rwtxn, &None)? {
-            accumulators.put(&mut rwtxn, &None, &Accumulator::default())?
+        let accumulators = DatabaseUnique::create(env, rwtxn, "accumulators")?;
+        if !accumulators.contains_key(rwtxn, &None)? {
+            accumulators.put(rwtxn, &None, &Accumulator::default())?
         }
         let block_hash_to_height =
-            DatabaseUnique::create(env, &mut rwtxn, "hash_to_height")?;
-        let bmm_results =
-            DatabaseUnique::create(env, &mut rwtxn, "bmm_results")?;
-        let bodies = DatabaseUnique::create(env, &mut rwtxn, "bodies")?;
+            DatabaseUnique::create(env, rwtxn, "hash_to_height")?;
+        let bmm_results = DatabaseUnique::create(env, rwtxn, "bmm_results")?;
+        let bodies = DatabaseUnique::create(env, rwtxn, "bodies")?;
         let exponential_ancestors =
-            DatabaseUnique::create(env, &mut rwtxn, "exponential_ancestors")?;
-        let exponential_main_ancestors = DatabaseUnique::create(
-            env,
-            &mut rwtxn,
-            "exponential_main_ancestors",
-        )?;
-        let headers = DatabaseUnique::create(env, &mut rwtxn, "headers")?;
+            DatabaseUnique::create(env, rwtxn, "exponential_ancestors")?;
+        let exponential_main_ancestors =
+            DatabaseUnique::create(env, rwtxn, "exponential_main_ancestors")?;
+        let headers = DatabaseUnique::create(env, rwtxn, "headers")?;
+        let invalid_blocks =
+            DatabaseUnique::create(env, rwtxn, "invalid_blocks")?;
         let main_block_hash_to_height =
-            DatabaseUnique::create(env, &mut rwtxn, "main_hash_to_height")?;
+            DatabaseUnique::create(env, rwtxn, "main_hash_to_height")?;
         let main_block_infos =
-            DatabaseUnique::create(env, &mut rwtxn, "main_block_infos")?;
+            DatabaseUnique::create(env, rwtxn, "main_block_infos")?;
         let main_header_infos =
-            DatabaseUnique::create(env, &mut rwtxn, "main_header_infos")?;
+            DatabaseUnique::create(env, rwtxn, "main_header_infos")?;
         let main_successors =
-            DatabaseUnique::create(env, &mut rwtxn, "main_successors")?;
+            DatabaseUnique::create(env, rwtxn, "main_successors")?;
         if main_successors
-            .try_get(&rwtxn, &bitcoin::BlockHash::all_zeros())?
+            .try_get(rwtxn, &bitcoin::BlockHash::all_zeros())?
             .is_none()
         {
             main_successors.put(
-                &mut rwtxn,
+                rwtxn,
                 &bitcoin::BlockHash::all_zeros(),
                 &HashSet::new(),
             )?;
         }
         let orchard_frontiers =
-            DatabaseUnique::create(env, &mut rwtxn, "orchard_frontiers")?;
-        if orchard_frontiers.try_get(&rwtxn, &None)?.is_none() {
-            orchard_frontiers.put(
-                &mut rwtxn,
-                &None,
-                &orchard::Frontier::empty(),
-            )?;
+            DatabaseUnique::create(env, rwtxn, "orchard_frontiers")?;
+        if orchard_frontiers.try_get(rwtxn, &None)?.is_none() {
+            orchard_frontiers.put(rwtxn, &None, &orchard::Frontier::empty())?;
         }
-        let successors = DatabaseUnique::create(env, &mut rwtxn, "successors")?;
-        if successors.try_get(&rwtxn, &None)?.is_none() {
-            successors.put(&mut rwtxn, &None, &HashSet::new())?;
+        let prune_height = DatabaseUnique::create(env, rwtxn, "prune_height")?;
+        let prune_tip = DatabaseUnique::create(env, rwtxn, "prune_tip")?;
+        let spent_outputs =
+            DatabaseUnique::create(env, rwtxn, "spent_outputs")?;
+        let successors = DatabaseUnique::create(env, rwtxn, "successors")?;
+        if successors.try_get(rwtxn, &None)?.is_none() {
+            successors.put(rwtxn, &None, &HashSet::new())?;
         }
-        let total_work = DatabaseUnique::create(env, &mut rwtxn, "total_work")?;
+        let total_work = DatabaseUnique::create(env, rwtxn, "total_work")?;
         let txid_to_inclusions =
-            DatabaseUnique::create(env, &mut rwtxn, "txid_to_inclusions")?;
-        rwtxn.commit().map_err(RwTxnError::from)?;
+            DatabaseUnique::create(env, rwtxn, "txid_to_inclusions")?;
         Ok(Self {
             accumulators,
             block_hash_to_height,
@@ -254,11 +346,15 @@ impl Archive {
             exponential_ancestors,
             exponential_main_ancestors,
             headers,
+            invalid_blocks,
             main_block_infos,
             main_block_hash_to_height,
             main_header_infos,
             main_successors,
             orchard_frontiers,
+            prune_height,
+            prune_tip,
+            spent_outputs,
             successors,
             total_work,
             txid_to_inclusions,
@@ -353,6 +449,15 @@ impl Archive {
         Ok(body)
     }
 
+    pub fn try_get_spent_outputs(
+        &self,
+        rotxn: &RoTxn,
+        block_hash: BlockHash,
+    ) -> Result<Option<Vec<Output>>, Error> {
+        let spent_outputs = self.spent_outputs.try_get(rotxn, &block_hash)?;
+        Ok(spent_outputs)
+    }
+
     pub fn get_body(
         &self,
         rotxn: &RoTxn,
@@ -511,6 +616,80 @@ impl Archive {
         Ok(inclusions)
     }
 
+    /// Bodies have been deleted for ancestors of the tip below this height.
+    /// Returns `None` if the archive has never been pruned.
+    pub fn try_get_prune_height(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<Option<u32>, Error> {
+        let prune_height = self.prune_height.try_get(rotxn, &())?;
+        Ok(prune_height)
+    }
+
+    /// Returns `true` if the block is the prune tip or one of its ancestors,
+    /// in which case its body may have been deleted.
+    /// Returns `false` for unknown blocks, and for fork blocks below the
+    /// prune height.
+    pub fn is_pruned(
+        &self,
+        rotxn: &RoTxn,
+        block_hash: BlockHash,
+    ) -> Result<bool, Error> {
+        let Some(prune_height) = self.try_get_prune_height(rotxn)? else {
+            return Ok(false);
+        };
+        let Some(height) = self.try_get_height(rotxn, block_hash)? else {
+            return Ok(false);
+        };
+        if height >= prune_height {
+            return Ok(false);
+        }
+        let Some(prune_tip) = self.prune_tip.try_get(rotxn, &())? else {
+            return Ok(true);
+        };
+        // When starting from a snapshot, the prune tip header may not have
+        // been synced yet, in which case its ancestry is unknown
+        let Some(prune_tip_height) = self.try_get_height(rotxn, prune_tip)?
+        else {
+            return Ok(true);
+        };
+        let Some(depth) = prune_tip_height.checked_sub(height) else {
+            return Ok(false);
+        };
+        let ancestor = self.get_nth_ancestor(rotxn, prune_tip, depth)?;
+        Ok(ancestor == block_hash)
+    }
+
+    /// Number of recent blocks below the tip for which bodies total
+    /// approximately `target_bytes`, estimated from the average size of up to
+    /// [`MIN_PRUNE_DEPTH`] recent bodies.
+    pub fn prune_depth_for_size(
+        &self,
+        rotxn: &RoTxn,
+        tip: BlockHash,
+        target_bytes: u64,
+    ) -> Result<u32, Error> {
+        let mut n_bodies: u64 = 0;
+        let mut total_bytes: u64 = 0;
+        let mut ancestors =
+            self.ancestors(rotxn, tip).take(MIN_PRUNE_DEPTH as usize);
+        while let Some(block_hash) = ancestors.next()? {
+            let Some(body) = self.try_get_body(rotxn, block_hash)? else {
+                break;
+            };
+            let body_bytes =
+                borsh::object_length(&body).map_err(Error::BorshSerialize)?;
+            n_bodies += 1;
+            total_bytes += body_bytes as u64;
+        }
+        if total_bytes == 0 {
+            return Ok(MIN_PRUNE_DEPTH);
+        }
+        let avg_body_bytes = total_bytes.div_ceil(n_bodies);
+        let depth = (target_bytes / avg_body_bytes).min(u32::MAX as u64);
+        Ok(depth as u32)
+    }
+
     /// Try to get the best valid mainchain verification for the specified block.
     pub fn try_get_best_main_verification(
         &self,
@@ -622,6 +801,30 @@ impl Archive {
         Ok(res)
     }
 
+    /// Get block hashes in the chain ending at `tip`, oldest-to-newest,
+    /// starting at the specified height.
+    /// At most `count` block hashes are returned.
+    pub fn get_block_hashes(
+        &self,
+        rotxn: &RoTxn,
+        tip: BlockHash,
+        start_height: u32,
+        count: u32,
+    ) -> Result<Vec<BlockHash>, Error> {
+        let tip_height = self.get_height(rotxn, tip)?;
+        if count == 0 || start_height > tip_height {
+            return Ok(Vec::new());
+        }
+        let end_height = start_height.saturating_add(count - 1).min(tip_height);
+        let end = self.get_nth_ancestor(rotxn, tip, tip_height - end_height)?;
+        let mut block_hashes: Vec<BlockHash> = self
+            .ancestors(rotxn, end)
+            .take((end_height - start_height + 1) as usize)
+            .collect()?;
+        block_hashes.reverse();
+        Ok(block_hashes)
+    }
+
     /// Returns true if the second specified block is a descendant of the first
     /// specified block.
     /// Returns an error if either of the specified block headers do not exist
@@ -649,6 +852,37 @@ impl Archive {
         Ok(res)
     }
 
+    /// Blocks that have been manually invalidated.
+    /// Descendants of these blocks are not included.
+    pub fn invalid_blocks(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<Vec<BlockHash>, Error> {
+        let invalid_blocks = self
+            .invalid_blocks
+            .iter_keys(rotxn)
+            .map_err(DbError::from)?
+            .map_err(DbError::from)
+            .collect()?;
+        Ok(invalid_blocks)
+    }
+
+    /// Returns `true` if the specified block, or any of its ancestors, has
+    /// been invalidated.
+    /// Returns an error if the block header does not exist in the archive.
+    pub fn is_invalid(
+        &self,
+        rotxn: &RoTxn,
+        block_hash: BlockHash,
+    ) -> Result<bool, Error> {
+        for invalid_block in self.invalid_blocks(rotxn)? {
+            if self.is_descendant(rotxn, invalid_block, block_hash)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     /// Returns true if the second specified mainchain block is a descendant of
     /// the first specified block.
     /// Returns an error if either of the specified block headers do not exist
@@ -711,6 +945,19 @@ impl Archive {
             })
     }
 
+    /// Store the outputs spent by a block's transactions, in input order
+    pub fn put_spent_outputs(
+        &self,
+        rwtxn: &mut RwTxn,
+        block_hash: BlockHash,
+        spent_outputs: &[Output],
+    ) -> Result<(), Error> {
+        let _header = self.get_header(rwtxn, block_hash)?;
+        self.spent_outputs
+            .put(rwtxn, &block_hash, &spent_outputs.to_vec())?;
+        Ok(())
+    }
+
     /// Store a header.
     ///
     /// The following predicates MUST be met before calling this function:
@@ -987,6 +1234,118 @@ impl Archive {
         Ok(())
     }
 
+    /// Mark a block, and all of its descendants, as invalid
+    pub fn invalidate_block(
+        &self,
+        rwtxn: &mut RwTxn,
+        block_hash: BlockHash,
+    ) -> Result<(), Error> {
+        let _header = self.get_header(rwtxn, block_hash)?;
+        self.invalid_blocks.put(rwtxn, &block_hash, &())?;
+        Ok(())
+    }
+
+    /// Remove invalidity from a block, and from any of its ancestors and
+    /// descendants.
+    /// Returns `true` if any blocks were previously invalid.
+    pub fn reconsider_block(
+        &self,
+        rwtxn: &mut RwTxn,
+        block_hash: BlockHash,
+    ) -> Result<bool, Error> {
+        let mut reconsidered = false;
+        for invalid_block in self.invalid_blocks(rwtxn)? {
+            if self.is_descendant(rwtxn, invalid_block, block_hash)?
+                || self.is_descendant(rwtxn, block_hash, invalid_block)?
+            {
+                self.invalid_blocks.delete(rwtxn, &invalid_block)?;
+                reconsidered = true;
+            }
+        }
+        Ok(reconsidered)
+    }
+
+    /// Delete bodies for ancestors of the tip that are more than `depth`
+    /// blocks below the tip, and optionally their tx inclusions.
+    /// Returns the number of bodies deleted.
+    pub fn prune(
+        &self,
+        rwtxn: &mut RwTxn,
+        tip: BlockHash,
+        depth: u32,
+        prune_tx_inclusions: bool,
+    ) -> Result<u32, Error> {
+        let tip_height = self.get_height(rwtxn, tip)?;
+        let Some(new_prune_height) = (tip_height + 1).checked_sub(depth) else {
+            return Ok(0);
+        };
+        let prune_height = self.try_get_prune_height(rwtxn)?.unwrap_or(0);
+        if new_prune_height <= prune_height {
+            return Ok(0);
+        }
+        // Ancestors to prune, from `new_prune_height - 1` down to
+        // `prune_height`
+        let start = self.get_nth_ancestor(
+            rwtxn,
+            tip,
+            tip_height - (new_prune_height - 1),
+        )?;
+        let block_hashes: Vec<BlockHash> = self
+            .ancestors(rwtxn, start)
+            .take((new_prune_height - prune_height) as usize)
+            .collect()?;
+        let mut pruned = 0;
+        for block_hash in block_hashes {
+            let Some(body) = self.try_get_body(rwtxn, block_hash)? else {
+                continue;
+            };
+            if prune_tx_inclusions {
+                for tx in &body.transactions {
+                    let txid = tx.txid();
+                    let mut inclusions = self.get_tx_inclusions(rwtxn, txid)?;
+                    inclusions.remove(&block_hash);
+                    if inclusions.is_empty() {
+                        self.txid_to_inclusions.delete(rwtxn, &txid)?;
+                    } else {
+                        self.txid_to_inclusions.put(
+                            rwtxn,
+                            &txid,
+                            &inclusions,
+                        )?;
+                    }
+                }
+            }
+            self.bodies.delete(rwtxn, &block_hash)?;
+            self.spent_outputs.delete(rwtxn, &block_hash)?;
+            pruned += 1;
+        }
+        self.prune_height.put(rwtxn, &(), &new_prune_height)?;
+        self.prune_tip.put(rwtxn, &(), &start)?;
+        Ok(pruned)
+    }
+
+    /// Treat the specified block at the specified height, and its ancestors,
+    /// as pruned, without deleting any bodies. Used when starting from a
+    /// state snapshot, so that bodies below the snapshot are never
+    /// requested. The block's header need not be known yet.
+    /// Has no effect if the prune height is already above the block.
+    pub fn set_prune_tip(
+        &self,
+        rwtxn: &mut RwTxn,
+        block_hash: BlockHash,
+        height: u32,
+    ) -> Result<(), Error> {
+        if self
+            .try_get_prune_height(rwtxn)?
+            .is_some_and(|current| current > height)
+        {
+            return Ok(());
+        }
+        self.prune_height.put(rwtxn, &(), &(height + 1))?;
+        self.prune_tip.put(rwtxn, &(), &block_hash)?;
+        Ok(())
+    }
+
     /// Return a fallible iterator over headers for ancestors of a block,
     /// starting with the specified block's header
     pub fn ancestor_headers<'a, 'rotxn>(
@@ -1032,6 +1391,7 @@ impl Archive {
             .filter_map(|block_hash| {
                 match self.try_get_body(rotxn, block_hash)? {
                     Some(_) => Ok(None),
+                    None if self.is_pruned(rotxn, block_hash)? => Ok(None),
                     None => Ok(Some(block_hash)),
                 }
             })
@@ -1049,7 +1409,9 @@ impl Archive {
     ) -> impl FallibleIterator<Item = BlockHash, Error = Error> + 'a {
         AncestorsRev::new(self, rotxn, block_hash, start_height).filter_map(
             |(ancestor, _)| {
-                if self.try_get_body(rotxn, ancestor)?.is_none() {
+                if self.try_get_body(rotxn, ancestor)?.is_none()
+                    && !self.is_pruned(rotxn, ancestor)?
+                {
                     Ok(Some(ancestor))
                 } else {
                     Ok(None)
@@ -1455,6 +1817,135 @@ impl Archive {
             }
         }
     }
+
+    /// Find the best BMM-verified tip that is the specified block or one of
+    /// its descendants, skipping invalidated blocks and blocks for which the
+    /// body is missing.
+    /// If the specified block is `None`, all blocks are searched.
+    /// Returns `None` if no such tip exists, or if the specified block is
+    /// invalid.
+    pub fn best_valid_descendant_tip(
+        &self,
+        rotxn: &RoTxn,
+        block_hash: Option<BlockHash>,
+    ) -> Result<Option<Tip>, Error> {
+        let mut queue: Vec<BlockHash> = match block_hash {
+            Some(block_hash) => {
+                if self.is_invalid(rotxn, block_hash)? {
+                    return Ok(None);
+                }
+                vec![block_hash]
+            }
+            None => self.get_successors(rotxn, None)?.into_iter().collect(),
+        };
+        let mut best_tip: Option<Tip> = None;
+        while let Some(block_hash) = queue.pop() {
+            if self.invalid_blocks.contains_key(rotxn, &block_hash)? {
+                continue;
+            }
+            if !self.bodies.contains_key(rotxn, &block_hash)?
+                && !self.is_pruned(rotxn, block_hash)?
+            {
+                continue;
+            }
+            let Some(main_block_hash) =
+                self.try_get_best_main_verification(rotxn, block_hash)?
+            else {
+                continue;
+            };
+            let tip = Tip {
+                block_hash,
+                main_block_hash,
+            };
+            best_tip = match best_tip {
+                None => Some(tip),
+                Some(best_tip) => Some(
+                    self.better_tip(rotxn, best_tip, tip)?.unwrap_or(best_tip),
+                ),
+            };
+            queue.extend(self.get_successors(rotxn, Some(block_hash))?);
+        }
+        Ok(best_tip)
+    }
+
+    /// Blocks for which headers exist, but no successor headers are known
+    pub fn leaf_headers(&self, rotxn: &RoTxn) -> Result<Vec<BlockHash>, Error> {
+        let leaves = self
+            .successors
+            .iter(rotxn)
+            .map_err(DbError::from)?
+            .filter_map(|(block_hash, successors)| {
+                Ok(block_hash.filter(|_| successors.is_empty()))
+            })
+            .collect()
+            .map_err(DbError::from)?;
+        Ok(leaves)
+    }
+
+    /// Get all known chain tips, including the tip of the active chain,
+    /// ordered by height descending
+    pub fn chain_tips(
+        &self,
+        rotxn: &RoTxn,
+        active_tip: Option<BlockHash>,
+    ) -> Result<Vec<ChainTip>, Error> {
+        let mut block_hashes = self.leaf_headers(rotxn)?;
+        if let Some(active_tip) = active_tip
+            && !block_hashes.contains(&active_tip)
+        {
+            block_hashes.push(active_tip);
+        }
+        let mut chain_tips = block_hashes
+            .into_iter()
+            .map(|block_hash| {
+                let height = self.get_height(rotxn, block_hash)?;
+                let fork_point = match active_tip {
+                    Some(active_tip) => self
+                        .last_common_ancestor(rotxn, active_tip, block_hash)?,
+                    None => None,
+                };
+                let branch_len = match fork_point {
+                    Some(fork_point) => {
+                        height - self.get_height(rotxn, fork_point)?
+                    }
+                    None => height + 1,
+                };
+                let main_block_hash =
+                    self.try_get_best_main_verification(rotxn, block_hash)?;
+                let total_work = main_block_hash
+                    .map(|main_block_hash| {
+                        self.get_total_work(rotxn, main_block_hash)
+                    })
+                    .transpose()?;
+                let status = if active_tip == Some(block_hash) {
+                    ChainTipStatus::Active
+                } else if self.is_invalid(rotxn, block_hash)? {
+                    ChainTipStatus::Invalid
+                } else {
+                    let n_missing_bodies =
+                        self.get_missing_bodies(rotxn, block_hash, fork_point)?
+                            .len() as u32;
+                    if n_missing_bodies == 0 {
+                        ChainTipStatus::ValidFork
+                    } else if n_missing_bodies == branch_len {
+                        ChainTipStatus::HeadersOnly
+                    } else {
+                        ChainTipStatus::MissingBody
+                    }
+                };
+                Ok(ChainTip {
+                    block_hash,
+                    height,
+                    branch_len,
+                    main_block_hash,
+                    total_work,
+                    status,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        chain_tips.sort_by(|tip0, tip1| tip1.height.cmp(&tip0.height));
+        Ok(chain_tips)
+    }
 }
 
 /// Return a fallible iterator over ancestor headers of a block,
@@ -1593,3 +2084,319 @@ impl FallibleIterator for AncestorsRev<'_, '_> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash as _;
+
+    use super::Archive;
+    use crate::{
+        state::test::temp_env_path,
+        types::{
+            BlockHash, Body, ChainTipStatus, Header, Tip, proto::mainchain,
+        },
+    };
+
+    fn temp_archive(test_name: &str) -> anyhow::Result<(sneed::Env, Archive)> {
+        let path = temp_env_path(test_name)?;
+        std::fs::create_dir_all(&path)?;
+        let mut opts = heed::EnvOpenOptions::new();
+        opts.map_size(64 * 1024 * 1024).max_dbs(Archive::NUM_DBS);
+        let env = unsafe { sneed::Env::open(&opts, &path) }?;
+        let archive = Archive::new(&env)?;
+        Ok((env, archive))
+    }
+
+    /// Store a mainchain block with unit work, optionally BMM-committing to a
+    /// sidechain block
+    fn put_main_block(
+        archive: &Archive,
+        rwtxn: &mut sneed::RwTxn,
+        main_hash: bitcoin::BlockHash,
+        prev_main_hash: bitcoin::BlockHash,
+        bmm_commitment: Option<BlockHash>,
+    ) -> anyhow::Result<()> {
+        let height = archive.get_main_height(rwtxn, prev_main_hash)? + 1;
+        let mut work = [0u8; 32];
+        work[0] = 1;
+        archive.put_main_header_info(
+            rwtxn,
+            &mainchain::BlockHeaderInfo {
+                block_hash: main_hash,
+                prev_block_hash: prev_main_hash,
+                height,
+                work: bitcoin::Work::from_le_bytes(work),
+            },
+        )?;
+        archive.put_main_block_info(
+            rwtxn,
+            main_hash,
+            &mainchain::BlockInfo {
+                bmm_commitment,
+                events: Vec::new(),
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Store a sidechain header and empty body
+    fn put_block(
+        archive: &Archive,
+        rwtxn: &mut sneed::RwTxn,
+        prev_side_hash: Option<BlockHash>,
+        prev_main_hash: bitcoin::BlockHash,
+    ) -> anyhow::Result<BlockHash> {
+        let body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let header = Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash,
+            prev_main_hash,
+            roots: Vec::new(),
+        };
+        archive.put_header(rwtxn, &header)?;
+        archive.put_body(rwtxn, header.hash(), &body)?;
+        Ok(header.hash())
+    }
+
+    /// Sidechain blocks `s1 <- s2 <- s3` and a fork `s1 <- s2b`, BMM-verified
+    /// in mainchain blocks `m1 <- m2 <- m3 <- m4`
+    struct Chain {
+        s1: BlockHash,
+        s2: Tip,
+        s3: Tip,
+        s2b: Tip,
+    }
+
+    fn build_chain(
+        archive: &Archive,
+        rwtxn: &mut sneed::RwTxn,
+    ) -> anyhow::Result<Chain> {
+        let m0 = bitcoin::BlockHash::all_zeros();
+        let [m1, m2, m3, m4] =
+            [1, 2, 3, 4].map(|n| bitcoin::BlockHash::from_byte_array([n; 32]));
+        let s1 = put_block(archive, rwtxn, None, m0)?;
+        put_main_block(archive, rwtxn, m1, m0, Some(s1))?;
+        let s2 = put_block(archive, rwtxn, Some(s1), m1)?;
+        put_main_block(archive, rwtxn, m2, m1, Some(s2))?;
+        let s2b = put_block(archive, rwtxn, Some(s1), m2)?;
+        put_main_block(archive, rwtxn, m3, m2, Some(s2b))?;
+        let s3 = put_block(archive, rwtxn, Some(s2), m3)?;
+        put_main_block(archive, rwtxn, m4, m3, Some(s3))?;
+        Ok(Chain {
+            s1,
+            s2: Tip {
+                block_hash: s2,
+                main_block_hash: m2,
+            },
+            s3: Tip {
+                block_hash: s3,
+                main_block_hash: m4,
+            },
+            s2b: Tip {
+                block_hash: s2b,
+                main_block_hash: m3,
+            },
+        })
+    }
+
+    #[test]
+    fn invalidate_block() -> anyhow::Result<()> {
+        let (env, archive) = temp_archive("archive-invalidate-block")?;
+        let mut rwtxn = env.write_txn()?;
+        let chain = build_chain(&archive, &mut rwtxn)?;
+        anyhow::ensure!(
+            archive.best_valid_descendant_tip(&rwtxn, None)? == Some(chain.s3)
+        );
+        archive.invalidate_block(&mut rwtxn, chain.s2.block_hash)?;
+        // Descendants of an invalid block are invalid
+        anyhow::ensure!(archive.is_invalid(&rwtxn, chain.s2.block_hash)?);
+        anyhow::ensure!(archive.is_invalid(&rwtxn, chain.s3.block_hash)?);
+        // Ancestors and other branches are not
+        anyhow::ensure!(!archive.is_invalid(&rwtxn, chain.s1)?);
+        anyhow::ensure!(!archive.is_invalid(&rwtxn, chain.s2b.block_hash)?);
+        anyhow::ensure!(
+            archive.best_valid_descendant_tip(&rwtxn, None)? == Some(chain.s2b)
+        );
+        anyhow::ensure!(
+            archive.best_valid_descendant_tip(&rwtxn, Some(chain.s1))?
+                == Some(chain.s2b)
+        );
+        anyhow::ensure!(
+            archive
+                .best_valid_descendant_tip(&rwtxn, Some(chain.s2.block_hash))?
+                .is_none()
+        );
+        anyhow::ensure!(
+            archive
+                .best_valid_descendant_tip(&rwtxn, Some(chain.s3.block_hash))?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reconsider_block() -> anyhow::Result<()> {
+        let (env, archive) = temp_archive("archive-reconsider-block")?;
+        let mut rwtxn = env.write_txn()?;
+        let chain = build_chain(&archive, &mut rwtxn)?;
+        // Reconsidering a valid block has no effect
+        anyhow::ensure!(
+            !archive.reconsider_block(&mut rwtxn, chain.s2.block_hash)?
+        );
+        archive.invalidate_block(&mut rwtxn, chain.s2.block_hash)?;
+        // Reconsidering a block on another branch has no effect
+        anyhow::ensure!(
+            !archive.reconsider_block(&mut rwtxn, chain.s2b.block_hash)?
+        );
+        anyhow::ensure!(archive.is_invalid(&rwtxn, chain.s3.block_hash)?);
+        // Reconsidering a descendant also reconsiders the invalid ancestor
+        anyhow::ensure!(
+            archive.reconsider_block(&mut rwtxn, chain.s3.block_hash)?
+        );
+        anyhow::ensure!(archive.invalid_blocks(&rwtxn)?.is_empty());
+        anyhow::ensure!(!archive.is_invalid(&rwtxn, chain.s3.block_hash)?);
+        anyhow::ensure!(
+            archive.best_valid_descendant_tip(&rwtxn, None)? == Some(chain.s3)
+        );
+        // Reconsidering an ancestor also reconsiders invalid descendants
+        archive.invalidate_block(&mut rwtxn, chain.s3.block_hash)?;
+        anyhow::ensure!(
+            archive
+                .best_valid_descendant_tip(&rwtxn, Some(chain.s2.block_hash))?
+                == Some(chain.s2)
+        );
+        anyhow::ensure!(archive.reconsider_block(&mut rwtxn, chain.s1)?);
+        anyhow::ensure!(
+            archive
+                .best_valid_descendant_tip(&rwtxn, Some(chain.s2.block_hash))?
+                == Some(chain.s3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chain_tips() -> anyhow::Result<()> {
+        let (env, archive) = temp_archive("archive-chain-tips")?;
+        let mut rwtxn = env.write_txn()?;
+        let chain = build_chain(&archive, &mut rwtxn)?;
+        let empty_header = |prev_side_hash, prev_main_hash| Header {
+            merkle_root: Body {
+                coinbase: Vec::new(),
+                transactions: Vec::new(),
+                authorizations: Vec::new(),
+            }
+            .compute_merkle_root(),
+            prev_side_hash: Some(prev_side_hash),
+            prev_main_hash,
+            roots: Vec::new(),
+        };
+        // Headers without bodies, extending the fork and forking from genesis
+        let m3 = chain.s2b.main_block_hash;
+        let s3b = empty_header(chain.s2b.block_hash, m3);
+        archive.put_header(&mut rwtxn, &s3b)?;
+        let s2c = empty_header(chain.s1, m3);
+        archive.put_header(&mut rwtxn, &s2c)?;
+        let status = |rwtxn: &sneed::RwTxn,
+                      block_hash: BlockHash|
+         -> anyhow::Result<(u32, ChainTipStatus)> {
+            let chain_tips =
+                archive.chain_tips(rwtxn, Some(chain.s3.block_hash))?;
+            chain_tips
+                .into_iter()
+                .find(|chain_tip| chain_tip.block_hash == block_hash)
+                .map(|chain_tip| (chain_tip.branch_len, chain_tip.status))
+                .ok_or_else(|| anyhow::anyhow!("missing chain tip"))
+        };
+        let chain_tips =
+            archive.chain_tips(&rwtxn, Some(chain.s3.block_hash))?;
+        anyhow::ensure!(chain_tips.len() == 3);
+        anyhow::ensure!(chain_tips[0].height == 2);
+        anyhow::ensure!(
+            status(&rwtxn, chain.s3.block_hash)? == (0, ChainTipStatus::Active)
+        );
+        anyhow::ensure!(
+            status(&rwtxn, s3b.hash())? == (2, ChainTipStatus::MissingBody)
+        );
+        anyhow::ensure!(
+            status(&rwtxn, s2c.hash())? == (1, ChainTipStatus::HeadersOnly)
+        );
+        archive.invalidate_block(&mut rwtxn, chain.s2b.block_hash)?;
+        anyhow::ensure!(
+            status(&rwtxn, s3b.hash())? == (2, ChainTipStatus::Invalid)
+        );
+        // A fork for which all bodies are available is valid
+        archive.put_body(
+            &mut rwtxn,
+            s2c.hash(),
+            &Body {
+                coinbase: Vec::new(),
+                transactions: Vec::new(),
+                authorizations: Vec::new(),
+            },
+        )?;
+        anyhow::ensure!(
+            status(&rwtxn, s2c.hash())? == (1, ChainTipStatus::ValidFork)
+        );
+        Ok(())
+    }
+
+    // only ancestors of the pruned chain are reported as pruned, so that
+    // bodies for forks below the prune height are still requested
+    #[test]
+    fn fork_blocks_are_not_pruned() -> anyhow::Result<()> {
+        let (env, archive) = temp_archive("archive-fork-blocks-not-pruned")?;
+        let mut rwtxn = env.write_txn()?;
+        let chain = build_chain(&archive, &mut rwtxn)?;
+        // Prune `s1` and `s2`
+        anyhow::ensure!(
+            archive.prune(&mut rwtxn, chain.s3.block_hash, 1, false)? == 2
+        );
+        archive.bodies.delete(&mut rwtxn, &chain.s2b.block_hash)?;
+        anyhow::ensure!(archive.is_pruned(&rwtxn, chain.s1)?);
+        anyhow::ensure!(archive.is_pruned(&rwtxn, chain.s2.block_hash)?);
+        anyhow::ensure!(!archive.is_pruned(&rwtxn, chain.s2b.block_hash)?);
+        anyhow::ensure!(!archive.is_pruned(&rwtxn, chain.s3.block_hash)?);
+        anyhow::ensure!(
+            archive
+                .get_missing_bodies(&rwtxn, chain.s3.block_hash, None)?
+                .is_empty()
+        );
+        anyhow::ensure!(
+            archive.get_missing_bodies(&rwtxn, chain.s2b.block_hash, None)?
+                == [chain.s2b.block_hash]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn block_hash_ranges() -> anyhow::Result<()> {
+        let (env, archive) = temp_archive("archive-block-hash-ranges")?;
+        let mut rwtxn = env.write_txn()?;
+        let chain = build_chain(&archive, &mut rwtxn)?;
+        let tip = chain.s3.block_hash;
+        let range = |start_height, count| {
+            archive.get_block_hashes(&rwtxn, tip, start_height, count)
+        };
+        let (s1, s2, s3) = (chain.s1, chain.s2.block_hash, tip);
+        anyhow::ensure!(range(0, 3)? == [s1, s2, s3]);
+        anyhow::ensure!(range(1, 1)? == [s2]);
+        // Ranges are clamped to the tip
+        anyhow::ensure!(range(1, 10)? == [s2, s3]);
+        anyhow::ensure!(range(2, u32::MAX)? == [s3]);
+        anyhow::ensure!(range(3, 1)?.is_empty());
+        anyhow::ensure!(range(0, 0)?.is_empty());
+        // Blocks on other branches are not included
+        let fork = archive.get_block_hashes(
+            &rwtxn,
+            chain.s2b.block_hash,
+            0,
+            u32::MAX,
+        )?;
+        anyhow::ensure!(fork == [s1, chain.s2b.block_hash]);
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@
 pub mod archive;
 pub mod authorization;
 pub mod mempool;
+pub mod migration;
 pub mod miner;
 pub mod net;
 pub mod node;
@@ -6,14 +6,18 @@ use sneed::{DatabaseUnique, RoTxn, RwTxn, RwTxnError, UnitKey, db, env};
 use thiserror::Error;
 use transitive::Transitive;
 
-use crate::types::{
-    Accumulator, AuthorizedTransaction, Body, OutPoint, Txid, UtreexoError,
-    VERSION, Version, orchard::Nullifier,
+use crate::{
+    migration,
+    types::{
+        Accumulator, AuthorizedTransaction, Body, OutPoint, Txid, UtreexoError,
+        Version, orchard::Nullifier,
+    },
 };
 
 #[allow(clippy::duplicated_attributes)]
 #[derive(Debug, Error, Transitive)]
 #[transitive(
+    from(db::error::Clear, db::Error),
     from(db::error::Delete, db::Error),
     from(db::error::Get, db::Error),
     from(db::error::IterInit, db::Error),
@@ -72,6 +76,24 @@ pub struct MemPool {
 impl MemPool {
     pub const NUM_DBS: u32 = 4;
 
+    /// Schema version of the mempool DBs
+    pub const SCHEMA_VERSION: Version = Version {
+        major: 0,
+        minor: 16,
+        patch: 0,
+    };
+
+    pub const SCHEMA: migration::Schema = migration::Schema {
+        version_db: "mempool_version",
+        version: Self::SCHEMA_VERSION,
+        min_version: Version {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        },
+        steps: &[],
+    };
+
     pub fn new(env: &sneed::Env) -> Result<Self, Error> {
         let mut rwtxn = env.write_txn()?;
         let transactions =
@@ -81,9 +103,9 @@ impl MemPool {
         let used_nullifiers =
             DatabaseUnique::create(env, &mut rwtxn, "used_nullifiers")?;
         let version =
-            DatabaseUnique::create(env, &mut rwtxn, "mempool_version")?;
+            DatabaseUnique::create(env, &mut rwtxn, Self::SCHEMA.version_db)?;
         if version.try_get(&rwtxn, &())?.is_none() {
-            version.put(&mut rwtxn, &(), &*VERSION)?;
+            version.put(&mut rwtxn, &(), &Self::SCHEMA_VERSION)?;
         }
         rwtxn.commit().map_err(RwTxnError::from)?;
         Ok(Self {
@@ -185,6 +207,14 @@ impl MemPool {
             .map_err(Error::from)
     }
 
+    /// Remove all transactions from the mempool
+    pub fn clear(&self, rwtxn: &mut RwTxn) -> Result<(), Error> {
+        self.transactions.clear(rwtxn)?;
+        self.spent_utxos.clear(rwtxn)?;
+        self.used_nullifiers.clear(rwtxn)?;
+        Ok(())
+    }
+
     /// regenerate utreexo proofs for all txs in the mempool
     pub fn regenerate_proofs(
         &self,
@@ -0,0 +1,444 @@
+//! Database schema migrations
+//!
+//! Each set of databases (`State`, `Archive`, `MemPool`, `Net`, `Wallet`)
+//! has its own schema version, which is stored alongside the databases.
+//! Schema versions are independent of the software version, and are only
+//! bumped when a migration step is added.
+//! When an env is opened, the stored versions are compared with the current
+//! schema versions, and any migration steps introduced since the stored
+//! version are applied in order, in a single write txn.
+
+use std::path::PathBuf;
+
+use heed::types::SerdeBincode;
+use sneed::{
+    DatabaseUnique, DbError, EnvError, RwTxn, RwTxnError, UnitKey, db, env,
+};
+
+use crate::types::Version;
+
+#[allow(clippy::duplicated_attributes)]
+#[derive(Debug, thiserror::Error, transitive::Transitive)]
+#[transitive(
+    from(db::error::IterInit, DbError),
+    from(db::error::IterItem, DbError),
+    from(db::error::Put, DbError),
+    from(db::error::TryGet, DbError),
+    from(env::error::CreateDb, EnvError),
+    from(env::error::WriteTxn, EnvError)
+)]
+pub enum Error {
+    #[error(transparent)]
+    Archive(#[from] crate::archive::Error),
+    #[error(
+        "failed to back up DB (`{}`) to `{}`",
+        .db_path.display(),
+        .backup_path.display()
+    )]
+    Backup {
+        db_path: PathBuf,
+        backup_path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error("Database env error")]
+    DbEnv(#[from] EnvError),
+    #[error("Database write error")]
+    DbWrite(#[from] RwTxnError),
+    #[error(
+        "Incompatible DB version ({}). Please clear the DB (`{}`) and re-sync",
+        .version,
+        .db_path.display()
+    )]
+    IncompatibleVersion { version: Version, db_path: PathBuf },
+    #[error(
+        "cannot migrate DB (`{}`): {reason}. Please clear the DB and re-sync",
+        .db_path.display()
+    )]
+    MissingData { db_path: PathBuf, reason: String },
+    #[error(
+        "DB (`{}`) was written with a newer schema version ({}), and cannot \
+         be opened by software that supports schema version {}",
+        .db_path.display(),
+        .version,
+        .supported
+    )]
+    NewerVersion {
+        version: Version,
+        supported: Version,
+        db_path: PathBuf,
+    },
+    #[error(transparent)]
+    State(#[from] Box<crate::state::Error>),
+    #[error("migration step to {version} (`{description}`) failed")]
+    Step {
+        version: Version,
+        description: &'static str,
+        source: Box<Error>,
+    },
+}
+
+impl From<crate::state::Error> for Error {
+    fn from(err: crate::state::Error) -> Self {
+        Self::State(Box::new(err))
+    }
+}
+
+/// A migration step, applied to databases written by versions before
+/// `version`
+#[derive(Clone, Copy, Debug)]
+pub struct Step {
+    /// Schema version in which the schema change was introduced
+    pub version: Version,
+    pub description: &'static str,
+    pub run: fn(&sneed::Env, &mut RwTxn) -> Result<(), Error>,
+}
+
+/// Versioning and migrations for a set of databases
+#[derive(Clone, Copy, Debug)]
+pub struct Schema {
+    /// Name of the database in which the version is stored
+    pub version_db: &'static str,
+    /// Current schema version. MUST be bumped when adding a step.
+    pub version: Version,
+    /// Databases written by versions before this cannot be migrated
+    pub min_version: Version,
+    /// Migration steps, ordered by version
+    pub steps: &'static [Step],
+}
+
+impl Schema {
+    /// Steps that must be applied to databases written by the specified
+    /// version
+    fn pending_steps(&self, stored_version: Version) -> Vec<&'static Step> {
+        debug_assert!(self.steps.is_sorted_by_key(|step| step.version));
+        self.steps
+            .iter()
+            .filter(|step| {
+                step.version > stored_version && step.version <= self.version
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Config {
+    /// Apply migration steps without committing them, so that failures are
+    /// reported without modifying the env
+    pub dry_run: bool,
+}
+
+/// Migration result for a set of databases
+#[derive(Clone, Debug)]
+pub struct SchemaReport {
+    pub version_db: &'static str,
+    /// `None` if the databases were created by this run
+    pub stored_version: Option<Version>,
+    /// Steps that were applied, or would be applied in a dry run
+    pub steps: Vec<&'static Step>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub dry_run: bool,
+    /// Path to the backup of the env, if one was made
+    pub backup_path: Option<PathBuf>,
+    pub schemas: Vec<SchemaReport>,
+}
+
+impl Report {
+    pub fn n_steps(&self) -> usize {
+        self.schemas.iter().map(|schema| schema.steps.len()).sum()
+    }
+}
+
+/// Copy the env data file to a timestamped directory alongside the env.
+/// The env is opened with `NO_SYNC` and `MAP_ASYNC`, so committed pages may
+/// not have been flushed to disk, but they are read through the OS page
+/// cache, so the copy includes them.
+/// Must be called while holding a write txn, since a commit during the copy
+/// could leave the backup inconsistent.
+fn backup_env(env: &sneed::Env) -> Result<PathBuf, Error> {
+    let db_path = env.path().to_path_buf();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut backup_name = db_path.file_name().unwrap_or_default().to_owned();
+    backup_name.push(format!(".backup-{timestamp}"));
+    let backup_path = db_path.with_file_name(backup_name);
+    let res = std::fs::create_dir_all(&backup_path).and_then(|()| {
+        std::fs::copy(db_path.join("data.mdb"), backup_path.join("data.mdb"))
+    });
+    match res {
+        Ok(_) => Ok(backup_path),
+        Err(source) => Err(Error::Backup {
+            db_path,
+            backup_path,
+            source,
+        }),
+    }
+}
+
+/// Check the stored versions for each schema, and apply any pending
+/// migration steps.
+/// If any steps are pending, the env is backed up before they are applied.
+/// Returns an error if any stored version is older than the minimum version
+/// for its schema, or is newer than the current schema version.
+pub fn migrate(
+    env: &sneed::Env,
+    schemas: &[Schema],
+    config: Config,
+) -> Result<Report, Error> {
+    let db_path = env.path().to_path_buf();
+    let mut rwtxn = env.write_txn()?;
+    let mut version_dbs = Vec::with_capacity(schemas.len());
+    let mut schema_reports = Vec::with_capacity(schemas.len());
+    for schema in schemas {
+        let version_db: DatabaseUnique<UnitKey, SerdeBincode<Version>> =
+            DatabaseUnique::create(env, &mut rwtxn, schema.version_db)?;
+        let stored_version = version_db.try_get(&rwtxn, &())?;
+        let steps = match stored_version {
+            Some(version) if version < schema.min_version => {
+                return Err(Error::IncompatibleVersion { version, db_path });
+            }
+            Some(version) if version > schema.version => {
+                return Err(Error::NewerVersion {
+                    version,
+                    supported: schema.version,
+                    db_path,
+                });
+            }
+            Some(version) => schema.pending_steps(version),
+            None => Vec::new(),
+        };
+        version_dbs.push(version_db);
+        schema_reports.push(SchemaReport {
+            version_db: schema.version_db,
+            stored_version,
+            steps,
+        });
+    }
+    let mut report = Report {
+        dry_run: config.dry_run,
+        backup_path: None,
+        schemas: schema_reports,
+    };
+    if report.n_steps() > 0 && !config.dry_run {
+        rwtxn.commit().map_err(RwTxnError::from)?;
+        // Hold the write lock while backing up, so that no other txn can
+        // commit during the copy
+        rwtxn = env.write_txn()?;
+        let backup_path = backup_env(env)?;
+        tracing::info!(
+            backup_path = %backup_path.display(),
+            "backed up DB before migrating"
+        );
+        report.backup_path = Some(backup_path);
+    }
+    for ((schema, schema_report), version_db) in
+        schemas.iter().zip(&report.schemas).zip(&version_dbs)
+    {
+        for step in &schema_report.steps {
+            tracing::info!(
+                version_db = schema_report.version_db,
+                version = %step.version,
+                dry_run = config.dry_run,
+                "applying migration step: {}",
+                step.description
+            );
+            let () =
+                (step.run)(env, &mut rwtxn).map_err(|err| Error::Step {
+                    version: step.version,
+                    description: step.description,
+                    source: Box::new(err),
+                })?;
+        }
+        if schema_report
+            .stored_version
+            .is_none_or(|version| version < schema.version)
+        {
+            version_db.put(&mut rwtxn, &(), &schema.version)?;
+        }
+    }
+    if !config.dry_run {
+        rwtxn.commit().map_err(RwTxnError::from)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use heed::types::SerdeBincode;
+    use sneed::{DatabaseUnique, RwTxn, UnitKey};
+
+    use super::{Config, Error, Schema, Step, migrate};
+    use crate::types::Version;
+
+    const V0_0_0: Version = Version {
+        major: 0,
+        minor: 0,
+        patch: 0,
+    };
+
+    const V0_1_0: Version = Version {
+        major: 0,
+        minor: 1,
+        patch: 0,
+    };
+
+    const V0_2_0: Version = Version {
+        major: 0,
+        minor: 2,
+        patch: 0,
+    };
+
+    fn put_marker(env: &sneed::Env, rwtxn: &mut RwTxn) -> Result<(), Error> {
+        let db: DatabaseUnique<UnitKey, SerdeBincode<u32>> =
+            DatabaseUnique::create(env, rwtxn, "test_marker")?;
+        let marker = db.try_get(rwtxn, &())?.unwrap_or(0);
+        db.put(rwtxn, &(), &(marker + 1))?;
+        Ok(())
+    }
+
+    const V0_3_0: Version = Version {
+        major: 0,
+        minor: 3,
+        patch: 0,
+    };
+
+    const SCHEMA: Schema = Schema {
+        version_db: "test_version",
+        version: V0_2_0,
+        min_version: V0_1_0,
+        steps: &[
+            Step {
+                version: V0_2_0,
+                description: "put marker",
+                run: put_marker,
+            },
+            // Not applied until the schema version is bumped
+            Step {
+                version: V0_3_0,
+                description: "put marker again",
+                run: put_marker,
+            },
+        ],
+    };
+
+    fn temp_env(test_name: &str) -> anyhow::Result<sneed::Env> {
+        let mut path = std::env::temp_dir();
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        path.push(format!(
+            "thunder-orchard-{test_name}-{}-{nanos}",
+            std::process::id()
+        ));
+        path.push("data.mdb");
+        std::fs::create_dir_all(&path)?;
+        let mut opts = heed::EnvOpenOptions::new();
+        opts.map_size(16 * 1024 * 1024).max_dbs(2);
+        let res = unsafe { sneed::Env::open(&opts, &path) }?;
+        Ok(res)
+    }
+
+    fn put_version(env: &sneed::Env, version: Version) -> anyhow::Result<()> {
+        let mut rwtxn = env.write_txn()?;
+        let db: DatabaseUnique<UnitKey, SerdeBincode<Version>> =
+            DatabaseUnique::create(env, &mut rwtxn, SCHEMA.version_db)?;
+        db.put(&mut rwtxn, &(), &version)?;
+        rwtxn.commit()?;
+        Ok(())
+    }
+
+    fn get_version_and_marker(
+        env: &sneed::Env,
+    ) -> anyhow::Result<(Option<Version>, Option<u32>)> {
+        let mut rwtxn = env.write_txn()?;
+        let version_db: DatabaseUnique<UnitKey, SerdeBincode<Version>> =
+            DatabaseUnique::create(env, &mut rwtxn, SCHEMA.version_db)?;
+        let marker_db: DatabaseUnique<UnitKey, SerdeBincode<u32>> =
+            DatabaseUnique::create(env, &mut rwtxn, "test_marker")?;
+        let version = version_db.try_get(&rwtxn, &())?;
+        let marker = marker_db.try_get(&rwtxn, &())?;
+        Ok((version, marker))
+    }
+
+    #[test]
+    fn new_env_is_not_migrated() -> anyhow::Result<()> {
+        let env = temp_env("migration-new-env")?;
+        let report = migrate(&env, &[SCHEMA], Config::default())?;
+        assert_eq!(report.n_steps(), 0);
+        assert!(report.backup_path.is_none());
+        assert_eq!(get_version_and_marker(&env)?, (Some(SCHEMA.version), None));
+        Ok(())
+    }
+
+    #[test]
+    fn old_env_is_migrated_once() -> anyhow::Result<()> {
+        let env = temp_env("migration-old-env")?;
+        put_version(&env, V0_1_0)?;
+        let report = migrate(&env, &[SCHEMA], Config::default())?;
+        assert_eq!(report.n_steps(), 1);
+        let backup_path = report.backup_path.expect("backup should exist");
+        assert!(backup_path.join("data.mdb").is_file());
+        assert_eq!(
+            get_version_and_marker(&env)?,
+            (Some(SCHEMA.version), Some(1))
+        );
+        // Steps are not re-applied
+        let report = migrate(&env, &[SCHEMA], Config::default())?;
+        assert_eq!(report.n_steps(), 0);
+        assert_eq!(
+            get_version_and_marker(&env)?,
+            (Some(SCHEMA.version), Some(1))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn steps_are_applied_when_schema_version_is_bumped() -> anyhow::Result<()> {
+        let env = temp_env("migration-bumped")?;
+        put_version(&env, V0_2_0)?;
+        let report = migrate(&env, &[SCHEMA], Config::default())?;
+        assert_eq!(report.n_steps(), 0);
+        let bumped = Schema {
+            version: V0_3_0,
+            ..SCHEMA
+        };
+        let report = migrate(&env, &[bumped], Config::default())?;
+        assert_eq!(report.n_steps(), 1);
+        assert_eq!(report.schemas[0].steps[0].version, V0_3_0);
+        assert_eq!(get_version_and_marker(&env)?, (Some(V0_3_0), Some(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_does_not_modify_env() -> anyhow::Result<()> {
+        let env = temp_env("migration-dry-run")?;
+        put_version(&env, V0_1_0)?;
+        let report = migrate(&env, &[SCHEMA], Config { dry_run: true })?;
+        assert_eq!(report.n_steps(), 1);
+        assert!(report.backup_path.is_none());
+        assert_eq!(get_version_and_marker(&env)?, (Some(V0_1_0), None));
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() -> anyhow::Result<()> {
+        let env = temp_env("migration-unsupported")?;
+        put_version(&env, V0_0_0)?;
+        assert!(matches!(
+            migrate(&env, &[SCHEMA], Config::default()),
+            Err(Error::IncompatibleVersion { .. })
+        ));
+        put_version(&env, V0_3_0)?;
+        assert!(matches!(
+            migrate(&env, &[SCHEMA], Config::default()),
+            Err(Error::NewerVersion { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! Byte-based bandwidth limits and accounting.
+//!
+//! Upload and download rates may be limited globally, and per peer.
+//! A peer connection waits on both its own limiter and the global limiter
+//! before sending or accepting data.
+//! A rolling daily upload target may also be set. Once the target is
+//! reached, only new-block relay is served to peers.
+
+use std::{
+    collections::VecDeque,
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use governor::{DefaultDirectRateLimiter, Quota};
+use parking_lot::Mutex;
+
+use crate::net::Config;
+
+/// Window over which the upload target applies
+const UPLOAD_TARGET_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Granularity of upload target accounting
+const UPLOAD_TARGET_BUCKET: Duration = Duration::from_secs(60 * 60);
+
+/// Once the upload target has been reached, only blocks within this many
+/// blocks of our tip are served
+pub(in crate::net) const NEW_BLOCK_RELAY_DEPTH: u32 = 6;
+
+/// Rate limiter with a burst size equal to the rate per second
+struct RateLimiter {
+    inner: DefaultDirectRateLimiter,
+    burst: NonZeroU32,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_second: NonZeroU32) -> Self {
+        Self {
+            inner: DefaultDirectRateLimiter::direct(Quota::per_second(
+                bytes_per_second,
+            )),
+            burst: bytes_per_second,
+        }
+    }
+
+    /// Wait until `bytes` can be transferred.
+    /// Transfers larger than the burst size are split into bursts.
+    async fn until_ready(&self, bytes: usize) {
+        let mut remaining = bytes;
+        while remaining > 0 {
+            let n = remaining.min(self.burst.get() as usize);
+            let () = self
+                .inner
+                .until_n_ready(NonZeroU32::new(n as u32).unwrap())
+                .await
+                .expect("transfer should not exceed burst size");
+            remaining -= n;
+        }
+    }
+}
+
+/// Rolling daily upload target
+struct UploadTarget {
+    target: u64,
+    /// Bytes uploaded, bucketed by the start time of each bucket.
+    /// Ordered from oldest to newest.
+    buckets: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl UploadTarget {
+    fn new(target: u64) -> Self {
+        Self {
+            target,
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn prune(buckets: &mut VecDeque<(Instant, u64)>, now: Instant) {
+        while let Some((bucket_start, _)) = buckets.front()
+            && now.duration_since(*bucket_start) >= UPLOAD_TARGET_WINDOW
+        {
+            buckets.pop_front();
+        }
+    }
+
+    fn record(&self, bytes: usize) {
+        self.record_at(bytes, Instant::now())
+    }
+
+    fn record_at(&self, bytes: usize, now: Instant) {
+        let mut buckets = self.buckets.lock();
+        Self::prune(&mut buckets, now);
+        match buckets.back_mut() {
+            Some((bucket_start, bucket_bytes))
+                if now.duration_since(*bucket_start) < UPLOAD_TARGET_BUCKET =>
+            {
+                *bucket_bytes += bytes as u64;
+            }
+            _ => buckets.push_back((now, bytes as u64)),
+        }
+    }
+
+    /// Bytes uploaded within the window
+    fn uploaded_at(&self, now: Instant) -> u64 {
+        let mut buckets = self.buckets.lock();
+        Self::prune(&mut buckets, now);
+        buckets.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    fn exceeded(&self) -> bool {
+        self.exceeded_at(Instant::now())
+    }
+
+    fn exceeded_at(&self, now: Instant) -> bool {
+        self.uploaded_at(now) >= self.target
+    }
+}
+
+/// Bandwidth limits and accounting shared by all peer connections
+pub(in crate::net) struct Global {
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+    upload_target: Option<UploadTarget>,
+}
+
+impl Global {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            upload: config.max_upload_rate.map(RateLimiter::new),
+            download: config.max_download_rate.map(RateLimiter::new),
+            upload_target: config.max_upload_target.map(UploadTarget::new),
+        }
+    }
+}
+
+/// Bandwidth limiter for a single peer connection
+pub(in crate::net) struct Limiter {
+    global: Arc<Global>,
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+}
+
+impl Limiter {
+    pub fn new(global: Arc<Global>, config: &Config) -> Self {
+        Self {
+            global,
+            upload: config.max_upload_rate_per_peer.map(RateLimiter::new),
+            download: config.max_download_rate_per_peer.map(RateLimiter::new),
+        }
+    }
+
+    /// Wait until `bytes` can be sent to the peer, and record them against
+    /// the upload target.
+    pub async fn upload(&self, bytes: usize) {
+        if let Some(upload) = &self.upload {
+            let () = upload.until_ready(bytes).await;
+        }
+        if let Some(upload) = &self.global.upload {
+            let () = upload.until_ready(bytes).await;
+        }
+        if let Some(upload_target) = &self.global.upload_target {
+            let () = upload_target.record(bytes);
+        }
+    }
+
+    /// Wait until `bytes` received from the peer can be accepted
+    pub async fn download(&self, bytes: usize) {
+        if let Some(download) = &self.download {
+            let () = download.until_ready(bytes).await;
+        }
+        if let Some(download) = &self.global.download {
+            let () = download.until_ready(bytes).await;
+        }
+    }
+
+    /// Returns `true` if the daily upload target has been reached, in which
+    /// case only new-block relay should be served.
+    pub fn upload_target_exceeded(&self) -> bool {
+        self.global
+            .upload_target
+            .as_ref()
+            .is_some_and(UploadTarget::exceeded)
+    }
+
+    /// Returns `true` if the upload target has been reached, and a block at
+    /// the specified height is too far behind our tip to count as new-block
+    /// relay
+    pub fn upload_target_refuses_block(
+        &self,
+        height: u32,
+        tip_height: Option<u32>,
+    ) -> bool {
+        self.upload_target_exceeded()
+            && tip_height.is_some_and(|tip_height| {
+                height.saturating_add(NEW_BLOCK_RELAY_DEPTH) <= tip_height
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        num::NonZeroU32,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        Global, Limiter, NEW_BLOCK_RELAY_DEPTH, RateLimiter,
+        UPLOAD_TARGET_BUCKET, UPLOAD_TARGET_WINDOW, UploadTarget,
+    };
+    use crate::net::Config;
+
+    // transfers larger than the burst size are split, and delayed
+    #[test]
+    fn rate_limiter_splits_large_transfers() -> anyhow::Result<()> {
+        let limiter = RateLimiter::new(NonZeroU32::new(1000).unwrap());
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let start = Instant::now();
+        let () = runtime.block_on(limiter.until_ready(2000));
+        anyhow::ensure!(start.elapsed() >= Duration::from_millis(900));
+        Ok(())
+    }
+
+    // uploads stop counting against the target once they leave the window
+    #[test]
+    fn upload_target_rolls_over() {
+        let upload_target = UploadTarget::new(1000);
+        let start = Instant::now();
+        upload_target.record_at(600, start);
+        upload_target.record_at(600, start + UPLOAD_TARGET_BUCKET);
+        assert!(upload_target.exceeded_at(start + UPLOAD_TARGET_BUCKET));
+        assert!(!upload_target.exceeded_at(start + UPLOAD_TARGET_WINDOW));
+        assert_eq!(
+            upload_target.uploaded_at(start + UPLOAD_TARGET_WINDOW),
+            600
+        );
+        assert_eq!(
+            upload_target.uploaded_at(
+                start + UPLOAD_TARGET_BUCKET + UPLOAD_TARGET_WINDOW
+            ),
+            0
+        );
+    }
+
+    // once the target is reached, only blocks near the tip are served
+    #[test]
+    fn upload_target_refuses_old_blocks() {
+        let config = Config {
+            max_upload_target: Some(1000),
+            ..Config::default()
+        };
+        let limiter = Limiter::new(Arc::new(Global::new(&config)), &config);
+        let tip_height = 100;
+        let old_height = tip_height - NEW_BLOCK_RELAY_DEPTH;
+        let new_height = old_height + 1;
+        assert!(
+            !limiter.upload_target_refuses_block(old_height, Some(tip_height))
+        );
+        limiter.global.upload_target.as_ref().unwrap().record(1000);
+        assert!(
+            limiter.upload_target_refuses_block(old_height, Some(tip_height))
+        );
+        assert!(
+            !limiter.upload_target_refuses_block(new_height, Some(tip_height))
+        );
+        assert!(!limiter.upload_target_refuses_block(old_height, None));
+    }
+}
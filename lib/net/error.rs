@@ -66,8 +66,12 @@ pub enum Error {
     DbEnv(#[from] env::Error),
     #[error("Database write error")]
     DbWrite(#[from] rwtxn::Error),
+    #[error("invalid node identity key at `{}`", .0.display())]
+    InvalidNodeIdentity(std::path::PathBuf),
     #[error("quinn error")]
     Io(#[from] std::io::Error),
+    #[error("max outbound connections ({0}) reached")]
+    MaxOutbound(usize),
     #[error("peer connection not found for {0}")]
     MissingPeerConnection(SocketAddr),
     /// Unspecified peer IP addresses cannot be connected to.
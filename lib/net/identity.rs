@@ -0,0 +1,193 @@
+//! Node identity keys.
+//!
+//! Each node has a persistent Ed25519 keypair, which is used to sign the
+//! self-signed certificate that the node presents to peers, both when
+//! accepting and when initiating QUIC connections.
+//! A peer's identity is the public key in the certificate that it presents.
+
+use std::{path::Path, str::FromStr, sync::Arc};
+
+use hex::FromHex;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Serialize};
+
+use crate::net::Error;
+
+/// DER encoding of an Ed25519 `SubjectPublicKeyInfo`, excluding the public
+/// key itself
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Public key identifying a node
+#[derive(
+    Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[repr(transparent)]
+pub struct NodePublicKey(#[serde(with = "hex::serde")] pub [u8; 32]);
+
+impl NodePublicKey {
+    /// Extract the public key from a certificate, if the certificate is
+    /// well-formed and uses an Ed25519 key.
+    pub(in crate::net) fn from_cert(cert: &CertificateDer<'_>) -> Option<Self> {
+        let cert = rustls::server::ParsedCertificate::try_from(cert).ok()?;
+        let spki = cert.subject_public_key_info();
+        let key_bytes = spki.as_ref().strip_prefix(&ED25519_SPKI_PREFIX)?;
+        key_bytes.try_into().ok().map(Self)
+    }
+}
+
+impl std::fmt::Debug for NodePublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Display for NodePublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for NodePublicKey {
+    type Err = hex::FromHexError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <[u8; 32]>::from_hex(s).map(Self)
+    }
+}
+
+impl utoipa::PartialSchema for NodePublicKey {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
+        let obj =
+            utoipa::openapi::Object::with_type(utoipa::openapi::Type::String);
+        utoipa::openapi::RefOr::T(utoipa::openapi::Schema::Object(obj))
+    }
+}
+
+impl utoipa::ToSchema for NodePublicKey {
+    fn name() -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Borrowed("NodePublicKey")
+    }
+}
+
+/// Create a key file that is only readable by the owner, on unix.
+/// Returns an error if the file already exists.
+fn write_key_file(path: &Path, pem: &str) -> std::io::Result<()> {
+    use std::io::Write as _;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(pem.as_bytes())?;
+    file.sync_all()
+}
+
+/// Persistent node identity
+#[derive(Clone)]
+pub struct NodeIdentity {
+    key_pair: Arc<rcgen::KeyPair>,
+    public_key: NodePublicKey,
+}
+
+impl NodeIdentity {
+    fn from_key_pair(key_pair: rcgen::KeyPair) -> Option<Self> {
+        if key_pair.algorithm() != &rcgen::PKCS_ED25519 {
+            return None;
+        }
+        let public_key = key_pair.public_key_raw().try_into().ok()?;
+        Some(Self {
+            key_pair: Arc::new(key_pair),
+            public_key: NodePublicKey(public_key),
+        })
+    }
+
+    /// Load the node identity from a PEM-encoded PKCS#8 key file,
+    /// generating and storing a new identity if the file does not exist.
+    pub fn load_or_generate(path: &Path) -> Result<Self, Error> {
+        let key_pair = if path.exists() {
+            let pem = std::fs::read_to_string(path)?;
+            rcgen::KeyPair::from_pem(&pem)?
+        } else {
+            let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519)?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let () = write_key_file(path, &key_pair.serialize_pem())?;
+            tracing::info!("Generated new node identity at {}", path.display());
+            key_pair
+        };
+        Self::from_key_pair(key_pair)
+            .ok_or_else(|| Error::InvalidNodeIdentity(path.to_owned()))
+    }
+
+    pub fn public_key(&self) -> NodePublicKey {
+        self.public_key
+    }
+
+    /// Generate a self-signed certificate for this identity
+    pub(in crate::net) fn certificate(
+        &self,
+    ) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), Error> {
+        let cert = rcgen::CertificateParams::new(vec!["localhost".into()])?
+            .self_signed(&self.key_pair)?;
+        let priv_key =
+            PrivateKeyDer::Pkcs8(self.key_pair.serialize_der().into());
+        Ok((cert.into(), priv_key))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::{NodeIdentity, NodePublicKey};
+
+    fn temp_key_path(test_name: &str) -> anyhow::Result<std::path::PathBuf> {
+        let mut path = std::env::temp_dir();
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        path.push(format!(
+            "thunder-orchard-{test_name}-{}-{nanos}",
+            std::process::id()
+        ));
+        path.push("identity.pem");
+        Ok(path)
+    }
+
+    /// Generate an identity stored in a unique temp dir
+    pub fn temp_identity(test_name: &str) -> anyhow::Result<NodeIdentity> {
+        let path = temp_key_path(test_name)?;
+        Ok(NodeIdentity::load_or_generate(&path)?)
+    }
+
+    #[test]
+    fn identity_is_persisted() -> anyhow::Result<()> {
+        let path = temp_key_path("identity-persisted")?;
+        let identity = NodeIdentity::load_or_generate(&path)?;
+        let loaded = NodeIdentity::load_or_generate(&path)?;
+        anyhow::ensure!(loaded.public_key() == identity.public_key());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = std::fs::metadata(&path)?.permissions().mode();
+            anyhow::ensure!(mode & 0o777 == 0o600, "mode: {mode:o}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn public_key_from_cert() -> anyhow::Result<()> {
+        let identity = temp_identity("public-key-from-cert")?;
+        let (cert, _) = identity.certificate()?;
+        anyhow::ensure!(
+            NodePublicKey::from_cert(&cert) == Some(identity.public_key())
+        );
+        // Certificates for other key types do not identify a node
+        let key_pair =
+            rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        let cert = rcgen::CertificateParams::new(vec!["localhost".into()])?
+            .self_signed(&key_pair)?;
+        anyhow::ensure!(NodePublicKey::from_cert(cert.der()).is_none());
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, HashSet, hash_map},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
     sync::Arc,
 };
 
@@ -15,51 +17,70 @@ use tracing::instrument;
 
 use crate::{
     archive::Archive,
+    migration,
     state::State,
-    types::{AuthorizedTransaction, Network, THIS_SIDECHAIN, VERSION, Version},
+    types::{AuthorizedTransaction, Network, THIS_SIDECHAIN, Version},
 };
 
+mod bandwidth;
 pub mod error;
+mod identity;
 mod peer;
 
+use bandwidth::Limiter as PeerBandwidthLimiter;
 pub use error::Error;
+pub use identity::{NodeIdentity, NodePublicKey};
 pub(crate) use peer::error::mailbox::Error as PeerConnectionMailboxError;
 use peer::{
     Connection, ConnectionContext as PeerConnectionCtxt,
-    ConnectionHandle as PeerConnectionHandle,
+    ConnectionHandle as PeerConnectionHandle, Stats as PeerConnectionStats,
 };
 pub use peer::{
+    ConnectionDirection as PeerConnectionDirection,
     ConnectionError as PeerConnectionError, Info as PeerConnectionInfo,
-    InternalMessage as PeerConnectionMessage, Peer, PeerConnectionStatus,
-    PeerStateId, Request as PeerRequest, ResponseMessage as PeerResponse,
-    message as peer_message,
+    InternalMessage as PeerConnectionMessage, MessageStats as PeerMessageStats,
+    Peer, PeerConnectionStatus, PeerStateId, Request as PeerRequest,
+    ResponseMessage as PeerResponse, message as peer_message,
 };
 
-/// Dummy certificate verifier that treats any certificate as valid.
-/// NOTE, such verification is vulnerable to MITM attacks, but convenient for testing.
+/// Certificate verifier for peer certificates.
+/// Certificates are self-signed, so the certificate chain is not verified.
+/// Handshake signatures are verified, so a peer must control the key in the
+/// certificate that it presents.
+/// If an allowlist is set, only certificates for allowlisted node public keys
+/// are accepted.
 #[derive(Debug)]
-struct SkipServerVerification;
+struct PeerCertVerifier {
+    allowlist: Option<Arc<HashSet<NodePublicKey>>>,
+}
 
-impl SkipServerVerification {
-    fn new() -> Arc<Self> {
-        Arc::new(Self)
+impl PeerCertVerifier {
+    fn new(allowlist: Option<Arc<HashSet<NodePublicKey>>>) -> Arc<Self> {
+        Arc::new(Self { allowlist })
     }
-}
 
-impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
-    fn verify_server_cert(
+    fn verify_cert(
         &self,
-        _end_entity: &rustls::pki_types::CertificateDer,
-        _intermediates: &[rustls::pki_types::CertificateDer],
-        _server_name: &rustls::pki_types::ServerName,
-        _ocsp_response: &[u8],
-        _now: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
+        end_entity: &rustls::pki_types::CertificateDer,
+    ) -> Result<(), rustls::Error> {
+        let Some(allowlist) = &self.allowlist else {
+            return Ok(());
+        };
+        match NodePublicKey::from_cert(end_entity) {
+            Some(public_key) if allowlist.contains(&public_key) => Ok(()),
+            Some(public_key) => {
+                tracing::warn!(%public_key, "rejecting peer not in allowlist");
+                Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            None => Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::BadEncoding,
+            )),
+        }
     }
 
     fn verify_tls12_signature(
-        &self,
         message: &[u8],
         cert: &rustls::pki_types::CertificateDer<'_>,
         dss: &rustls::DigitallySignedStruct,
@@ -75,7 +96,6 @@ impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
     }
 
     fn verify_tls13_signature(
-        &self,
         message: &[u8],
         cert: &rustls::pki_types::CertificateDer<'_>,
         dss: &rustls::DigitallySignedStruct,
@@ -90,35 +110,123 @@ impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
         )
     }
 
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+    fn supported_verify_schemes() -> Vec<rustls::SignatureScheme> {
         rustls::crypto::ring::default_provider()
             .signature_verification_algorithms
             .supported_schemes()
     }
 }
 
-fn configure_client()
--> Result<ClientConfig, quinn::crypto::rustls::NoInitialCipherSuite> {
+impl rustls::client::danger::ServerCertVerifier for PeerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer,
+        _intermediates: &[rustls::pki_types::CertificateDer],
+        _server_name: &rustls::pki_types::ServerName,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let () = self.verify_cert(end_entity)?;
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        Self::verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        Self::verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        Self::supported_verify_schemes()
+    }
+}
+
+impl rustls::server::danger::ClientCertVerifier for PeerCertVerifier {
+    /// Peers without an identity can only connect if there is no allowlist
+    fn client_auth_mandatory(&self) -> bool {
+        self.allowlist.is_some()
+    }
+
+    fn root_hint_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::server::danger::ClientCertVerified, rustls::Error> {
+        let () = self.verify_cert(end_entity)?;
+        Ok(rustls::server::danger::ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        Self::verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        Self::verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        Self::supported_verify_schemes()
+    }
+}
+
+fn configure_client(
+    identity: &NodeIdentity,
+    allowlist: Option<Arc<HashSet<NodePublicKey>>>,
+) -> Result<ClientConfig, Error> {
+    let (cert, priv_key) = identity.certificate()?;
     let crypto = rustls::ClientConfig::builder()
         .dangerous()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(PeerCertVerifier::new(allowlist))
+        .with_client_auth_cert(vec![cert], priv_key)?;
     let client_config =
         quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?;
     Ok(ClientConfig::new(Arc::new(client_config)))
 }
 
-/// Returns default server configuration along with its certificate.
-fn configure_server() -> Result<(ServerConfig, Vec<u8>), Error> {
-    let cert_key =
-        rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
-    let keypair_der = cert_key.key_pair.serialize_der();
-    let priv_key = rustls::pki_types::PrivateKeyDer::Pkcs8(keypair_der.into());
-    let cert_der = cert_key.cert.der().to_vec();
-    let cert_chain = vec![cert_key.cert.into()];
-
-    let mut server_config =
-        ServerConfig::with_single_cert(cert_chain, priv_key)?;
+/// Returns server configuration along with its certificate.
+fn configure_server(
+    identity: &NodeIdentity,
+    allowlist: Option<Arc<HashSet<NodePublicKey>>>,
+) -> Result<(ServerConfig, Vec<u8>), Error> {
+    let (cert, priv_key) = identity.certificate()?;
+    let cert_der = cert.to_vec();
+    let crypto = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(PeerCertVerifier::new(allowlist))
+        .with_single_cert(vec![cert], priv_key)?;
+    let server_config =
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?;
+    let mut server_config = ServerConfig::with_crypto(Arc::new(server_config));
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
     transport_config.max_concurrent_uni_streams(1_u8.into());
 
@@ -127,6 +235,9 @@ fn configure_server() -> Result<(ServerConfig, Vec<u8>), Error> {
 
 /// Constructs a QUIC endpoint configured to listen for incoming connections on a certain address
 /// and port.
+/// The node identity is used to authenticate both inbound and outbound
+/// connections. If an allowlist is set, only peers with allowlisted public
+/// keys can be connected to.
 ///
 /// ## Returns
 ///
@@ -134,13 +245,16 @@ fn configure_server() -> Result<(ServerConfig, Vec<u8>), Error> {
 /// - server certificate serialized into DER format
 pub fn make_server_endpoint(
     bind_addr: SocketAddr,
+    identity: &NodeIdentity,
+    allowlist: Option<Arc<HashSet<NodePublicKey>>>,
 ) -> Result<(Endpoint, Vec<u8>), Error> {
-    let (server_config, server_cert) = configure_server()?;
+    let (server_config, server_cert) =
+        configure_server(identity, allowlist.clone())?;
 
     tracing::info!("creating server endpoint: binding to {bind_addr}",);
 
     let mut endpoint = Endpoint::server(server_config, bind_addr)?;
-    let client_cfg = configure_client()?;
+    let client_cfg = configure_client(identity, allowlist)?;
     endpoint.set_default_client_config(client_cfg);
     Ok((endpoint, server_cert))
 }
@@ -179,6 +293,143 @@ const fn seed_node_addrs(network: Network) -> &'static [SocketAddr] {
     }
 }
 
+/// Subnet used for inbound connection limits.
+/// `/16` for IPv4, `/32` for IPv6.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ipv4) => {
+            let [a, b, _, _] = ipv4.octets();
+            IpAddr::V4(std::net::Ipv4Addr::new(a, b, 0, 0))
+        }
+        IpAddr::V6(ipv6) => {
+            let segments = ipv6.segments();
+            IpAddr::V6(std::net::Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
+
+/// Priority for keeping an inbound peer when another inbound peer connects.
+/// Peers that have not sent a valid message are evicted first, followed
+/// by peers advertising the least work, the highest latency, and the
+/// most recent connection time.
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct EvictionPriority {
+    received_msg_successfully: bool,
+    total_work: Option<bitcoin::Work>,
+    heartbeat_rtt_ms: Reverse<Option<u64>>,
+    connected_at: Reverse<Option<u64>>,
+}
+
+impl EvictionPriority {
+    fn new(received_msg_successfully: bool, peer: &Peer) -> Self {
+        Self {
+            received_msg_successfully,
+            total_work: peer.total_work,
+            heartbeat_rtt_ms: Reverse(
+                peer.heartbeat_rtt_ms.map(|rtt_ms| rtt_ms as u64),
+            ),
+            connected_at: Reverse(peer.connected_at),
+        }
+    }
+}
+
+/// Select the peer with the lowest eviction priority
+fn select_eviction_candidate<Peers>(peers: Peers) -> Option<SocketAddr>
+where
+    Peers: IntoIterator<Item = (SocketAddr, EvictionPriority)>,
+{
+    peers
+        .into_iter()
+        .min_by(|(_, lhs), (_, rhs)| lhs.cmp(rhs))
+        .map(|(addr, _)| addr)
+}
+
+/// Connection limits, bandwidth limits, and startup peers
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Maximum number of inbound connections
+    pub max_inbound: usize,
+    /// Maximum number of outbound connections
+    pub max_outbound: usize,
+    /// Maximum number of inbound connections from a single IP address.
+    /// Loopback addresses are exempt.
+    pub max_inbound_per_ip: usize,
+    /// Maximum number of inbound connections from a single subnet
+    /// (`/16` for IPv4, `/32` for IPv6).
+    /// Loopback addresses are exempt.
+    pub max_inbound_per_subnet: usize,
+    /// Peers to connect to on startup
+    pub add_nodes: Vec<SocketAddr>,
+    /// If `true`, only connect to the peers in `add_nodes` on startup,
+    /// and refuse inbound connections.
+    /// Known peers and seed nodes are not connected to.
+    pub connect_only: bool,
+    /// If set, only peers with these public keys are accepted, for both
+    /// inbound and outbound connections.
+    pub allowlist: Option<HashSet<NodePublicKey>>,
+    /// Maximum upload rate across all peers, in bytes per second
+    pub max_upload_rate: Option<NonZeroU32>,
+    /// Maximum upload rate to a single peer, in bytes per second
+    pub max_upload_rate_per_peer: Option<NonZeroU32>,
+    /// Maximum download rate across all peers, in bytes per second
+    pub max_download_rate: Option<NonZeroU32>,
+    /// Maximum download rate from a single peer, in bytes per second
+    pub max_download_rate_per_peer: Option<NonZeroU32>,
+    /// Upload target in bytes, over a rolling 24 hour window.
+    /// Once reached, only new-block relay is served to peers.
+    pub max_upload_target: Option<u64>,
+}
+
+impl Config {
+    pub const DEFAULT_MAX_INBOUND: usize = 64;
+    pub const DEFAULT_MAX_OUTBOUND: usize = 16;
+    pub const DEFAULT_MAX_INBOUND_PER_IP: usize = 4;
+    pub const DEFAULT_MAX_INBOUND_PER_SUBNET: usize = 16;
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_inbound: Self::DEFAULT_MAX_INBOUND,
+            max_outbound: Self::DEFAULT_MAX_OUTBOUND,
+            max_inbound_per_ip: Self::DEFAULT_MAX_INBOUND_PER_IP,
+            max_inbound_per_subnet: Self::DEFAULT_MAX_INBOUND_PER_SUBNET,
+            add_nodes: Vec::new(),
+            connect_only: false,
+            allowlist: None,
+            max_upload_rate: None,
+            max_upload_rate_per_peer: None,
+            max_download_rate: None,
+            max_download_rate_per_peer: None,
+            max_upload_target: None,
+        }
+    }
+}
+
+/// Reason for refusing an inbound connection
+#[derive(Clone, Copy, Debug, strum::Display)]
+enum RefuseInbound {
+    #[strum(to_string = "already connected")]
+    AlreadyConnected,
+    #[strum(to_string = "not accepting inbound connections")]
+    ConnectOnly,
+    #[strum(to_string = "too many connections from this address")]
+    MaxInboundPerIp,
+    #[strum(to_string = "too many connections from this subnet")]
+    MaxInboundPerSubnet,
+    #[strum(to_string = "too many connections")]
+    MaxInbound,
+}
+
 // Keep track of peer state
 // Exchange metadata
 // Bulk download
@@ -196,6 +447,9 @@ pub struct Net {
     network: Network,
     state: State,
     active_peers: Arc<RwLock<HashMap<SocketAddr, PeerConnectionHandle>>>,
+    config: Arc<Config>,
+    bandwidth: Arc<bandwidth::Global>,
+    public_key: NodePublicKey,
     // None indicates that the stream has ended
     peer_info_tx:
         mpsc::UnboundedSender<(SocketAddr, Option<PeerConnectionInfo>)>,
@@ -206,6 +460,32 @@ pub struct Net {
 impl Net {
     pub const NUM_DBS: u32 = 2;
 
+    /// Schema version of the net DBs
+    pub const SCHEMA_VERSION: Version = Version {
+        major: 0,
+        minor: 16,
+        patch: 0,
+    };
+
+    pub const SCHEMA: migration::Schema = migration::Schema {
+        version_db: "net_version",
+        version: Self::SCHEMA_VERSION,
+        min_version: Version {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        },
+        steps: &[],
+    };
+
+    /// Bandwidth limiter for a new peer connection
+    fn peer_bandwidth_limiter(&self) -> Arc<PeerBandwidthLimiter> {
+        Arc::new(PeerBandwidthLimiter::new(
+            self.bandwidth.clone(),
+            &self.config,
+        ))
+    }
+
     fn add_active_peer(
         &self,
         addr: SocketAddr,
@@ -248,16 +528,113 @@ impl Net {
         active_peers_read.get(&addr).map(f)
     }
 
-    // TODO: This should have more context.
-    // Last received message, connection state, etc.
+    /// Select the least useful inbound peer, to be evicted in order to make
+    /// room for a new inbound connection.
+    /// Returns `None` if there are no inbound peers.
+    fn select_inbound_eviction_candidate(
+        active_peers: &HashMap<SocketAddr, PeerConnectionHandle>,
+    ) -> Option<SocketAddr> {
+        let inbound_peers = active_peers
+            .iter()
+            .filter(|(_, conn_handle)| {
+                conn_handle.stats.direction()
+                    == PeerConnectionDirection::Inbound
+            })
+            .map(|(addr, conn_handle)| {
+                let priority = EvictionPriority::new(
+                    conn_handle.received_msg_successfully(),
+                    &conn_handle.peer(*addr),
+                );
+                (*addr, priority)
+            });
+        select_eviction_candidate(inbound_peers)
+    }
+
+    /// Add an inbound peer if a connection from the specified address should
+    /// be accepted, evicting an inbound peer if necessary.
+    /// Limits are checked and the peer is added under a single lock, so that
+    /// concurrent connections cannot exceed the limits.
+    fn add_inbound_peer(
+        &self,
+        addr: SocketAddr,
+        peer_connection_handle: PeerConnectionHandle,
+    ) -> Result<(), RefuseInbound> {
+        if self.config.connect_only {
+            return Err(RefuseInbound::ConnectOnly);
+        }
+        let mut active_peers_write = self.active_peers.write();
+        if active_peers_write.contains_key(&addr) {
+            return Err(RefuseInbound::AlreadyConnected);
+        }
+        let inbound_addrs: Vec<SocketAddr> = active_peers_write
+            .iter()
+            .filter(|(_, conn_handle)| {
+                conn_handle.stats.direction()
+                    == PeerConnectionDirection::Inbound
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        if !addr.ip().to_canonical().is_loopback() {
+            let ip = addr.ip().to_canonical();
+            let same_ip = inbound_addrs
+                .iter()
+                .filter(|inbound_addr| inbound_addr.ip().to_canonical() == ip)
+                .count();
+            if same_ip >= self.config.max_inbound_per_ip {
+                return Err(RefuseInbound::MaxInboundPerIp);
+            }
+            let same_subnet = inbound_addrs
+                .iter()
+                .filter(|inbound_addr| {
+                    subnet(inbound_addr.ip()) == subnet(addr.ip())
+                })
+                .count();
+            if same_subnet >= self.config.max_inbound_per_subnet {
+                return Err(RefuseInbound::MaxInboundPerSubnet);
+            }
+        }
+        if inbound_addrs.len() >= self.config.max_inbound {
+            let Some(evict_addr) =
+                Self::select_inbound_eviction_candidate(&active_peers_write)
+            else {
+                return Err(RefuseInbound::MaxInbound);
+            };
+            if let Some(peer_connection) =
+                active_peers_write.remove(&evict_addr)
+            {
+                drop(peer_connection);
+                tracing::info!(
+                    %addr,
+                    %evict_addr,
+                    "inbound connection: evicted inbound peer"
+                );
+            }
+        }
+        active_peers_write.insert(addr, peer_connection_handle);
+        Ok(())
+    }
+
+    fn outbound_count(&self) -> usize {
+        self.active_peers
+            .read()
+            .values()
+            .filter(|conn_handle| {
+                conn_handle.stats.direction()
+                    == PeerConnectionDirection::Outbound
+            })
+            .count()
+    }
+
+    /// Public key identifying this node
+    pub fn public_key(&self) -> NodePublicKey {
+        self.public_key
+    }
+
     pub fn get_active_peers(&self) -> Vec<Peer> {
         self.active_peers
             .read()
             .iter()
-            .map(|(addr, conn_handle)| Peer {
-                address: *addr,
-                status: conn_handle.connection_status(),
-            })
+            .map(|(addr, conn_handle)| conn_handle.peer(*addr))
             .collect()
     }
 
@@ -271,6 +648,10 @@ impl Net {
             tracing::error!("connect peer: already connected");
             return Err(error::AlreadyConnected(addr).into());
         }
+        if self.outbound_count() >= self.config.max_outbound {
+            tracing::warn!("connect peer: max outbound connections reached");
+            return Err(Error::MaxOutbound(self.config.max_outbound));
+        }
 
         // This check happens within Quinn with a
         // generic "invalid remote address". We run the
@@ -290,6 +671,10 @@ impl Net {
             archive: self.archive.clone(),
             network: self.network,
             state: self.state.clone(),
+            stats: Arc::new(PeerConnectionStats::new(
+                PeerConnectionDirection::Outbound,
+            )),
+            bandwidth: self.peer_bandwidth_limiter(),
         };
 
         let (connection_handle, info_rx) =
@@ -329,8 +714,11 @@ impl Net {
         network: Network,
         state: State,
         bind_addr: SocketAddr,
+        identity: &NodeIdentity,
+        config: Config,
     ) -> Result<(Self, PeerInfoRx), Error> {
-        let (server, _) = make_server_endpoint(bind_addr)?;
+        let allowlist = config.allowlist.clone().map(Arc::new);
+        let (server, _) = make_server_endpoint(bind_addr, identity, allowlist)?;
         let active_peers = Arc::new(RwLock::new(HashMap::new()));
         let mut rwtxn = env.write_txn()?;
         let known_peers =
@@ -345,9 +733,10 @@ impl Net {
                     known_peers
                 }
             };
-        let version = DatabaseUnique::create(env, &mut rwtxn, "net_version")?;
+        let version =
+            DatabaseUnique::create(env, &mut rwtxn, Self::SCHEMA.version_db)?;
         if version.try_get(&rwtxn, &())?.is_none() {
-            version.put(&mut rwtxn, &(), &*VERSION)?;
+            version.put(&mut rwtxn, &(), &Self::SCHEMA_VERSION)?;
         }
         rwtxn.commit().map_err(RwTxnError::from)?;
         let (peer_info_tx, peer_info_rx) = mpsc::unbounded();
@@ -357,12 +746,26 @@ impl Net {
             network,
             state,
             active_peers,
+            bandwidth: Arc::new(bandwidth::Global::new(&config)),
+            config: Arc::new(config),
+            public_key: identity.public_key(),
             peer_info_tx,
             known_peers,
             _version: version,
         };
+        for add_node in &net.config.add_nodes {
+            tracing::trace!("new net: connecting to added node at {add_node}");
+            if let Err(err) = net.connect_peer(env.clone(), *add_node) {
+                let err = anyhow::Error::from(err);
+                tracing::error!(
+                    "new net: failed to connect to added node at {add_node}: {err:#}"
+                );
+            }
+        }
         #[allow(clippy::let_and_return)]
-        let known_peers: Vec<_> = {
+        let known_peers: Vec<_> = if net.config.connect_only {
+            Vec::new()
+        } else {
             let rotxn = env.read_txn().map_err(EnvError::from)?;
             let known_peers = net
                 .known_peers
@@ -373,6 +776,15 @@ impl Net {
             known_peers
         };
         let () = known_peers.into_iter().try_for_each(|(peer_addr, _)| {
+            if net.active_peers.read().contains_key(&peer_addr) {
+                return Ok(());
+            }
+            if net.outbound_count() >= net.config.max_outbound {
+                tracing::trace!(
+                    "new net: max outbound connections reached, not connecting to known peer at {peer_addr}"
+                );
+                return Ok(());
+            }
             tracing::trace!(
                 "new net: connecting to already known peer at {peer_addr}"
             );
@@ -416,6 +828,10 @@ impl Net {
                 .map(|socket| socket.to_string())
                 .unwrap_or("unknown address".into())
         );
+        let stats = Arc::new(PeerConnectionStats::new(
+            PeerConnectionDirection::Inbound,
+        ));
+        let bandwidth = self.peer_bandwidth_limiter();
         let connection = match self.server.accept().await {
             Some(conn) => {
                 let remote_address = conn.remote_address();
@@ -427,7 +843,12 @@ impl Net {
                         remote_address,
                     }
                 })?;
-                Connection::new(raw_conn, self.network)
+                Connection::new(
+                    raw_conn,
+                    self.network,
+                    stats.clone(),
+                    bandwidth.clone(),
+                )
             }
             None => {
                 tracing::debug!("server endpoint closed");
@@ -437,33 +858,36 @@ impl Net {
         let addr = connection.addr();
 
         tracing::trace!(%addr, "accepted incoming connection");
-        if self.active_peers.read().contains_key(&addr) {
-            tracing::info!(
-                %addr, "incoming connection: already peered, refusing duplicate",
-            );
-            connection
-                .inner
-                .close(quinn::VarInt::from_u32(1), b"already connected");
-        }
         if connection.inner.close_reason().is_some() {
             return Ok(None);
         }
-        tracing::info!(%addr, "connected to new peer");
-        let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
-        self.known_peers
-            .put(&mut rwtxn, &addr, &())
-            .map_err(DbError::from)?;
-        rwtxn.commit().map_err(RwTxnError::from)?;
-
-        tracing::trace!(%addr, "wrote peer to database");
+        let raw_connection = connection.inner.clone();
         let connection_ctxt = PeerConnectionCtxt {
-            env,
+            env: env.clone(),
             archive: self.archive.clone(),
             network: self.network,
             state: self.state.clone(),
+            stats,
+            bandwidth,
         };
         let (connection_handle, info_rx) =
             peer::handle(connection_ctxt, connection);
+        // The connection task is aborted if the handle is dropped
+        if let Err(reason) = self.add_inbound_peer(addr, connection_handle) {
+            tracing::info!(%addr, %reason, "incoming connection: refusing");
+            raw_connection.close(
+                quinn::VarInt::from_u32(1),
+                reason.to_string().as_bytes(),
+            );
+            return Ok(None);
+        }
+        tracing::info!(%addr, "connected to new peer");
+        let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+        self.known_peers
+            .put(&mut rwtxn, &addr, &())
+            .map_err(DbError::from)?;
+        rwtxn.commit().map_err(RwTxnError::from)?;
+        tracing::trace!(%addr, "wrote peer to database");
         tokio::spawn({
             let info_rx = StreamNotifyClose::new(info_rx)
                 .map(move |info| Ok((addr, info)));
@@ -474,8 +898,6 @@ impl Net {
                 }
             }
         });
-        // TODO: is this the right state?
-        self.add_active_peer(addr, connection_handle)?;
         Ok(Some(addr))
     }
 
@@ -537,3 +959,97 @@ impl Net {
             })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cmp::Reverse,
+        collections::HashSet,
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+    };
+
+    use super::{
+        EvictionPriority, PeerCertVerifier, identity::test::temp_identity,
+        select_eviction_candidate, subnet,
+    };
+
+    #[test]
+    fn peer_cert_verifier_allowlist() -> anyhow::Result<()> {
+        let allowed = temp_identity("allowlist-allowed")?;
+        let other = temp_identity("allowlist-other")?;
+        let (allowed_cert, _) = allowed.certificate()?;
+        let (other_cert, _) = other.certificate()?;
+        // Any peer is accepted without an allowlist
+        let verifier = PeerCertVerifier::new(None);
+        anyhow::ensure!(verifier.verify_cert(&allowed_cert).is_ok());
+        anyhow::ensure!(verifier.verify_cert(&other_cert).is_ok());
+        let allowlist = HashSet::from([allowed.public_key()]);
+        let verifier = PeerCertVerifier::new(Some(Arc::new(allowlist)));
+        anyhow::ensure!(verifier.verify_cert(&allowed_cert).is_ok());
+        anyhow::ensure!(matches!(
+            verifier.verify_cert(&other_cert),
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn subnets() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(subnet(ip("192.168.1.2")), ip("192.168.0.0"));
+        assert_eq!(subnet(ip("192.168.255.255")), ip("192.168.0.0"));
+        assert_ne!(subnet(ip("192.169.1.2")), subnet(ip("192.168.1.2")));
+        assert_eq!(subnet(ip("2001:db8:1:2::1")), ip("2001:db8::"));
+        assert_ne!(subnet(ip("2001:db9::1")), subnet(ip("2001:db8::1")));
+        // IPv4-mapped IPv6 addresses are treated as IPv4
+        assert_eq!(subnet(ip("::ffff:192.168.1.2")), ip("192.168.0.0"));
+    }
+
+    #[test]
+    fn eviction_candidate() {
+        let addr = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+        let priority = |received_msg_successfully: bool,
+                        total_work: Option<u64>,
+                        heartbeat_rtt_ms: Option<u64>,
+                        connected_at: Option<u64>| {
+            EvictionPriority {
+                received_msg_successfully,
+                total_work: total_work.map(|work| {
+                    let mut bytes = [0u8; 32];
+                    bytes[..8].copy_from_slice(&work.to_le_bytes());
+                    bitcoin::Work::from_le_bytes(bytes)
+                }),
+                heartbeat_rtt_ms: Reverse(heartbeat_rtt_ms),
+                connected_at: Reverse(connected_at),
+            }
+        };
+        assert_eq!(select_eviction_candidate([]), None);
+        // Peers that have not sent a valid message are evicted first
+        let peers = [
+            (addr(1), priority(true, None, Some(500), Some(10))),
+            (addr(2), priority(false, Some(1), Some(10), Some(1))),
+        ];
+        assert_eq!(select_eviction_candidate(peers), Some(addr(2)));
+        // Then the least work
+        let peers = [
+            (addr(1), priority(true, Some(2), Some(500), Some(10))),
+            (addr(2), priority(true, Some(1), Some(10), Some(1))),
+        ];
+        assert_eq!(select_eviction_candidate(peers), Some(addr(2)));
+        // Then the highest latency
+        let peers = [
+            (addr(1), priority(true, None, Some(10), Some(10))),
+            (addr(2), priority(true, None, Some(500), Some(1))),
+        ];
+        assert_eq!(select_eviction_candidate(peers), Some(addr(2)));
+        // Then the most recent connection
+        let peers = [
+            (addr(1), priority(true, None, Some(10), Some(1))),
+            (addr(2), priority(true, None, Some(10), Some(10))),
+        ];
+        assert_eq!(select_eviction_candidate(peers), Some(addr(2)));
+    }
+}
@@ -180,6 +180,7 @@ impl ChannelPool {
         self.send_request_task_spawner
             .spawn(async move {
                 let response = conn.send_request(&request).await;
+                let () = conn.stats.request_completed();
                 drop(guard);
                 response.map(|response| PeerResponseItem { request, response })
             })
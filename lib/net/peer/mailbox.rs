@@ -19,7 +19,7 @@ use tokio_stream::wrappers::IntervalStream;
 
 use crate::{
     net::peer::{
-        Connection, PeerResponseItem, PeerStateId,
+        Connection, PeerResponseItem, PeerStateId, Stats,
         error::{self, mailbox::Error},
         message::{Request, RequestMessage},
         request_queue,
@@ -58,6 +58,8 @@ impl From<Request> for InternalMessage {
 }
 
 pub struct ForwardResponseItem {
+    /// Response message type, for peer statistics
+    pub response_type: &'static str,
     pub serialized_response: Vec<u8>,
     pub response_tx: SendStream,
 }
@@ -228,12 +230,12 @@ pub struct Sender {
     pub request_tx: request_queue::Sender,
 }
 
-pub fn new() -> (Sender, Receiver) {
+pub fn new(stats: Arc<Stats>) -> (Sender, Receiver) {
     let (internal_message_tx, internal_message_rx) = mpsc::unbounded();
     let (blocking_task_queue_tx, blocking_task_queue_rx) = mpsc::unbounded();
     let (send_response_spawner, send_response_rx) = join_set::new();
     let (forward_response_spawner, forward_response_rx) = join_set::new();
-    let (request_tx, request_queue_err_rx) = request_queue::new();
+    let (request_tx, request_queue_err_rx) = request_queue::new(stats);
     let receiver = Receiver {
         internal_message_rx,
         blocking_task_queue_rx,
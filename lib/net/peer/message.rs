@@ -1,6 +1,9 @@
 //! P2P message types
 
-use std::{collections::HashSet, num::NonZeroUsize};
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+};
 
 use borsh::BorshSerialize;
 use serde::{Deserialize, Serialize};
@@ -8,7 +11,8 @@ use serde::{Deserialize, Serialize};
 use crate::{
     net::peer::{PeerState, PeerStateId},
     types::{
-        AuthorizedTransaction, BlockHash, Body, Header, Network, Tip, Txid,
+        AuthorizedTransaction, BlockHash, Body, Header, Network, Output, Tip,
+        Txid, Version, hash,
     },
 };
 
@@ -31,8 +35,29 @@ pub const fn magic_bytes(network: Network) -> MagicBytes {
     [b0, b1, b2, b3]
 }
 
-#[derive(BorshSerialize, Clone, Debug, Deserialize, Serialize)]
-pub struct Heartbeat(pub PeerState);
+/// Minimum peer version that supports [`PruneInfo`] in heartbeats.
+/// Heartbeats with prune info are encoded as a separate message variant,
+/// since older peers cannot deserialize it.
+pub const PRUNE_INFO_MIN_VERSION: Version = Version {
+    major: 0,
+    minor: 17,
+    patch: 0,
+};
+
+/// Pruning status advertised in heartbeats
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct PruneInfo {
+    /// If the sender is pruned, bodies are not available for blocks below
+    /// this height
+    pub prune_height: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    pub peer_state: PeerState,
+    /// Only sent to peers with version at least [`PRUNE_INFO_MIN_VERSION`]
+    pub prune_info: Option<PruneInfo>,
+}
 
 #[derive(BorshSerialize, Clone, Debug, Deserialize, Serialize)]
 pub struct GetBlockRequest {
@@ -60,6 +85,185 @@ impl GetBlockRequest {
         // 10MB limit for blocks
         NonZeroUsize::new(10 * 1024 * 1024).unwrap()
     }
+
+    /// Limit bytes to read in a response to a request for a block with
+    /// spent outputs
+    pub const fn read_response_limit_with_spent_outputs(&self) -> NonZeroUsize {
+        // 20MB limit for blocks with spent outputs
+        NonZeroUsize::new(20 * 1024 * 1024).unwrap()
+    }
+}
+
+/// Minimum peer version that supports
+/// [`Request::GetBlockWithSpentOutputs`].
+/// Older peers cannot deserialize these requests, so compact-state nodes do
+/// not sync from them.
+pub const SPENT_OUTPUTS_MIN_VERSION: Version = Version {
+    major: 0,
+    minor: 17,
+    patch: 0,
+};
+
+/// Minimum peer version that supports [`GetCompactBlockRequest`] and
+/// [`GetBlockTransactionsRequest`].
+/// Older peers cannot deserialize these requests, so full blocks are
+/// requested from them instead.
+pub const COMPACT_BLOCK_MIN_VERSION: Version = Version {
+    major: 0,
+    minor: 17,
+    patch: 0,
+};
+
+/// Short transaction ID, used to identify transactions in a
+/// [`CompactBlock`].
+/// Short IDs are salted with the block hash, so that collisions cannot be
+/// precomputed independently of the block.
+#[derive(
+    BorshSerialize,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    Hash,
+    PartialEq,
+    Serialize,
+)]
+pub struct ShortTxid([u8; Self::LEN]);
+
+impl ShortTxid {
+    pub const LEN: usize = 6;
+
+    pub fn new(block_hash: BlockHash, txid: Txid) -> Self {
+        let digest = hash(&(block_hash, txid));
+        let mut bytes = [0; Self::LEN];
+        bytes.copy_from_slice(&digest[..Self::LEN]);
+        Self(bytes)
+    }
+}
+
+/// Block with transactions replaced by short transaction IDs.
+/// Used to relay new tips, since most transactions in a new block should
+/// already be in the receiver's mempool.
+#[derive(BorshSerialize, Clone, Debug, Deserialize, Serialize)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub coinbase: Vec<Output>,
+    pub short_txids: Vec<ShortTxid>,
+}
+
+impl CompactBlock {
+    pub fn new(header: Header, body: &Body) -> Self {
+        let block_hash = header.hash();
+        let short_txids = body
+            .transactions
+            .iter()
+            .map(|tx| ShortTxid::new(block_hash, tx.txid()))
+            .collect();
+        Self {
+            header,
+            coinbase: body.coinbase.clone(),
+            short_txids,
+        }
+    }
+
+    /// Attempt to reconstruct the block body from candidate transactions.
+    /// If a transaction appears more than once in `candidates`, the last
+    /// occurrence is used.
+    /// Returns the indexes of missing transactions if the body could not be
+    /// reconstructed.
+    /// The merkle root of a reconstructed body MUST be checked against the
+    /// header, since short IDs may collide.
+    pub fn reconstruct<Txs>(&self, candidates: Txs) -> Result<Body, Vec<u32>>
+    where
+        Txs: IntoIterator<Item = AuthorizedTransaction>,
+    {
+        let block_hash = self.header.hash();
+        let mut candidates: HashMap<ShortTxid, AuthorizedTransaction> =
+            candidates
+                .into_iter()
+                .map(|tx| {
+                    let short_txid =
+                        ShortTxid::new(block_hash, tx.transaction.txid());
+                    (short_txid, tx)
+                })
+                .collect();
+        let mut transactions = Vec::with_capacity(self.short_txids.len());
+        let mut missing = Vec::new();
+        for (idx, short_txid) in self.short_txids.iter().enumerate() {
+            match candidates.remove(short_txid) {
+                Some(tx) => transactions.push(tx),
+                None => missing.push(idx as u32),
+            }
+        }
+        if missing.is_empty() {
+            Ok(Body::new(transactions, self.coinbase.clone()))
+        } else {
+            Err(missing)
+        }
+    }
+}
+
+#[derive(BorshSerialize, Clone, Debug, Deserialize, Serialize)]
+pub struct GetCompactBlockRequest {
+    pub block_hash: BlockHash,
+    /// Mainchain descendant tip that we are requesting the block to reach.
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub descendant_tip: Option<Tip>,
+    /// Ancestor block. If no bodies are missing between `descendant_tip`
+    /// and `ancestor`, then `descendant_tip` is ready to apply.
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub ancestor: Option<BlockHash>,
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub peer_state_id: Option<PeerStateId>,
+}
+
+impl GetCompactBlockRequest {
+    /// Limit bytes to read in a response to a request
+    pub const fn read_response_limit(&self) -> NonZeroUsize {
+        // 1MB limit for compact blocks
+        NonZeroUsize::new(1024 * 1024).unwrap()
+    }
+}
+
+/// Request transactions that could not be found when reconstructing a
+/// compact block
+#[derive(BorshSerialize, Clone, Debug, Deserialize, Serialize)]
+pub struct GetBlockTransactionsRequest {
+    pub block_hash: BlockHash,
+    /// Indexes of the requested transactions in the block
+    pub indexes: Vec<u32>,
+    /// Compact block to reconstruct once the transactions are received.
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub compact_block: Option<Box<CompactBlock>>,
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub descendant_tip: Option<Tip>,
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub ancestor: Option<BlockHash>,
+    /// Only relevant for the requester, so serialization is skipped
+    #[borsh(skip)]
+    #[serde(skip)]
+    pub peer_state_id: Option<PeerStateId>,
+}
+
+impl GetBlockTransactionsRequest {
+    /// Limit bytes to read in a response to a request
+    pub const fn read_response_limit(&self) -> NonZeroUsize {
+        // 10MB limit, as for blocks
+        NonZeroUsize::new(10 * 1024 * 1024).unwrap()
+    }
 }
 
 /// Request headers up to [`end`]
@@ -110,9 +314,14 @@ impl PushTransactionRequest {
     }
 }
 
-#[derive(BorshSerialize, Clone, Debug)]
+#[derive(BorshSerialize, Clone, Debug, strum::IntoStaticStr)]
 pub enum Request {
     GetBlock(GetBlockRequest),
+    GetBlockTransactions(GetBlockTransactionsRequest),
+    /// Request a block, and the outputs spent by its transactions.
+    /// Used by compact-state nodes, which do not store spent outputs.
+    GetBlockWithSpentOutputs(GetBlockRequest),
+    GetCompactBlock(GetCompactBlockRequest),
     GetHeaders(GetHeadersRequest),
     PushTransaction(PushTransactionRequest),
 }
@@ -123,6 +332,13 @@ impl Request {
         // TODO: Add constant for discriminant
         match self {
             Self::GetBlock(request) => request.read_response_limit(),
+            Self::GetBlockTransactions(request) => {
+                request.read_response_limit()
+            }
+            Self::GetBlockWithSpentOutputs(request) => {
+                request.read_response_limit_with_spent_outputs()
+            }
+            Self::GetCompactBlock(request) => request.read_response_limit(),
             Self::GetHeaders(request) => request.read_response_limit(),
             Self::PushTransaction(request) => request.read_response_limit(),
         }
@@ -135,6 +351,18 @@ impl From<GetBlockRequest> for Request {
     }
 }
 
+impl From<GetBlockTransactionsRequest> for Request {
+    fn from(request: GetBlockTransactionsRequest) -> Self {
+        Self::GetBlockTransactions(request)
+    }
+}
+
+impl From<GetCompactBlockRequest> for Request {
+    fn from(request: GetCompactBlockRequest) -> Self {
+        Self::GetCompactBlock(request)
+    }
+}
+
 impl From<GetHeadersRequest> for Request {
     fn from(request: GetHeadersRequest) -> Self {
         Self::GetHeaders(request)
@@ -163,6 +391,14 @@ impl RequestMessageRef<'_> {
             Self::Request(request) => Some(request.read_response_limit()),
         }
     }
+
+    /// Message type, for peer statistics
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            Self::Heartbeat(_) => "Heartbeat",
+            Self::Request(request) => (*request).into(),
+        }
+    }
 }
 
 impl<'a> From<&'a Heartbeat> for RequestMessageRef<'a> {
@@ -182,20 +418,40 @@ impl<'a> Serialize for RequestMessageRef<'a> {
     where
         S: serde::Serializer,
     {
+        // New variants MUST be appended, in order to preserve the encoding
+        // of existing variants
         #[derive(Serialize)]
         enum Repr<'b> {
-            Heartbeat(&'b Heartbeat),
+            Heartbeat(&'b PeerState),
             GetBlock(&'b GetBlockRequest),
             GetHeaders(&'b GetHeadersRequest),
             PushTransaction(&'b PushTransactionRequest),
+            GetCompactBlock(&'b GetCompactBlockRequest),
+            GetBlockTransactions(&'b GetBlockTransactionsRequest),
+            GetBlockWithSpentOutputs(&'b GetBlockRequest),
+            HeartbeatWithPruneInfo(&'b PeerState, &'b PruneInfo),
         }
 
         let repr = match self {
-            RequestMessageRef::Heartbeat(heartbeat) => {
-                Repr::Heartbeat(heartbeat)
-            }
+            RequestMessageRef::Heartbeat(Heartbeat {
+                peer_state,
+                prune_info: None,
+            }) => Repr::Heartbeat(peer_state),
+            RequestMessageRef::Heartbeat(Heartbeat {
+                peer_state,
+                prune_info: Some(prune_info),
+            }) => Repr::HeartbeatWithPruneInfo(peer_state, prune_info),
             RequestMessageRef::Request(request) => match request {
                 Request::GetBlock(request) => Repr::GetBlock(request),
+                Request::GetBlockTransactions(request) => {
+                    Repr::GetBlockTransactions(request)
+                }
+                Request::GetBlockWithSpentOutputs(request) => {
+                    Repr::GetBlockWithSpentOutputs(request)
+                }
+                Request::GetCompactBlock(request) => {
+                    Repr::GetCompactBlock(request)
+                }
                 Request::GetHeaders(request) => Repr::GetHeaders(request),
                 Request::PushTransaction(request) => {
                     Repr::PushTransaction(request)
@@ -210,6 +466,8 @@ impl<'a> Serialize for RequestMessageRef<'a> {
 #[derive(transitive::Transitive, Debug)]
 #[transitive(
     from(GetBlockRequest, Request),
+    from(GetBlockTransactionsRequest, Request),
+    from(GetCompactBlockRequest, Request),
     from(GetHeadersRequest, Request),
     from(PushTransactionRequest, Request)
 )]
@@ -227,6 +485,11 @@ impl RequestMessage {
             Self::Request(request) => RequestMessageRef::Request(request),
         }
     }
+
+    /// Message type, for peer statistics
+    pub fn message_type(&self) -> &'static str {
+        self.as_ref().message_type()
+    }
 }
 
 impl<'a> From<&'a RequestMessage> for RequestMessageRef<'a> {
@@ -252,24 +515,44 @@ impl<'de> Deserialize<'de> for RequestMessage {
     where
         D: serde::Deserializer<'de>,
     {
+        // New variants MUST be appended, in order to preserve the encoding
+        // of existing variants
         #[derive(Deserialize)]
         enum Repr {
-            Heartbeat(Heartbeat),
+            Heartbeat(PeerState),
             GetBlock(GetBlockRequest),
             GetHeaders(GetHeadersRequest),
             PushTransaction(PushTransactionRequest),
+            GetCompactBlock(GetCompactBlockRequest),
+            GetBlockTransactions(GetBlockTransactionsRequest),
+            GetBlockWithSpentOutputs(GetBlockRequest),
+            HeartbeatWithPruneInfo(PeerState, PruneInfo),
         }
         let res = match Repr::deserialize(deserializer)? {
-            Repr::Heartbeat(heartbeat) => heartbeat.into(),
+            Repr::Heartbeat(peer_state) => Heartbeat {
+                peer_state,
+                prune_info: None,
+            }
+            .into(),
             Repr::GetBlock(request) => request.into(),
             Repr::GetHeaders(request) => request.into(),
             Repr::PushTransaction(request) => request.into(),
+            Repr::GetCompactBlock(request) => request.into(),
+            Repr::GetBlockTransactions(request) => request.into(),
+            Repr::GetBlockWithSpentOutputs(request) => {
+                Request::GetBlockWithSpentOutputs(request).into()
+            }
+            Repr::HeartbeatWithPruneInfo(peer_state, prune_info) => Heartbeat {
+                peer_state,
+                prune_info: Some(prune_info),
+            }
+            .into(),
         };
         Ok(res)
     }
 }
 
-#[derive(educe::Educe, Serialize, Deserialize)]
+#[derive(educe::Educe, Serialize, Deserialize, strum::IntoStaticStr)]
 #[educe(Debug)]
 pub enum ResponseMessage {
     Block {
@@ -286,6 +569,24 @@ pub enum ResponseMessage {
     },
     TransactionAccepted(Txid),
     TransactionRejected(Txid),
+    // New variants MUST be appended, in order to preserve the encoding of
+    // existing variants
+    CompactBlock(Box<CompactBlock>),
+    /// Transactions requested from a compact block, in the requested order
+    BlockTransactions {
+        block_hash: BlockHash,
+        transactions: Vec<AuthorizedTransaction>,
+    },
+    /// The requested block is known, but its body has been pruned
+    BlockPruned {
+        block_hash: BlockHash,
+    },
+    /// Block, and the outputs spent by its transactions, in input order
+    BlockWithSpentOutputs {
+        header: Header,
+        body: Body,
+        spent_outputs: Vec<Output>,
+    },
 }
 
 impl ResponseMessage {
@@ -301,3 +602,170 @@ impl ResponseMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash as _;
+    use rustreexo::accumulator::proof::Proof;
+
+    use super::{
+        COMPACT_BLOCK_MIN_VERSION, CompactBlock, Heartbeat,
+        PRUNE_INFO_MIN_VERSION, PruneInfo, RequestMessage, RequestMessageRef,
+        SPENT_OUTPUTS_MIN_VERSION, ShortTxid,
+    };
+    use crate::{
+        net::peer::PeerState,
+        types::{
+            AuthorizedTransaction, Body, Header, Output, OutputContent,
+            Transaction, TransparentAddress, VERSION,
+        },
+    };
+
+    /// Transaction with a single output, distinguished by its value
+    fn transaction(sats: u64) -> AuthorizedTransaction {
+        AuthorizedTransaction {
+            transaction: Transaction {
+                inputs: Vec::new(),
+                proof: Proof::default(),
+                outputs: vec![Output {
+                    address: TransparentAddress::ALL_ZEROS,
+                    content: OutputContent::Value(bitcoin::Amount::from_sat(
+                        sats,
+                    )),
+                }],
+                orchard_bundle: None,
+            },
+            authorizations: Vec::new(),
+        }
+    }
+
+    fn block(transactions: Vec<AuthorizedTransaction>) -> (Header, Body) {
+        let body = Body::new(transactions, Vec::new());
+        let header = Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash: None,
+            prev_main_hash: bitcoin::BlockHash::from_byte_array([1; 32]),
+            roots: Vec::new(),
+        };
+        (header, body)
+    }
+
+    #[test]
+    fn reconstruct_with_all_transactions() -> anyhow::Result<()> {
+        let txs = vec![transaction(1), transaction(2), transaction(3)];
+        let (header, body) = block(txs.clone());
+        let compact_block = CompactBlock::new(header.clone(), &body);
+        // Candidate order and unrelated candidates do not matter
+        let candidates = txs.into_iter().rev().chain([transaction(4)]);
+        let reconstructed = compact_block
+            .reconstruct(candidates)
+            .map_err(|missing| anyhow::anyhow!("missing txs: {missing:?}"))?;
+        anyhow::ensure!(
+            reconstructed.compute_merkle_root() == header.merkle_root
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reconstruct_reports_missing_transactions() {
+        let txs = vec![transaction(1), transaction(2), transaction(3)];
+        let (header, body) = block(txs.clone());
+        let compact_block = CompactBlock::new(header, &body);
+        let res = compact_block.reconstruct([txs[1].clone()]);
+        assert!(matches!(res, Err(missing) if missing == [0, 2]));
+    }
+
+    // a colliding short ID reconstructs the wrong body, which is detected
+    // by the merkle root
+    #[test]
+    fn reconstruct_with_short_txid_collision() -> anyhow::Result<()> {
+        let (header, body) = block(vec![transaction(1)]);
+        let mut compact_block = CompactBlock::new(header.clone(), &body);
+        let colliding_tx = transaction(2);
+        compact_block.short_txids[0] =
+            ShortTxid::new(header.hash(), colliding_tx.transaction.txid());
+        let reconstructed = compact_block
+            .reconstruct([colliding_tx])
+            .map_err(|missing| anyhow::anyhow!("missing txs: {missing:?}"))?;
+        anyhow::ensure!(
+            reconstructed.compute_merkle_root() != header.merkle_root
+        );
+        Ok(())
+    }
+
+    // short IDs salted with a different block hash do not match any
+    // candidates
+    #[test]
+    fn reconstruct_with_wrong_salt() {
+        let txs = vec![transaction(1), transaction(2)];
+        let (header, body) = block(txs.clone());
+        let (other_header, _) = block(vec![transaction(3)]);
+        let mut compact_block = CompactBlock::new(header, &body);
+        compact_block.short_txids = txs
+            .iter()
+            .map(|tx| {
+                ShortTxid::new(other_header.hash(), tx.transaction.txid())
+            })
+            .collect();
+        let res = compact_block.reconstruct(txs);
+        assert!(matches!(res, Err(missing) if missing == [0, 1]));
+    }
+
+    // heartbeats without prune info keep the encoding understood by older
+    // peers
+    #[test]
+    fn heartbeat_encoding() -> anyhow::Result<()> {
+        let peer_state = PeerState {
+            tip_info: None,
+            version: *VERSION,
+        };
+        let legacy = Heartbeat {
+            peer_state,
+            prune_info: None,
+        };
+        let legacy_bytes =
+            bincode::serialize(&RequestMessageRef::Heartbeat(&legacy))?;
+        anyhow::ensure!(
+            legacy_bytes == bincode::serialize(&(0u32, peer_state))?
+        );
+        let with_prune_info = Heartbeat {
+            peer_state,
+            prune_info: Some(PruneInfo {
+                prune_height: Some(5),
+            }),
+        };
+        let bytes = bincode::serialize(&RequestMessageRef::Heartbeat(
+            &with_prune_info,
+        ))?;
+        let RequestMessage::Heartbeat(heartbeat) =
+            bincode::deserialize(&bytes)?
+        else {
+            anyhow::bail!("expected heartbeat");
+        };
+        anyhow::ensure!(matches!(
+            heartbeat.prune_info,
+            Some(PruneInfo {
+                prune_height: Some(5)
+            })
+        ));
+        Ok(())
+    }
+
+    // peers at or above a min version send version-gated messages to each
+    // other, so every gate must be covered by the workspace version
+    #[test]
+    fn min_versions_are_released() -> anyhow::Result<()> {
+        for min_version in [
+            COMPACT_BLOCK_MIN_VERSION,
+            PRUNE_INFO_MIN_VERSION,
+            SPENT_OUTPUTS_MIN_VERSION,
+        ] {
+            anyhow::ensure!(
+                *VERSION >= min_version,
+                "version {} is below min version {min_version}",
+                *VERSION
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
 use std::{
+    collections::BTreeMap,
     net::SocketAddr,
     num::NonZeroUsize,
     sync::{
         Arc,
         atomic::{self, AtomicBool},
     },
+    time::{Instant, UNIX_EPOCH},
 };
 
 use bitcoin::Work;
@@ -17,8 +19,12 @@ use tokio::{spawn, task::JoinHandle, time::Duration};
 
 use crate::{
     archive::Archive,
+    net::{NodePublicKey, bandwidth::Limiter as BandwidthLimiter},
     state::State,
-    types::{AuthorizedTransaction, Hash, Network, Tip, Version, hash, schema},
+    types::{
+        AuthorizedTransaction, BlockHash, Hash, Network, Tip, Version, hash,
+        schema,
+    },
 };
 
 mod channel_pool;
@@ -26,12 +32,15 @@ pub(crate) mod error;
 pub(crate) mod mailbox;
 pub mod message;
 mod request_queue;
+mod stats;
 mod task;
 
 pub use error::Error as ConnectionError;
 pub use mailbox::InternalMessage;
 use message::{Heartbeat, RequestMessage, RequestMessageRef};
 pub use message::{Request, ResponseMessage};
+pub(in crate::net) use stats::Stats;
+pub use stats::{ConnectionDirection, MessageStats};
 use task::ConnectionTask;
 
 #[derive(Debug, Error)]
@@ -141,6 +150,8 @@ where
 pub struct Connection {
     pub(in crate::net) inner: quinn::Connection,
     pub network: Network,
+    pub(in crate::net) stats: Arc<Stats>,
+    pub(in crate::net) bandwidth: Arc<BandwidthLimiter>,
 }
 
 impl Connection {
@@ -151,20 +162,45 @@ impl Connection {
 
     pub const HEARTBEAT_TIMEOUT_INTERVAL: Duration = Duration::from_secs(5);
 
+    /// Size of chunks for rate-limited writes
+    const WRITE_CHUNK_SIZE: usize = 16 * 1024;
+
+    /// Maximum size of chunks for rate-limited reads
+    const READ_CHUNK_SIZE: usize = 16 * 1024;
+
     pub fn addr(&self) -> SocketAddr {
         self.inner.remote_address()
     }
 
-    pub fn new(connection: quinn::Connection, network: Network) -> Self {
+    /// Public key presented by the peer, if any
+    pub fn peer_public_key(&self) -> Option<NodePublicKey> {
+        let certs = self
+            .inner
+            .peer_identity()?
+            .downcast::<Vec<rustls::pki_types::CertificateDer<'static>>>()
+            .ok()?;
+        certs.first().and_then(NodePublicKey::from_cert)
+    }
+
+    pub(in crate::net) fn new(
+        connection: quinn::Connection,
+        network: Network,
+        stats: Arc<Stats>,
+        bandwidth: Arc<BandwidthLimiter>,
+    ) -> Self {
         Self {
             inner: connection,
             network,
+            stats,
+            bandwidth,
         }
     }
 
-    pub async fn from_connecting(
+    pub(in crate::net) async fn from_connecting(
         connecting: quinn::Connecting,
         network: Network,
+        stats: Arc<Stats>,
+        bandwidth: Arc<BandwidthLimiter>,
     ) -> Result<Self, quinn::ConnectionError> {
         let addr = connecting.remote_address();
         tracing::trace!(%addr, "connecting to peer");
@@ -173,9 +209,49 @@ impl Connection {
         Ok(Self {
             inner: connection,
             network,
+            stats,
+            bandwidth,
         })
     }
 
+    /// Write to a stream in chunks, waiting for the bandwidth limiter before
+    /// each chunk is written
+    async fn write_rate_limited(
+        bandwidth: &BandwidthLimiter,
+        send: &mut SendStream,
+        buf: &[u8],
+    ) -> Result<(), quinn::WriteError> {
+        for chunk in buf.chunks(Self::WRITE_CHUNK_SIZE) {
+            let () = bandwidth.upload(chunk.len()).await;
+            send.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Read a stream to the end in chunks, waiting for the bandwidth limiter
+    /// after each chunk is read.
+    /// Data is not read from the stream while waiting, so the peer's sending
+    /// rate is limited by flow control.
+    async fn read_rate_limited(
+        bandwidth: &BandwidthLimiter,
+        recv: &mut RecvStream,
+        limit: usize,
+    ) -> Result<Vec<u8>, quinn::ReadToEndError> {
+        let mut buf = Vec::new();
+        while let Some(chunk) = recv
+            .read_chunk(Self::READ_CHUNK_SIZE, true)
+            .await
+            .map_err(quinn::ReadToEndError::Read)?
+        {
+            if buf.len() + chunk.bytes.len() > limit {
+                return Err(quinn::ReadToEndError::TooLong);
+            }
+            buf.extend_from_slice(&chunk.bytes);
+            let () = bandwidth.download(chunk.bytes.len()).await;
+        }
+        Ok(buf)
+    }
+
     async fn receive_request(
         &self,
     ) -> Result<(RequestMessage, SendStream), error::connection::ReceiveRequest>
@@ -191,8 +267,18 @@ impl Connection {
                 error::connection::Receive::BadMagic(magic_bytes).into()
             );
         }
-        let msg_bytes = rx.read_to_end(Connection::READ_REQUEST_LIMIT).await?;
+        let () = self.bandwidth.download(message::MAGIC_BYTES_LEN).await;
+        let msg_bytes = Self::read_rate_limited(
+            &self.bandwidth,
+            &mut rx,
+            Connection::READ_REQUEST_LIMIT,
+        )
+        .await?;
         let msg: RequestMessage = bincode::deserialize(&msg_bytes)?;
+        self.stats.record_received(
+            msg.message_type(),
+            message::MAGIC_BYTES_LEN + msg_bytes.len(),
+        );
         tracing::trace!(
             recv_id = %rx.id(),
             ?msg,
@@ -214,6 +300,7 @@ impl Connection {
         let message = RequestMessageRef::from(heartbeat);
         let mut message_buf = message::magic_bytes(self.network).to_vec();
         bincode::serialize_into::<&mut Vec<_>, _>(&mut message_buf, &message)?;
+        let sent_at = Instant::now();
         send.write_all(&message_buf).await.map_err(|err| {
             error::connection::Send::Write {
                 stream_id: send.id(),
@@ -221,11 +308,17 @@ impl Connection {
             }
         })?;
         send.finish()?;
+        self.stats
+            .record_sent(message.message_type(), message_buf.len());
+        // Resolves with `None` once the peer has acknowledged all stream data
+        if let Ok(None) = send.stopped().await {
+            self.stats.set_heartbeat_rtt(sent_at.elapsed());
+        }
         Ok(())
     }
 
     async fn receive_response(
-        network: Network,
+        &self,
         mut recv: RecvStream,
         read_response_limit: NonZeroUsize,
     ) -> ResponseResult {
@@ -234,14 +327,24 @@ impl Connection {
         recv.read_exact(&mut magic_bytes)
             .await
             .map_err(error::connection::Receive::ReadMagic)?;
-        if magic_bytes != message::magic_bytes(network) {
+        if magic_bytes != message::magic_bytes(self.network) {
             return Err(
                 error::connection::Receive::BadMagic(magic_bytes).into()
             );
         }
-        let response_bytes =
-            recv.read_to_end(read_response_limit.get()).await?;
+        // Holds the channel until the download limiter allows the response
+        let () = self.bandwidth.download(message::MAGIC_BYTES_LEN).await;
+        let response_bytes = Self::read_rate_limited(
+            &self.bandwidth,
+            &mut recv,
+            read_response_limit.get(),
+        )
+        .await?;
         let response: ResponseMessage = bincode::deserialize(&response_bytes)?;
+        self.stats.record_received(
+            (&response).into(),
+            message::MAGIC_BYTES_LEN + response_bytes.len(),
+        );
         tracing::trace!(
             recv_id = %recv.id(),
             ?response,
@@ -264,24 +367,23 @@ impl Connection {
         let message = RequestMessageRef::from(request);
         let mut message_buf = message::magic_bytes(self.network).to_vec();
         bincode::serialize_into::<&mut Vec<_>, _>(&mut message_buf, &message)?;
-        send.write_all(&message_buf).await.map_err(|err| {
-            error::connection::Send::Write {
+        Self::write_rate_limited(&self.bandwidth, &mut send, &message_buf)
+            .await
+            .map_err(|err| error::connection::Send::Write {
                 stream_id: send.id(),
                 source: err,
-            }
-        })?;
+            })?;
         send.finish()?;
-        Ok(
-            Self::receive_response(self.network, recv, read_response_limit)
-                .await,
-        )
+        self.stats.record_sent(request.into(), message_buf.len());
+        Ok(self.receive_response(recv, read_response_limit).await)
     }
 
     // Send a pre-serialized response, where the response does not include
     // magic bytes
     async fn send_serialized_response(
-        network: Network,
+        ctxt: &ConnectionContext,
         mut response_tx: SendStream,
+        response_type: &'static str,
         serialized_response: &[u8],
     ) -> Result<(), error::connection::SendResponse> {
         tracing::trace!(
@@ -289,25 +391,33 @@ impl Connection {
             "Sending response"
         );
         async {
-            response_tx
-                .write_all(&message::magic_bytes(network))
-                .await?;
-            response_tx.write_all(serialized_response).await
+            Self::write_rate_limited(
+                &ctxt.bandwidth,
+                &mut response_tx,
+                &message::magic_bytes(ctxt.network),
+            )
+            .await?;
+            Self::write_rate_limited(
+                &ctxt.bandwidth,
+                &mut response_tx,
+                serialized_response,
+            )
+            .await
         }
         .await
-        .map_err(|err| {
-            {
-                error::connection::Send::Write {
-                    stream_id: response_tx.id(),
-                    source: err,
-                }
-            }
-            .into()
-        })
+        .map_err(|err| error::connection::Send::Write {
+            stream_id: response_tx.id(),
+            source: err,
+        })?;
+        ctxt.stats.record_sent(
+            response_type,
+            message::MAGIC_BYTES_LEN + serialized_response.len(),
+        );
+        Ok(())
     }
 
     async fn send_response(
-        network: Network,
+        ctxt: &ConnectionContext,
         mut response_tx: SendStream,
         response: ResponseMessage,
     ) -> Result<(), error::connection::SendResponse> {
@@ -316,17 +426,21 @@ impl Connection {
             send_id = %response_tx.id(),
             "Sending response"
         );
-        let mut message_buf = message::magic_bytes(network).to_vec();
+        let mut message_buf = message::magic_bytes(ctxt.network).to_vec();
         bincode::serialize_into::<&mut Vec<_>, _>(&mut message_buf, &response)?;
-        response_tx.write_all(&message_buf).await.map_err(|err| {
-            {
-                error::connection::Send::Write {
-                    stream_id: response_tx.id(),
-                    source: err,
-                }
-            }
-            .into()
-        })
+        Self::write_rate_limited(
+            &ctxt.bandwidth,
+            &mut response_tx,
+            &message_buf,
+        )
+        .await
+        .map_err(|err| error::connection::Send::Write {
+            stream_id: response_tx.id(),
+            source: err,
+        })?;
+        ctxt.stats
+            .record_sent((&response).into(), message_buf.len());
+        Ok(())
     }
 }
 
@@ -335,6 +449,8 @@ pub struct ConnectionContext {
     pub archive: Archive,
     pub network: Network,
     pub state: State,
+    pub(in crate::net) stats: Arc<Stats>,
+    pub(in crate::net) bandwidth: Arc<BandwidthLimiter>,
 }
 
 #[derive(
@@ -381,6 +497,7 @@ pub struct ConnectionHandle {
     pub(in crate::net) received_msg_successfully: Arc<AtomicBool>,
     /// Representation of [`PeerConnectionStatus`]
     pub(in crate::net) status_repr: Arc<AtomicBool>,
+    pub(in crate::net) stats: Arc<Stats>,
     /// Push messages from connection task / net task / node
     pub internal_message_tx: mpsc::UnboundedSender<InternalMessage>,
 }
@@ -397,6 +514,42 @@ impl ConnectionHandle {
         self.received_msg_successfully
             .load(atomic::Ordering::SeqCst)
     }
+
+    /// Info and statistics for the peer connection
+    pub fn peer(&self, address: SocketAddr) -> Peer {
+        let peer_state = self.stats.peer_state();
+        let tip_info = peer_state.and_then(|peer_state| peer_state.tip_info);
+        Peer {
+            address,
+            status: self.connection_status(),
+            public_key: self.stats.public_key(),
+            direction: self.stats.direction(),
+            connected_at: self.stats.connected_at().map(|connected_at| {
+                connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            heartbeat_rtt_ms: self
+                .stats
+                .heartbeat_rtt()
+                .map(|rtt| rtt.as_secs_f64() * 1000.),
+            version: peer_state.map(|peer_state| peer_state.version),
+            tip: tip_info.map(|tip_info| tip_info.tip.block_hash),
+            main_tip: tip_info.map(|tip_info| tip_info.tip.main_block_hash),
+            height: tip_info.map(|tip_info| tip_info.block_height),
+            prune_height: self.stats.prune_height(),
+            total_work: tip_info.map(|tip_info| tip_info.total_work),
+            messages: self
+                .stats
+                .messages()
+                .into_iter()
+                .map(|(message_type, stats)| (message_type.to_owned(), stats))
+                .collect(),
+            queued_requests: self.stats.queued_requests(),
+            in_flight_requests: self.stats.in_flight_requests(),
+        }
+    }
 }
 
 impl Drop for ConnectionHandle {
@@ -411,9 +564,11 @@ pub fn handle(
     connection: Connection,
 ) -> (ConnectionHandle, mpsc::UnboundedReceiver<Info>) {
     let addr = connection.addr();
+    let stats = ctxt.stats.clone();
+    let () = stats.set_connected(connection.peer_public_key());
 
     let (info_tx, info_rx) = mpsc::unbounded();
-    let (mailbox_tx, mailbox_rx) = mailbox::new();
+    let (mailbox_tx, mailbox_rx) = mailbox::new(stats.clone());
     let internal_message_tx = mailbox_tx.internal_message_tx.clone();
     let received_msg_successfully = Arc::new(AtomicBool::new(false));
     let connection_task = {
@@ -447,6 +602,7 @@ pub fn handle(
         task,
         received_msg_successfully,
         status_repr: Arc::new(AtomicBool::new(status.as_repr())),
+        stats,
         internal_message_tx,
     };
     (connection_handle, info_rx)
@@ -459,16 +615,23 @@ pub fn connect(
     let connection_status = PeerConnectionStatus::Connecting;
     let status_repr = Arc::new(AtomicBool::new(connection_status.as_repr()));
     let received_msg_successfully = Arc::new(AtomicBool::new(false));
+    let stats = ctxt.stats.clone();
     let (info_tx, info_rx) = mpsc::unbounded();
-    let (mailbox_tx, mailbox_rx) = mailbox::new();
+    let (mailbox_tx, mailbox_rx) = mailbox::new(stats.clone());
     let internal_message_tx = mailbox_tx.internal_message_tx.clone();
     let connection_task = {
         let received_msg_successfully = received_msg_successfully.clone();
         let status_repr = status_repr.clone();
         let info_tx = info_tx.clone();
         move || async move {
-            let connection =
-                Connection::from_connecting(connecting, ctxt.network).await?;
+            let connection = Connection::from_connecting(
+                connecting,
+                ctxt.network,
+                ctxt.stats.clone(),
+                ctxt.bandwidth.clone(),
+            )
+            .await?;
+            let () = ctxt.stats.set_connected(connection.peer_public_key());
             status_repr.store(
                 PeerConnectionStatus::Connected.as_repr(),
                 atomic::Ordering::SeqCst,
@@ -497,6 +660,7 @@ pub fn connect(
         task,
         received_msg_successfully,
         status_repr,
+        stats,
         internal_message_tx,
     };
     (connection_handle, info_rx)
@@ -508,4 +672,32 @@ pub struct Peer {
     #[schema(value_type = schema::SocketAddr)]
     pub address: SocketAddr,
     pub status: PeerConnectionStatus,
+    /// Public key presented by the peer, if any
+    pub public_key: Option<NodePublicKey>,
+    pub direction: ConnectionDirection,
+    /// Unix timestamp (seconds) at which the connection was established
+    pub connected_at: Option<u64>,
+    /// Round trip time for the last acknowledged heartbeat, in milliseconds
+    pub heartbeat_rtt_ms: Option<f64>,
+    /// Version advertised by the peer
+    pub version: Option<Version>,
+    /// Tip advertised by the peer
+    pub tip: Option<BlockHash>,
+    /// Mainchain block that commits to the tip advertised by the peer
+    #[schema(value_type = Option<schema::BitcoinBlockHash>)]
+    pub main_tip: Option<bitcoin::BlockHash>,
+    /// Height of the tip advertised by the peer
+    pub height: Option<u32>,
+    /// If the peer is pruned, bodies are not available for blocks below this
+    /// height
+    pub prune_height: Option<u32>,
+    /// Total work of the tip advertised by the peer
+    #[schema(value_type = Option<String>)]
+    pub total_work: Option<Work>,
+    /// Traffic per message type
+    pub messages: BTreeMap<String, MessageStats>,
+    /// Requests waiting to be sent to the peer
+    pub queued_requests: usize,
+    /// Requests sent to the peer, awaiting a response
+    pub in_flight_requests: usize,
 }
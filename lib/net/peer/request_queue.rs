@@ -8,7 +8,7 @@ use parking_lot::Mutex;
 
 use crate::{
     net::peer::{
-        Connection, PeerResponseItem,
+        Connection, PeerResponseItem, Stats,
         channel_pool::{self, ChannelPool},
         error,
         message::{Heartbeat, Request},
@@ -23,6 +23,11 @@ const REQUEST_QUOTA: Quota =
 const fn request_cost(req: &Request) -> NonZeroU32 {
     match req {
         Request::GetBlock { .. } => NonZeroU32::new(1000).unwrap(),
+        Request::GetBlockTransactions { .. } => NonZeroU32::new(1000).unwrap(),
+        Request::GetBlockWithSpentOutputs { .. } => {
+            NonZeroU32::new(2000).unwrap()
+        }
+        Request::GetCompactBlock { .. } => NonZeroU32::new(100).unwrap(),
         Request::GetHeaders { .. } => NonZeroU32::new(10_000).unwrap(),
         Request::PushTransaction { .. } => NonZeroU32::new(10).unwrap(),
     }
@@ -51,7 +56,8 @@ impl ErrorRx {
             PeerResponse(PeerResponseItem),
             Request(Request, channel_pool::LimiterGuard<Request>),
         }
-        let (channel_pool, channel_pool_rx) = ChannelPool::new(connection);
+        let (channel_pool, channel_pool_rx) =
+            ChannelPool::new(connection.clone());
         let channel_pool_stream = channel_pool_rx
             .into_stream()
             .map(|item| match item {
@@ -81,15 +87,18 @@ impl ErrorRx {
             .request_rx
             .then({
                 let limiter = channel_pool.outbound_request_limiter().clone();
+                let stats = connection.stats.clone();
                 move |request| {
                     let limiter = limiter.clone();
                     let rate_limiter = self.rate_limiter.clone();
+                    let stats = stats.clone();
                     async move {
                         let guard = limiter.acquire().await;
                         rate_limiter
                             .until_n_ready(request_cost(&request))
                             .await
                             .unwrap();
+                        let () = stats.request_sending();
                         SourceItem::Request(request, guard)
                     }
                 }
@@ -135,6 +144,7 @@ pub struct Sender {
     request_tx: mpsc::UnboundedSender<Request>,
     /// Used to deduplicate requests
     request_hashes: Arc<Mutex<HashSet<Hash>>>,
+    stats: Arc<Stats>,
 }
 
 impl Sender {
@@ -160,6 +170,7 @@ impl Sender {
                 .request_tx
                 .unbounded_send(request)
                 .map_err(|_| error::request_queue::SendRequest)?;
+            let () = self.stats.request_queued();
             Ok(true)
         } else {
             Ok(false)
@@ -167,13 +178,14 @@ impl Sender {
     }
 }
 
-pub fn new() -> (Sender, ErrorRx) {
+pub fn new(stats: Arc<Stats>) -> (Sender, ErrorRx) {
     let (heartbeat_tx, heartbeat_rx) = mpsc::unbounded();
     let (request_tx, request_rx) = mpsc::unbounded();
     let sender = Sender {
         heartbeat_tx,
         request_tx,
         request_hashes: Arc::new(Mutex::new(HashSet::new())),
+        stats,
     };
     let rate_limiter = DefaultDirectRateLimiter::direct(REQUEST_QUOTA);
     let error_rx = ErrorRx {
@@ -0,0 +1,219 @@
+//! Statistics for a peer connection
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{self, AtomicUsize},
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use crate::net::{NodePublicKey, peer::PeerState};
+
+/// Whether a connection was initiated by us, or by the peer
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    strum::Display,
+    utoipa::ToSchema,
+)]
+pub enum ConnectionDirection {
+    /// The peer connected to us
+    Inbound,
+    /// We connected to the peer
+    Outbound,
+}
+
+/// Traffic for a single message type
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, utoipa::ToSchema,
+)]
+pub struct MessageStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// Statistics for a peer connection, shared between the connection handle
+/// and the connection task
+pub struct Stats {
+    direction: ConnectionDirection,
+    /// `None` if the connection has not yet been established
+    connected_at: RwLock<Option<SystemTime>>,
+    /// Public key presented by the peer, if any
+    public_key: RwLock<Option<NodePublicKey>>,
+    /// Last peer state advertised by the peer
+    peer_state: RwLock<Option<PeerState>>,
+    /// Last prune height advertised by the peer
+    prune_height: RwLock<Option<u32>>,
+    /// Round trip time for the last acknowledged heartbeat
+    heartbeat_rtt: RwLock<Option<Duration>>,
+    /// Traffic per message type
+    messages: Mutex<BTreeMap<&'static str, MessageStats>>,
+    /// Requests waiting for a channel or rate limiter permit
+    queued_requests: AtomicUsize,
+    /// Requests that have been sent, and are awaiting a response
+    in_flight_requests: AtomicUsize,
+}
+
+impl Stats {
+    pub fn new(direction: ConnectionDirection) -> Self {
+        Self {
+            direction,
+            connected_at: RwLock::new(None),
+            public_key: RwLock::new(None),
+            peer_state: RwLock::new(None),
+            prune_height: RwLock::new(None),
+            heartbeat_rtt: RwLock::new(None),
+            messages: Mutex::new(BTreeMap::new()),
+            queued_requests: AtomicUsize::new(0),
+            in_flight_requests: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn direction(&self) -> ConnectionDirection {
+        self.direction
+    }
+
+    pub fn connected_at(&self) -> Option<SystemTime> {
+        *self.connected_at.read()
+    }
+
+    pub fn public_key(&self) -> Option<NodePublicKey> {
+        *self.public_key.read()
+    }
+
+    pub fn set_connected(&self, public_key: Option<NodePublicKey>) {
+        *self.connected_at.write() = Some(SystemTime::now());
+        *self.public_key.write() = public_key;
+    }
+
+    pub fn peer_state(&self) -> Option<PeerState> {
+        *self.peer_state.read()
+    }
+
+    pub fn set_peer_state(&self, peer_state: PeerState) {
+        *self.peer_state.write() = Some(peer_state);
+    }
+
+    /// If the peer is pruned, bodies are not available for blocks below this
+    /// height
+    pub fn prune_height(&self) -> Option<u32> {
+        *self.prune_height.read()
+    }
+
+    pub fn set_prune_height(&self, prune_height: Option<u32>) {
+        *self.prune_height.write() = prune_height;
+    }
+
+    pub fn heartbeat_rtt(&self) -> Option<Duration> {
+        *self.heartbeat_rtt.read()
+    }
+
+    pub fn set_heartbeat_rtt(&self, rtt: Duration) {
+        *self.heartbeat_rtt.write() = Some(rtt);
+    }
+
+    pub fn messages(&self) -> BTreeMap<&'static str, MessageStats> {
+        self.messages.lock().clone()
+    }
+
+    pub fn record_sent(&self, message_type: &'static str, bytes: usize) {
+        let mut messages = self.messages.lock();
+        let stats = messages.entry(message_type).or_default();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes as u64;
+    }
+
+    pub fn record_received(&self, message_type: &'static str, bytes: usize) {
+        let mut messages = self.messages.lock();
+        let stats = messages.entry(message_type).or_default();
+        stats.messages_received += 1;
+        stats.bytes_received += bytes as u64;
+    }
+
+    pub fn queued_requests(&self) -> usize {
+        self.queued_requests.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight_requests.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Record that a request was added to the request queue
+    pub fn request_queued(&self) {
+        self.queued_requests.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    /// Record that a queued request is being sent
+    pub fn request_sending(&self) {
+        self.queued_requests.fetch_sub(1, atomic::Ordering::SeqCst);
+        self.in_flight_requests
+            .fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    /// Record that an in-flight request completed or failed
+    pub fn request_completed(&self) {
+        self.in_flight_requests
+            .fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConnectionDirection, Stats};
+
+    #[test]
+    fn message_stats() {
+        let stats = Stats::new(ConnectionDirection::Outbound);
+        stats.record_sent("GetBlock", 100);
+        stats.record_sent("GetBlock", 50);
+        stats.record_received("Block", 1000);
+        stats.record_received("GetBlock", 10);
+        let messages = stats.messages();
+        assert_eq!(messages.len(), 2);
+        let get_block = messages["GetBlock"];
+        assert_eq!(get_block.messages_sent, 2);
+        assert_eq!(get_block.bytes_sent, 150);
+        assert_eq!(get_block.messages_received, 1);
+        assert_eq!(get_block.bytes_received, 10);
+        let block = messages["Block"];
+        assert_eq!(block.messages_sent, 0);
+        assert_eq!(block.messages_received, 1);
+        assert_eq!(block.bytes_received, 1000);
+    }
+
+    #[test]
+    fn request_counts() {
+        let stats = Stats::new(ConnectionDirection::Inbound);
+        stats.request_queued();
+        stats.request_queued();
+        assert_eq!(
+            (stats.queued_requests(), stats.in_flight_requests()),
+            (2, 0)
+        );
+        stats.request_sending();
+        assert_eq!(
+            (stats.queued_requests(), stats.in_flight_requests()),
+            (1, 1)
+        );
+        stats.request_completed();
+        stats.request_sending();
+        assert_eq!(
+            (stats.queued_requests(), stats.in_flight_requests()),
+            (0, 1)
+        );
+        stats.request_completed();
+        assert_eq!(
+            (stats.queued_requests(), stats.in_flight_requests()),
+            (0, 0)
+        );
+    }
+}
@@ -9,19 +9,22 @@ use std::{
 use fallible_iterator::FallibleIterator;
 use futures::{StreamExt as _, channel::mpsc};
 use quinn::SendStream;
-use sneed::EnvError;
+use sneed::{EnvError, RoTxn};
 
 use crate::{
-    net::peer::{
-        BanReason, Connection, ConnectionContext, Info, PeerState, PeerStateId,
-        Request, TipInfo,
-        error::{Error, blocking_task},
-        mailbox::{
-            self, BlockingTaskFn, ForwardResponseItem, ForwardResponseResult,
-            InternalMessage, MailboxItem,
+    net::{
+        bandwidth::NEW_BLOCK_RELAY_DEPTH,
+        peer::{
+            BanReason, Connection, ConnectionContext, Info, PeerState,
+            PeerStateId, Request, TipInfo,
+            error::{Error, blocking_task},
+            mailbox::{
+                self, BlockingTaskFn, ForwardResponseItem,
+                ForwardResponseResult, InternalMessage, MailboxItem,
+            },
+            message::{self, Heartbeat, RequestMessage, ResponseMessage},
+            request_queue,
         },
-        message::{self, Heartbeat, RequestMessage, ResponseMessage},
-        request_queue,
     },
     state,
     types::{
@@ -544,13 +547,32 @@ impl ConnectionTask {
                     BanReason::BmmVerificationFailed(peer_tip_info.tip);
                 return Err(blocking_task::TaskError::PeerBan(ban_reason));
             };
+            if ctxt
+                .archive
+                .is_invalid(&rotxn, peer_tip_info.tip.block_hash)?
+            {
+                tracing::debug!(
+                    tip = %peer_tip_info.tip.block_hash,
+                    "Peer tip has been invalidated"
+                );
+                return Ok(());
+            }
         }
         // Request missing bodies, or notify that a new tip is ready
-        let (common_ancestor, missing_bodies): (
+        let (common_ancestor, missing_bodies, extends_tip): (
             Option<BlockHash>,
             Vec<BlockHash>,
+            bool,
         ) = {
             let rotxn = ctxt.env.read_txn()?;
+            let extends_tip = if let Some(tip_info) = tip_info {
+                ctxt.archive
+                    .get_header(&rotxn, peer_tip_info.tip.block_hash)?
+                    .prev_side_hash
+                    == Some(tip_info.tip.block_hash)
+            } else {
+                false
+            };
             let common_ancestor = if let Some(tip_info) = tip_info {
                 ctxt.archive.last_common_ancestor(
                     &rotxn,
@@ -575,13 +597,63 @@ impl ConnectionTask {
                 )
                 .take(MAX_BLOCK_REQUESTS)
                 .collect()?;
-            (common_ancestor, missing_bodies)
+            // Bodies that the peer has pruned cannot be requested
+            if let Some(peer_prune_height) = ctxt.stats.prune_height()
+                && let Some(earliest_missing_body) = missing_bodies.first()
+                && ctxt.archive.get_height(&rotxn, *earliest_missing_body)?
+                    < peer_prune_height
+            {
+                tracing::debug!(
+                    tip = %peer_tip_info.tip.block_hash,
+                    %peer_prune_height,
+                    "Peer has pruned bodies required to sync to its tip"
+                );
+                return Ok(());
+            }
+            (common_ancestor, missing_bodies, extends_tip)
         };
         if missing_bodies.is_empty() {
             let info = Info::NewTipReady(peer_tip_info.tip);
             info_tx
                 .unbounded_send(info)
                 .map_err(|_| blocking_task::TaskError::SendInfo)?;
+        } else if ctxt.state.is_compact() {
+            // Compact-state nodes need the spent outputs to validate blocks
+            if peer_state.version < message::SPENT_OUTPUTS_MIN_VERSION {
+                tracing::debug!(
+                    tip = %peer_tip_info.tip.block_hash,
+                    peer_version = %peer_state.version,
+                    "Peer does not support requests for blocks with spent \
+                     outputs"
+                );
+                return Ok(());
+            }
+            missing_bodies.into_iter().try_for_each(|block_hash| {
+                let request = message::GetBlockRequest {
+                    block_hash,
+                    descendant_tip: Some(peer_tip_info.tip),
+                    peer_state_id: Some(peer_state.into()),
+                    ancestor: common_ancestor,
+                };
+                let _: bool = request_queue
+                    .send_request(Request::GetBlockWithSpentOutputs(request))?;
+                Ok::<_, blocking_task::TaskError>(())
+            })?;
+        } else if extends_tip
+            && missing_bodies == [peer_tip_info.tip.block_hash]
+            && peer_state.version >= message::COMPACT_BLOCK_MIN_VERSION
+        {
+            // The peer tip is a new block on top of the current tip, so most
+            // of its transactions should already be in the mempool.
+            // Request a compact block instead of the full body, if the peer
+            // supports compact blocks.
+            let request = message::GetCompactBlockRequest {
+                block_hash: peer_tip_info.tip.block_hash,
+                descendant_tip: Some(peer_tip_info.tip),
+                peer_state_id: Some(peer_state.into()),
+                ancestor: common_ancestor,
+            };
+            let _: bool = request_queue.send_request(request.into())?;
         } else {
             // Request missing bodies
             missing_bodies.into_iter().try_for_each(|block_hash| {
@@ -598,25 +670,189 @@ impl ConnectionTask {
         Ok(())
     }
 
+    /// Returns `true` if the upload target has been reached, and the block
+    /// is too far behind our tip to count as new-block relay
+    fn upload_target_refuses_block(
+        ctxt: &ConnectionContext,
+        rotxn: &RoTxn,
+        block_hash: BlockHash,
+    ) -> Result<bool, Error> {
+        if !ctxt.bandwidth.upload_target_exceeded() {
+            return Ok(false);
+        }
+        let Some(height) = ctxt.archive.try_get_height(rotxn, block_hash)?
+        else {
+            return Ok(false);
+        };
+        let tip_height = ctxt
+            .state
+            .try_get_height(rotxn)
+            .map_err(state::Error::from)?;
+        Ok(ctxt
+            .bandwidth
+            .upload_target_refuses_block(height, tip_height))
+    }
+
+    /// Returns `true` if the peer can deserialize prune info, including
+    /// [`ResponseMessage::BlockPruned`]
+    fn peer_supports_prune_info(ctxt: &ConnectionContext) -> bool {
+        ctxt.stats.peer_state().is_some_and(|peer_state| {
+            peer_state.version >= message::PRUNE_INFO_MIN_VERSION
+        })
+    }
+
+    /// Returns `true` if a block without a body should be reported to the
+    /// peer as pruned. Older peers are sent `NoBlock` instead.
+    fn report_pruned(
+        ctxt: &ConnectionContext,
+        rotxn: &RoTxn,
+        block_hash: BlockHash,
+    ) -> Result<bool, Error> {
+        if !Self::peer_supports_prune_info(ctxt) {
+            return Ok(false);
+        }
+        Ok(ctxt.archive.is_pruned(rotxn, block_hash)?)
+    }
+
     async fn handle_get_block(
         ctxt: &ConnectionContext,
         response_tx: SendStream,
         block_hash: BlockHash,
     ) -> Result<(), Error> {
-        let (header, body) = {
+        let (header, body, pruned) = {
             let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
-            let header = ctxt.archive.try_get_header(&rotxn, block_hash)?;
-            let body = ctxt.archive.try_get_body(&rotxn, block_hash)?;
-            (header, body)
+            if Self::upload_target_refuses_block(ctxt, &rotxn, block_hash)? {
+                tracing::debug!(
+                    %block_hash,
+                    "upload target reached, not serving block"
+                );
+                (None, None, false)
+            } else {
+                let header = ctxt.archive.try_get_header(&rotxn, block_hash)?;
+                let body = ctxt.archive.try_get_body(&rotxn, block_hash)?;
+                let pruned = body.is_none()
+                    && Self::report_pruned(ctxt, &rotxn, block_hash)?;
+                (header, body, pruned)
+            }
         };
         let resp = match (header, body) {
             (Some(header), Some(body)) => {
                 ResponseMessage::Block { header, body }
             }
+            (_, _) if pruned => ResponseMessage::BlockPruned { block_hash },
+            (_, _) => ResponseMessage::NoBlock { block_hash },
+        };
+        let () = Connection::send_response(ctxt, response_tx, resp).await?;
+        Ok(())
+    }
+
+    async fn handle_get_block_with_spent_outputs(
+        ctxt: &ConnectionContext,
+        response_tx: SendStream,
+        block_hash: BlockHash,
+    ) -> Result<(), Error> {
+        let (header, body, spent_outputs, pruned) = {
+            let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
+            if Self::upload_target_refuses_block(ctxt, &rotxn, block_hash)? {
+                (None, None, None, false)
+            } else {
+                let header = ctxt.archive.try_get_header(&rotxn, block_hash)?;
+                let body = ctxt.archive.try_get_body(&rotxn, block_hash)?;
+                let spent_outputs = match &body {
+                    Some(body) => {
+                        match ctxt
+                            .archive
+                            .try_get_spent_outputs(&rotxn, block_hash)?
+                        {
+                            Some(spent_outputs) => Some(spent_outputs),
+                            None => ctxt
+                                .state
+                                .try_get_block_spent_outputs(&rotxn, body)?,
+                        }
+                    }
+                    None => None,
+                };
+                let pruned = body.is_none()
+                    && Self::report_pruned(ctxt, &rotxn, block_hash)?;
+                (header, body, spent_outputs, pruned)
+            }
+        };
+        let resp = match (header, body, spent_outputs) {
+            (Some(header), Some(body), Some(spent_outputs)) => {
+                ResponseMessage::BlockWithSpentOutputs {
+                    header,
+                    body,
+                    spent_outputs,
+                }
+            }
+            (_, _, _) if pruned => ResponseMessage::BlockPruned { block_hash },
+            (_, _, _) => ResponseMessage::NoBlock { block_hash },
+        };
+        let () = Connection::send_response(ctxt, response_tx, resp).await?;
+        Ok(())
+    }
+
+    async fn handle_get_compact_block(
+        ctxt: &ConnectionContext,
+        response_tx: SendStream,
+        block_hash: BlockHash,
+    ) -> Result<(), Error> {
+        let (header, body, pruned) = {
+            let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
+            if Self::upload_target_refuses_block(ctxt, &rotxn, block_hash)? {
+                (None, None, false)
+            } else {
+                let header = ctxt.archive.try_get_header(&rotxn, block_hash)?;
+                let body = ctxt.archive.try_get_body(&rotxn, block_hash)?;
+                let pruned = body.is_none()
+                    && Self::report_pruned(ctxt, &rotxn, block_hash)?;
+                (header, body, pruned)
+            }
+        };
+        let resp = match (header, body) {
+            (Some(header), Some(body)) => ResponseMessage::CompactBlock(
+                Box::new(message::CompactBlock::new(header, &body)),
+            ),
+            (_, _) if pruned => ResponseMessage::BlockPruned { block_hash },
             (_, _) => ResponseMessage::NoBlock { block_hash },
         };
-        let () =
-            Connection::send_response(ctxt.network, response_tx, resp).await?;
+        let () = Connection::send_response(ctxt, response_tx, resp).await?;
+        Ok(())
+    }
+
+    async fn handle_get_block_transactions(
+        ctxt: &ConnectionContext,
+        response_tx: SendStream,
+        block_hash: BlockHash,
+        indexes: Vec<u32>,
+    ) -> Result<(), Error> {
+        let (body, pruned) = {
+            let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
+            if Self::upload_target_refuses_block(ctxt, &rotxn, block_hash)? {
+                (None, false)
+            } else {
+                let body = ctxt.archive.try_get_body(&rotxn, block_hash)?;
+                let pruned = body.is_none()
+                    && Self::report_pruned(ctxt, &rotxn, block_hash)?;
+                (body, pruned)
+            }
+        };
+        let transactions: Option<Vec<_>> = body.and_then(|body| {
+            let authorized_txs = body.authorized_transactions();
+            indexes
+                .iter()
+                .map(|idx| authorized_txs.get(*idx as usize).cloned())
+                .collect()
+        });
+        let resp = match transactions {
+            Some(transactions) => ResponseMessage::BlockTransactions {
+                block_hash,
+                transactions,
+            },
+            None if pruned => ResponseMessage::BlockPruned { block_hash },
+            None => ResponseMessage::NoBlock { block_hash },
+        };
+        let () = Connection::send_response(ctxt, response_tx, resp).await?;
         Ok(())
     }
 
@@ -629,6 +865,7 @@ impl ConnectionTask {
     ) {
         let env = ctxt.env.clone();
         let archive = ctxt.archive.clone();
+        let bandwidth = ctxt.bandwidth.clone();
         forward_response_spawner.spawn_blocking(move || {
             let response = {
                 let rotxn = env.read_txn()?;
@@ -641,13 +878,22 @@ impl ConnectionTask {
                         .map(|(_, header)| Ok(header))
                         .collect()?;
                     headers.reverse();
-                    ResponseMessage::Headers(headers)
+                    // Serving more headers than needed to relay new blocks
+                    // would exceed the upload target
+                    if headers.len() > NEW_BLOCK_RELAY_DEPTH as usize
+                        && bandwidth.upload_target_exceeded()
+                    {
+                        ResponseMessage::NoHeader { block_hash: end }
+                    } else {
+                        ResponseMessage::Headers(headers)
+                    }
                 } else {
                     ResponseMessage::NoHeader { block_hash: end }
                 }
             };
             let serialized_response = bincode::serialize(&response)?;
             Ok(ForwardResponseItem {
+                response_type: (&response).into(),
                 serialized_response,
                 response_tx,
             })
@@ -668,7 +914,7 @@ impl ConnectionTask {
         match validate_tx_result {
             Err(err) => {
                 Connection::send_response(
-                    ctxt.network,
+                    ctxt,
                     response_tx,
                     ResponseMessage::TransactionRejected(txid),
                 )
@@ -677,7 +923,7 @@ impl ConnectionTask {
             }
             Ok(_) => {
                 Connection::send_response(
-                    ctxt.network,
+                    ctxt,
                     response_tx,
                     ResponseMessage::TransactionAccepted(txid),
                 )
@@ -702,7 +948,12 @@ impl ConnectionTask {
     ) -> Result<(), Error> {
         match request_msg {
             RequestMessage::Heartbeat(heartbeat) => {
-                let new_peer_state = heartbeat.0;
+                let new_peer_state = heartbeat.peer_state;
+                let () = ctxt.stats.set_peer_state(new_peer_state);
+                if let Some(prune_info) = heartbeat.prune_info {
+                    let () =
+                        ctxt.stats.set_prune_height(prune_info.prune_height);
+                }
                 let new_peer_state_id = (&new_peer_state).into();
                 peer_states.insert(new_peer_state_id, new_peer_state);
                 if *peer_state != Some(new_peer_state_id) {
@@ -732,6 +983,50 @@ impl ConnectionTask {
                     peer_state_id: _,
                 },
             )) => Self::handle_get_block(ctxt, response_tx, block_hash).await,
+            RequestMessage::Request(Request::GetBlockTransactions(
+                message::GetBlockTransactionsRequest {
+                    block_hash,
+                    indexes,
+                    compact_block: _,
+                    descendant_tip: _,
+                    ancestor: _,
+                    peer_state_id: _,
+                },
+            )) => {
+                Self::handle_get_block_transactions(
+                    ctxt,
+                    response_tx,
+                    block_hash,
+                    indexes,
+                )
+                .await
+            }
+            RequestMessage::Request(Request::GetBlockWithSpentOutputs(
+                message::GetBlockRequest {
+                    block_hash,
+                    descendant_tip: _,
+                    ancestor: _,
+                    peer_state_id: _,
+                },
+            )) => {
+                Self::handle_get_block_with_spent_outputs(
+                    ctxt,
+                    response_tx,
+                    block_hash,
+                )
+                .await
+            }
+            RequestMessage::Request(Request::GetCompactBlock(
+                message::GetCompactBlockRequest {
+                    block_hash,
+                    descendant_tip: _,
+                    ancestor: _,
+                    peer_state_id: _,
+                },
+            )) => {
+                Self::handle_get_compact_block(ctxt, response_tx, block_hash)
+                    .await
+            }
             RequestMessage::Request(Request::GetHeaders(
                 message::GetHeadersRequest {
                     start,
@@ -850,14 +1145,16 @@ impl ConnectionTask {
                     )?;
                 }
                 MailboxItem::ForwardResponse(ForwardResponseItem {
+                    response_type,
                     serialized_response,
                     response_tx,
                 }) => {
-                    let network = ctxt.network;
+                    let ctxt = ctxt.clone();
                     self.mailbox_tx.send_response_spawner.spawn(async move {
                         Connection::send_serialized_response(
-                            network,
+                            &ctxt,
                             response_tx,
+                            response_type,
                             &serialized_response,
                         )
                         .await
@@ -895,10 +1192,23 @@ impl ConnectionTask {
                             total_work,
                         })
                     };
-                    let heartbeat_msg = Heartbeat(PeerState {
-                        tip_info,
-                        version: *VERSION,
-                    });
+                    // Older peers cannot deserialize prune info
+                    let prune_info = if Self::peer_supports_prune_info(ctxt) {
+                        let rotxn =
+                            ctxt.env.read_txn().map_err(EnvError::from)?;
+                        let prune_height =
+                            ctxt.archive.try_get_prune_height(&rotxn)?;
+                        Some(message::PruneInfo { prune_height })
+                    } else {
+                        None
+                    };
+                    let heartbeat_msg = Heartbeat {
+                        peer_state: PeerState {
+                            tip_info,
+                            version: *VERSION,
+                        },
+                        prune_info,
+                    };
                     self.mailbox_tx.request_tx.send_heartbeat(heartbeat_msg)?;
                 }
                 MailboxItem::PeerRequest((request, response_tx)) => {
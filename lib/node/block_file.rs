@@ -0,0 +1,212 @@
+//! Block files, used to export blocks from a node and import them into
+//! another node without a peer connection
+
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::types::{Body, Header};
+
+/// Magic bytes at the start of a block file
+const MAGIC: &[u8; 8] = b"THORBLKS";
+
+/// Block file format version
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Maximum size of a serialized block record
+const MAX_RECORD_SIZE: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("block record (de)serialization error")]
+    Bincode(#[from] bincode::Error),
+    #[error("invalid block file (bad magic bytes)")]
+    InvalidMagic,
+    #[error("block file I/O error")]
+    Io(#[from] io::Error),
+    #[error("block record too large ({size} bytes)")]
+    RecordTooLarge { size: u64 },
+    #[error("unsupported block file format version ({version})")]
+    UnsupportedVersion { version: u32 },
+}
+
+/// A block, along with the mainchain blocks that BMM-verify it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockRecord {
+    pub header: Header,
+    pub body: Body,
+    pub bmm_inclusions: Vec<bitcoin::BlockHash>,
+}
+
+/// Writes a block file.
+/// The file consists of magic bytes, the format version, and a sequence of
+/// block records, each prefixed with its length as a little-endian `u32`.
+pub struct Writer<W> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W) -> Result<Self, Error> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        Ok(Self { inner })
+    }
+
+    pub fn write(&mut self, record: &BlockRecord) -> Result<(), Error> {
+        let bytes = bincode::serialize(record)?;
+        if bytes.len() > MAX_RECORD_SIZE as usize {
+            return Err(Error::RecordTooLarge {
+                size: bytes.len() as u64,
+            });
+        }
+        self.inner.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads a block file written by [`Writer`]
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut inner: R) -> Result<Self, Error> {
+        let mut magic = [0; MAGIC.len()];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let mut version = [0; 4];
+        inner.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion { version });
+        }
+        Ok(Self { inner })
+    }
+
+    /// Read the next block record.
+    /// Returns `None` at the end of the file.
+    pub fn read(&mut self) -> Result<Option<BlockRecord>, Error> {
+        let mut size = [0; 4];
+        let mut n_read = 0;
+        // The file may only end between records
+        while n_read < size.len() {
+            match self.inner.read(&mut size[n_read..]) {
+                Ok(0) if n_read == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+                }
+                Ok(n) => n_read += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let size = u32::from_le_bytes(size);
+        if size > MAX_RECORD_SIZE {
+            return Err(Error::RecordTooLarge { size: size as u64 });
+        }
+        let mut bytes = vec![0; size as usize];
+        self.inner.read_exact(&mut bytes)?;
+        let record = bincode::deserialize(&bytes)?;
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use bitcoin::hashes::Hash as _;
+
+    use super::{BlockRecord, Error, Reader, Writer};
+    use crate::types::{Body, Header};
+
+    fn test_record(n: u8) -> BlockRecord {
+        let body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let header = Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash: None,
+            prev_main_hash: bitcoin::BlockHash::from_byte_array([n; 32]),
+            roots: Vec::new(),
+        };
+        BlockRecord {
+            header,
+            body,
+            bmm_inclusions: vec![bitcoin::BlockHash::from_byte_array(
+                [n + 1; 32],
+            )],
+        }
+    }
+
+    fn write_records(records: &[BlockRecord]) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        let mut writer = Writer::new(&mut bytes)?;
+        for record in records {
+            writer.write(record)?;
+        }
+        writer.finish()?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn block_file_round_trip() -> anyhow::Result<()> {
+        let records = [test_record(1), test_record(2)];
+        let bytes = write_records(&records)?;
+        let mut reader = Reader::new(bytes.as_slice())?;
+        for record in &records {
+            let read = reader
+                .read()?
+                .ok_or_else(|| anyhow::anyhow!("missing record"))?;
+            anyhow::ensure!(read.header.hash() == record.header.hash());
+            anyhow::ensure!(
+                read.body.compute_merkle_root()
+                    == record.body.compute_merkle_root()
+            );
+            anyhow::ensure!(read.bmm_inclusions == record.bmm_inclusions);
+        }
+        anyhow::ensure!(reader.read()?.is_none());
+        // An empty block file is valid
+        let bytes = write_records(&[])?;
+        anyhow::ensure!(Reader::new(bytes.as_slice())?.read()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_block_file() -> anyhow::Result<()> {
+        let bytes = write_records(&[test_record(1)])?;
+        // Truncated within the file header
+        anyhow::ensure!(matches!(
+            Reader::new(&bytes[..6]),
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        // Truncated within a length prefix, or within a record
+        let header_len = 12;
+        for len in [header_len + 2, bytes.len() - 1] {
+            let mut reader = Reader::new(&bytes[..len])?;
+            anyhow::ensure!(matches!(
+                reader.read(),
+                Err(Error::Io(err))
+                    if err.kind() == io::ErrorKind::UnexpectedEof
+            ));
+        }
+        let mut bad_magic = bytes;
+        bad_magic[0] ^= 1;
+        anyhow::ensure!(matches!(
+            Reader::new(bad_magic.as_slice()),
+            Err(Error::InvalidMagic)
+        ));
+        Ok(())
+    }
+}
@@ -17,20 +17,28 @@ use tonic::transport::Channel;
 use crate::{
     archive::{self, Archive},
     mempool::{self, MemPool},
+    migration,
     net::{self, Net, Peer},
     state::{self, State},
     types::{
         Accumulator, AmountOverflowError, AmountUnderflowError,
-        AuthorizedTransaction, BlockHash, BmmResult, Body, GetValue, Header,
-        Network, OutPoint, OutPointKey, Output, SpentOutput, Tip, Transaction,
+        AuthorizedTransaction, BlockHash, BlockInfo, BmmResult, Body, ChainTip,
+        DepositInfo, GetValue, Header, HeaderInfo, M6id, Network, OutPoint,
+        OutPointKey, Output, SnapshotHash, SpentOutput, Tip, Transaction,
         TransparentAddress, Txid, WithdrawalBundle,
+        WithdrawalBundleEventStatus, WithdrawalBundleHistory,
+        WithdrawalBundleInclusion, WithdrawalBundleStatus,
+        WithdrawalBundleStatusTransition,
         proto::{self, mainchain},
     },
     util::Watchable,
 };
 
+pub mod block_file;
 mod mainchain_task;
 mod net_task;
+pub mod treasury_audit;
+pub mod verify;
 
 use mainchain_task::MainchainTaskHandle;
 
@@ -47,6 +55,8 @@ pub enum Error {
     AmountUnderflow(#[from] AmountUnderflowError),
     #[error("archive error")]
     Archive(#[from] archive::Error),
+    #[error("block file error")]
+    BlockFile(#[from] block_file::Error),
     #[error("CUSF mainchain proto error")]
     CusfMainchain(#[from] proto::Error),
     #[error(transparent)]
@@ -55,12 +65,16 @@ pub enum Error {
     DbEnv(#[from] EnvError),
     #[error("Database write error")]
     DbWrite(#[from] RwTxnError),
+    #[error("failed to import block {block_hash}")]
+    ImportBlock { block_hash: BlockHash },
     #[error("I/O error")]
     Io(#[from] std::io::Error),
     #[error("error requesting mainchain ancestors")]
     MainchainAncestors(#[source] mainchain_task::ResponseError),
     #[error("mempool error")]
     MemPool(#[from] mempool::Error),
+    #[error("migration error")]
+    Migration(#[from] migration::Error),
     #[error("net error")]
     Net(#[from] Box<net::Error>),
     #[error("net task error")]
@@ -75,6 +89,8 @@ pub enum Error {
     SendMainchainTaskRequest,
     #[error("state error")]
     State(#[source] Box<state::Error>),
+    #[error("treasury audit error")]
+    TreasuryAudit(#[from] treasury_audit::Error),
     #[error("Utreexo error: {0}")]
     Utreexo(String),
     #[error("Verify BMM error")]
@@ -99,6 +115,65 @@ impl From<state::Error> for Error {
     }
 }
 
+/// Databases stored in the node env
+const SCHEMAS: [migration::Schema; 4] =
+    [State::SCHEMA, Archive::SCHEMA, MemPool::SCHEMA, Net::SCHEMA];
+
+/// Open the node env in the specified data directory
+fn open_env(datadir: &Path) -> Result<Env, Error> {
+    let env_path = datadir.join("data.mdb");
+    // let _ = std::fs::remove_dir_all(&env_path);
+    std::fs::create_dir_all(&env_path)?;
+    let env = {
+        let mut env_open_opts = heed::EnvOpenOptions::new();
+        env_open_opts
+            .map_size(128 * 1024 * 1024 * 1024) // 128 GB
+            .max_dbs(
+                State::NUM_DBS
+                    + Archive::NUM_DBS
+                    + MemPool::NUM_DBS
+                    + Net::NUM_DBS,
+            );
+        // Apply LMDB "fast" flags consistent with our benchmark setup:
+        // - WRITE_MAP lets us write directly into the memory map instead of
+        //   copying into LMDB's page buffer, reducing syscall overhead for
+        //   write-heavy workloads.
+        // - MAP_ASYNC hands dirty-page flushing to the kernel so commits do
+        //   not block waiting for msync, keeping latencies tight.
+        // - NO_SYNC and NO_META_SYNC skip fsync calls for data and
+        //   metadata; this trades durability for throughput, which is
+        //   acceptable here because the state can be reconstructed from the
+        //   canonical chain if a crash occurs.
+        // - NO_READ_AHEAD disables kernel readahead that would otherwise
+        //   touch cold pages we immediately overwrite, improving random
+        //   access behaviour on SSDs used in testing.
+        // - NO_TLS stops LMDB from relying on thread-local storage for
+        //   reader slots so transactions can be moved across Tokio tasks.
+        let fast_flags = EnvFlags::WRITE_MAP
+            | EnvFlags::MAP_ASYNC
+            | EnvFlags::NO_SYNC
+            | EnvFlags::NO_META_SYNC
+            | EnvFlags::NO_READ_AHEAD
+            | EnvFlags::NO_TLS;
+        unsafe { env_open_opts.flags(fast_flags) };
+        unsafe { Env::open(&env_open_opts, &env_path) }
+            .map_err(EnvError::from)?
+    };
+    Ok(env)
+}
+
+/// Report pending migrations for the node env in the specified data
+/// directory, without modifying it
+pub fn migration_dry_run(datadir: &Path) -> Result<migration::Report, Error> {
+    let env = open_env(datadir)?;
+    let report = migration::migrate(
+        &env,
+        &SCHEMAS,
+        migration::Config { dry_run: true },
+    )?;
+    Ok(report)
+}
+
 #[derive(Clone)]
 pub struct Node<MainchainTransport = Channel> {
     archive: Archive,
@@ -110,6 +185,9 @@ pub struct Node<MainchainTransport = Channel> {
     mempool: MemPool,
     net: Net,
     net_task: NetTaskHandle,
+    network: Network,
+    /// Removed when the last handle to the node is dropped
+    _running_marker: Arc<verify::RunningMarker>,
     state: State,
 }
 
@@ -120,11 +198,17 @@ where
     pub fn new(
         datadir: &Path,
         bind_addr: SocketAddr,
+        net_config: net::Config,
         cusf_mainchain: mainchain::ValidatorClient<MainchainTransport>,
         cusf_mainchain_wallet: Option<
             mainchain::WalletClient<MainchainTransport>,
         >,
+        compact_state: bool,
         network: Network,
+        prune: Option<archive::PruneConfig>,
+        reindex: bool,
+        snapshot: Option<state::snapshot::LoadConfig>,
+        verify_chain: verify::Config,
         runtime: &tokio::runtime::Runtime,
     ) -> Result<Self, Error>
     where
@@ -134,55 +218,64 @@ where
             tonic::body::Body,
         >>::Future: Send,
     {
-        let env_path = datadir.join("data.mdb");
-        // let _ = std::fs::remove_dir_all(&env_path);
-        std::fs::create_dir_all(&env_path)?;
-        let env = {
-            let mut env_open_opts = heed::EnvOpenOptions::new();
-            env_open_opts
-                .map_size(128 * 1024 * 1024 * 1024) // 128 GB
-                .max_dbs(
-                    State::NUM_DBS
-                        + Archive::NUM_DBS
-                        + MemPool::NUM_DBS
-                        + Net::NUM_DBS,
-                );
-            // Apply LMDB "fast" flags consistent with our benchmark setup:
-            // - WRITE_MAP lets us write directly into the memory map instead of
-            //   copying into LMDB's page buffer, reducing syscall overhead for
-            //   write-heavy workloads.
-            // - MAP_ASYNC hands dirty-page flushing to the kernel so commits do
-            //   not block waiting for msync, keeping latencies tight.
-            // - NO_SYNC and NO_META_SYNC skip fsync calls for data and
-            //   metadata; this trades durability for throughput, which is
-            //   acceptable here because the state can be reconstructed from the
-            //   canonical chain if a crash occurs.
-            // - NO_READ_AHEAD disables kernel readahead that would otherwise
-            //   touch cold pages we immediately overwrite, improving random
-            //   access behaviour on SSDs used in testing.
-            // - NO_TLS stops LMDB from relying on thread-local storage for
-            //   reader slots so transactions can be moved across Tokio tasks.
-            let fast_flags = EnvFlags::WRITE_MAP
-                | EnvFlags::MAP_ASYNC
-                | EnvFlags::NO_SYNC
-                | EnvFlags::NO_META_SYNC
-                | EnvFlags::NO_READ_AHEAD
-                | EnvFlags::NO_TLS;
-            unsafe { env_open_opts.flags(fast_flags) };
-            unsafe { Env::open(&env_open_opts, &env_path) }
-                .map_err(EnvError::from)?
-        };
-        let state = State::new(&env)?;
+        let env = open_env(datadir)?;
+        let (running_marker, unclean_shutdown) =
+            verify::RunningMarker::create(datadir)?;
+        let _: migration::Report =
+            migration::migrate(&env, &SCHEMAS, migration::Config::default())?;
+        let state = State::new(&env, compact_state, network)?;
         let archive = Archive::new(&env)?;
         let mempool = MemPool::new(&env)?;
+        if reindex {
+            let _: Option<BlockHash> =
+                net_task::reindex(&env, &archive, &mempool, &state)?;
+        }
+        let state_tip = {
+            let rotxn = env.read_txn().map_err(EnvError::from)?;
+            state.try_get_tip(&rotxn).map_err(state::Error::from)?
+        };
+        let pending_snapshot = match (snapshot, state_tip) {
+            (Some(snapshot), None) => {
+                let (snapshot, hash) =
+                    snapshot.read(network).map_err(state::Error::from)?;
+                tracing::info!(%hash, "loading state snapshot");
+                net_task::load_snapshot(&env, &archive, &state, snapshot)?
+            }
+            (Some(_), Some(tip)) => {
+                tracing::warn!(%tip, "state is not empty, ignoring snapshot");
+                None
+            }
+            (None, _) => None,
+        };
+        let () = verify::startup_check(
+            &env,
+            &archive,
+            &mempool,
+            &state,
+            verify_chain,
+            unclean_shutdown && !reindex,
+        )?;
+        if let Some(prune) = prune {
+            let () = net_task::prune(&env, &archive, &state, prune)?;
+        }
+        let identity =
+            net::NodeIdentity::load_or_generate(&datadir.join("node_key.pem"))?;
+        tracing::info!(public_key = %identity.public_key(), "Loaded node identity");
         let (mainchain_task, mainchain_task_response_rx) =
             MainchainTaskHandle::new(
                 env.clone(),
                 archive.clone(),
                 cusf_mainchain.clone(),
             );
-        let (net, peer_info_rx) =
-            Net::new(&env, archive.clone(), network, state.clone(), bind_addr)?;
+        let (net, peer_info_rx) = Net::new(
+            &env,
+            archive.clone(),
+            network,
+            state.clone(),
+            bind_addr,
+            &identity,
+            net_config,
+        )?;
 
         let net_task = NetTaskHandle::new(
             runtime,
@@ -193,6 +286,8 @@ where
             mempool.clone(),
             net.clone(),
             peer_info_rx,
+            pending_snapshot,
+            prune,
             state.clone(),
         );
         let cusf_mainchain_wallet =
@@ -206,6 +301,8 @@ where
             mempool,
             net,
             net_task,
+            network,
+            _running_marker: Arc::new(running_marker),
             state,
         })
     }
@@ -283,6 +380,145 @@ where
         Ok(res)
     }
 
+    /// Withdrawal bundles that include any of the specified outpoints, along
+    /// with their status history
+    pub fn get_withdrawal_bundle_inclusions(
+        &self,
+        outpoints: &HashSet<OutPoint>,
+    ) -> Result<HashMap<OutPoint, Vec<WithdrawalBundleInclusion>>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let res = self
+            .state
+            .get_withdrawal_bundle_inclusions(&rotxn, outpoints)?;
+        Ok(res)
+    }
+
+    /// Deposits that have been credited on the sidechain, in the order that
+    /// they were credited.
+    /// If `address` is set, only deposits to this address are listed.
+    /// If a mainchain height range is set, only deposits included in
+    /// mainchain blocks within the (inclusive) range are listed.
+    pub fn get_deposits(
+        &self,
+        address: Option<TransparentAddress>,
+        start_main_height: Option<u32>,
+        end_main_height: Option<u32>,
+    ) -> Result<Vec<DepositInfo>, Error> {
+        let main_height_range = start_main_height.unwrap_or(0)
+            ..=end_main_height.unwrap_or(u32::MAX);
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let deposit_blocks: Vec<_> = self
+            .state
+            .deposit_blocks
+            .iter(&rotxn)
+            .map_err(DbError::from)?
+            .collect()
+            .map_err(DbError::from)?;
+        let mut res = Vec::new();
+        // Deposits credited at each deposit block's height are those in
+        // mainchain blocks since the previous deposit block
+        let mut prev_deposit_block_hash = None;
+        for (_, (deposit_block_hash, height)) in deposit_blocks {
+            let mut main_blocks: Vec<_> = self
+                .archive
+                .main_ancestors(&rotxn, deposit_block_hash)
+                .take_while(|main_hash| {
+                    Ok(Some(*main_hash) != prev_deposit_block_hash)
+                })
+                .collect()?;
+            prev_deposit_block_hash = Some(deposit_block_hash);
+            main_blocks.reverse();
+            for main_block_hash in main_blocks {
+                let main_height =
+                    self.archive.get_main_height(&rotxn, main_block_hash)?;
+                if !main_height_range.contains(&main_height) {
+                    continue;
+                }
+                let block_info = self
+                    .archive
+                    .get_main_block_info(&rotxn, &main_block_hash)?;
+                for event in block_info.events {
+                    let mainchain::BlockEvent::Deposit(deposit) = event else {
+                        continue;
+                    };
+                    if address.is_some_and(|address| {
+                        address != deposit.output.address
+                    }) {
+                        continue;
+                    }
+                    let spent_by = self
+                        .state
+                        .stxos
+                        .try_get(
+                            &rotxn,
+                            &OutPointKey::from(OutPoint::Deposit(
+                                deposit.outpoint,
+                            )),
+                        )
+                        .map_err(DbError::from)?
+                        .map(|spent_output| spent_output.inpoint);
+                    res.push(DepositInfo {
+                        main_outpoint: deposit.outpoint,
+                        value: deposit.output.get_value(),
+                        address: deposit.output.address,
+                        main_block_hash,
+                        main_height,
+                        height,
+                        spent_by,
+                    });
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Shielded deposits that minted notes in the two-way peg data of the
+    /// specified block, in the order that they were applied
+    pub fn get_shielded_deposits(
+        &self,
+        rotxn: &RoTxn,
+        header: &Header,
+    ) -> Result<Vec<mainchain::ShieldedDeposit>, Error> {
+        let prev_main_hash = match header.prev_side_hash {
+            Some(prev_side_hash) => Some(
+                self.archive
+                    .get_header(rotxn, prev_side_hash)?
+                    .prev_main_hash,
+            ),
+            None => None,
+        };
+        let mut main_blocks: Vec<_> = self
+            .archive
+            .main_ancestors(rotxn, header.prev_main_hash)
+            .take_while(|main_hash| Ok(Some(*main_hash) != prev_main_hash))
+            .collect()?;
+        main_blocks.reverse();
+        let height = self.archive.get_height(rotxn, header.hash())?;
+        let mut res = Vec::new();
+        for main_block_hash in main_blocks {
+            let block_info =
+                self.archive.get_main_block_info(rotxn, &main_block_hash)?;
+            res.extend(
+                block_info
+                    .shielded_deposits()
+                    .filter(|deposit| {
+                        self.state.mints_shielded_deposit(height, deposit)
+                    })
+                    .cloned(),
+            );
+        }
+        Ok(res)
+    }
+
+    /// Get the mainchain outpoints of all credited deposits
+    pub fn get_deposit_outpoints(
+        &self,
+    ) -> Result<HashSet<bitcoin::OutPoint>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let res = self.state.get_deposit_outpoints(&rotxn)?;
+        Ok(res)
+    }
+
     pub fn get_spent_utxos(
         &self,
         rotxn: &RoTxn,
@@ -392,6 +628,97 @@ where
         }
     }
 
+    /// Get block hashes in the current chain, oldest-to-newest, starting at
+    /// the specified height.
+    /// At most `count` block hashes are returned.
+    fn get_block_hashes(
+        &self,
+        rotxn: &RoTxn,
+        start_height: u32,
+        count: u32,
+    ) -> Result<Vec<BlockHash>, Error> {
+        let Some(tip) =
+            self.state.try_get_tip(rotxn).map_err(state::Error::from)?
+        else {
+            return Ok(Vec::new());
+        };
+        let block_hashes =
+            self.archive
+                .get_block_hashes(rotxn, tip, start_height, count)?;
+        Ok(block_hashes)
+    }
+
+    fn header_info(
+        &self,
+        rotxn: &RoTxn,
+        header: Header,
+        body: Option<&Body>,
+    ) -> Result<HeaderInfo, Error> {
+        let block_hash = header.hash();
+        let height = self.archive.get_height(rotxn, block_hash)?;
+        let bmm_inclusions = self.bmm_inclusions(rotxn, block_hash)?;
+        Ok(HeaderInfo {
+            block_hash,
+            height,
+            header,
+            bmm_inclusions,
+            n_transactions: body.map(|body| body.transactions.len() as u32),
+        })
+    }
+
+    /// Get the header with the specified block hash, along with its height,
+    /// BMM inclusions and transaction count, if it exists
+    pub fn try_get_header_info(
+        &self,
+        block_hash: BlockHash,
+    ) -> Result<Option<HeaderInfo>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let Some(header) = self.archive.try_get_header(&rotxn, block_hash)?
+        else {
+            return Ok(None);
+        };
+        let body = self.archive.try_get_body(&rotxn, block_hash)?;
+        self.header_info(&rotxn, header, body.as_ref()).map(Some)
+    }
+
+    /// Get up to `count` headers in the current chain, starting at the
+    /// specified height
+    pub fn get_header_infos(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> Result<Vec<HeaderInfo>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        self.get_block_hashes(&rotxn, start_height, count)?
+            .into_iter()
+            .map(|block_hash| {
+                let header = self.archive.get_header(&rotxn, block_hash)?;
+                let body = self.archive.try_get_body(&rotxn, block_hash)?;
+                self.header_info(&rotxn, header, body.as_ref())
+            })
+            .collect()
+    }
+
+    /// Get up to `count` blocks in the current chain, starting at the
+    /// specified height
+    pub fn get_block_infos(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> Result<Vec<BlockInfo>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        self.get_block_hashes(&rotxn, start_height, count)?
+            .into_iter()
+            .map(|block_hash| {
+                let header = self.archive.get_header(&rotxn, block_hash)?;
+                let body = self.archive.get_body(&rotxn, block_hash)?;
+                let header_info =
+                    self.header_info(&rotxn, header, Some(&body))?;
+                Ok(BlockInfo { header_info, body })
+            })
+            .collect()
+    }
+
     pub fn try_get_body(
         &self,
         block_hash: BlockHash,
@@ -414,14 +741,14 @@ where
         Ok(hash)
     }
 
-    pub fn get_bmm_inclusions(
+    fn bmm_inclusions(
         &self,
+        rotxn: &RoTxn,
         block_hash: BlockHash,
     ) -> Result<Vec<bitcoin::BlockHash>, Error> {
-        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
         let bmm_inclusions = self
             .archive
-            .get_bmm_results(&rotxn, block_hash)?
+            .get_bmm_results(rotxn, block_hash)?
             .into_iter()
             .filter_map(|(block_hash, bmm_res)| match bmm_res {
                 BmmResult::Verified => Some(block_hash),
@@ -431,6 +758,24 @@ where
         Ok(bmm_inclusions)
     }
 
+    pub fn get_bmm_inclusions(
+        &self,
+        block_hash: BlockHash,
+    ) -> Result<Vec<bitcoin::BlockHash>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        self.bmm_inclusions(&rotxn, block_hash)
+    }
+
+    /// Get all known chain tips, including the tip of the active chain,
+    /// ordered by height descending
+    pub fn get_chain_tips(&self) -> Result<Vec<ChainTip>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let active_tip =
+            self.state.try_get_tip(&rotxn).map_err(state::Error::from)?;
+        let chain_tips = self.archive.chain_tips(&rotxn, active_tip)?;
+        Ok(chain_tips)
+    }
+
     pub fn get_all_transactions(
         &self,
     ) -> Result<Vec<AuthorizedTransaction>, Error> {
@@ -445,6 +790,12 @@ where
         Ok(self.state.sidechain_wealth(&rotxn)?)
     }
 
+    /// Get total value of notes in the shielded pool
+    pub fn get_shielded_pool_value(&self) -> Result<bitcoin::Amount, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        Ok(self.state.get_shielded_pool_value(&rotxn)?)
+    }
+
     pub fn get_transactions(
         &self,
         number: usize,
@@ -454,6 +805,8 @@ where
         let mut fee = bitcoin::Amount::ZERO;
         let mut returned_transactions = vec![];
         let mut spent_utxos = HashSet::new();
+        let mut shielded_pool_value =
+            self.state.get_shielded_pool_value(&txn)?;
         for transaction in &transactions {
             let inputs: HashSet<_> =
                 transaction.transaction.inputs.iter().copied().collect();
@@ -489,10 +842,21 @@ where
             {
                 let value_balance = orchard_bundle.value_balance();
                 if value_balance.is_positive() {
+                    // Skip transactions that would remove more value from
+                    // the shielded pool than it holds
+                    let Some(pool_value) = shielded_pool_value
+                        .checked_sub(value_balance.unsigned_abs())
+                    else {
+                        continue;
+                    };
+                    shielded_pool_value = pool_value;
                     value_in = value_in
                         .checked_add(value_balance.unsigned_abs())
                         .ok_or(AmountOverflowError)?;
                 } else {
+                    shielded_pool_value = shielded_pool_value
+                        .checked_add(value_balance.unsigned_abs())
+                        .ok_or(AmountOverflowError)?;
                     value_out = value_out
                         .checked_add(value_balance.unsigned_abs())
                         .ok_or(AmountOverflowError)?;
@@ -531,8 +895,8 @@ where
                 .find(|(block_hash, _idx)| {
                     self.archive.is_descendant(&rotxn, tip, *block_hash)
                 })?
+            && let Some(body) = self.archive.try_get_body(&rotxn, block_hash)?
         {
-            let body = self.archive.get_body(&rotxn, block_hash)?;
             let tx = body.transactions.into_iter().nth(txin as usize).unwrap();
             Ok(Some((tx, Some(block_hash))))
         } else if let Some(auth_tx) = self
@@ -547,6 +911,133 @@ where
         }
     }
 
+    /// Find the mainchain block containing the withdrawal bundle event that
+    /// caused a status transition, by searching the mainchain blocks whose
+    /// two-way peg data was applied with the sidechain block at the
+    /// transition height
+    fn withdrawal_bundle_event_main_block(
+        &self,
+        rotxn: &RoTxn,
+        m6id: M6id,
+        transition: &WithdrawalBundleStatusTransition,
+    ) -> Result<Option<bitcoin::BlockHash>, Error> {
+        let event_status = match transition.status {
+            WithdrawalBundleStatus::Confirmed => {
+                WithdrawalBundleEventStatus::Confirmed
+            }
+            WithdrawalBundleStatus::Failed => {
+                WithdrawalBundleEventStatus::Failed
+            }
+            WithdrawalBundleStatus::Submitted
+            | WithdrawalBundleStatus::SubmittedUnexpected => {
+                WithdrawalBundleEventStatus::Submitted
+            }
+            WithdrawalBundleStatus::Dropped
+            | WithdrawalBundleStatus::Pending => return Ok(None),
+        };
+        let Some(block_hash) = self
+            .get_block_hashes(rotxn, transition.height, 1)?
+            .first()
+            .copied()
+        else {
+            return Ok(None);
+        };
+        let header = self.archive.get_header(rotxn, block_hash)?;
+        let prev_main_hash = match header.prev_side_hash {
+            Some(prev_side_hash) => Some(
+                self.archive
+                    .get_header(rotxn, prev_side_hash)?
+                    .prev_main_hash,
+            ),
+            None => None,
+        };
+        let main_block_hash = self
+            .archive
+            .main_ancestors(rotxn, header.prev_main_hash)
+            .take_while(|main_hash| Ok(Some(*main_hash) != prev_main_hash))
+            .find(|main_hash| {
+                let block_info =
+                    self.archive.get_main_block_info(rotxn, main_hash)?;
+                let found = block_info.events.iter().any(|event| {
+                    matches!(
+                        event,
+                        mainchain::BlockEvent::WithdrawalBundle(event)
+                            if event.m6id == m6id
+                                && event.status == event_status
+                    )
+                });
+                Ok(found)
+            })?;
+        Ok(main_block_hash)
+    }
+
+    fn fill_withdrawal_bundle_event_blocks(
+        &self,
+        rotxn: &RoTxn,
+        history: &mut WithdrawalBundleHistory,
+    ) -> Result<(), Error> {
+        for transition in &mut history.status_history {
+            transition.main_block_hash = self
+                .withdrawal_bundle_event_main_block(
+                    rotxn,
+                    history.m6id,
+                    transition,
+                )?;
+        }
+        Ok(())
+    }
+
+    /// Get a withdrawal bundle and its status history, including the
+    /// mainchain blocks that caused each status transition
+    pub fn try_get_withdrawal_bundle(
+        &self,
+        m6id: M6id,
+    ) -> Result<Option<WithdrawalBundleHistory>, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let Some(mut history) =
+            self.state.try_get_withdrawal_bundle_history(&rotxn, m6id)?
+        else {
+            return Ok(None);
+        };
+        let () =
+            self.fill_withdrawal_bundle_event_blocks(&rotxn, &mut history)?;
+        Ok(Some(history))
+    }
+
+    /// List withdrawal bundles and their status history, ordered by the
+    /// height at which they were first seen.
+    /// If `status` is set, only bundles with this latest status are listed.
+    /// If a height range is set, only bundles with a status transition
+    /// within the (inclusive) range are listed.
+    pub fn get_withdrawal_bundles(
+        &self,
+        status: Option<WithdrawalBundleStatus>,
+        start_height: Option<u32>,
+        end_height: Option<u32>,
+    ) -> Result<Vec<WithdrawalBundleHistory>, Error> {
+        let height_range =
+            start_height.unwrap_or(0)..=end_height.unwrap_or(u32::MAX);
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let mut histories =
+            self.state.get_withdrawal_bundle_histories(&rotxn)?;
+        histories.retain(|history| {
+            status.is_none_or(|status| {
+                history
+                    .status_history
+                    .last()
+                    .is_some_and(|transition| transition.status == status)
+            }) && history
+                .status_history
+                .iter()
+                .any(|transition| height_range.contains(&transition.height))
+        });
+        for history in &mut histories {
+            let () =
+                self.fill_withdrawal_bundle_event_blocks(&rotxn, history)?;
+        }
+        Ok(histories)
+    }
+
     pub fn try_get_pending_withdrawal_bundle(
         &self,
     ) -> Result<Option<WithdrawalBundle>, Error> {
@@ -582,6 +1073,11 @@ where
         self.net.get_active_peers()
     }
 
+    /// Public key identifying this node to peers
+    pub fn public_key(&self) -> net::NodePublicKey {
+        self.net.public_key()
+    }
+
     pub async fn request_mainchain_ancestor_infos(
         &self,
         block_hash: bitcoin::BlockHash,
@@ -601,16 +1097,15 @@ where
     /// Returns `Ok(true)` if the block was accepted successfully as the new tip.
     /// Returns `Ok(false)` if the block could not be submitted for some reason,
     /// or was rejected as the new tip.
-    pub async fn submit_block(
+    /// Store a block that is BMM-verified by the specified mainchain block,
+    /// and attempt to re-org to it.
+    /// Returns `false` if the block was rejected.
+    async fn accept_block(
         &self,
         main_block_hash: bitcoin::BlockHash,
         header: &Header,
         body: &Body,
     ) -> Result<bool, Error> {
-        let Some(cusf_mainchain_wallet) = self.cusf_mainchain_wallet.as_ref()
-        else {
-            return Err(Error::NoCusfMainchainWalletClient);
-        };
         let block_hash = header.hash();
         // Store the header, if ancestors exist
         if let Some(parent) = header.prev_side_hash
@@ -695,6 +1190,22 @@ where
             tracing::warn!(%block_hash, "Not ready to reorg");
             return Ok(false);
         };
+        Ok(true)
+    }
+
+    pub async fn submit_block(
+        &self,
+        main_block_hash: bitcoin::BlockHash,
+        header: &Header,
+        body: &Body,
+    ) -> Result<bool, Error> {
+        let Some(cusf_mainchain_wallet) = self.cusf_mainchain_wallet.as_ref()
+        else {
+            return Err(Error::NoCusfMainchainWalletClient);
+        };
+        if !self.accept_block(main_block_hash, header, body).await? {
+            return Ok(false);
+        }
         let rotxn = self.env.read_txn().map_err(EnvError::from)?;
         let bundle = self.state.try_get_pending_withdrawal_bundle(&rotxn)?;
         if let Some((bundle, _)) = bundle {
@@ -710,6 +1221,177 @@ where
         Ok(true)
     }
 
+    /// Write blocks in the current chain, from height `from` to height `to`
+    /// inclusive, to a block file.
+    /// Returns the number of blocks written.
+    pub fn export_blocks(
+        &self,
+        path: &Path,
+        from: u32,
+        to: u32,
+    ) -> Result<u32, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        let count = if to >= from {
+            (to - from).saturating_add(1)
+        } else {
+            0
+        };
+        let block_hashes = self.get_block_hashes(&rotxn, from, count)?;
+        let file = std::fs::File::create(path)?;
+        let mut writer =
+            block_file::Writer::new(std::io::BufWriter::new(file))?;
+        for block_hash in &block_hashes {
+            let record = block_file::BlockRecord {
+                header: self.archive.get_header(&rotxn, *block_hash)?,
+                body: self.archive.get_body(&rotxn, *block_hash)?,
+                bmm_inclusions: self.bmm_inclusions(&rotxn, *block_hash)?,
+            };
+            let () = writer.write(&record)?;
+        }
+        let () = writer.finish()?;
+        let n_blocks = block_hashes.len() as u32;
+        tracing::info!(path = %path.display(), %n_blocks, "exported blocks");
+        Ok(n_blocks)
+    }
+
+    /// Read blocks from a block file, and validate and connect them as if
+    /// they were received from a peer. Blocks MUST be ordered
+    /// oldest-to-newest. Blocks that are already in the current chain are
+    /// skipped.
+    /// Returns the number of blocks imported.
+    pub async fn import_blocks(&self, path: &Path) -> Result<u32, Error> {
+        let file = std::fs::File::open(path)?;
+        let mut reader =
+            block_file::Reader::new(std::io::BufReader::new(file))?;
+        let mut n_blocks = 0;
+        while let Some(record) = reader.read()? {
+            let block_hash = record.header.hash();
+            {
+                let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+                if let Some(tip) = self
+                    .state
+                    .try_get_tip(&rotxn)
+                    .map_err(state::Error::from)?
+                    && self
+                        .archive
+                        .try_get_header(&rotxn, block_hash)?
+                        .is_some()
+                    && self.archive.is_descendant(&rotxn, block_hash, tip)?
+                {
+                    continue;
+                }
+            }
+            let mut accepted = false;
+            for main_block_hash in &record.bmm_inclusions {
+                if self
+                    .accept_block(
+                        *main_block_hash,
+                        &record.header,
+                        &record.body,
+                    )
+                    .await?
+                {
+                    accepted = true;
+                    break;
+                }
+            }
+            if !accepted {
+                return Err(Error::ImportBlock { block_hash });
+            }
+            n_blocks += 1;
+        }
+        tracing::info!(path = %path.display(), %n_blocks, "imported blocks");
+        Ok(n_blocks)
+    }
+
+    /// Wipe the state and mempool, and re-apply the current chain from the
+    /// archive. Returns the re-applied tip.
+    pub async fn reindex(&self) -> Result<Option<BlockHash>, Error> {
+        Ok(self.net_task.reindex().await?)
+    }
+
+    /// Mark a block and its descendants as invalid, disconnecting them if
+    /// they are in the current chain. The block is treated as invalid until
+    /// it is reconsidered, including across restarts.
+    /// Returns the new tip.
+    pub async fn invalidate_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> Result<Option<BlockHash>, Error> {
+        Ok(self.net_task.invalidate_block(block_hash).await?)
+    }
+
+    /// Remove invalidity from a block, and from its ancestors and
+    /// descendants, and re-org to the best valid chain.
+    /// Returns the new tip.
+    pub async fn reconsider_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> Result<Option<BlockHash>, Error> {
+        Ok(self.net_task.reconsider_block(block_hash).await?)
+    }
+
+    /// Verify the last `depth` blocks of the current chain against the
+    /// archive, and check UTXO set invariants
+    pub fn verify_chain(&self, depth: u32) -> Result<verify::Report, Error> {
+        let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+        verify::verify_chain(&rotxn, &self.archive, &self.state, depth)
+    }
+
+    /// Reconcile the sidechain treasury UTXO on the mainchain with the
+    /// deposits and withdrawals recorded in the sidechain state
+    pub async fn audit_treasury(
+        &self,
+    ) -> Result<treasury_audit::Report, Error> {
+        treasury_audit::audit_treasury(
+            &self.env,
+            &self.archive,
+            &self.state,
+            &self.cusf_mainchain,
+        )
+        .await
+    }
+
+    /// Write a snapshot of the state at the current tip to a file.
+    /// Returns the snapshot hash.
+    pub fn dump_state_snapshot(
+        &self,
+        path: &Path,
+    ) -> Result<SnapshotHash, Error> {
+        let snapshot = {
+            let rotxn = self.env.read_txn().map_err(EnvError::from)?;
+            self.state.snapshot(&rotxn)?
+        };
+        let file = std::fs::File::create(path)?;
+        let hash = snapshot
+            .write(std::io::BufWriter::new(file))
+            .map_err(state::Error::from)?;
+        tracing::info!(%hash, tip = %snapshot.tip(), "dumped state snapshot");
+        Ok(hash)
+    }
+
+    /// Start from a state snapshot. The state MUST be empty.
+    /// If the snapshot tip header has not been synced yet, the snapshot is
+    /// applied once it is available.
+    /// Returns the snapshot hash.
+    pub fn load_state_snapshot(
+        &self,
+        snapshot: &state::snapshot::LoadConfig,
+    ) -> Result<SnapshotHash, Error> {
+        let (snapshot, hash) =
+            snapshot.read(self.network).map_err(state::Error::from)?;
+        tracing::info!(%hash, "loading state snapshot");
+        if let Some(snapshot) = net_task::load_snapshot(
+            &self.env,
+            &self.archive,
+            &self.state,
+            snapshot,
+        )? {
+            self.net_task.set_pending_snapshot(snapshot);
+        }
+        Ok(hash)
+    }
+
     /// Get a notification whenever the tip changes
     pub fn watch_state(&self) -> impl Stream<Item = ()> {
         self.state.watch()
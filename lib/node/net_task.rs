@@ -26,7 +26,7 @@ use tokio_stream::StreamNotifyClose;
 
 use super::mainchain_task::{self, MainchainTaskHandle};
 use crate::{
-    archive::{self, Archive},
+    archive::{self, Archive, PruneConfig, PruneTarget},
     mempool::{self, MemPool},
     net::{
         self, Net, PeerConnectionError, PeerConnectionInfo,
@@ -35,7 +35,7 @@ use crate::{
     },
     state::{self, State},
     types::{
-        BmmResult, Body, Header, Tip,
+        AuthorizedTransaction, BlockHash, BmmResult, Body, Header, Tip,
         proto::{self, mainchain},
     },
     util::join_set,
@@ -58,22 +58,55 @@ pub enum Error {
     DbWrite(#[from] RwTxnError),
     #[error("Forward mainchain task request failed")]
     ForwardMainchainTaskRequest,
+    #[error(
+        "cannot invalidate block {block_hash}, since it is in the current \
+         chain and its body has been pruned"
+    )]
+    InvalidateBlockPruned { block_hash: BlockHash },
     #[error("mempool error")]
     MemPool(#[from] mempool::Error),
+    #[error("missing spent outputs for block {block_hash}")]
+    MissingSpentOutputs { block_hash: BlockHash },
     #[error("Net error")]
     Net(#[from] Box<net::Error>),
     #[error("peer info stream closed")]
     PeerInfoRxClosed,
     #[error("Receive mainchain task response cancelled")]
     ReceiveMainchainTaskResponse,
+    #[error("Receive block validity result cancelled (oneshot)")]
+    ReceiveBlockValidityResultOneshot(#[source] oneshot::Canceled),
+    #[error("Receive reindex result cancelled (oneshot)")]
+    ReceiveReindexResultOneshot(#[source] oneshot::Canceled),
+    #[error(
+        "cannot reindex, block bodies below height {prune_height} have been \
+         pruned"
+    )]
+    ReindexPruned { prune_height: u32 },
     #[error("Receive reorg result cancelled (oneshot)")]
     ReceiveReorgResultOneshot(#[source] oneshot::Canceled),
+    #[error("Send block validity request failed")]
+    SendBlockValidity,
+    #[error("Send block validity result error (oneshot)")]
+    SendBlockValidityResultOneshot,
     #[error("Send mainchain task request failed")]
     SendMainchainTaskRequest,
     #[error("Send new tip ready failed")]
     SendNewTipReady(#[source] TrySendError<NewTipReadyMessage>),
+    #[error("Send reindex request failed")]
+    SendReindex,
+    #[error("Send reindex result error (oneshot)")]
+    SendReindexResultOneshot,
     #[error("Send reorg result error (oneshot)")]
     SendReorgResultOneshot,
+    #[error(
+        "snapshot tip {block_hash} is at height {archive_height} in the \
+         archive, but the snapshot height is {snapshot_height}"
+    )]
+    SnapshotHeight {
+        block_hash: BlockHash,
+        archive_height: u32,
+        snapshot_height: u32,
+    },
     #[error("state error")]
     State(#[from] Box<state::Error>),
 }
@@ -100,6 +133,15 @@ fn connect_tip_(
     two_way_peg_data: &mainchain::TwoWayPegData,
 ) -> Result<(), Error> {
     let block_hash = header.hash();
+    // Compact states validate transactions using the spent outputs that
+    // were relayed with the block
+    if state.is_compact() {
+        let spent_outputs = archive
+            .try_get_spent_outputs(rwtxn, block_hash)?
+            .ok_or(Error::MissingSpentOutputs { block_hash })?;
+        let () =
+            state.put_relayed_spent_outputs(rwtxn, body, &spent_outputs)?;
+    }
     // Use optimised prevalidation + connect path to avoid recomputation
     let orchard_frontier = if tracing::enabled!(tracing::Level::DEBUG) {
         let merkle_root = body.compute_merkle_root();
@@ -115,6 +157,9 @@ fn connect_tip_(
             .apply_block(rwtxn, header, body)
             .map_err(state::Error::from)?
     };
+    if state.is_compact() {
+        let () = state.clear_relayed_spent_outputs(rwtxn)?;
+    }
     if let Some(orchard_frontier) = orchard_frontier {
         let () = archive.put_orchard_frontier(
             rwtxn,
@@ -122,8 +167,16 @@ fn connect_tip_(
             &orchard_frontier,
         )?;
     }
-    let accumulator =
+    let (accumulator, orchard_frontier) =
         state.connect_two_way_peg_data(rwtxn, two_way_peg_data)?;
+    // Shielded deposits append to the frontier after the block body
+    if let Some(orchard_frontier) = orchard_frontier {
+        let () = archive.put_orchard_frontier(
+            rwtxn,
+            block_hash,
+            &orchard_frontier,
+        )?;
+    }
     let () = archive.put_header(rwtxn, header)?;
     let () = archive.put_body(rwtxn, block_hash, body)?;
     let () = archive.put_accumulator(rwtxn, block_hash, &accumulator)?;
@@ -277,6 +330,10 @@ fn reorg_to_tip(
             })
         })
         .transpose()?;
+    if archive.is_invalid(&rwtxn, new_tip.block_hash)? {
+        tracing::debug!(?new_tip, "Not re-orging to invalidated tip");
+        return Ok(false);
+    }
     if let Some(tip) = tip {
         // check that new tip is better than current tip
         if archive.better_tip(&rwtxn, tip, new_tip)? != Some(new_tip) {
@@ -297,6 +354,26 @@ fn reorg_to_tip(
     } else {
         None
     };
+    // Blocks below the prune height cannot be disconnected
+    if let Some(prune_height) = archive.try_get_prune_height(&rwtxn)? {
+        let common_ancestor_height = match common_ancestor {
+            Some(common_ancestor) => {
+                Some(archive.get_height(&rwtxn, common_ancestor)?)
+            }
+            None => None,
+        };
+        if common_ancestor_height.is_none_or(|height| height + 1 < prune_height)
+        {
+            tracing::warn!(
+                ?tip,
+                ?new_tip,
+                ?common_ancestor,
+                %prune_height,
+                "Not re-orging to new tip; re-org is deeper than pruned history"
+            );
+            return Ok(false);
+        }
+    }
     // Check that all necessary bodies exist before disconnecting tip
     let blocks_to_apply: NonEmpty<(Header, Body)> = {
         let header = archive.get_header(&rwtxn, new_tip.block_hash)?;
@@ -422,6 +499,323 @@ fn reorg_to_tip(
     Ok(true)
 }
 
+/// Number of blocks to apply in each write transaction when reindexing
+const REINDEX_COMMIT_INTERVAL: usize = 1000;
+
+/// Wipe the state and mempool, and re-apply the current chain from the
+/// archive, from genesis to the current tip.
+/// Blocks are applied in batches, so an interrupted reindex leaves the state
+/// at an intermediate tip, from which the node can continue to sync.
+/// Returns the tip that was re-applied, if any.
+pub(super) fn reindex(
+    env: &sneed::Env,
+    archive: &Archive,
+    mempool: &MemPool,
+    state: &State,
+) -> Result<Option<BlockHash>, Error> {
+    let (tip, block_hashes, mut main_block_hashes) = 'chain: {
+        let rotxn = env.read_txn().map_err(EnvError::from)?;
+        if let Some(prune_height) = archive.try_get_prune_height(&rotxn)? {
+            return Err(Error::ReindexPruned { prune_height });
+        }
+        let Some(tip) =
+            state.try_get_tip(&rotxn).map_err(state::Error::from)?
+        else {
+            break 'chain (None, Vec::new(), Vec::new());
+        };
+        let tip_header = archive.get_header(&rotxn, tip)?;
+        // Sidechain blocks to apply, from tip to genesis
+        let block_hashes: Vec<BlockHash> =
+            archive.ancestors(&rotxn, tip).collect()?;
+        // Mainchain blocks that may contain two way peg data, from the tip's
+        // mainchain block to the earliest known mainchain block
+        let main_block_hashes: Vec<bitcoin::BlockHash> = archive
+            .main_ancestors(&rotxn, tip_header.prev_main_hash)
+            .collect()?;
+        (Some(tip), block_hashes, main_block_hashes)
+    };
+    let n_blocks = block_hashes.len();
+    tracing::info!(?tip, "reindex: re-applying {n_blocks} blocks");
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    let () = state.reset(&mut rwtxn)?;
+    let () = mempool.clear(&mut rwtxn)?;
+    for (idx, block_hash) in block_hashes.into_iter().rev().enumerate() {
+        let header = archive.get_header(&rwtxn, block_hash)?;
+        let body = archive.get_body(&rwtxn, block_hash)?;
+        let two_way_peg_data = {
+            let mut two_way_peg_data = mainchain::TwoWayPegData::default();
+            'fill_2wpd: while let Some(main_block_hash) =
+                main_block_hashes.pop()
+            {
+                let block_info =
+                    archive.get_main_block_info(&rwtxn, &main_block_hash)?;
+                two_way_peg_data
+                    .block_info
+                    .replace(main_block_hash, block_info);
+                if main_block_hash == header.prev_main_hash {
+                    break 'fill_2wpd;
+                }
+            }
+            two_way_peg_data
+        };
+        let () = connect_tip_(
+            &mut rwtxn,
+            archive,
+            mempool,
+            state,
+            &header,
+            &body,
+            &two_way_peg_data,
+        )?;
+        let applied = idx + 1;
+        if applied % REINDEX_COMMIT_INTERVAL == 0 {
+            rwtxn.commit().map_err(RwTxnError::from)?;
+            tracing::info!(
+                "reindex: applied {applied}/{n_blocks} blocks ({:.1}%)",
+                100. * applied as f64 / n_blocks as f64
+            );
+            rwtxn = env.write_txn().map_err(EnvError::from)?;
+        }
+    }
+    rwtxn.commit().map_err(RwTxnError::from)?;
+    tracing::info!(?tip, "reindex: complete, applied {n_blocks} blocks");
+    Ok(tip)
+}
+
+/// Disconnect the specified number of blocks from the current tip, and then
+/// re-apply them from the archive.
+/// Blocks are disconnected in a single write transaction, so an interrupted
+/// rollback leaves the state at the earlier tip, from which the node can
+/// continue to sync.
+pub(super) fn rollback(
+    env: &sneed::Env,
+    archive: &Archive,
+    mempool: &MemPool,
+    state: &State,
+    disconnects: u32,
+) -> Result<(), Error> {
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    let Some(tip_hash) =
+        state.try_get_tip(&rwtxn).map_err(state::Error::from)?
+    else {
+        return Ok(());
+    };
+    let tip = Tip {
+        block_hash: tip_hash,
+        main_block_hash: archive
+            .get_best_main_verification(&rwtxn, tip_hash)?,
+    };
+    tracing::info!(?tip, "rollback: disconnecting {disconnects} blocks");
+    for _ in 0..disconnects {
+        if state
+            .try_get_tip(&rwtxn)
+            .map_err(state::Error::from)?
+            .is_none()
+        {
+            break;
+        }
+        let () = disconnect_tip_(&mut rwtxn, archive, mempool, state)?;
+    }
+    rwtxn.commit().map_err(RwTxnError::from)?;
+    if !reorg_to_tip(env, archive, mempool, state, tip)? {
+        tracing::warn!(?tip, "rollback: failed to re-apply blocks");
+    }
+    Ok(())
+}
+
+/// Re-org to the best known valid tip that descends from the specified
+/// block, if it is better than the current tip.
+/// If the specified block is `None`, all known blocks are searched.
+fn reorg_to_best_valid_descendant(
+    env: &sneed::Env,
+    archive: &Archive,
+    mempool: &MemPool,
+    state: &State,
+    block_hash: Option<BlockHash>,
+) -> Result<(), Error> {
+    let best_tip = {
+        let rotxn = env.read_txn().map_err(EnvError::from)?;
+        archive.best_valid_descendant_tip(&rotxn, block_hash)?
+    };
+    if let Some(best_tip) = best_tip {
+        let _: bool = reorg_to_tip(env, archive, mempool, state, best_tip)?;
+    }
+    Ok(())
+}
+
+/// Mark a block and its descendants as invalid. If the block is in the
+/// current chain, the tip is disconnected back to the block's parent, and
+/// then the node re-orgs to the best valid tip descending from the parent.
+/// Returns the new tip.
+pub(super) fn invalidate_block(
+    env: &sneed::Env,
+    archive: &Archive,
+    mempool: &MemPool,
+    state: &State,
+    block_hash: BlockHash,
+) -> Result<Option<BlockHash>, Error> {
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    let header = archive.get_header(&rwtxn, block_hash)?;
+    let in_chain =
+        match state.try_get_tip(&rwtxn).map_err(state::Error::from)? {
+            Some(tip) => archive.is_descendant(&rwtxn, block_hash, tip)?,
+            None => false,
+        };
+    // Blocks below the prune height cannot be disconnected
+    if in_chain && archive.is_pruned(&rwtxn, block_hash)? {
+        return Err(Error::InvalidateBlockPruned { block_hash });
+    }
+    let () = archive.invalidate_block(&mut rwtxn, block_hash)?;
+    if in_chain {
+        while state.try_get_tip(&rwtxn).map_err(state::Error::from)?
+            != header.prev_side_hash
+        {
+            let () = disconnect_tip_(&mut rwtxn, archive, mempool, state)?;
+        }
+    }
+    rwtxn.commit().map_err(RwTxnError::from)?;
+    tracing::info!(%block_hash, "invalidated block");
+    let () = reorg_to_best_valid_descendant(
+        env,
+        archive,
+        mempool,
+        state,
+        header.prev_side_hash,
+    )?;
+    let rotxn = env.read_txn().map_err(EnvError::from)?;
+    let tip = state.try_get_tip(&rotxn).map_err(state::Error::from)?;
+    Ok(tip)
+}
+
+/// Remove invalidity from a block, and from its ancestors and descendants,
+/// and then re-org to the best valid tip descending from the block, if it is
+/// better than the current tip.
+/// Returns the new tip.
+pub(super) fn reconsider_block(
+    env: &sneed::Env,
+    archive: &Archive,
+    mempool: &MemPool,
+    state: &State,
+    block_hash: BlockHash,
+) -> Result<Option<BlockHash>, Error> {
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    if archive.reconsider_block(&mut rwtxn, block_hash)? {
+        rwtxn.commit().map_err(RwTxnError::from)?;
+        tracing::info!(%block_hash, "reconsidered block");
+    } else {
+        drop(rwtxn);
+    }
+    let () = reorg_to_best_valid_descendant(
+        env,
+        archive,
+        mempool,
+        state,
+        Some(block_hash),
+    )?;
+    let rotxn = env.read_txn().map_err(EnvError::from)?;
+    let tip = state.try_get_tip(&rotxn).map_err(state::Error::from)?;
+    Ok(tip)
+}
+
+/// Delete block bodies deeper than the prune target below the current tip.
+/// At least [`archive::MIN_PRUNE_DEPTH`] blocks are always kept.
+pub(super) fn prune(
+    env: &sneed::Env,
+    archive: &Archive,
+    state: &State,
+    config: PruneConfig,
+) -> Result<(), Error> {
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    let Some(tip) = state.try_get_tip(&rwtxn).map_err(state::Error::from)?
+    else {
+        return Ok(());
+    };
+    let depth = match config.target {
+        PruneTarget::Depth(depth) => depth,
+        PruneTarget::Megabytes(megabytes) => archive.prune_depth_for_size(
+            &rwtxn,
+            tip,
+            megabytes.saturating_mul(1024 * 1024),
+        )?,
+    }
+    .max(archive::MIN_PRUNE_DEPTH);
+    let pruned =
+        archive.prune(&mut rwtxn, tip, depth, config.prune_tx_inclusions)?;
+    if pruned > 0 {
+        rwtxn.commit().map_err(RwTxnError::from)?;
+        tracing::debug!(%tip, %depth, "pruned {pruned} block bodies");
+    }
+    Ok(())
+}
+
+/// Apply a state snapshot, if the snapshot tip header is available in the
+/// archive. Returns `true` if the snapshot was applied.
+fn apply_snapshot(
+    env: &sneed::Env,
+    archive: &Archive,
+    state: &State,
+    snapshot: &state::Snapshot,
+) -> Result<bool, Error> {
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    let tip = snapshot.tip();
+    let Some(height) = archive.try_get_height(&rwtxn, tip)? else {
+        return Ok(false);
+    };
+    if height != snapshot.height() {
+        return Err(Error::SnapshotHeight {
+            block_hash: tip,
+            archive_height: height,
+            snapshot_height: snapshot.height(),
+        });
+    }
+    let () = state.load_snapshot(&mut rwtxn, snapshot)?;
+    let () = archive.put_accumulator(
+        &mut rwtxn,
+        tip,
+        snapshot.utreexo_accumulator(),
+    )?;
+    let () = archive.put_orchard_frontier(
+        &mut rwtxn,
+        tip,
+        snapshot.orchard_frontier(),
+    )?;
+    rwtxn.commit().map_err(RwTxnError::from)?;
+    tracing::info!(%tip, %height, "applied state snapshot");
+    Ok(true)
+}
+
+/// Start from a state snapshot. The state MUST be empty.
+/// Blocks up to and including the snapshot tip are treated as pruned, so
+/// that their bodies are never requested.
+/// If the snapshot tip header is not yet available, the snapshot is
+/// returned, and should be applied once the header has been synced.
+pub(super) fn load_snapshot(
+    env: &sneed::Env,
+    archive: &Archive,
+    state: &State,
+    snapshot: state::Snapshot,
+) -> Result<Option<state::Snapshot>, Error> {
+    let mut rwtxn = env.write_txn().map_err(EnvError::from)?;
+    if let Some(tip) = state.try_get_tip(&rwtxn).map_err(state::Error::from)? {
+        return Err(state::Error::from(state::SnapshotError::StateNotEmpty {
+            tip,
+        })
+        .into());
+    }
+    let () =
+        archive.set_prune_tip(&mut rwtxn, snapshot.tip(), snapshot.height())?;
+    rwtxn.commit().map_err(RwTxnError::from)?;
+    if apply_snapshot(env, archive, state, &snapshot)? {
+        Ok(None)
+    } else {
+        tracing::info!(
+            tip = %snapshot.tip(),
+            "state snapshot will be applied once the tip header is synced"
+        );
+        Ok(Some(snapshot))
+    }
+}
+
 #[derive(Clone)]
 struct NetTaskContext {
     env: sneed::Env,
@@ -429,6 +823,9 @@ struct NetTaskContext {
     mainchain_task: MainchainTaskHandle,
     mempool: MemPool,
     net: Net,
+    /// State snapshot to apply once the snapshot tip header is available
+    pending_snapshot: Arc<parking_lot::Mutex<Option<state::Snapshot>>>,
+    prune: Option<PruneConfig>,
     state: State,
 }
 
@@ -441,6 +838,18 @@ struct NetTaskContext {
 type NewTipReadyMessage =
     (Tip, Option<SocketAddr>, Option<oneshot::Sender<bool>>);
 
+/// Message requesting a reindex, with a oneshot sender used to receive the
+/// re-applied tip once the reindex is complete
+type ReindexMessage = oneshot::Sender<Option<BlockHash>>;
+
+/// Message requesting that a block be invalidated or reconsidered, with a
+/// oneshot sender used to receive the resulting tip
+#[derive(Debug)]
+enum BlockValidityMessage {
+    Invalidate(BlockHash, oneshot::Sender<Result<Option<BlockHash>, Error>>),
+    Reconsider(BlockHash, oneshot::Sender<Result<Option<BlockHash>, Error>>),
+}
+
 struct NetTask {
     ctxt: NetTaskContext,
     /// Receive a request to forward to the mainchain task, with the address of
@@ -461,6 +870,7 @@ struct NetTask {
     /// An optional oneshot sender can be used receive the result of attempting
     /// to reorg to the new tip, on the corresponding oneshot receiver.
     new_tip_ready_rx: UnboundedReceiver<NewTipReadyMessage>,
+    block_validity_rx: UnboundedReceiver<BlockValidityMessage>,
     /// Push a tip that is ready to reorg to, with the address of the peer
     /// connection that caused the request, if it originated from a peer.
     /// If the request originates from this node, then the socket address is
@@ -469,9 +879,309 @@ struct NetTask {
     /// to reorg to the new tip, on the corresponding oneshot receiver.
     new_tip_ready_tx: UnboundedSender<NewTipReadyMessage>,
     peer_info_rx: PeerInfoRx,
+    reindex_rx: UnboundedReceiver<ReindexMessage>,
 }
 
 impl NetTask {
+    /// Store a block body received from a peer, notifying the peer
+    /// connection if all requested bodies are available, and sending new tip
+    /// ready if any new tips can be applied.
+    /// The header hash MUST be checked against the requested block hash
+    /// before calling this function.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_block(
+        ctxt: &NetTaskContext,
+        descendant_tips: &mut HashMap<
+            crate::types::BlockHash,
+            HashMap<Tip, HashSet<SocketAddr>>,
+        >,
+        new_tip_ready_tx: &UnboundedSender<NewTipReadyMessage>,
+        addr: SocketAddr,
+        descendant_tip: Tip,
+        ancestor: Option<crate::types::BlockHash>,
+        peer_state_id: PeerStateId,
+        header: &Header,
+        body: &Body,
+    ) -> Result<(), Error> {
+        let block_hash = header.hash();
+        {
+            let mut rwtxn = ctxt.env.write_txn().map_err(EnvError::from)?;
+            let () = ctxt.archive.put_body(&mut rwtxn, block_hash, body)?;
+            rwtxn.commit().map_err(RwTxnError::from)?;
+        }
+        // Notify the peer connection if all requested block bodies are
+        // now available
+        {
+            let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
+            let ancestor_height = if let Some(ancestor) = ancestor {
+                Some(ctxt.archive.get_height(&rotxn, ancestor)?)
+            } else {
+                None
+            };
+            let earliest_missing_body = ctxt
+                .archive
+                .iter_missing_bodies(
+                    &rotxn,
+                    block_hash,
+                    ancestor_height.map_or(0, |height| height + 1),
+                )
+                .next()?;
+            if let Some(earliest_missing_body) = earliest_missing_body {
+                descendant_tips
+                    .entry(earliest_missing_body)
+                    .or_default()
+                    .entry(descendant_tip)
+                    .or_default()
+                    .insert(addr);
+            } else {
+                let message =
+                    PeerConnectionMessage::BodiesAvailable(peer_state_id);
+                let _: bool = ctxt.net.push_internal_message(message, addr);
+            }
+        }
+        // Check if any new tips can be applied,
+        // and send new tip ready if so
+        {
+            let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
+            let tip = ctxt
+                .state
+                .try_get_tip(&rotxn)
+                .map_err(state::Error::from)?
+                .map(|tip_hash| {
+                    let bmm_verification = ctxt
+                        .archive
+                        .get_best_main_verification(&rotxn, tip_hash)?;
+                    Ok::<_, Error>(Tip {
+                        block_hash: tip_hash,
+                        main_block_hash: bmm_verification,
+                    })
+                })
+                .transpose()?;
+            // Find the BMM verification that is an ancestor of
+            // `main_descendant_tip`
+            let main_block_hash = ctxt
+                .archive
+                .get_bmm_results(&rotxn, block_hash)?
+                .into_iter()
+                .map(Result::<_, Error>::Ok)
+                .transpose_into_fallible()
+                .find_map(|(main_block_hash, bmm_result)| match bmm_result {
+                    BmmResult::Failed => Ok(None),
+                    BmmResult::Verified => {
+                        if ctxt.archive.is_main_descendant(
+                            &rotxn,
+                            main_block_hash,
+                            descendant_tip.main_block_hash,
+                        )? {
+                            Ok(Some(main_block_hash))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                })?
+                .unwrap();
+            let block_tip = Tip {
+                block_hash,
+                main_block_hash,
+            };
+
+            if header.prev_side_hash == tip.map(|tip| tip.block_hash) {
+                tracing::trace!(
+                    ?block_tip,
+                    %addr,
+                    "sending new tip ready, originating from peer"
+                );
+
+                let () = new_tip_ready_tx
+                    .unbounded_send((block_tip, Some(addr), None))
+                    .map_err(Error::SendNewTipReady)?;
+            }
+            let Some(block_descendant_tips) =
+                descendant_tips.remove(&block_hash)
+            else {
+                return Ok(());
+            };
+            for (descendant_tip, sources) in block_descendant_tips {
+                let common_ancestor_height = if let Some(tip) = tip
+                    && let Some(common_ancestor) =
+                        ctxt.archive.last_common_ancestor(
+                            &rotxn,
+                            descendant_tip.block_hash,
+                            tip.block_hash,
+                        )? {
+                    Some(ctxt.archive.get_height(&rotxn, common_ancestor)?)
+                } else {
+                    None
+                };
+                let earliest_missing_body = ctxt
+                    .archive
+                    .iter_missing_bodies(
+                        &rotxn,
+                        descendant_tip.block_hash,
+                        common_ancestor_height.map_or(0, |height| height + 1),
+                    )
+                    .next()?;
+                // If a better tip is ready, send a notification
+                'better_tip: {
+                    let next_tip = if let Some(earliest_missing_body) =
+                        earliest_missing_body
+                    {
+                        descendant_tips
+                            .entry(earliest_missing_body)
+                            .or_default()
+                            .entry(descendant_tip)
+                            .or_default()
+                            .extend(sources.iter().cloned());
+
+                        // Parent of the earlist missing body
+                        ctxt.archive
+                            .get_header(&rotxn, earliest_missing_body)?
+                            .prev_side_hash
+                            .map(|tip_hash| {
+                                let bmm_verification =
+                                    ctxt.archive.get_best_main_verification(
+                                        &rotxn, tip_hash,
+                                    )?;
+                                Ok::<_, Error>(Tip {
+                                    block_hash: tip_hash,
+                                    main_block_hash: bmm_verification,
+                                })
+                            })
+                            .transpose()?
+                    } else {
+                        Some(descendant_tip)
+                    };
+                    let Some(next_tip) = next_tip else {
+                        break 'better_tip;
+                    };
+                    if let Some(tip) = tip
+                        && ctxt.archive.better_tip(&rotxn, tip, next_tip)?
+                            != Some(next_tip)
+                    {
+                        break 'better_tip;
+                    } else {
+                        tracing::debug!(
+                            new_tip = ?next_tip,
+                            "sending new tip ready to sources"
+                        );
+                        for addr in sources {
+                            tracing::trace!(%addr, new_tip = ?next_tip, "sending new tip ready");
+                            let () = new_tip_ready_tx
+                                .unbounded_send((next_tip, Some(addr), None))
+                                .map_err(Error::SendNewTipReady)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Attempt to reconstruct a compact block from the mempool and any
+    /// transactions received from the peer.
+    /// If transactions are missing, they are requested from the peer.
+    /// If reconstruction fails after transactions have been received from
+    /// the peer, or if the compact block does not extend the current tip,
+    /// then the full block is requested instead.
+    /// The header hash MUST be checked against the requested block hash
+    /// before calling this function.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_compact_block(
+        ctxt: &NetTaskContext,
+        descendant_tips: &mut HashMap<
+            crate::types::BlockHash,
+            HashMap<Tip, HashSet<SocketAddr>>,
+        >,
+        new_tip_ready_tx: &UnboundedSender<NewTipReadyMessage>,
+        addr: SocketAddr,
+        descendant_tip: Tip,
+        ancestor: Option<crate::types::BlockHash>,
+        peer_state_id: PeerStateId,
+        compact_block: Box<peer_message::CompactBlock>,
+        // `None` if transactions have not yet been requested from the peer
+        block_transactions: Option<Vec<AuthorizedTransaction>>,
+    ) -> Result<(), Error> {
+        let block_hash = compact_block.header.hash();
+        let reconstructed = {
+            let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
+            let tip =
+                ctxt.state.try_get_tip(&rotxn).map_err(state::Error::from)?;
+            // Utreexo proofs for mempool txs are only valid against the
+            // current tip
+            if compact_block.header.prev_side_hash == tip {
+                let mempool_txs = ctxt.mempool.take_all(&rotxn)?;
+                let candidates = mempool_txs
+                    .into_iter()
+                    .chain(block_transactions.iter().flatten().cloned());
+                Some(compact_block.reconstruct(candidates))
+            } else {
+                None
+            }
+        };
+        match reconstructed {
+            Some(Ok(body))
+                if body.compute_merkle_root()
+                    == compact_block.header.merkle_root =>
+            {
+                tracing::debug!(
+                    %addr,
+                    %block_hash,
+                    "reconstructed compact block"
+                );
+                Self::handle_block(
+                    ctxt,
+                    descendant_tips,
+                    new_tip_ready_tx,
+                    addr,
+                    descendant_tip,
+                    ancestor,
+                    peer_state_id,
+                    &compact_block.header,
+                    &body,
+                )
+            }
+            Some(Err(missing)) if block_transactions.is_none() => {
+                tracing::debug!(
+                    %addr,
+                    %block_hash,
+                    missing = missing.len(),
+                    "requesting missing transactions for compact block"
+                );
+                let request = peer_message::GetBlockTransactionsRequest {
+                    block_hash,
+                    indexes: missing,
+                    compact_block: Some(compact_block),
+                    descendant_tip: Some(descendant_tip),
+                    ancestor,
+                    peer_state_id: Some(peer_state_id),
+                };
+                let message = PeerConnectionMessage::ForwardRequest(
+                    PeerRequest::from(request),
+                );
+                let _: bool = ctxt.net.push_internal_message(message, addr);
+                Ok(())
+            }
+            Some(_) | None => {
+                tracing::debug!(
+                    %addr,
+                    %block_hash,
+                    "failed to reconstruct compact block, requesting block"
+                );
+                let request = peer_message::GetBlockRequest {
+                    block_hash,
+                    descendant_tip: Some(descendant_tip),
+                    ancestor,
+                    peer_state_id: Some(peer_state_id),
+                };
+                let message = PeerConnectionMessage::ForwardRequest(
+                    PeerRequest::from(request),
+                );
+                let _: bool = ctxt.net.push_internal_message(message, addr);
+                Ok(())
+            }
+        }
+    }
+
     fn handle_response(
         ctxt: &NetTaskContext,
         // Attempt to switch to a descendant tip once a body has been
@@ -508,194 +1218,68 @@ impl NetTask {
                     let () = ctxt.net.remove_active_peer(addr);
                     return Ok::<_, Error>(());
                 }
-                {
-                    let mut rwtxn =
-                        ctxt.env.write_txn().map_err(EnvError::from)?;
-                    let () =
-                        ctxt.archive.put_body(&mut rwtxn, block_hash, body)?;
-                    rwtxn.commit().map_err(RwTxnError::from)?;
+                Self::handle_block(
+                    ctxt,
+                    descendant_tips,
+                    new_tip_ready_tx,
+                    addr,
+                    descendant_tip,
+                    ancestor,
+                    peer_state_id,
+                    header,
+                    body,
+                )
+            }
+            (
+                PeerRequest::GetBlockWithSpentOutputs(
+                    req @ peer_message::GetBlockRequest {
+                        block_hash,
+                        descendant_tip: Some(descendant_tip),
+                        ancestor,
+                        peer_state_id: Some(peer_state_id),
+                    },
+                ),
+                ref resp @ PeerResponse::BlockWithSpentOutputs {
+                    ref header,
+                    ref body,
+                    ref spent_outputs,
+                },
+            ) => {
+                if header.hash() != block_hash {
+                    // Invalid response
+                    tracing::warn!(%addr, ?req, ?resp,"Invalid response from peer; unexpected block hash");
+                    let () = ctxt.net.remove_active_peer(addr);
+                    return Ok::<_, Error>(());
                 }
-                // Notify the peer connection if all requested block bodies are
-                // now available
+                if let Err(err) =
+                    State::validate_relayed_spent_outputs(body, spent_outputs)
                 {
-                    let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
-                    let ancestor_height = if let Some(ancestor) = ancestor {
-                        Some(ctxt.archive.get_height(&rotxn, ancestor)?)
-                    } else {
-                        None
-                    };
-                    let earliest_missing_body = ctxt
-                        .archive
-                        .iter_missing_bodies(
-                            &rotxn,
-                            block_hash,
-                            ancestor_height.map_or(0, |height| height + 1),
-                        )
-                        .next()?;
-                    if let Some(earliest_missing_body) = earliest_missing_body {
-                        descendant_tips
-                            .entry(earliest_missing_body)
-                            .or_default()
-                            .entry(descendant_tip)
-                            .or_default()
-                            .insert(addr);
-                    } else {
-                        let message = PeerConnectionMessage::BodiesAvailable(
-                            peer_state_id,
-                        );
-                        let _: bool =
-                            ctxt.net.push_internal_message(message, addr);
-                    }
+                    // Invalid response
+                    tracing::warn!(%addr, ?req, "Invalid response from peer; invalid spent outputs: {err:#}");
+                    let () = ctxt.net.remove_active_peer(addr);
+                    return Ok(());
                 }
-                // Check if any new tips can be applied,
-                // and send new tip ready if so
                 {
-                    let rotxn = ctxt.env.read_txn().map_err(EnvError::from)?;
-                    let tip = ctxt
-                        .state
-                        .try_get_tip(&rotxn)
-                        .map_err(state::Error::from)?
-                        .map(|tip_hash| {
-                            let bmm_verification = ctxt
-                                .archive
-                                .get_best_main_verification(&rotxn, tip_hash)?;
-                            Ok::<_, Error>(Tip {
-                                block_hash: tip_hash,
-                                main_block_hash: bmm_verification,
-                            })
-                        })
-                        .transpose()?;
-                    // Find the BMM verification that is an ancestor of
-                    // `main_descendant_tip`
-                    let main_block_hash = ctxt
-                        .archive
-                        .get_bmm_results(&rotxn, block_hash)?
-                        .into_iter()
-                        .map(Result::<_, Error>::Ok)
-                        .transpose_into_fallible()
-                        .find_map(|(main_block_hash, bmm_result)| {
-                            match bmm_result {
-                                BmmResult::Failed => Ok(None),
-                                BmmResult::Verified => {
-                                    if ctxt.archive.is_main_descendant(
-                                        &rotxn,
-                                        main_block_hash,
-                                        descendant_tip.main_block_hash,
-                                    )? {
-                                        Ok(Some(main_block_hash))
-                                    } else {
-                                        Ok(None)
-                                    }
-                                }
-                            }
-                        })?
-                        .unwrap();
-                    let block_tip = Tip {
+                    let mut rwtxn =
+                        ctxt.env.write_txn().map_err(EnvError::from)?;
+                    let () = ctxt.archive.put_spent_outputs(
+                        &mut rwtxn,
                         block_hash,
-                        main_block_hash,
-                    };
-
-                    if header.prev_side_hash == tip.map(|tip| tip.block_hash) {
-                        tracing::trace!(
-                            ?block_tip,
-                            %addr,
-                            "sending new tip ready, originating from peer"
-                        );
-
-                        let () = new_tip_ready_tx
-                            .unbounded_send((block_tip, Some(addr), None))
-                            .map_err(Error::SendNewTipReady)?;
-                    }
-                    let Some(block_descendant_tips) =
-                        descendant_tips.remove(&block_hash)
-                    else {
-                        return Ok(());
-                    };
-                    for (descendant_tip, sources) in block_descendant_tips {
-                        let common_ancestor_height = if let Some(tip) = tip
-                            && let Some(common_ancestor) =
-                                ctxt.archive.last_common_ancestor(
-                                    &rotxn,
-                                    descendant_tip.block_hash,
-                                    tip.block_hash,
-                                )? {
-                            Some(
-                                ctxt.archive
-                                    .get_height(&rotxn, common_ancestor)?,
-                            )
-                        } else {
-                            None
-                        };
-                        let earliest_missing_body = ctxt
-                            .archive
-                            .iter_missing_bodies(
-                                &rotxn,
-                                descendant_tip.block_hash,
-                                common_ancestor_height
-                                    .map_or(0, |height| height + 1),
-                            )
-                            .next()?;
-                        // If a better tip is ready, send a notification
-                        'better_tip: {
-                            let next_tip = if let Some(earliest_missing_body) =
-                                earliest_missing_body
-                            {
-                                descendant_tips
-                                    .entry(earliest_missing_body)
-                                    .or_default()
-                                    .entry(descendant_tip)
-                                    .or_default()
-                                    .extend(sources.iter().cloned());
-
-                                // Parent of the earlist missing body
-                                ctxt.archive
-                                    .get_header(&rotxn, earliest_missing_body)?
-                                    .prev_side_hash
-                                    .map(|tip_hash| {
-                                        let bmm_verification = ctxt
-                                            .archive
-                                            .get_best_main_verification(
-                                                &rotxn, tip_hash,
-                                            )?;
-                                        Ok::<_, Error>(Tip {
-                                            block_hash: tip_hash,
-                                            main_block_hash: bmm_verification,
-                                        })
-                                    })
-                                    .transpose()?
-                            } else {
-                                Some(descendant_tip)
-                            };
-                            let Some(next_tip) = next_tip else {
-                                break 'better_tip;
-                            };
-                            if let Some(tip) = tip
-                                && ctxt
-                                    .archive
-                                    .better_tip(&rotxn, tip, next_tip)?
-                                    != Some(next_tip)
-                            {
-                                break 'better_tip;
-                            } else {
-                                tracing::debug!(
-                                    new_tip = ?next_tip,
-                                    "sending new tip ready to sources"
-                                );
-                                for addr in sources {
-                                    tracing::trace!(%addr, new_tip = ?next_tip, "sending new tip ready");
-                                    let () = new_tip_ready_tx
-                                        .unbounded_send((
-                                            next_tip,
-                                            Some(addr),
-                                            None,
-                                        ))
-                                        .map_err(Error::SendNewTipReady)?;
-                                }
-                            }
-                        }
-                    }
+                        spent_outputs,
+                    )?;
+                    rwtxn.commit().map_err(RwTxnError::from)?;
                 }
-                Ok(())
+                Self::handle_block(
+                    ctxt,
+                    descendant_tips,
+                    new_tip_ready_tx,
+                    addr,
+                    descendant_tip,
+                    ancestor,
+                    peer_state_id,
+                    header,
+                    body,
+                )
             }
             (
                 PeerRequest::GetBlock(peer_message::GetBlockRequest {
@@ -703,11 +1287,146 @@ impl NetTask {
                     descendant_tip: Some(_),
                     ancestor: _,
                     peer_state_id: Some(_),
-                }),
+                })
+                | PeerRequest::GetBlockWithSpentOutputs(
+                    peer_message::GetBlockRequest {
+                        block_hash: req_block_hash,
+                        descendant_tip: Some(_),
+                        ancestor: _,
+                        peer_state_id: Some(_),
+                    },
+                ),
                 PeerResponse::NoBlock {
                     block_hash: resp_block_hash,
                 },
             ) if req_block_hash == resp_block_hash => Ok(()),
+            (
+                PeerRequest::GetCompactBlock(
+                    peer_message::GetCompactBlockRequest {
+                        block_hash,
+                        descendant_tip: Some(descendant_tip),
+                        ancestor,
+                        peer_state_id: Some(peer_state_id),
+                    },
+                ),
+                PeerResponse::CompactBlock(compact_block),
+            ) => {
+                if compact_block.header.hash() != block_hash {
+                    // Invalid response
+                    tracing::warn!(%addr, %block_hash, ?compact_block, "Invalid response from peer; unexpected block hash");
+                    let () = ctxt.net.remove_active_peer(addr);
+                    return Ok(());
+                }
+                Self::handle_compact_block(
+                    ctxt,
+                    descendant_tips,
+                    new_tip_ready_tx,
+                    addr,
+                    descendant_tip,
+                    ancestor,
+                    peer_state_id,
+                    compact_block,
+                    None,
+                )
+            }
+            (
+                PeerRequest::GetCompactBlock(
+                    peer_message::GetCompactBlockRequest {
+                        block_hash: req_block_hash,
+                        descendant_tip: Some(_),
+                        ancestor: _,
+                        peer_state_id: Some(_),
+                    },
+                ),
+                PeerResponse::NoBlock {
+                    block_hash: resp_block_hash,
+                },
+            ) if req_block_hash == resp_block_hash => Ok(()),
+            (
+                PeerRequest::GetBlockTransactions(
+                    peer_message::GetBlockTransactionsRequest {
+                        block_hash,
+                        indexes,
+                        compact_block: Some(compact_block),
+                        descendant_tip: Some(descendant_tip),
+                        ancestor,
+                        peer_state_id: Some(peer_state_id),
+                    },
+                ),
+                PeerResponse::BlockTransactions {
+                    block_hash: resp_block_hash,
+                    transactions,
+                },
+            ) => {
+                if resp_block_hash != block_hash
+                    || transactions.len() != indexes.len()
+                {
+                    // Invalid response
+                    tracing::warn!(%addr, %block_hash, %resp_block_hash, "Invalid response from peer; unexpected block transactions");
+                    let () = ctxt.net.remove_active_peer(addr);
+                    return Ok(());
+                }
+                Self::handle_compact_block(
+                    ctxt,
+                    descendant_tips,
+                    new_tip_ready_tx,
+                    addr,
+                    descendant_tip,
+                    ancestor,
+                    peer_state_id,
+                    compact_block,
+                    Some(transactions),
+                )
+            }
+            (
+                PeerRequest::GetBlockTransactions(
+                    peer_message::GetBlockTransactionsRequest {
+                        block_hash: req_block_hash,
+                        indexes: _,
+                        compact_block: Some(_),
+                        descendant_tip: Some(_),
+                        ancestor: _,
+                        peer_state_id: Some(_),
+                    },
+                ),
+                PeerResponse::NoBlock {
+                    block_hash: resp_block_hash,
+                },
+            ) if req_block_hash == resp_block_hash => Ok(()),
+            (
+                PeerRequest::GetBlock(peer_message::GetBlockRequest {
+                    block_hash: req_block_hash,
+                    ..
+                })
+                | PeerRequest::GetBlockWithSpentOutputs(
+                    peer_message::GetBlockRequest {
+                        block_hash: req_block_hash,
+                        ..
+                    },
+                )
+                | PeerRequest::GetCompactBlock(
+                    peer_message::GetCompactBlockRequest {
+                        block_hash: req_block_hash,
+                        ..
+                    },
+                )
+                | PeerRequest::GetBlockTransactions(
+                    peer_message::GetBlockTransactionsRequest {
+                        block_hash: req_block_hash,
+                        ..
+                    },
+                ),
+                PeerResponse::BlockPruned {
+                    block_hash: resp_block_hash,
+                },
+            ) if req_block_hash == resp_block_hash => {
+                tracing::debug!(
+                    %addr,
+                    block_hash = %req_block_hash,
+                    "Peer has pruned requested block"
+                );
+                Ok(())
+            }
             (
                 PeerRequest::GetHeaders(
                     ref req @ peer_message::GetHeadersRequest {
@@ -825,6 +1544,9 @@ impl NetTask {
             ) => Ok(()),
             (
                 req @ (PeerRequest::GetBlock { .. }
+                | PeerRequest::GetBlockTransactions { .. }
+                | PeerRequest::GetBlockWithSpentOutputs { .. }
+                | PeerRequest::GetCompactBlock { .. }
                 | PeerRequest::GetHeaders { .. }
                 | PeerRequest::PushTransaction { .. }),
                 resp,
@@ -863,6 +1585,10 @@ impl NetTask {
             PeerInfo(Option<(SocketAddr, Option<PeerConnectionInfo>)>),
             // Signal to reconnect to a peer
             ReconnectPeer(SocketAddr),
+            // Wipe and rebuild the state from the archive
+            Reindex(ReindexMessage),
+            // Invalidate or reconsider a block
+            BlockValidity(BlockValidityMessage),
         }
         let accept_connections = stream::try_unfold((), |()| {
             let env = self.ctxt.env.clone();
@@ -916,6 +1642,9 @@ impl NetTask {
         let (reconnect_peer_spawner, reconnect_peer_rx) = join_set::new();
         let reconnect_peer_stream = reconnect_peer_rx
             .map(|addr| MailboxItem::ReconnectPeer(addr.unwrap()));
+        let reindex_stream = self.reindex_rx.map(MailboxItem::Reindex);
+        let block_validity_stream =
+            self.block_validity_rx.map(MailboxItem::BlockValidity);
         let mut mailbox_stream = stream::select_all([
             accept_connections.boxed(),
             forward_request_stream.boxed(),
@@ -923,6 +1652,8 @@ impl NetTask {
             new_tip_ready_stream.boxed(),
             peer_info_stream.boxed(),
             reconnect_peer_stream.boxed(),
+            reindex_stream.boxed(),
+            block_validity_stream.boxed(),
         ]);
         // Attempt to switch to a descendant tip once a body has been
         // stored, if all other ancestor bodies are available.
@@ -1008,6 +1739,22 @@ impl NetTask {
                     }
                 }
                 MailboxItem::NewTipReady(new_tip, _addr, resp_tx) => {
+                    {
+                        let mut pending_snapshot =
+                            self.ctxt.pending_snapshot.lock();
+                        if let Some(snapshot) = pending_snapshot.as_ref()
+                            && task::block_in_place(|| {
+                                apply_snapshot(
+                                    &self.ctxt.env,
+                                    &self.ctxt.archive,
+                                    &self.ctxt.state,
+                                    snapshot,
+                                )
+                            })?
+                        {
+                            *pending_snapshot = None;
+                        }
+                    }
                     let reorg_applied = task::block_in_place(|| {
                         reorg_to_tip(
                             &self.ctxt.env,
@@ -1017,6 +1764,17 @@ impl NetTask {
                             new_tip,
                         )
                     })?;
+                    if reorg_applied && let Some(prune_config) = self.ctxt.prune
+                    {
+                        let () = task::block_in_place(|| {
+                            prune(
+                                &self.ctxt.env,
+                                &self.ctxt.archive,
+                                &self.ctxt.state,
+                                prune_config,
+                            )
+                        })?;
+                    }
                     if let Some(resp_tx) = resp_tx {
                         let () = resp_tx
                             .send(reorg_applied)
@@ -1147,6 +1905,52 @@ impl NetTask {
                         }
                     }
                 }
+                MailboxItem::Reindex(resp_tx) => {
+                    let tip = task::block_in_place(|| {
+                        reindex(
+                            &self.ctxt.env,
+                            &self.ctxt.archive,
+                            &self.ctxt.mempool,
+                            &self.ctxt.state,
+                        )
+                    })?;
+                    let () = resp_tx
+                        .send(tip)
+                        .map_err(|_| Error::SendReindexResultOneshot)?;
+                }
+                MailboxItem::BlockValidity(msg) => {
+                    let (res, resp_tx) = task::block_in_place(|| match msg {
+                        BlockValidityMessage::Invalidate(
+                            block_hash,
+                            resp_tx,
+                        ) => {
+                            let res = invalidate_block(
+                                &self.ctxt.env,
+                                &self.ctxt.archive,
+                                &self.ctxt.mempool,
+                                &self.ctxt.state,
+                                block_hash,
+                            );
+                            (res, resp_tx)
+                        }
+                        BlockValidityMessage::Reconsider(
+                            block_hash,
+                            resp_tx,
+                        ) => {
+                            let res = reconsider_block(
+                                &self.ctxt.env,
+                                &self.ctxt.archive,
+                                &self.ctxt.mempool,
+                                &self.ctxt.state,
+                                block_hash,
+                            );
+                            (res, resp_tx)
+                        }
+                    });
+                    let () = resp_tx
+                        .send(res)
+                        .map_err(|_| Error::SendBlockValidityResultOneshot)?;
+                }
             }
         }
         Ok(())
@@ -1165,6 +1969,9 @@ pub(super) struct NetTaskHandle {
     /// An optional oneshot sender can be used receive the result of attempting
     /// to reorg to the new tip, on the corresponding oneshot receiver.
     new_tip_ready_tx: UnboundedSender<NewTipReadyMessage>,
+    pending_snapshot: Arc<parking_lot::Mutex<Option<state::Snapshot>>>,
+    reindex_tx: UnboundedSender<ReindexMessage>,
+    block_validity_tx: UnboundedSender<BlockValidityMessage>,
 }
 
 impl NetTaskHandle {
@@ -1178,14 +1985,20 @@ impl NetTaskHandle {
         mempool: MemPool,
         net: Net,
         peer_info_rx: PeerInfoRx,
+        pending_snapshot: Option<state::Snapshot>,
+        prune: Option<PruneConfig>,
         state: State,
     ) -> Self {
+        let pending_snapshot =
+            Arc::new(parking_lot::Mutex::new(pending_snapshot));
         let ctxt = NetTaskContext {
             env,
             archive,
             mainchain_task,
             mempool,
             net,
+            pending_snapshot: pending_snapshot.clone(),
+            prune,
             state,
         };
         let (
@@ -1193,6 +2006,8 @@ impl NetTaskHandle {
             forward_mainchain_task_request_rx,
         ) = mpsc::unbounded();
         let (new_tip_ready_tx, new_tip_ready_rx) = mpsc::unbounded();
+        let (reindex_tx, reindex_rx) = mpsc::unbounded();
+        let (block_validity_tx, block_validity_rx) = mpsc::unbounded();
         let task = NetTask {
             ctxt,
             forward_mainchain_task_request_tx,
@@ -1200,7 +2015,9 @@ impl NetTaskHandle {
             mainchain_task_response_rx,
             new_tip_ready_tx: new_tip_ready_tx.clone(),
             new_tip_ready_rx,
+            block_validity_rx,
             peer_info_rx,
+            reindex_rx,
         };
         let task = runtime.spawn(async {
             if let Err(err) = task.run().await {
@@ -1211,9 +2028,17 @@ impl NetTaskHandle {
         NetTaskHandle {
             task: Arc::new(task),
             new_tip_ready_tx,
+            pending_snapshot,
+            reindex_tx,
+            block_validity_tx,
         }
     }
 
+    /// Apply a state snapshot once the snapshot tip header is available
+    pub fn set_pending_snapshot(&self, snapshot: state::Snapshot) {
+        *self.pending_snapshot.lock() = Some(snapshot);
+    }
+
     /// Push a tip that is ready to reorg to, and await successful application.
     /// A result of Ok(true) indicates that the tip was applied and reorged
     /// to successfully.
@@ -1231,6 +2056,53 @@ impl NetTaskHandle {
             .map_err(Error::SendNewTipReady)?;
         oneshot_rx.await.map_err(Error::ReceiveReorgResultOneshot)
     }
+
+    /// Wipe the state and mempool, and re-apply the current chain from the
+    /// archive. Returns the re-applied tip once the reindex is complete.
+    pub async fn reindex(&self) -> Result<Option<BlockHash>, Error> {
+        let (oneshot_tx, oneshot_rx) = oneshot::channel();
+        let () = self
+            .reindex_tx
+            .unbounded_send(oneshot_tx)
+            .map_err(|_| Error::SendReindex)?;
+        oneshot_rx.await.map_err(Error::ReceiveReindexResultOneshot)
+    }
+
+    /// Mark a block and its descendants as invalid, disconnecting them if
+    /// they are in the current chain. Returns the new tip.
+    pub async fn invalidate_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> Result<Option<BlockHash>, Error> {
+        let (oneshot_tx, oneshot_rx) = oneshot::channel();
+        let () = self
+            .block_validity_tx
+            .unbounded_send(BlockValidityMessage::Invalidate(
+                block_hash, oneshot_tx,
+            ))
+            .map_err(|_| Error::SendBlockValidity)?;
+        oneshot_rx
+            .await
+            .map_err(Error::ReceiveBlockValidityResultOneshot)?
+    }
+
+    /// Remove invalidity from a block, and from its ancestors and
+    /// descendants. Returns the new tip.
+    pub async fn reconsider_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> Result<Option<BlockHash>, Error> {
+        let (oneshot_tx, oneshot_rx) = oneshot::channel();
+        let () = self
+            .block_validity_tx
+            .unbounded_send(BlockValidityMessage::Reconsider(
+                block_hash, oneshot_tx,
+            ))
+            .map_err(|_| Error::SendBlockValidity)?;
+        oneshot_rx
+            .await
+            .map_err(Error::ReceiveBlockValidityResultOneshot)?
+    }
 }
 
 impl Drop for NetTaskHandle {
@@ -1244,3 +2116,141 @@ impl Drop for NetTaskHandle {
         }
     }
 }
+
+#[cfg(test)]
+pub(super) mod test {
+    use bitcoin::hashes::Hash as _;
+
+    use super::{connect_tip_, reindex};
+    use crate::{
+        archive::Archive,
+        mempool::MemPool,
+        state::{
+            State,
+            test::{temp_env_path, value_output},
+        },
+        types::{
+            Body, Header, Network, TransparentAddress,
+            proto::mainchain::{
+                BlockEvent, BlockHeaderInfo, BlockInfo, Deposit, TwoWayPegData,
+            },
+        },
+    };
+
+    pub struct TestChain {
+        pub env: sneed::Env,
+        pub archive: Archive,
+        pub mempool: MemPool,
+        pub state: State,
+    }
+
+    /// Connect a sidechain block on top of each of `n_blocks` mainchain
+    /// blocks. The mainchain block at height `n` contains a deposit of
+    /// `1000 * n` sats.
+    pub fn connected_chain(
+        test_name: &str,
+        n_blocks: u8,
+    ) -> anyhow::Result<TestChain> {
+        let path = temp_env_path(test_name)?;
+        std::fs::create_dir_all(&path)?;
+        let mut opts = heed::EnvOpenOptions::new();
+        opts.map_size(64 * 1024 * 1024)
+            .max_dbs(State::NUM_DBS + Archive::NUM_DBS + MemPool::NUM_DBS);
+        let env = unsafe { sneed::Env::open(&opts, &path) }?;
+        let state = State::new(&env, false, Network::default())?;
+        let archive = Archive::new(&env)?;
+        let mempool = MemPool::new(&env)?;
+
+        let body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let mut rwtxn = env.write_txn()?;
+        let mut prev_main_hash = bitcoin::BlockHash::all_zeros();
+        let mut prev_side_hash = None;
+        for n in 1..=n_blocks {
+            let main_hash = bitcoin::BlockHash::from_byte_array([n; 32]);
+            archive.put_main_header_info(
+                &mut rwtxn,
+                &BlockHeaderInfo {
+                    block_hash: main_hash,
+                    prev_block_hash: prev_main_hash,
+                    height: n as u32,
+                    work: bitcoin::Work::from_be_bytes({
+                        let mut work = [0; 32];
+                        work[31] = 1;
+                        work
+                    }),
+                },
+            )?;
+            let block_info = BlockInfo {
+                bmm_commitment: None,
+                events: vec![BlockEvent::Deposit(Deposit {
+                    tx_index: 0,
+                    outpoint: bitcoin::OutPoint {
+                        txid: bitcoin::Txid::from_byte_array([n; 32]),
+                        vout: 0,
+                    },
+                    output: value_output(
+                        TransparentAddress::ALL_ZEROS,
+                        1000 * n as u64,
+                    ),
+                })],
+            };
+            archive.put_main_block_info(&mut rwtxn, main_hash, &block_info)?;
+            let header = Header {
+                merkle_root: body.compute_merkle_root(),
+                prev_side_hash,
+                prev_main_hash: main_hash,
+                roots: state.get_accumulator(&rwtxn)?.get_roots(),
+            };
+            let mut two_way_peg_data = TwoWayPegData::default();
+            two_way_peg_data.block_info.insert(main_hash, block_info);
+            connect_tip_(
+                &mut rwtxn,
+                &archive,
+                &mempool,
+                &state,
+                &header,
+                &body,
+                &two_way_peg_data,
+            )?;
+            prev_main_hash = main_hash;
+            prev_side_hash = Some(header.hash());
+        }
+        anyhow::ensure!(state.try_get_tip(&rwtxn)? == prev_side_hash);
+        rwtxn.commit()?;
+        Ok(TestChain {
+            env,
+            archive,
+            mempool,
+            state,
+        })
+    }
+
+    // reindexing reproduces the state reached by connecting blocks one at a
+    // time
+    #[test]
+    fn reindex_matches_incremental_connect() -> anyhow::Result<()> {
+        let TestChain {
+            env,
+            archive,
+            mempool,
+            state,
+        } = connected_chain("reindex", 3)?;
+        let (tip, expected) = {
+            let rotxn = env.read_txn()?;
+            (state.try_get_tip(&rotxn)?, state.snapshot(&rotxn)?.hash()?)
+        };
+        anyhow::ensure!(reindex(&env, &archive, &mempool, &state)? == tip);
+        let rotxn = env.read_txn()?;
+        anyhow::ensure!(state.try_get_tip(&rotxn)? == tip);
+        anyhow::ensure!(state.try_get_height(&rotxn)? == Some(2));
+        anyhow::ensure!(state.snapshot(&rotxn)?.hash()? == expected);
+        anyhow::ensure!(
+            state.sidechain_wealth(&rotxn)? == bitcoin::Amount::from_sat(6000)
+        );
+        Ok(())
+    }
+}
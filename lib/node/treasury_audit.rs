@@ -0,0 +1,432 @@
+//! Treasury reconciliation against the mainchain
+//!
+//! The sidechain's treasury UTXO (CTIP) on the mainchain should hold exactly
+//! the credited deposits, less the value spent by confirmed withdrawal
+//! bundles. Events in mainchain blocks that the sidechain has not processed
+//! yet are accounted for separately.
+
+use serde::{Deserialize, Serialize};
+use sneed::{Env, EnvError, RoTxn};
+use tokio::sync::Mutex;
+
+use crate::{
+    archive::Archive,
+    state::{State, TreasuryBreakdown},
+    types::{
+        AmountOverflowError, AmountUnderflowError, BlockHash, GetValue as _,
+        M6id, WithdrawalBundleEventStatus,
+        proto::{
+            self,
+            mainchain::{self, BlockHeaderInfo, BlockInfo},
+        },
+    },
+};
+
+/// Number of times to query the mainchain and sidechain state, if either
+/// changes while querying
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Maximum number of mainchain blocks that have not been processed by the
+/// sidechain, for which events are requested.
+/// If the sidechain is further behind the mainchain, it is not synced, and
+/// the treasury cannot be audited.
+const MAX_UNPROCESSED_MAIN_BLOCKS: u32 = 144;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("mainchain block {0} not found")]
+    MainBlockNotFound(bitcoin::BlockHash),
+    #[error(
+        "mainchain block {main_block} processed by the sidechain is not an \
+         ancestor of the mainchain tip {main_tip}"
+    )]
+    MainBlockNotInBestChain {
+        main_block: bitcoin::BlockHash,
+        main_tip: bitcoin::BlockHash,
+    },
+    #[error("sidechain has no blocks")]
+    NoSidechainTip,
+    #[error(
+        "sidechain is not synced: {unprocessed_main_blocks} mainchain blocks \
+         have not been processed, which exceeds the maximum of \
+         {MAX_UNPROCESSED_MAIN_BLOCKS}"
+    )]
+    NotSynced { unprocessed_main_blocks: u32 },
+    #[error(
+        "mainchain or sidechain tip changed while auditing, after \
+         {MAX_ATTEMPTS} attempts"
+    )]
+    TipChanged,
+}
+
+#[derive(
+    Clone,
+    Debug,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    thiserror::Error,
+    utoipa::ToSchema,
+)]
+pub enum Inconsistency {
+    #[error(
+        "treasury UTXO value ({ctip_sats} sats) does not match expected \
+         value ({expected_sats} sats)"
+    )]
+    CtipValue { ctip_sats: u64, expected_sats: u64 },
+    #[error(
+        "sidechain wealth ({sidechain_wealth_sats} sats) does not match \
+         expected value ({expected_sats} sats)"
+    )]
+    SidechainWealth {
+        sidechain_wealth_sats: u64,
+        expected_sats: u64,
+    },
+    #[error(
+        "shielded pool value ({shielded_pool_sats} sats) exceeds sidechain \
+         wealth ({sidechain_wealth_sats} sats)"
+    )]
+    ShieldedPoolValue {
+        shielded_pool_sats: u64,
+        sidechain_wealth_sats: u64,
+    },
+    #[error(
+        "withdrawal bundle {m6id} was confirmed in an unprocessed mainchain \
+         block, but its value is unknown"
+    )]
+    UnknownConfirmedBundle { m6id: M6id },
+    #[error(
+        "withdrawals ({withdrawals_sats} sats) exceed deposits \
+         ({deposits_sats} sats)"
+    )]
+    NegativeTreasury {
+        deposits_sats: u64,
+        withdrawals_sats: u64,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct Report {
+    pub tip: BlockHash,
+    /// Latest mainchain block processed by the sidechain
+    #[schema(value_type = crate::types::schema::BitcoinBlockHash)]
+    pub sidechain_main_block: bitcoin::BlockHash,
+    /// Mainchain tip at which the treasury UTXO was queried
+    #[schema(value_type = crate::types::schema::BitcoinBlockHash)]
+    pub mainchain_tip: bitcoin::BlockHash,
+    /// Number of mainchain blocks that have not been processed by the
+    /// sidechain
+    pub unprocessed_main_blocks: u32,
+    /// Treasury UTXO value on the mainchain
+    #[serde(rename = "ctip_sats", with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub ctip: bitcoin::Amount,
+    /// Credited deposits, including spent and shielded deposits
+    #[serde(rename = "deposits_sats", with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub deposits: bitcoin::Amount,
+    /// Value spent by withdrawal bundles that have been submitted, but not
+    /// yet confirmed
+    #[serde(
+        rename = "submitted_withdrawals_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub submitted_withdrawals: bitcoin::Amount,
+    /// Value spent by confirmed withdrawal bundles
+    #[serde(
+        rename = "confirmed_withdrawals_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub confirmed_withdrawals: bitcoin::Amount,
+    /// Withdrawal outputs that have not been paid out by a submitted or
+    /// confirmed bundle
+    #[serde(
+        rename = "pending_withdrawals_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub pending_withdrawals: bitcoin::Amount,
+    #[serde(
+        rename = "sidechain_wealth_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub sidechain_wealth: bitcoin::Amount,
+    /// Total value of notes in the shielded pool
+    #[serde(
+        rename = "shielded_pool_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub shielded_pool: bitcoin::Amount,
+    /// Deposits in mainchain blocks that have not been processed by the
+    /// sidechain
+    #[serde(
+        rename = "unprocessed_deposits_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub unprocessed_deposits: bitcoin::Amount,
+    /// Value spent by withdrawal bundles confirmed in mainchain blocks that
+    /// have not been processed by the sidechain
+    #[serde(
+        rename = "unprocessed_confirmed_withdrawals_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub unprocessed_confirmed_withdrawals: bitcoin::Amount,
+    /// Expected treasury UTXO value
+    #[serde(
+        rename = "expected_ctip_sats",
+        with = "bitcoin::amount::serde::as_sat"
+    )]
+    #[schema(value_type = u64)]
+    pub expected_ctip: bitcoin::Amount,
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+/// Mainchain treasury, and events in blocks that have not been processed by
+/// the sidechain
+struct MainchainTreasury {
+    tip: bitcoin::BlockHash,
+    ctip: bitcoin::Amount,
+    /// Unprocessed blocks, latest first
+    unprocessed_blocks: Vec<(BlockHeaderInfo, BlockInfo)>,
+}
+
+/// Sidechain tip, and the latest mainchain block that it has processed
+fn sidechain_tip(
+    rotxn: &RoTxn,
+    archive: &Archive,
+    state: &State,
+) -> Result<(BlockHash, bitcoin::BlockHash), super::Error> {
+    let tip = state
+        .try_get_tip(rotxn)
+        .map_err(crate::state::Error::from)?
+        .ok_or(Error::NoSidechainTip)?;
+    let main_block = archive.get_header(rotxn, tip)?.prev_main_hash;
+    Ok((tip, main_block))
+}
+
+/// Query the mainchain treasury.
+/// The client is locked for each request, and released while processing
+/// responses.
+async fn query_mainchain<Transport>(
+    cusf_mainchain: &Mutex<mainchain::ValidatorClient<Transport>>,
+    sidechain_main_block: bitcoin::BlockHash,
+) -> Result<Option<MainchainTreasury>, super::Error>
+where
+    Transport: proto::Transport,
+{
+    let tip = cusf_mainchain.lock().await.get_chain_tip().await?;
+    let ctip = cusf_mainchain.lock().await.get_ctip().await?;
+    let latest_tip = cusf_mainchain.lock().await.get_chain_tip().await?;
+    if latest_tip.block_hash != tip.block_hash {
+        return Ok(None);
+    }
+    let main_block = cusf_mainchain
+        .lock()
+        .await
+        .get_block_header_info(sidechain_main_block)
+        .await?
+        .ok_or(Error::MainBlockNotFound(sidechain_main_block))?;
+    let not_in_best_chain = || Error::MainBlockNotInBestChain {
+        main_block: sidechain_main_block,
+        main_tip: tip.block_hash,
+    };
+    let unprocessed_blocks = match tip.height.checked_sub(main_block.height) {
+        None => return Err(not_in_best_chain().into()),
+        Some(0) if tip.block_hash == sidechain_main_block => Vec::new(),
+        Some(0) => return Err(not_in_best_chain().into()),
+        Some(depth) if depth > MAX_UNPROCESSED_MAIN_BLOCKS => {
+            return Err(Error::NotSynced {
+                unprocessed_main_blocks: depth,
+            }
+            .into());
+        }
+        Some(depth) => {
+            let blocks = cusf_mainchain
+                .lock()
+                .await
+                .get_block_infos(tip.block_hash, depth - 1)
+                .await?
+                .ok_or(Error::MainBlockNotFound(tip.block_hash))?;
+            if blocks.last().0.prev_block_hash != sidechain_main_block {
+                return Err(not_in_best_chain().into());
+            }
+            blocks.into()
+        }
+    };
+    Ok(Some(MainchainTreasury {
+        tip: tip.block_hash,
+        ctip: ctip.map_or(bitcoin::Amount::ZERO, |ctip| ctip.value),
+        unprocessed_blocks,
+    }))
+}
+
+fn reconcile(
+    rotxn: &RoTxn,
+    state: &State,
+    tip: BlockHash,
+    sidechain_main_block: bitcoin::BlockHash,
+    mainchain: MainchainTreasury,
+) -> Result<Report, super::Error> {
+    let TreasuryBreakdown {
+        deposits,
+        submitted_withdrawals,
+        confirmed_withdrawals,
+        pending_withdrawals,
+    } = state.treasury_breakdown(rotxn)?;
+    let sidechain_wealth = state.sidechain_wealth(rotxn)?;
+    let shielded_pool = state.get_shielded_pool_value(rotxn)?;
+    let mut inconsistencies = Vec::new();
+    if shielded_pool > sidechain_wealth {
+        inconsistencies.push(Inconsistency::ShieldedPoolValue {
+            shielded_pool_sats: shielded_pool.to_sat(),
+            sidechain_wealth_sats: sidechain_wealth.to_sat(),
+        });
+    }
+    let mut unprocessed_deposits = bitcoin::Amount::ZERO;
+    let mut unprocessed_confirmed_withdrawals = bitcoin::Amount::ZERO;
+    for (_, block_info) in &mainchain.unprocessed_blocks {
+        for deposit in block_info.deposits() {
+            unprocessed_deposits = unprocessed_deposits
+                .checked_add(deposit.output.get_value())
+                .ok_or(AmountOverflowError)?;
+        }
+        for deposit in block_info.shielded_deposits() {
+            unprocessed_deposits = unprocessed_deposits
+                .checked_add(deposit.value)
+                .ok_or(AmountOverflowError)?;
+        }
+        for event in block_info.withdrawal_bundle_events() {
+            match event.status {
+                WithdrawalBundleEventStatus::Confirmed => (),
+                WithdrawalBundleEventStatus::Failed
+                | WithdrawalBundleEventStatus::Submitted => continue,
+            }
+            match state
+                .try_get_withdrawal_bundle_spent_value(rotxn, event.m6id)?
+            {
+                Some(value) => {
+                    unprocessed_confirmed_withdrawals =
+                        unprocessed_confirmed_withdrawals
+                            .checked_add(value)
+                            .ok_or(AmountOverflowError)?;
+                }
+                None => {
+                    inconsistencies.push(
+                        Inconsistency::UnknownConfirmedBundle {
+                            m6id: event.m6id,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    let total_deposits = deposits
+        .checked_add(unprocessed_deposits)
+        .ok_or(AmountOverflowError)?;
+    let total_confirmed_withdrawals = confirmed_withdrawals
+        .checked_add(unprocessed_confirmed_withdrawals)
+        .ok_or(AmountOverflowError)?;
+    let expected_ctip =
+        match total_deposits.checked_sub(total_confirmed_withdrawals) {
+            Some(expected_ctip) => expected_ctip,
+            None => {
+                inconsistencies.push(Inconsistency::NegativeTreasury {
+                    deposits_sats: total_deposits.to_sat(),
+                    withdrawals_sats: total_confirmed_withdrawals.to_sat(),
+                });
+                bitcoin::Amount::ZERO
+            }
+        };
+    if mainchain.ctip != expected_ctip {
+        inconsistencies.push(Inconsistency::CtipValue {
+            ctip_sats: mainchain.ctip.to_sat(),
+            expected_sats: expected_ctip.to_sat(),
+        });
+    }
+    let breakdown = TreasuryBreakdown {
+        deposits,
+        submitted_withdrawals,
+        confirmed_withdrawals,
+        pending_withdrawals,
+    };
+    match breakdown.expected_sidechain_wealth() {
+        Ok(expected) if expected == sidechain_wealth => (),
+        Ok(expected) => {
+            inconsistencies.push(Inconsistency::SidechainWealth {
+                sidechain_wealth_sats: sidechain_wealth.to_sat(),
+                expected_sats: expected.to_sat(),
+            });
+        }
+        Err(AmountUnderflowError) => {
+            inconsistencies.push(Inconsistency::NegativeTreasury {
+                deposits_sats: deposits.to_sat(),
+                withdrawals_sats: confirmed_withdrawals
+                    .checked_add(submitted_withdrawals)
+                    .ok_or(AmountOverflowError)?
+                    .to_sat(),
+            });
+        }
+    }
+    Ok(Report {
+        tip,
+        sidechain_main_block,
+        mainchain_tip: mainchain.tip,
+        unprocessed_main_blocks: mainchain.unprocessed_blocks.len() as u32,
+        ctip: mainchain.ctip,
+        deposits,
+        submitted_withdrawals,
+        confirmed_withdrawals,
+        pending_withdrawals,
+        sidechain_wealth,
+        shielded_pool,
+        unprocessed_deposits,
+        unprocessed_confirmed_withdrawals,
+        expected_ctip,
+        inconsistencies,
+    })
+}
+
+/// Compare the treasury UTXO on the mainchain with the value expected from
+/// the sidechain state.
+/// Retries if the mainchain or sidechain tip changes while auditing.
+/// Returns an error if the sidechain is not synced with the mainchain.
+pub(super) async fn audit_treasury<Transport>(
+    env: &Env,
+    archive: &Archive,
+    state: &State,
+    cusf_mainchain: &Mutex<mainchain::ValidatorClient<Transport>>,
+) -> Result<Report, super::Error>
+where
+    Transport: proto::Transport,
+{
+    for _ in 0..MAX_ATTEMPTS {
+        let (tip, sidechain_main_block) = {
+            let rotxn = env.read_txn().map_err(EnvError::from)?;
+            sidechain_tip(&rotxn, archive, state)?
+        };
+        let mainchain =
+            query_mainchain(cusf_mainchain, sidechain_main_block).await?;
+        let Some(mainchain) = mainchain else {
+            continue;
+        };
+        let rotxn = env.read_txn().map_err(EnvError::from)?;
+        if sidechain_tip(&rotxn, archive, state)?.0 != tip {
+            continue;
+        }
+        return reconcile(&rotxn, state, tip, sidechain_main_block, mainchain);
+    }
+    Err(Error::TipChanged.into())
+}
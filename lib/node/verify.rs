@@ -0,0 +1,658 @@
+//! Chain consistency checks
+//!
+//! The LMDB env is opened without syncing to disk, so an unclean shutdown can
+//! leave the state tip, Utreexo accumulator and Orchard frontier out of
+//! agreement with the archive.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use fallible_iterator::FallibleIterator as _;
+use serde::{Deserialize, Serialize};
+use sneed::{DbError, EnvError, RoTxn};
+
+use super::{Error, net_task};
+use crate::{
+    archive::{self, Archive},
+    mempool::MemPool,
+    state::{self, State},
+    types::{
+        AccumulatorDiff, AmountOverflowError, BlockHash, Body, GetValue as _,
+        InPoint, M6id, OutPoint, PointedOutput,
+        orchard::{Anchor, Frontier},
+        proto::mainchain::BlockEvent,
+    },
+};
+
+/// Default number of blocks to verify at startup
+pub const DEFAULT_DEPTH: u32 = 6;
+
+/// Minimum number of blocks to verify at startup, after an unclean shutdown
+pub const UNCLEAN_SHUTDOWN_DEPTH: u32 = 288;
+
+/// File in the data directory that exists while the node is running
+const RUNNING_MARKER_FILE: &str = "node.running";
+
+/// Action to take if the startup check finds an inconsistency
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "clap",
+    derive(clap::ValueEnum, strum::Display),
+    strum(serialize_all = "lowercase")
+)]
+pub enum Recovery {
+    /// Log the inconsistencies, and continue
+    #[default]
+    None,
+    /// Disconnect and re-apply the verified blocks, falling back to a
+    /// reindex if the chain is still inconsistent
+    Rollback,
+    /// Rebuild the state from the archive
+    Reindex,
+}
+
+/// Startup chain verification
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Number of blocks to verify
+    pub depth: u32,
+    pub recovery: Recovery,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            depth: DEFAULT_DEPTH,
+            recovery: Recovery::default(),
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Debug,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    thiserror::Error,
+    utoipa::ToSchema,
+)]
+pub enum Inconsistency {
+    #[error("tip {tip} does not exist in the archive")]
+    MissingTip { tip: BlockHash },
+    #[error(
+        "state height ({state:?}) does not match archive height ({archive})"
+    )]
+    TipHeight { state: Option<u32>, archive: u32 },
+    #[error("state accumulator does not match archive accumulator for tip")]
+    TipAccumulator,
+    #[error(
+        "recomputed accumulator does not match header roots for block \
+         {block_hash} at height {height}"
+    )]
+    HeaderRoots { block_hash: BlockHash, height: u32 },
+    #[error(
+        "recomputed Orchard frontier does not match archive for block \
+         {block_hash} at height {height}"
+    )]
+    OrchardFrontier { block_hash: BlockHash, height: u32 },
+    #[error("state Orchard frontier does not match recomputed frontier")]
+    TipOrchardFrontier,
+    #[error(
+        "deposit UTXO and STXO value ({utxo_set_sats} sats) does not match \
+         deposit history ({history_sats} sats)"
+    )]
+    DepositValue {
+        utxo_set_sats: u64,
+        history_sats: u64,
+    },
+    #[error(
+        "withdrawal STXO value ({stxos_sats} sats) for bundle {m6id} does \
+         not match bundle value ({bundle_sats} sats)"
+    )]
+    WithdrawalValue {
+        m6id: M6id,
+        stxos_sats: u64,
+        bundle_sats: u64,
+    },
+    #[error(
+        "withdrawal STXO value ({withdrawals_sats} sats) exceeds deposit \
+         value ({deposits_sats} sats)"
+    )]
+    NegativeWealth {
+        deposits_sats: u64,
+        withdrawals_sats: u64,
+    },
+    #[error(
+        "transparent UTXO value ({utxos_sats} sats) exceeds sidechain \
+         wealth ({sidechain_wealth_sats} sats)"
+    )]
+    TransparentValue {
+        utxos_sats: u64,
+        sidechain_wealth_sats: u64,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct Report {
+    pub tip: Option<BlockHash>,
+    pub height: Option<u32>,
+    /// Number of blocks for which the accumulator and Orchard frontier were
+    /// recomputed
+    pub blocks_checked: u32,
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+/// Marker file that exists while the node is running.
+/// If the marker already exists at startup, the previous shutdown was
+/// unclean.
+pub(super) struct RunningMarker(PathBuf);
+
+impl RunningMarker {
+    /// Create the marker. Returns `true` if the marker already existed.
+    pub fn create(datadir: &Path) -> Result<(Self, bool), io::Error> {
+        let path = datadir.join(RUNNING_MARKER_FILE);
+        let existed = path.try_exists()?;
+        let () = fs::write(&path, std::process::id().to_string())?;
+        Ok((Self(path), existed))
+    }
+}
+
+impl Drop for RunningMarker {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            tracing::warn!(
+                path = %self.0.display(),
+                "Failed to remove running marker: {err:#}"
+            );
+        }
+    }
+}
+
+/// Accumulator diff for a block body, excluding two way peg data
+fn body_accumulator_diff(body: &Body) -> AccumulatorDiff {
+    let mut accumulator_diff = AccumulatorDiff::default();
+    let merkle_root = body.compute_merkle_root();
+    for (vout, output) in body.coinbase.iter().enumerate() {
+        let pointed_output = PointedOutput {
+            outpoint: OutPoint::Coinbase {
+                merkle_root,
+                vout: vout as u32,
+            },
+            output: output.clone(),
+        };
+        accumulator_diff.insert((&pointed_output).into());
+    }
+    for transaction in &body.transactions {
+        let txid = transaction.txid();
+        for (_, utxo_hash) in &transaction.inputs {
+            accumulator_diff.remove(utxo_hash.into());
+        }
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            let pointed_output = PointedOutput {
+                outpoint: OutPoint::Regular {
+                    txid,
+                    vout: vout as u32,
+                },
+                output: output.clone(),
+            };
+            accumulator_diff.insert((&pointed_output).into());
+        }
+    }
+    accumulator_diff
+}
+
+/// Recompute the accumulator and Orchard frontier for the last `depth`
+/// blocks, and compare them with the header roots and stored values.
+/// Returns the number of blocks checked.
+fn verify_blocks(
+    rotxn: &RoTxn,
+    archive: &Archive,
+    state: &State,
+    tip: BlockHash,
+    height: Option<u32>,
+    depth: u32,
+    inconsistencies: &mut Vec<Inconsistency>,
+) -> Result<u32, Error> {
+    let Some(archive_height) = archive.try_get_height(rotxn, tip)? else {
+        inconsistencies.push(Inconsistency::MissingTip { tip });
+        return Ok(0);
+    };
+    if height != Some(archive_height) {
+        inconsistencies.push(Inconsistency::TipHeight {
+            state: height,
+            archive: archive_height,
+        });
+    }
+    let state_accumulator = state.get_accumulator(rotxn)?;
+    match archive.try_get_accumulator(rotxn, &Some(tip))? {
+        Some(accumulator)
+            if accumulator.get_roots() == state_accumulator.get_roots() => {}
+        _ => inconsistencies.push(Inconsistency::TipAccumulator),
+    }
+    // Blocks to check, from tip to oldest.
+    // Pruned blocks cannot be checked.
+    let block_hashes: Vec<BlockHash> = archive
+        .ancestors(rotxn, tip)
+        .take(depth as usize)
+        .take_while(|block_hash| Ok(!archive.is_pruned(rotxn, *block_hash)?))
+        .collect()?;
+    let Some(oldest) = block_hashes.last() else {
+        return Ok(0);
+    };
+    // Frontiers are only archived for blocks that changed the root, so the
+    // frontier before the oldest block is the nearest archived frontier
+    let prev_side_hash = archive.get_header(rotxn, *oldest)?.prev_side_hash;
+    let archived_frontier = match prev_side_hash {
+        Some(parent) => {
+            archive.ancestors(rotxn, parent).find_map(|block_hash| {
+                archive
+                    .orchard_frontiers()
+                    .try_get(rotxn, &Some(block_hash))
+                    .map_err(archive::Error::from)
+            })?
+        }
+        None => None,
+    };
+    let mut frontier = archived_frontier.unwrap_or_else(Frontier::empty);
+    let blocks_checked = block_hashes.len() as u32;
+    for block_hash in block_hashes.into_iter().rev() {
+        let header = archive.get_header(rotxn, block_hash)?;
+        let body = archive.get_body(rotxn, block_hash)?;
+        let height = archive.get_height(rotxn, block_hash)?;
+        let roots_match =
+            match archive.try_get_accumulator(rotxn, &header.prev_side_hash)? {
+                Some(mut accumulator) => {
+                    accumulator.apply_diff(body_accumulator_diff(&body)).is_ok()
+                        && accumulator.get_roots() == header.roots
+                }
+                None => false,
+            };
+        if !roots_match {
+            inconsistencies
+                .push(Inconsistency::HeaderRoots { block_hash, height });
+        }
+        let prev_root = frontier.root();
+        let appended = body
+            .transactions
+            .iter()
+            .filter_map(|transaction| transaction.orchard_bundle.as_ref())
+            .flat_map(|bundle| bundle.extracted_note_commitments())
+            .all(|cmx| frontier.append(cmx));
+        let root = frontier.root();
+        let frontier_matches = appended
+            && (root == prev_root || {
+                let archived_root = archive
+                    .orchard_frontiers()
+                    .try_get(rotxn, &Some(block_hash))
+                    .map_err(archive::Error::from)?
+                    .map(|frontier| frontier.root());
+                let historical_root = state
+                    .orchard
+                    .historical_roots()
+                    .contains_key(rotxn, &Anchor::from(root))
+                    .map_err(DbError::from)?;
+                archived_root == Some(root) && historical_root
+            });
+        if !frontier_matches {
+            inconsistencies
+                .push(Inconsistency::OrchardFrontier { block_hash, height });
+        }
+    }
+    let state_root = state
+        .orchard
+        .frontier()
+        .try_get(rotxn, &())
+        .map_err(DbError::from)?
+        .map(|frontier| frontier.root());
+    if state_root != Some(frontier.root()) {
+        inconsistencies.push(Inconsistency::TipOrchardFrontier);
+    }
+    Ok(blocks_checked)
+}
+
+/// Check that UTXO and STXO values agree with the deposit and withdrawal
+/// history, and that transparent value does not exceed sidechain wealth.
+fn verify_value(
+    rotxn: &RoTxn,
+    archive: &Archive,
+    state: &State,
+    tip: Option<BlockHash>,
+    inconsistencies: &mut Vec<Inconsistency>,
+) -> Result<(), Error> {
+    let mut utxo_value = bitcoin::Amount::ZERO;
+    let mut deposit_value = bitcoin::Amount::ZERO;
+    let mut withdrawal_value = bitcoin::Amount::ZERO;
+    let mut withdrawal_stxo_values = HashMap::<M6id, bitcoin::Amount>::new();
+    state
+        .utxos
+        .iter(rotxn)
+        .map_err(DbError::from)?
+        .map_err(|err| DbError::from(err).into())
+        .for_each(|(outpoint, output)| {
+            utxo_value = utxo_value
+                .checked_add(output.get_value())
+                .ok_or(AmountOverflowError)?;
+            if let OutPoint::Deposit(_) = OutPoint::from(outpoint) {
+                deposit_value = deposit_value
+                    .checked_add(output.get_value())
+                    .ok_or(AmountOverflowError)?;
+            }
+            Ok::<_, Error>(())
+        })?;
+    state
+        .stxos
+        .iter(rotxn)
+        .map_err(DbError::from)?
+        .map_err(|err| DbError::from(err).into())
+        .for_each(|(outpoint, spent_output)| {
+            let value = spent_output.output.get_value();
+            if let OutPoint::Deposit(_) = OutPoint::from(outpoint) {
+                deposit_value = deposit_value
+                    .checked_add(value)
+                    .ok_or(AmountOverflowError)?;
+            }
+            if let InPoint::Withdrawal { m6id } = spent_output.inpoint {
+                withdrawal_value = withdrawal_value
+                    .checked_add(value)
+                    .ok_or(AmountOverflowError)?;
+                let stxo_value =
+                    withdrawal_stxo_values.entry(m6id).or_default();
+                *stxo_value =
+                    stxo_value.checked_add(value).ok_or(AmountOverflowError)?;
+            }
+            Ok::<_, Error>(())
+        })?;
+    // Deposits applied up to the tip's mainchain block. Later deposits to
+    // the same outpoint replace earlier ones.
+    if let Some(tip) = tip
+        && let Some(tip_header) = archive.try_get_header(rotxn, tip)?
+    {
+        let mut deposit_outpoints = HashSet::new();
+        let mut history_value = bitcoin::Amount::ZERO;
+        archive
+            .main_ancestors(rotxn, tip_header.prev_main_hash)
+            .map_err(Error::from)
+            .for_each(|main_block_hash| {
+                let Some(block_info) =
+                    archive.try_get_main_block_info(rotxn, &main_block_hash)?
+                else {
+                    return Ok(());
+                };
+                for event in block_info.events.iter().rev() {
+                    let (outpoint, value) = match event {
+                        BlockEvent::Deposit(deposit) => {
+                            (deposit.outpoint, deposit.output.get_value())
+                        }
+                        // Shielded deposits that did not mint a note were
+                        // credited as deposit UTXOs
+                        BlockEvent::ShieldedDeposit(deposit)
+                            if !state
+                                .shielded_deposits
+                                .contains_key(rotxn, &deposit.outpoint)? =>
+                        {
+                            (deposit.outpoint, deposit.value)
+                        }
+                        BlockEvent::ShieldedDeposit(_)
+                        | BlockEvent::WithdrawalBundle(_) => continue,
+                    };
+                    if deposit_outpoints.insert(outpoint) {
+                        history_value = history_value
+                            .checked_add(value)
+                            .ok_or(AmountOverflowError)?;
+                    }
+                }
+                Ok(())
+            })?;
+        if deposit_value != history_value {
+            inconsistencies.push(Inconsistency::DepositValue {
+                utxo_set_sats: deposit_value.to_sat(),
+                history_sats: history_value.to_sat(),
+            });
+        }
+    }
+    let bundle_values = state.withdrawal_bundle_spent_values(rotxn)?;
+    let mut m6ids: Vec<M6id> = bundle_values
+        .keys()
+        .chain(withdrawal_stxo_values.keys())
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    m6ids.sort_by_key(|m6id| m6id.0);
+    for m6id in m6ids {
+        let stxos = withdrawal_stxo_values
+            .get(&m6id)
+            .copied()
+            .unwrap_or_default();
+        let bundle = bundle_values.get(&m6id).copied().unwrap_or_default();
+        if stxos != bundle {
+            inconsistencies.push(Inconsistency::WithdrawalValue {
+                m6id,
+                stxos_sats: stxos.to_sat(),
+                bundle_sats: bundle.to_sat(),
+            });
+        }
+    }
+    match deposit_value.checked_sub(withdrawal_value) {
+        Some(sidechain_wealth) if utxo_value > sidechain_wealth => {
+            inconsistencies.push(Inconsistency::TransparentValue {
+                utxos_sats: utxo_value.to_sat(),
+                sidechain_wealth_sats: sidechain_wealth.to_sat(),
+            });
+        }
+        Some(_) => (),
+        None => inconsistencies.push(Inconsistency::NegativeWealth {
+            deposits_sats: deposit_value.to_sat(),
+            withdrawals_sats: withdrawal_value.to_sat(),
+        }),
+    }
+    Ok(())
+}
+
+/// Verify the last `depth` blocks of the current chain, and the UTXO set.
+pub fn verify_chain(
+    rotxn: &RoTxn,
+    archive: &Archive,
+    state: &State,
+    depth: u32,
+) -> Result<Report, Error> {
+    let tip = state.try_get_tip(rotxn).map_err(state::Error::from)?;
+    let height = state.try_get_height(rotxn).map_err(state::Error::from)?;
+    let mut inconsistencies = Vec::new();
+    let blocks_checked = match tip {
+        Some(tip) => verify_blocks(
+            rotxn,
+            archive,
+            state,
+            tip,
+            height,
+            depth,
+            &mut inconsistencies,
+        )?,
+        None => 0,
+    };
+    let () = verify_value(rotxn, archive, state, tip, &mut inconsistencies)?;
+    Ok(Report {
+        tip,
+        height,
+        blocks_checked,
+        inconsistencies,
+    })
+}
+
+fn log_report(report: &Report) {
+    for inconsistency in &report.inconsistencies {
+        tracing::error!(
+            tip = ?report.tip,
+            height = ?report.height,
+            "Chain inconsistency: {inconsistency}"
+        );
+    }
+}
+
+/// Verify the chain at startup, and recover according to the config if an
+/// inconsistency is found.
+pub(super) fn startup_check(
+    env: &sneed::Env,
+    archive: &Archive,
+    mempool: &MemPool,
+    state: &State,
+    config: Config,
+    unclean_shutdown: bool,
+) -> Result<(), Error> {
+    let depth = if unclean_shutdown {
+        tracing::warn!(
+            "Previous shutdown was unclean, verifying at least \
+             {UNCLEAN_SHUTDOWN_DEPTH} blocks"
+        );
+        config.depth.max(UNCLEAN_SHUTDOWN_DEPTH)
+    } else {
+        config.depth
+    };
+    let report = {
+        let rotxn = env.read_txn().map_err(EnvError::from)?;
+        verify_chain(&rotxn, archive, state, depth)?
+    };
+    if report.is_consistent() {
+        tracing::info!(
+            tip = ?report.tip,
+            height = ?report.height,
+            "Verified {} blocks",
+            report.blocks_checked
+        );
+        return Ok(());
+    }
+    let () = log_report(&report);
+    match config.recovery {
+        Recovery::None => {
+            tracing::error!(
+                "Chain is inconsistent; restart with rollback or reindex \
+                 recovery to repair"
+            );
+            return Ok(());
+        }
+        Recovery::Rollback => {
+            match net_task::rollback(
+                env,
+                archive,
+                mempool,
+                state,
+                report.blocks_checked,
+            ) {
+                Ok(()) => {
+                    let rotxn = env.read_txn().map_err(EnvError::from)?;
+                    let report = verify_chain(&rotxn, archive, state, depth)?;
+                    if report.is_consistent() {
+                        tracing::info!(
+                            tip = ?report.tip,
+                            "Rollback repaired chain"
+                        );
+                        return Ok(());
+                    }
+                    let () = log_report(&report);
+                }
+                Err(err) => {
+                    let err = anyhow::Error::from(err);
+                    tracing::error!("Rollback failed: {err:#}");
+                }
+            }
+            tracing::warn!("Rollback did not repair chain, reindexing");
+        }
+        Recovery::Reindex => (),
+    }
+    let _: Option<BlockHash> = net_task::reindex(env, archive, mempool, state)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash as _;
+
+    use super::{Inconsistency, verify_chain};
+    use crate::{
+        node::net_task::test::{TestChain, connected_chain},
+        state::test::value_output,
+        types::{Accumulator, OutPoint, OutPointKey, TransparentAddress},
+    };
+
+    #[test]
+    fn consistent_chain() -> anyhow::Result<()> {
+        let TestChain {
+            env,
+            archive,
+            state,
+            ..
+        } = connected_chain("verify-consistent-chain", 3)?;
+        let rotxn = env.read_txn()?;
+        let report = verify_chain(&rotxn, &archive, &state, 2)?;
+        anyhow::ensure!(report.is_consistent(), "{report:?}");
+        anyhow::ensure!(report.blocks_checked == 2);
+        anyhow::ensure!(report.height == Some(2));
+        // Depth is limited by the chain length
+        let report = verify_chain(&rotxn, &archive, &state, 10)?;
+        anyhow::ensure!(report.is_consistent(), "{report:?}");
+        anyhow::ensure!(report.blocks_checked == 3);
+        Ok(())
+    }
+
+    #[test]
+    fn inconsistent_chain() -> anyhow::Result<()> {
+        let TestChain {
+            env,
+            archive,
+            state,
+            ..
+        } = connected_chain("verify-inconsistent-chain", 3)?;
+        let mut rwtxn = env.write_txn()?;
+        let tip = state.try_get_tip(&rwtxn)?.unwrap();
+        // Lose the accumulator update for the tip, and a deposit UTXO
+        archive.put_accumulator(&mut rwtxn, tip, &Accumulator::default())?;
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([1; 32]),
+            vout: 0,
+        });
+        anyhow::ensure!(
+            state
+                .utxos
+                .delete(&mut rwtxn, &OutPointKey::from(&deposit))?
+        );
+        // Create transparent value from nothing
+        let regular = OutPoint::Regular {
+            txid: [2; 32].into(),
+            vout: 0,
+        };
+        state.utxos.put(
+            &mut rwtxn,
+            &OutPointKey::from(&regular),
+            &value_output(TransparentAddress::ALL_ZEROS, 1000),
+        )?;
+        let report = verify_chain(&rwtxn, &archive, &state, 3)?;
+        anyhow::ensure!(
+            report.inconsistencies
+                == [
+                    Inconsistency::TipAccumulator,
+                    Inconsistency::DepositValue {
+                        utxo_set_sats: 5000,
+                        history_sats: 6000,
+                    },
+                    Inconsistency::TransparentValue {
+                        utxos_sats: 6000,
+                        sidechain_wealth_sats: 5000,
+                    },
+                ],
+            "{report:?}"
+        );
+        Ok(())
+    }
+}
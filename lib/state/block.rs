@@ -5,7 +5,10 @@ use rustreexo::accumulator::node_hash::BitcoinNodeHash;
 use sneed::{RoTxn, RwTxn};
 
 use crate::{
-    state::{Error, PrevalidatedBlock, State, error},
+    state::{
+        Error, PrevalidatedBlock, State, error,
+        orchard::value_balance as orchard_value_balance,
+    },
     types::{
         Accumulator, AccumulatorDiff, AmountOverflowError, Body, GetValue as _,
         Header, InPoint, OutPoint, OutPointKey, Output, PointedOutput,
@@ -88,6 +91,21 @@ pub fn validate(
     if all_input_keys.windows(2).any(|w| w[0] == w[1]) {
         return Err(Error::UtxoDoubleSpent);
     }
+    let () = state.validate_block_withdrawal_spends(
+        rotxn,
+        height,
+        &filled_transactions,
+    )?;
+    let () = state.validate_block_withdrawal_outputs(
+        height,
+        body.coinbase.iter().chain(
+            body.transactions
+                .iter()
+                .flat_map(|transaction| &transaction.outputs),
+        ),
+    )?;
+    let _: bitcoin::Amount =
+        state.validate_shielded_pool_value(rotxn, &body.transactions)?;
 
     // Process transactions for utreexo and fee validation
     for filled_transaction in &filled_transactions {
@@ -222,6 +240,21 @@ pub fn prevalidate(
     if all_input_keys.windows(2).any(|w| w[0] == w[1]) {
         return Err(Error::UtxoDoubleSpent);
     }
+    let () = state.validate_block_withdrawal_spends(
+        rotxn,
+        height,
+        &filled_transactions,
+    )?;
+    let () = state.validate_block_withdrawal_outputs(
+        height,
+        body.coinbase.iter().chain(
+            body.transactions
+                .iter()
+                .flat_map(|transaction| &transaction.outputs),
+        ),
+    )?;
+    let _: bitcoin::Amount =
+        state.validate_shielded_pool_value(rotxn, &body.transactions)?;
 
     // Process transactions for utreexo and fee validation
     for filled_transaction in &filled_transactions {
@@ -324,8 +357,17 @@ pub fn connect_prevalidated(
             merkle_root,
             vout: vout as u32,
         };
-        let key = OutPointKey::from(outpoint);
-        utxo_puts.push((key, output.clone()));
+        if output.content.is_withdrawal() {
+            state.withdrawal_heights.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                &prevalidated.next_height,
+            )?;
+        }
+        if state.stores_utxo(&outpoint, output) {
+            let key = OutPointKey::from(outpoint);
+            utxo_puts.push((key, output.clone()));
+        }
     }
 
     // Collect TX mutations
@@ -339,16 +381,19 @@ pub fn connect_prevalidated(
             let spent_utxo = &filled_transaction.spent_utxos[vin];
             let key = OutPointKey::from(outpoint);
             utxo_deletes.push(key);
-            stxo_puts.push((
-                key,
-                SpentOutput {
-                    output: spent_utxo.clone(),
-                    inpoint: InPoint::Regular {
-                        txid,
-                        vin: vin as u32,
-                    },
+            if spent_utxo.content.is_withdrawal() {
+                state.spend_withdrawal_height(rwtxn, &key)?;
+            }
+            let spent_output = SpentOutput {
+                output: spent_utxo.clone(),
+                inpoint: InPoint::Regular {
+                    txid,
+                    vin: vin as u32,
                 },
-            ));
+            };
+            if state.stores_stxo(outpoint, &spent_output) {
+                stxo_puts.push((key, spent_output));
+            }
         }
 
         // Outputs: create UTXOs
@@ -359,8 +404,17 @@ pub fn connect_prevalidated(
                 txid,
                 vout: vout as u32,
             };
-            let key = OutPointKey::from(outpoint);
-            utxo_puts.push((key, output.clone()));
+            if output.content.is_withdrawal() {
+                state.withdrawal_heights.put(
+                    rwtxn,
+                    &OutPointKey::from(outpoint),
+                    &prevalidated.next_height,
+                )?;
+            }
+            if state.stores_utxo(&outpoint, output) {
+                let key = OutPointKey::from(outpoint);
+                utxo_puts.push((key, output.clone()));
+            }
         }
 
         // Handle orchard bundle if present
@@ -403,6 +457,9 @@ pub fn connect_prevalidated(
     let () = accumulator.apply_diff(accumulator_diff)?;
     state.utreexo_accumulator.put(rwtxn, &(), &accumulator)?;
     let () = state.orchard.put_frontier(rwtxn, &frontier)?;
+    let value_balance = orchard_value_balance(&body.transactions)
+        .map_err(error::Orchard::from)?;
+    let () = state.orchard.apply_value_balance(rwtxn, value_balance)?;
     let root_changed: bool = state.orchard.put_historical_root(
         rwtxn,
         block_hash,
@@ -443,19 +500,23 @@ fn connect_transaction(
     state: &State,
     rwtxn: &mut RwTxn,
     transaction: &Transaction,
+    height: u32,
     accumulator_diff: &mut AccumulatorDiff,
     frontier: &mut orchard::Frontier,
 ) -> Result<(), error::ConnectTransaction> {
     let txid = transaction.txid();
     for (vin, (outpoint, utxo_hash)) in transaction.inputs.iter().enumerate() {
         let spent_output = state
-            .utxos
-            .try_get(rwtxn, &OutPointKey::from(outpoint))?
+            .try_get_unspent_output(rwtxn, outpoint)?
             .ok_or(error::NoUtxo {
                 outpoint: *outpoint,
             })?;
         accumulator_diff.remove(utxo_hash.into());
         state.utxos.delete(rwtxn, &OutPointKey::from(outpoint))?;
+        if spent_output.content.is_withdrawal() {
+            state
+                .spend_withdrawal_height(rwtxn, &OutPointKey::from(outpoint))?;
+        }
         let spent_output = SpentOutput {
             output: spent_output,
             inpoint: InPoint::Regular {
@@ -463,9 +524,13 @@ fn connect_transaction(
                 vin: vin as u32,
             },
         };
-        state
-            .stxos
-            .put(rwtxn, &OutPointKey::from(outpoint), &spent_output)?;
+        if state.stores_stxo(outpoint, &spent_output) {
+            state.stxos.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                &spent_output,
+            )?;
+        }
     }
     for (vout, output) in transaction.outputs.iter().enumerate() {
         let outpoint = OutPoint::Regular {
@@ -477,8 +542,17 @@ fn connect_transaction(
             output: output.clone(),
         };
         accumulator_diff.insert((&pointed_output).into());
-        let key = OutPointKey::from(outpoint);
-        state.utxos.put(rwtxn, &key, output)?;
+        if output.content.is_withdrawal() {
+            state.withdrawal_heights.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                &height,
+            )?;
+        }
+        if state.stores_utxo(&outpoint, output) {
+            let key = OutPointKey::from(outpoint);
+            state.utxos.put(rwtxn, &key, output)?;
+        }
     }
     if let Some(orchard_bundle) = transaction.orchard_bundle.as_ref() {
         let () = connect_orchard(state, rwtxn, orchard_bundle, frontier)?;
@@ -517,6 +591,7 @@ pub fn connect(
         .frontier()
         .get(rwtxn, &())
         .map_err(error::Orchard::from)?;
+    let height = state.try_get_height(rwtxn)?.map_or(0, |height| height + 1);
     for (vout, output) in body.coinbase.iter().enumerate() {
         let outpoint = OutPoint::Coinbase {
             merkle_root,
@@ -527,14 +602,24 @@ pub fn connect(
             output: output.clone(),
         };
         accumulator_diff.insert((&pointed_output).into());
-        let key = OutPointKey::from(outpoint);
-        state.utxos.put(rwtxn, &key, output)?;
+        if output.content.is_withdrawal() {
+            state.withdrawal_heights.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                &height,
+            )?;
+        }
+        if state.stores_utxo(&outpoint, output) {
+            let key = OutPointKey::from(outpoint);
+            state.utxos.put(rwtxn, &key, output)?;
+        }
     }
     for transaction in &body.transactions {
         let () = connect_transaction(
             state,
             rwtxn,
             transaction,
+            height,
             &mut accumulator_diff,
             &mut frontier,
         )
@@ -544,12 +629,14 @@ pub fn connect(
         })?;
     }
     let block_hash = header.hash();
-    let height = state.try_get_height(rwtxn)?.map_or(0, |height| height + 1);
     state.tip.put(rwtxn, &(), &block_hash)?;
     state.height.put(rwtxn, &(), &height)?;
     let () = accumulator.apply_diff(accumulator_diff)?;
     state.utreexo_accumulator.put(rwtxn, &(), &accumulator)?;
     let () = state.orchard.put_frontier(rwtxn, &frontier)?;
+    let value_balance = orchard_value_balance(&body.transactions)
+        .map_err(error::Orchard::from)?;
+    let () = state.orchard.apply_value_balance(rwtxn, value_balance)?;
     let root_changed: bool = state.orchard.put_historical_root(
         rwtxn,
         block_hash,
@@ -596,7 +683,14 @@ fn disconnect_transaction(
                 output: output.clone(),
             };
             accumulator_diff.remove((&pointed_output).into());
-            if state.utxos.delete(rwtxn, &OutPointKey::from(outpoint))? {
+            if output.content.is_withdrawal() {
+                let _: bool = state
+                    .withdrawal_heights
+                    .delete(rwtxn, &OutPointKey::from(outpoint))?;
+            }
+            if state.utxos.delete(rwtxn, &OutPointKey::from(outpoint))?
+                || !state.stores_utxo(&outpoint, output)
+            {
                 Ok::<_, Error>(())
             } else {
                 Err(error::NoUtxo { outpoint }.into())
@@ -619,6 +713,17 @@ fn disconnect_transaction(
                     &OutPointKey::from(outpoint),
                     &spent_output.output,
                 )?;
+                if spent_output.output.content.is_withdrawal() {
+                    state.unspend_withdrawal_height(
+                        rwtxn,
+                        &OutPointKey::from(outpoint),
+                    )?;
+                }
+                Ok(())
+            } else if state.is_compact() {
+                // Regular STXOs are not stored in compact mode, and cannot
+                // be restored as UTXOs
+                accumulator_diff.insert(utxo_hash.into());
                 Ok(())
             } else {
                 Err(Error::NoStxo {
@@ -674,7 +779,14 @@ pub fn disconnect_tip(
                 output: output.clone(),
             };
             accumulator_diff.remove((&pointed_output).into());
-            if state.utxos.delete(rwtxn, &OutPointKey::from(outpoint))? {
+            if output.content.is_withdrawal() {
+                let _: bool = state
+                    .withdrawal_heights
+                    .delete(rwtxn, &OutPointKey::from(outpoint))?;
+            }
+            if state.utxos.delete(rwtxn, &OutPointKey::from(outpoint))?
+                || !state.stores_utxo(&outpoint, output)
+            {
                 Ok::<_, Error>(())
             } else {
                 Err(error::NoUtxo { outpoint }.into())
@@ -704,6 +816,9 @@ pub fn disconnect_tip(
         state.orchard.put_frontier(rwtxn, frontier)?;
     }
     let _: bool = state.orchard.delete_historical_root(rwtxn, tip_hash)?;
+    // Return value removed from the shielded pool by the block
+    let value_balance = orchard_value_balance(&body.transactions)?;
+    let () = state.orchard.apply_value_balance(rwtxn, -value_balance)?;
     Ok(())
 }
 
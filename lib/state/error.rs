@@ -4,7 +4,8 @@ use transitive::Transitive;
 
 use crate::types::{
     AmountOverflowError, AmountUnderflowError, BlockHash, M6id, MerkleRoot,
-    OutPoint, Txid, UtreexoError, WithdrawalBundleError, orchard,
+    OutPoint, SnapshotHash, Txid, UtreexoError, WithdrawalBundleError, orchard,
+    withdrawal_policy,
 };
 
 #[derive(Debug, Error)]
@@ -34,12 +35,15 @@ pub enum InvalidHeader {
 
 #[allow(clippy::duplicated_attributes)]
 #[derive(Debug, Error, Transitive)]
+#[transitive(from(db::Clear, db::Error))]
 #[transitive(from(db::Delete, db::Error))]
 #[transitive(from(db::Get, db::Error))]
 #[transitive(from(db::Last, db::Error))]
 #[transitive(from(db::Put, db::Error))]
 #[transitive(from(db::TryGet, db::Error))]
 pub enum Orchard {
+    #[error(transparent)]
+    AmountOverflow(#[from] AmountOverflowError),
     #[error("Cannot append commitment to frontier: would exceed max depth")]
     AppendCommitment,
     #[error(transparent)]
@@ -52,6 +56,14 @@ pub enum Orchard {
     MissingNullifier { nullifier: orchard::Nullifier },
     #[error("Nullifier double spent (`{nullifier}`)")]
     NullifierDoubleSpent { nullifier: orchard::Nullifier },
+    #[error(
+        "Shielded pool value ({pool_value}) is less than the value removed \
+         from the pool ({value_out})"
+    )]
+    NegativePoolValue {
+        pool_value: bitcoin::Amount,
+        value_out: bitcoin::Amount,
+    },
 }
 
 impl From<db::Error> for Orchard {
@@ -166,6 +178,12 @@ pub enum ConnectWithdrawalBundleSubmitted {
     NoUtxo(#[from] NoUtxo),
     #[error(transparent)]
     PendingWithdrawalBundleUnknown(#[from] PendingWithdrawalBundleUnknown),
+    #[error(
+        "expected {expected} relayed spent outputs, but received {received}"
+    )]
+    RelayedSpentOutputs { expected: usize, received: usize },
+    #[error("snapshot error")]
+    Snapshot(#[from] Snapshot),
     #[error(
         "withdrawal bundle {} submitted in {} resubmitted in {}",
         m6id,
@@ -212,6 +230,29 @@ impl From<db::Error> for ConnectWithdrawalBundleSubmitted {
     }
 }
 
+#[derive(Debug, Error)]
+pub enum Snapshot {
+    #[error("snapshot (de)serialization error")]
+    Bincode(#[from] bincode::Error),
+    #[error("cannot create a snapshot of a compact state")]
+    CompactState,
+    #[error("snapshot hash mismatch: expected {expected}, computed {computed}")]
+    HashMismatch {
+        expected: SnapshotHash,
+        computed: SnapshotHash,
+    },
+    #[error("invalid snapshot file (bad magic bytes)")]
+    InvalidMagic,
+    #[error("snapshot I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("cannot load a snapshot into a non-empty state (tip {tip})")]
+    StateNotEmpty { tip: BlockHash },
+    #[error("snapshot {hash} is not trusted for this network")]
+    Untrusted { hash: SnapshotHash },
+    #[error("unsupported snapshot format version ({version})")]
+    UnsupportedVersion { version: u32 },
+}
+
 #[allow(clippy::duplicated_attributes)]
 #[derive(Debug, Error, Transitive)]
 #[transitive(
@@ -243,6 +284,10 @@ pub enum Error {
     BodyTooLarge,
     #[error(transparent)]
     BorshSerialize(borsh::io::Error),
+    #[error(
+        "state was created with compact mode {stored}, and cannot be opened with compact mode {requested}"
+    )]
+    CompactStateMismatch { stored: bool, requested: bool },
     #[error("failed to connect block")]
     ConnectBlock(#[from] ConnectBlock),
     #[error(transparent)]
@@ -250,6 +295,8 @@ pub enum Error {
     #[error(transparent)]
     Db(Box<sneed::Error>),
     #[error(transparent)]
+    DepositNote(#[from] orchard::DepositNoteError),
+    #[error(transparent)]
     InvalidBody(InvalidBody),
     #[error("invalid header: {0}")]
     InvalidHeader(InvalidHeader),
@@ -257,6 +304,8 @@ pub enum Error {
     NoDepositBlock,
     #[error("total fees less than coinbase value")]
     NotEnoughFees,
+    #[error("shielded deposit {outpoint} doesn't exist")]
+    NoShieldedDeposit { outpoint: bitcoin::OutPoint },
     #[error("no tip")]
     NoTip,
     #[error("stxo {outpoint} doesn't exist")]
@@ -271,6 +320,12 @@ pub enum Error {
     Orchard(#[from] Orchard),
     #[error(transparent)]
     PendingWithdrawalBundleUnknown(#[from] PendingWithdrawalBundleUnknown),
+    #[error(
+        "expected {expected} relayed spent outputs, but received {received}"
+    )]
+    RelayedSpentOutputs { expected: usize, received: usize },
+    #[error("snapshot error")]
+    Snapshot(#[from] Snapshot),
     #[error(transparent)]
     Utreexo(#[from] UtreexoError),
     #[error("Utreexo proof verification failed for tx {txid}")]
@@ -305,6 +360,12 @@ pub enum Error {
     },
     #[error("Unknown withdrawal bundle: {m6id}")]
     UnknownWithdrawalBundle { m6id: M6id },
+    #[error(
+        "withdrawal {outpoint} is included in the pending withdrawal bundle, and cannot be cancelled"
+    )]
+    WithdrawalBundled { outpoint: OutPoint },
+    #[error(transparent)]
+    WithdrawalPolicy(#[from] withdrawal_policy::Error),
     #[error(
         "Unknown withdrawal bundle confirmed in {event_block_hash}: {m6id}"
     )]
@@ -0,0 +1,325 @@
+//! Migration steps for the state DBs.
+//! Steps open the DBs that they need by name, and rebuild data from the
+//! archive, since they run before the state is opened.
+
+use std::collections::{HashMap, HashSet};
+
+use fallible_iterator::FallibleIterator as _;
+use heed::types::SerdeBincode;
+use sneed::{DatabaseUnique, RoTxn, RwTxn, UnitKey};
+
+use crate::{
+    archive::{self, Archive},
+    migration,
+    state::{self, orchard},
+    types::{
+        AmountOverflowError, BlockHash, MerkleRoot, OutPoint, OutPointKey,
+        Output, Txid,
+    },
+};
+
+/// Tip of the state, if any
+fn try_get_tip(
+    env: &sneed::Env,
+    rwtxn: &mut RwTxn,
+) -> Result<Option<BlockHash>, migration::Error> {
+    let tip: DatabaseUnique<UnitKey, SerdeBincode<BlockHash>> =
+        DatabaseUnique::create(env, rwtxn, "tip")?;
+    let res = tip.try_get(rwtxn, &())?;
+    Ok(res)
+}
+
+/// Create the shielded deposits DB.
+/// Software that predates this DB credited every shielded deposit as a
+/// transparent deposit UTXO, so no existing state has applied any shielded
+/// deposit as an Orchard note, and there is nothing to backfill.
+pub(super) fn create_shielded_deposits(
+    env: &sneed::Env,
+    rwtxn: &mut RwTxn,
+) -> Result<(), migration::Error> {
+    let _shielded_deposits: DatabaseUnique<
+        SerdeBincode<bitcoin::OutPoint>,
+        SerdeBincode<bitcoin::Amount>,
+    > = DatabaseUnique::create(env, rwtxn, "shielded_deposits")?;
+    Ok(())
+}
+
+/// Find the height of the block that created each of the specified
+/// withdrawal outputs, by walking back from the tip
+fn find_withdrawal_heights(
+    archive: &Archive,
+    rotxn: &RoTxn,
+    tip: BlockHash,
+    mut coinbase_withdrawals: HashMap<MerkleRoot, Vec<OutPointKey>>,
+    mut tx_withdrawals: HashMap<Txid, Vec<OutPointKey>>,
+) -> Result<Vec<(OutPointKey, u32)>, archive::Error> {
+    let mut block_txids = HashMap::<BlockHash, Vec<Txid>>::new();
+    // Txs for which inclusions were pruned, and must be found in bodies
+    let mut unindexed_txids = HashSet::<Txid>::new();
+    for txid in tx_withdrawals.keys() {
+        let inclusions = archive.get_tx_inclusions(rotxn, *txid)?;
+        if inclusions.is_empty() {
+            unindexed_txids.insert(*txid);
+        }
+        for block_hash in inclusions.into_keys() {
+            block_txids.entry(block_hash).or_default().push(*txid);
+        }
+    }
+    let mut res = Vec::new();
+    let mut ancestors = archive.ancestor_headers(rotxn, tip);
+    while !(coinbase_withdrawals.is_empty() && tx_withdrawals.is_empty()) {
+        let Some((block_hash, header)) = ancestors.next()? else {
+            break;
+        };
+        let height = archive.get_height(rotxn, block_hash)?;
+        let mut outpoints = coinbase_withdrawals
+            .remove(&header.merkle_root)
+            .unwrap_or_default();
+        let mut txids = block_txids.remove(&block_hash).unwrap_or_default();
+        if !unindexed_txids.is_empty()
+            && let Some(body) = archive.try_get_body(rotxn, block_hash)?
+        {
+            txids.extend(
+                body.transactions
+                    .iter()
+                    .map(|tx| tx.txid())
+                    .filter(|txid| unindexed_txids.remove(txid)),
+            );
+        }
+        for txid in txids {
+            outpoints.extend(tx_withdrawals.remove(&txid).unwrap_or_default());
+        }
+        res.extend(outpoints.into_iter().map(|outpoint| (outpoint, height)));
+    }
+    Ok(res)
+}
+
+/// Record the height at which each unspent withdrawal output was created,
+/// so that bundles prioritize withdrawals by age consistently across nodes
+pub(super) fn backfill_withdrawal_heights(
+    env: &sneed::Env,
+    rwtxn: &mut RwTxn,
+) -> Result<(), migration::Error> {
+    let utxos: DatabaseUnique<OutPointKey, SerdeBincode<Output>> =
+        DatabaseUnique::create(env, rwtxn, "utxos")?;
+    let withdrawal_heights: DatabaseUnique<OutPointKey, SerdeBincode<u32>> =
+        DatabaseUnique::create(env, rwtxn, "withdrawal_heights")?;
+    let withdrawal_outpoints: Vec<OutPointKey> = utxos
+        .iter(rwtxn)?
+        .filter(|(_, output)| Ok(output.content.is_withdrawal()))
+        .map(|(outpoint, _)| Ok(outpoint))
+        .collect()?;
+    let mut coinbase_withdrawals =
+        HashMap::<MerkleRoot, Vec<OutPointKey>>::new();
+    let mut tx_withdrawals = HashMap::<Txid, Vec<OutPointKey>>::new();
+    for outpoint_key in withdrawal_outpoints {
+        if withdrawal_heights.try_get(rwtxn, &outpoint_key)?.is_some() {
+            continue;
+        }
+        match OutPoint::from(outpoint_key) {
+            OutPoint::Regular { txid, vout: _ } => {
+                tx_withdrawals.entry(txid).or_default().push(outpoint_key)
+            }
+            OutPoint::Coinbase {
+                merkle_root,
+                vout: _,
+            } => coinbase_withdrawals
+                .entry(merkle_root)
+                .or_default()
+                .push(outpoint_key),
+            // Deposits cannot create withdrawal outputs
+            OutPoint::Deposit(_) => (),
+        }
+    }
+    let n_pending: usize = coinbase_withdrawals
+        .values()
+        .chain(tx_withdrawals.values())
+        .map(Vec::len)
+        .sum();
+    if n_pending == 0 {
+        return Ok(());
+    }
+    let missing_data = |n_missing: usize| migration::Error::MissingData {
+        db_path: env.path().to_path_buf(),
+        reason: format!(
+            "creation heights for {n_missing} withdrawal outputs were not \
+             found in the archive"
+        ),
+    };
+    let Some(tip) = try_get_tip(env, rwtxn)? else {
+        return Err(missing_data(n_pending));
+    };
+    let archive = Archive::open(env, rwtxn)?;
+    let heights = find_withdrawal_heights(
+        &archive,
+        rwtxn,
+        tip,
+        coinbase_withdrawals,
+        tx_withdrawals,
+    )?;
+    if heights.len() != n_pending {
+        return Err(missing_data(n_pending - heights.len()));
+    }
+    for (outpoint_key, height) in heights {
+        withdrawal_heights.put(rwtxn, &outpoint_key, &height)?;
+    }
+    Ok(())
+}
+
+/// Rebuild the shielded pool value from the shielded deposits that were
+/// applied to the state, and the value balances of the bodies in the tip's
+/// ancestry
+pub(super) fn rebuild_orchard_pool_value(
+    env: &sneed::Env,
+    rwtxn: &mut RwTxn,
+) -> Result<(), migration::Error> {
+    let shielded_deposits: DatabaseUnique<
+        SerdeBincode<bitcoin::OutPoint>,
+        SerdeBincode<bitcoin::Amount>,
+    > = DatabaseUnique::create(env, rwtxn, "shielded_deposits")?;
+    let pool_value_db: DatabaseUnique<UnitKey, SerdeBincode<bitcoin::Amount>> =
+        DatabaseUnique::create(env, rwtxn, "orchard_pool_value")?;
+    let mut pool_value = bitcoin::Amount::ZERO;
+    if let Some(tip) = try_get_tip(env, rwtxn)? {
+        let deposit_values: Vec<bitcoin::Amount> = shielded_deposits
+            .iter(rwtxn)?
+            .map(|(_, value)| Ok(value))
+            .collect()?;
+        for value in deposit_values {
+            pool_value = pool_value
+                .checked_add(value)
+                .ok_or(state::Error::from(AmountOverflowError))?;
+        }
+        let archive = Archive::open(env, rwtxn)?;
+        let block_hashes: Vec<BlockHash> =
+            archive.ancestors(rwtxn, tip).collect()?;
+        // Deposits are credited before any value balances are applied, so
+        // the pool value cannot become negative part-way through the rebuild
+        for block_hash in block_hashes {
+            let Some(body) = archive.try_get_body(rwtxn, block_hash)? else {
+                return Err(migration::Error::MissingData {
+                    db_path: env.path().to_path_buf(),
+                    reason: format!(
+                        "body for block {block_hash} was not found in the \
+                         archive"
+                    ),
+                });
+            };
+            let value_balance = orchard::value_balance(&body.transactions)
+                .map_err(state::Error::from)?;
+            pool_value =
+                orchard::apply_value_balance(pool_value, value_balance)
+                    .map_err(state::Error::from)?;
+        }
+    }
+    pool_value_db.put(rwtxn, &(), &pool_value)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash as _;
+    use hashlink::LinkedHashMap;
+    use heed::types::SerdeBincode;
+    use sneed::{DatabaseUnique, UnitKey};
+
+    use crate::{
+        archive::Archive,
+        migration,
+        state::{State, test::temp_env_path},
+        types::{
+            Body, Header, Network, ShieldedAddress, Version,
+            orchard::{FullViewingKey, Scope, SpendingKey},
+            proto::mainchain::{
+                BlockEvent, BlockInfo, ShieldedDeposit, TwoWayPegData,
+            },
+        },
+    };
+
+    // a state that was created before the pool value was tracked recovers
+    // its pool value from the archive
+    #[test]
+    fn pool_value_is_rebuilt() -> anyhow::Result<()> {
+        let path = temp_env_path("pool_value_is_rebuilt")?;
+        std::fs::create_dir_all(&path)?;
+        let mut opts = heed::EnvOpenOptions::new();
+        opts.map_size(64 * 1024 * 1024)
+            .max_dbs(State::NUM_DBS + Archive::NUM_DBS);
+        let env = unsafe { sneed::Env::open(&opts, &path) }?;
+        let state = State::new(&env, false, Network::Regtest)?;
+        let archive = Archive::new(&env)?;
+
+        let body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let main1 = bitcoin::BlockHash::from_byte_array([11; 32]);
+        let header = Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash: None,
+            prev_main_hash: main1,
+            roots: Vec::new(),
+        };
+        let sk = SpendingKey::from_bytes([7; 32])
+            .into_option()
+            .ok_or_else(|| anyhow::anyhow!("invalid spending key"))?;
+        let deposit = ShieldedDeposit {
+            tx_index: 0,
+            outpoint: bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_byte_array([3; 32]),
+                vout: 1,
+            },
+            address: ShieldedAddress(
+                FullViewingKey::from(&sk).address_at(0u32, Scope::External),
+            ),
+            value: bitcoin::Amount::from_sat(1000),
+        };
+        let two_way_peg_data = {
+            let mut block_info = LinkedHashMap::new();
+            block_info.insert(
+                main1,
+                BlockInfo {
+                    bmm_commitment: None,
+                    events: vec![BlockEvent::ShieldedDeposit(deposit.clone())],
+                },
+            );
+            TwoWayPegData { block_info }
+        };
+        let mut rwtxn = env.write_txn()?;
+        archive.put_header(&mut rwtxn, &header)?;
+        archive.put_body(&mut rwtxn, header.hash(), &body)?;
+        state.apply_block(&mut rwtxn, &header, &body)?;
+        state.connect_two_way_peg_data(&mut rwtxn, &two_way_peg_data)?;
+        anyhow::ensure!(
+            state.get_shielded_pool_value(&rwtxn)? == deposit.value
+        );
+        // Simulate a state written by software that did not track the pool
+        // value
+        state.orchard.pool_value().delete(&mut rwtxn, &())?;
+        let version_db: DatabaseUnique<UnitKey, SerdeBincode<Version>> =
+            DatabaseUnique::create(&env, &mut rwtxn, State::SCHEMA.version_db)?;
+        version_db.put(
+            &mut rwtxn,
+            &(),
+            &Version {
+                major: 0,
+                minor: 16,
+                patch: 2,
+            },
+        )?;
+        rwtxn.commit()?;
+
+        let report = migration::migrate(
+            &env,
+            &[State::SCHEMA],
+            migration::Config::default(),
+        )?;
+        anyhow::ensure!(report.n_steps() == 1);
+        let rotxn = env.read_txn()?;
+        anyhow::ensure!(
+            state.get_shielded_pool_value(&rotxn)? == deposit.value
+        );
+        Ok(())
+    }
+}
@@ -12,13 +12,17 @@ use sneed::{
 };
 
 use crate::{
+    migration,
     types::{
         self, Accumulator, AmountOverflowError, AmountUnderflowError,
         AuthorizedTransaction, BlockHash, Body, FilledTransaction, GetValue,
-        Header, InPoint, M6id, MerkleRoot, OutPoint, OutPointKey, Output,
-        PointedOutput, PointedOutputRef, SpentOutput, Transaction,
-        TransparentAddress, VERSION, Version, WithdrawalBundle,
-        WithdrawalBundleStatus, proto::mainchain::TwoWayPegData,
+        Header, InPoint, M6id, MerkleRoot, Network, OutPoint, OutPointKey,
+        Output, PointedOutput, PointedOutputRef, SpentOutput, Transaction,
+        TransparentAddress, Version, WithdrawalBundle, WithdrawalBundleHistory,
+        WithdrawalBundleInclusion, WithdrawalBundleStatus,
+        WithdrawalBundleStatusTransition, WithdrawalBundleStatusUpdate,
+        proto::mainchain::{ShieldedDeposit, TwoWayPegData},
+        withdrawal_policy,
     },
     util::Watchable,
     wallet::Authorization,
@@ -26,18 +30,83 @@ use crate::{
 
 mod block;
 mod error;
+mod migrations;
 mod orchard;
 #[cfg(test)]
 mod orchard_anchor_tests;
 mod rollback;
+pub mod snapshot;
 mod two_way_peg_data;
 
-pub use error::Error;
+pub use error::{Error, Snapshot as SnapshotError};
 pub use orchard::Orchard;
 use rollback::RollBack;
+pub use snapshot::Snapshot;
 
 pub const WITHDRAWAL_BUNDLE_FAILURE_GAP: u32 = 4;
 
+/// Number of blocks after which a pending withdrawal's effective mainchain
+/// fee rate has increased by its original fee rate, when prioritizing
+/// withdrawals for inclusion in a bundle
+pub const WITHDRAWAL_AGE_BOOST_INTERVAL: u32 = 144;
+
+/// Pending withdrawals that have waited at least this many blocks are
+/// included in bundles before all other withdrawals, oldest first
+pub const MAX_WITHDRAWAL_WAIT: u32 = 1008;
+
+/// Sidechain height from which pending withdrawals are prioritized by fee
+/// rate and age when collecting a withdrawal bundle.
+/// Returns `None` if prioritization is not scheduled on the network.
+pub const fn withdrawal_priority_activation_height(
+    network: Network,
+) -> Option<u32> {
+    match network {
+        Network::Regtest => Some(0),
+        Network::Signet | Network::Forknet => None,
+    }
+}
+
+/// Sidechain height from which blocks are rejected if they include
+/// withdrawal outputs that fail the [`withdrawal_policy`] checks.
+/// Before activation, the checks are only applied to mempool transactions.
+/// Returns `None` if the rule is not scheduled on the network.
+pub const fn withdrawal_policy_activation_height(
+    network: Network,
+) -> Option<u32> {
+    match network {
+        Network::Regtest => Some(0),
+        Network::Signet | Network::Forknet => None,
+    }
+}
+
+/// Sidechain height from which blocks are rejected if they spend withdrawal
+/// outputs that are included in the pending withdrawal bundle.
+/// Before activation, such spends are only rejected from the mempool.
+/// Returns `None` if the rule is not scheduled on the network.
+pub const fn withdrawal_cancellation_activation_height(
+    network: Network,
+) -> Option<u32> {
+    match network {
+        Network::Regtest => Some(0),
+        Network::Signet | Network::Forknet => None,
+    }
+}
+
+/// Sidechain height from which deposits to shielded addresses mint Orchard
+/// notes.
+/// Before activation, and if no note can be derived from the deposit,
+/// shielded deposits are credited to [`TransparentAddress::ALL_ZEROS`], like
+/// deposits to any other unrecognized address.
+/// Returns `None` if minting is not scheduled on the network.
+pub const fn shielded_deposit_activation_height(
+    network: Network,
+) -> Option<u32> {
+    match network {
+        Network::Regtest => Some(0),
+        Network::Signet | Network::Forknet => None,
+    }
+}
+
 /// Prevalidated block data containing computed values from validation
 /// to avoid redundant computation during connection
 pub struct PrevalidatedBlock {
@@ -63,6 +132,64 @@ enum WithdrawalBundleInfo {
     },
 }
 
+impl WithdrawalBundleInfo {
+    /// Total value of UTXOs spent by the bundle, if known
+    fn spent_value(
+        &self,
+    ) -> Result<Option<bitcoin::Amount>, AmountOverflowError> {
+        let spend_utxos = match self {
+            Self::Known(bundle) => bundle.spend_utxos(),
+            Self::UnknownConfirmed { spend_utxos } => spend_utxos,
+            Self::Unknown => return Ok(None),
+        };
+        spend_utxos
+            .values()
+            .try_fold(bitcoin::Amount::ZERO, |acc, output| {
+                acc.checked_add(output.get_value())
+            })
+            .map(Some)
+            .ok_or(AmountOverflowError)
+    }
+}
+
+/// Value that the sidechain treasury should hold, according to the
+/// sidechain state.
+/// Withdrawal values are the total value of UTXOs spent by withdrawal
+/// bundles, including mainchain fees.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TreasuryBreakdown {
+    /// Credited deposits, including spent and shielded deposits
+    pub deposits: bitcoin::Amount,
+    /// Value spent by withdrawal bundles that have been submitted, but not
+    /// yet confirmed
+    pub submitted_withdrawals: bitcoin::Amount,
+    /// Value spent by confirmed withdrawal bundles
+    pub confirmed_withdrawals: bitcoin::Amount,
+    /// Unspent withdrawal outputs, that have not been paid out by a
+    /// submitted or confirmed bundle
+    pub pending_withdrawals: bitcoin::Amount,
+}
+
+impl TreasuryBreakdown {
+    /// Expected value of the treasury UTXO on the mainchain
+    pub fn expected_ctip_value(
+        &self,
+    ) -> Result<bitcoin::Amount, AmountUnderflowError> {
+        self.deposits
+            .checked_sub(self.confirmed_withdrawals)
+            .ok_or(AmountUnderflowError)
+    }
+
+    /// Expected sidechain wealth
+    pub fn expected_sidechain_wealth(
+        &self,
+    ) -> Result<bitcoin::Amount, AmountUnderflowError> {
+        self.expected_ctip_value()?
+            .checked_sub(self.submitted_withdrawals)
+            .ok_or(AmountUnderflowError)
+    }
+}
+
 #[derive(Clone)]
 pub struct State {
     /// Current tip
@@ -88,6 +215,19 @@ pub struct State {
         SerdeBincode<u32>,
         SerdeBincode<(bitcoin::BlockHash, u32)>,
     >,
+    /// Values of shielded deposits, which mint notes instead of creating
+    /// UTXOs
+    pub shielded_deposits: DatabaseUnique<
+        SerdeBincode<bitcoin::OutPoint>,
+        SerdeBincode<bitcoin::Amount>,
+    >,
+    /// Height at which each withdrawal output was created, used to
+    /// prioritize withdrawals that have waited longest
+    pub withdrawal_heights: DatabaseUnique<OutPointKey, SerdeBincode<u32>>,
+    /// Creation heights of spent withdrawal outputs, restored to
+    /// `withdrawal_heights` if the spend is disconnected
+    pub spent_withdrawal_heights:
+        DatabaseUnique<OutPointKey, SerdeBincode<u32>>,
     /// withdrawal bundle event blocks and the height at which they were applied, keyed sequentially
     pub withdrawal_bundle_event_blocks: DatabaseUnique<
         SerdeBincode<u32>,
@@ -96,13 +236,76 @@ pub struct State {
     /// Orchard DBs
     pub orchard: Orchard,
     pub utreexo_accumulator: DatabaseUnique<UnitKey, SerdeBincode<Accumulator>>,
+    /// Whether the state was created in compact mode
+    compact_state: DatabaseUnique<UnitKey, SerdeBincode<bool>>,
+    /// Spent outputs relayed with the block that is being connected.
+    /// Only used in compact mode, in which regular UTXOs are not stored.
+    relayed_spent_outputs: DatabaseUnique<OutPointKey, SerdeBincode<Output>>,
+    compact: bool,
+    /// Used to check withdrawal destinations
+    network: Network,
     _version: DatabaseUnique<UnitKey, SerdeBincode<Version>>,
 }
 
 impl State {
-    pub const NUM_DBS: u32 = Orchard::NUM_DBS + 11;
+    pub const NUM_DBS: u32 = Orchard::NUM_DBS + 16;
 
-    pub fn new(env: &sneed::Env) -> Result<Self, Error> {
+    /// Schema version of the state DBs
+    pub const SCHEMA_VERSION: Version = Version {
+        major: 0,
+        minor: 16,
+        patch: 3,
+    };
+
+    pub const SCHEMA: migration::Schema = migration::Schema {
+        version_db: "state_version",
+        version: Self::SCHEMA_VERSION,
+        min_version: Version {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        },
+        steps: &[
+            migration::Step {
+                version: Version {
+                    major: 0,
+                    minor: 16,
+                    patch: 1,
+                },
+                description: "add `shielded_deposits`",
+                run: migrations::create_shielded_deposits,
+            },
+            migration::Step {
+                version: Version {
+                    major: 0,
+                    minor: 16,
+                    patch: 2,
+                },
+                description: "add `withdrawal_heights`",
+                run: migrations::backfill_withdrawal_heights,
+            },
+            migration::Step {
+                version: Version {
+                    major: 0,
+                    minor: 16,
+                    patch: 3,
+                },
+                description: "rebuild `orchard_pool_value`",
+                run: migrations::rebuild_orchard_pool_value,
+            },
+        ],
+    };
+
+    /// In compact mode, only UTXOs and STXOs that are needed to track
+    /// deposits and withdrawals are stored. Transactions are validated
+    /// against the Utreexo accumulator, using spent outputs that are relayed
+    /// with each block.
+    /// A state MUST always be opened in the mode in which it was created.
+    pub fn new(
+        env: &sneed::Env,
+        compact: bool,
+        network: Network,
+    ) -> Result<Self, Error> {
         let mut rwtxn = env.write_txn()?;
         let tip = DatabaseUnique::create(env, &mut rwtxn, "tip")?;
         let height = DatabaseUnique::create(env, &mut rwtxn, "height")?;
@@ -122,6 +325,15 @@ impl State {
             DatabaseUnique::create(env, &mut rwtxn, "withdrawal_bundles")?;
         let deposit_blocks =
             DatabaseUnique::create(env, &mut rwtxn, "deposit_blocks")?;
+        let shielded_deposits =
+            DatabaseUnique::create(env, &mut rwtxn, "shielded_deposits")?;
+        let withdrawal_heights =
+            DatabaseUnique::create(env, &mut rwtxn, "withdrawal_heights")?;
+        let spent_withdrawal_heights = DatabaseUnique::create(
+            env,
+            &mut rwtxn,
+            "spent_withdrawal_heights",
+        )?;
         let withdrawal_bundle_event_blocks = DatabaseUnique::create(
             env,
             &mut rwtxn,
@@ -137,9 +349,32 @@ impl State {
                 &Accumulator::default(),
             )?;
         }
-        let version = DatabaseUnique::create(env, &mut rwtxn, "state_version")?;
+        let compact_state =
+            DatabaseUnique::create(env, &mut rwtxn, "compact_state")?;
+        match compact_state.try_get(&rwtxn, &())? {
+            Some(stored) if stored != compact => {
+                return Err(Error::CompactStateMismatch {
+                    stored,
+                    requested: compact,
+                });
+            }
+            Some(_) => (),
+            None => {
+                if compact && tip.try_get(&rwtxn, &())?.is_some() {
+                    return Err(Error::CompactStateMismatch {
+                        stored: false,
+                        requested: compact,
+                    });
+                }
+                compact_state.put(&mut rwtxn, &(), &compact)?;
+            }
+        }
+        let relayed_spent_outputs =
+            DatabaseUnique::create(env, &mut rwtxn, "relayed_spent_outputs")?;
+        let version =
+            DatabaseUnique::create(env, &mut rwtxn, Self::SCHEMA.version_db)?;
         if version.try_get(&rwtxn, &())?.is_none() {
-            version.put(&mut rwtxn, &(), &*VERSION)?;
+            version.put(&mut rwtxn, &(), &Self::SCHEMA_VERSION)?;
         }
         rwtxn.commit().map_err(RwTxnError::from)?;
         Ok(Self {
@@ -151,13 +386,208 @@ impl State {
             latest_failed_withdrawal_bundle,
             withdrawal_bundles,
             deposit_blocks,
+            shielded_deposits,
+            withdrawal_heights,
+            spent_withdrawal_heights,
             withdrawal_bundle_event_blocks,
             orchard,
             utreexo_accumulator,
+            compact_state,
+            relayed_spent_outputs,
+            compact,
+            network,
             _version: version,
         })
     }
 
+    /// Delete all state, including Orchard state, so that blocks can be
+    /// re-applied from genesis
+    pub fn reset(&self, rwtxn: &mut RwTxn) -> Result<(), Error> {
+        self.tip.clear(rwtxn)?;
+        self.height.clear(rwtxn)?;
+        self.utxos.clear(rwtxn)?;
+        self.stxos.clear(rwtxn)?;
+        self.pending_withdrawal_bundle.clear(rwtxn)?;
+        self.latest_failed_withdrawal_bundle.clear(rwtxn)?;
+        self.withdrawal_bundles.clear(rwtxn)?;
+        self.deposit_blocks.clear(rwtxn)?;
+        self.shielded_deposits.clear(rwtxn)?;
+        self.withdrawal_heights.clear(rwtxn)?;
+        self.spent_withdrawal_heights.clear(rwtxn)?;
+        self.withdrawal_bundle_event_blocks.clear(rwtxn)?;
+        let () = self.orchard.reset(rwtxn)?;
+        self.utreexo_accumulator
+            .put(rwtxn, &(), &Accumulator::default())?;
+        self.relayed_spent_outputs.clear(rwtxn)?;
+        Ok(())
+    }
+
+    /// `true` if the state is in compact mode
+    pub fn is_compact(&self) -> bool {
+        self.compact
+    }
+
+    /// `true` if the UTXO should be stored.
+    /// In compact mode, only deposits and withdrawal outputs are stored.
+    fn stores_utxo(&self, outpoint: &OutPoint, output: &Output) -> bool {
+        !self.compact
+            || matches!(outpoint, OutPoint::Deposit(_))
+            || output.content.is_withdrawal()
+    }
+
+    /// `true` if the STXO should be stored.
+    /// In compact mode, only STXOs that are used to compute sidechain wealth
+    /// are stored.
+    fn stores_stxo(
+        &self,
+        outpoint: &OutPoint,
+        spent_output: &SpentOutput,
+    ) -> bool {
+        self.stores_utxo(outpoint, &spent_output.output)
+            || matches!(spent_output.inpoint, InPoint::Withdrawal { .. })
+    }
+
+    /// Move the creation height of a withdrawal output that is being spent
+    /// to `spent_withdrawal_heights`
+    fn spend_withdrawal_height(
+        &self,
+        rwtxn: &mut RwTxn,
+        outpoint: &OutPointKey,
+    ) -> Result<(), DbError> {
+        if let Some(height) =
+            self.withdrawal_heights.try_get(rwtxn, outpoint)?
+        {
+            self.withdrawal_heights.delete(rwtxn, outpoint)?;
+            self.spent_withdrawal_heights
+                .put(rwtxn, outpoint, &height)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the creation height of a withdrawal output that is being
+    /// unspent, when the spend is disconnected
+    fn unspend_withdrawal_height(
+        &self,
+        rwtxn: &mut RwTxn,
+        outpoint: &OutPointKey,
+    ) -> Result<(), DbError> {
+        if let Some(height) =
+            self.spent_withdrawal_heights.try_get(rwtxn, outpoint)?
+        {
+            self.spent_withdrawal_heights.delete(rwtxn, outpoint)?;
+            self.withdrawal_heights.put(rwtxn, outpoint, &height)?;
+        }
+        Ok(())
+    }
+
+    /// Check that spent outputs relayed with a block match the utxo hashes
+    /// of the block's inputs, in the order in which they are spent
+    pub fn validate_relayed_spent_outputs(
+        body: &Body,
+        spent_outputs: &[Output],
+    ) -> Result<(), Error> {
+        let n_inputs: usize =
+            body.transactions.iter().map(|tx| tx.inputs.len()).sum();
+        if spent_outputs.len() != n_inputs {
+            return Err(Error::RelayedSpentOutputs {
+                expected: n_inputs,
+                received: spent_outputs.len(),
+            });
+        }
+        let inputs = body.transactions.iter().flat_map(|tx| &tx.inputs);
+        for ((outpoint, utxo_hash), output) in inputs.zip(spent_outputs) {
+            let outpoint = *outpoint;
+            let computed_utxo_hash =
+                crate::types::hash(&PointedOutputRef { outpoint, output });
+            if *utxo_hash != computed_utxo_hash {
+                return Err(Error::UtxoHashMismatch {
+                    computed: computed_utxo_hash,
+                    outpoint,
+                    input_hash: *utxo_hash,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the spent outputs relayed with a block, in the order in which
+    /// they are spent by the block's transactions
+    pub fn put_relayed_spent_outputs(
+        &self,
+        rwtxn: &mut RwTxn,
+        body: &Body,
+        spent_outputs: &[Output],
+    ) -> Result<(), Error> {
+        let () = Self::validate_relayed_spent_outputs(body, spent_outputs)?;
+        let outpoints = body
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.inputs.iter().map(|(outpoint, _)| outpoint));
+        for (outpoint, output) in outpoints.zip(spent_outputs) {
+            self.relayed_spent_outputs.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                output,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_relayed_spent_outputs(
+        &self,
+        rwtxn: &mut RwTxn,
+    ) -> Result<(), Error> {
+        self.relayed_spent_outputs.clear(rwtxn)?;
+        Ok(())
+    }
+
+    /// Get the spent outputs for a block's inputs, if all of them are known
+    pub fn try_get_block_spent_outputs(
+        &self,
+        rotxn: &RoTxn,
+        body: &Body,
+    ) -> Result<Option<Vec<Output>>, Error> {
+        let mut spent_outputs = Vec::new();
+        for tx in &body.transactions {
+            let txid = tx.txid();
+            for (vin, (outpoint, _)) in tx.inputs.iter().enumerate() {
+                let Some(spent_output) =
+                    self.stxos.try_get(rotxn, &OutPointKey::from(outpoint))?
+                else {
+                    return Ok(None);
+                };
+                if spent_output.inpoint
+                    != (InPoint::Regular {
+                        txid,
+                        vin: vin as u32,
+                    })
+                {
+                    return Ok(None);
+                }
+                spent_outputs.push(spent_output.output);
+            }
+        }
+        Ok(Some(spent_outputs))
+    }
+
+    /// Get an unspent output, falling back to relayed spent outputs in
+    /// compact mode
+    fn try_get_unspent_output(
+        &self,
+        rotxn: &RoTxn,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Output>, db_error::TryGet> {
+        let key = OutPointKey::from(outpoint);
+        if let Some(output) = self.utxos.try_get(rotxn, &key)? {
+            return Ok(Some(output));
+        }
+        if self.compact {
+            self.relayed_spent_outputs.try_get(rotxn, &key)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn try_get_tip(
         &self,
         rotxn: &RoTxn,
@@ -284,11 +714,11 @@ impl State {
     ) -> Result<FilledTransaction, Error> {
         let mut spent_utxos = vec![];
         for (outpoint, _) in &transaction.inputs {
-            let key = OutPointKey::from(outpoint);
-            let utxo =
-                self.utxos.try_get(rotxn, &key)?.ok_or(error::NoUtxo {
+            let utxo = self.try_get_unspent_output(rotxn, outpoint)?.ok_or(
+                error::NoUtxo {
                     outpoint: *outpoint,
-                })?;
+                },
+            )?;
             spent_utxos.push(utxo);
         }
         Ok(FilledTransaction {
@@ -377,6 +807,98 @@ impl State {
             .ok_or_else(|| AmountUnderflowError.into())
     }
 
+    /// Check that transactions do not spend withdrawal outputs that are
+    /// included in the pending withdrawal bundle.
+    /// Withdrawals that have not been included in a bundle can be cancelled
+    /// by their owner, by spending them.
+    pub fn validate_withdrawal_spends(
+        &self,
+        rotxn: &RoTxn,
+        filled_transactions: &[FilledTransaction],
+    ) -> Result<(), Error> {
+        let mut withdrawal_spends = filled_transactions
+            .iter()
+            .flat_map(|filled_transaction| {
+                filled_transaction
+                    .transaction
+                    .inputs
+                    .iter()
+                    .zip(&filled_transaction.spent_utxos)
+            })
+            .filter(|(_, spent_utxo)| spent_utxo.content.is_withdrawal())
+            .peekable();
+        if withdrawal_spends.peek().is_none() {
+            return Ok(());
+        }
+        let Some((pending_bundle, _)) =
+            self.try_get_pending_withdrawal_bundle(rotxn)?
+        else {
+            return Ok(());
+        };
+        for ((outpoint, _), _) in withdrawal_spends {
+            if pending_bundle.spend_utxos().contains_key(outpoint) {
+                return Err(Error::WithdrawalBundled {
+                    outpoint: *outpoint,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that transactions in a block at the specified height do not
+    /// spend withdrawal outputs that are included in the pending withdrawal
+    /// bundle, if the rule is active at that height
+    pub fn validate_block_withdrawal_spends(
+        &self,
+        rotxn: &RoTxn,
+        height: u32,
+        filled_transactions: &[FilledTransaction],
+    ) -> Result<(), Error> {
+        if !withdrawal_cancellation_activation_height(self.network)
+            .is_some_and(|activation_height| height >= activation_height)
+        {
+            return Ok(());
+        }
+        self.validate_withdrawal_spends(rotxn, filled_transactions)
+    }
+
+    /// Check that the orchard bundles in the specified transactions do not
+    /// remove more value from the shielded pool than it holds.
+    /// Returns the shielded pool value after applying the transactions.
+    pub fn validate_shielded_pool_value<'a, Txs>(
+        &self,
+        rotxn: &RoTxn,
+        transactions: Txs,
+    ) -> Result<bitcoin::Amount, Error>
+    where
+        Txs: IntoIterator<Item = &'a Transaction>,
+    {
+        let value_balance = orchard::value_balance(transactions)?;
+        let pool_value = self.get_shielded_pool_value(rotxn)?;
+        Ok(orchard::apply_value_balance(pool_value, value_balance)?)
+    }
+
+    /// Returns `true` if a shielded deposit applied at the specified height
+    /// mints a note. Otherwise, the deposit is credited as a transparent
+    /// deposit UTXO.
+    pub fn mints_shielded_deposit(
+        &self,
+        height: u32,
+        deposit: &ShieldedDeposit,
+    ) -> bool {
+        shielded_deposit_activation_height(self.network)
+            .is_some_and(|activation_height| height >= activation_height)
+            && deposit.note().is_ok()
+    }
+
+    /// Total value of notes in the shielded pool
+    pub fn get_shielded_pool_value(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<bitcoin::Amount, Error> {
+        Ok(self.orchard.pool_value().get(rotxn, &())?)
+    }
+
     /// Validate the anchor of an orchard bundle against known historical roots.
     /// The empty anchor is only allowed if no spends exist.
     pub fn validate_orchard_anchor(
@@ -400,6 +922,28 @@ impl State {
         Ok(())
     }
 
+    /// Check that the withdrawal outputs in a block at the specified height
+    /// can be paid out on the mainchain, if the withdrawal policy is active
+    /// at that height
+    pub fn validate_block_withdrawal_outputs<'a, Outputs>(
+        &self,
+        height: u32,
+        outputs: Outputs,
+    ) -> Result<(), Error>
+    where
+        Outputs: IntoIterator<Item = &'a Output>,
+    {
+        if !withdrawal_policy_activation_height(self.network)
+            .is_some_and(|activation_height| height >= activation_height)
+        {
+            return Ok(());
+        }
+        for output in outputs {
+            let () = withdrawal_policy::check_output(self.network, output)?;
+        }
+        Ok(())
+    }
+
     pub fn validate_transaction(
         &self,
         rotxn: &RoTxn,
@@ -422,6 +966,17 @@ impl State {
         {
             let () = self.validate_orchard_anchor(rotxn, orchard_bundle)?;
         }
+        for output in &transaction.transaction.outputs {
+            let () = withdrawal_policy::check_output(self.network, output)?;
+        }
+        let () = self.validate_withdrawal_spends(
+            rotxn,
+            std::slice::from_ref(&filled_transaction),
+        )?;
+        let _: bitcoin::Amount = self.validate_shielded_pool_value(
+            rotxn,
+            std::iter::once(&transaction.transaction),
+        )?;
         if Authorization::verify_transaction(transaction).is_err() {
             return Err(Error::AuthorizationError);
         }
@@ -520,14 +1075,340 @@ impl State {
                 Ok::<_, Error>(())
             })?;
 
+        let mut total_shielded_deposit_value = bitcoin::Amount::ZERO;
+        self.shielded_deposits
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(_, value)| {
+                total_shielded_deposit_value = total_shielded_deposit_value
+                    .checked_add(value)
+                    .ok_or(AmountOverflowError)?;
+                Ok::<_, Error>(())
+            })?;
+
         let total_wealth: bitcoin::Amount = total_deposit_utxo_value
             .checked_add(total_deposit_stxo_value)
             .ok_or(AmountOverflowError)?
+            .checked_add(total_shielded_deposit_value)
+            .ok_or(AmountOverflowError)?
             .checked_sub(total_withdrawal_stxo_value)
             .ok_or(AmountOverflowError)?;
         Ok(total_wealth)
     }
 
+    /// Get the mainchain outpoints of all credited deposits, including
+    /// deposits that have been spent, and shielded deposits
+    pub fn get_deposit_outpoints(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<HashSet<bitcoin::OutPoint>, Error> {
+        let mut res = HashSet::new();
+        self.utxos
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(outpoint, _)| {
+                if let OutPoint::Deposit(outpoint) = OutPoint::from(outpoint) {
+                    res.insert(outpoint);
+                }
+                Ok::<_, Error>(())
+            })?;
+        self.stxos
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(outpoint, _)| {
+                if let OutPoint::Deposit(outpoint) = OutPoint::from(outpoint) {
+                    res.insert(outpoint);
+                }
+                Ok::<_, Error>(())
+            })?;
+        self.shielded_deposits
+            .iter_keys(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|outpoint| {
+                res.insert(outpoint);
+                Ok::<_, Error>(())
+            })?;
+        Ok(res)
+    }
+
+    /// Total value of UTXOs spent by each withdrawal bundle that is
+    /// currently submitted or confirmed.
+    /// Withdrawal STXOs should account for exactly these values.
+    pub fn withdrawal_bundle_spent_values(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<HashMap<M6id, bitcoin::Amount>, Error> {
+        let mut res = HashMap::new();
+        self.withdrawal_bundles
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(m6id, (bundle_info, bundle_status))| {
+                match bundle_status.latest().value {
+                    WithdrawalBundleStatus::Confirmed
+                    | WithdrawalBundleStatus::Submitted => (),
+                    WithdrawalBundleStatus::Dropped
+                    | WithdrawalBundleStatus::Failed
+                    | WithdrawalBundleStatus::Pending
+                    | WithdrawalBundleStatus::SubmittedUnexpected => {
+                        return Ok(());
+                    }
+                }
+                if let Some(value) = bundle_info.spent_value()? {
+                    res.insert(m6id, value);
+                }
+                Ok::<_, Error>(())
+            })?;
+        Ok(res)
+    }
+
+    /// Total value of UTXOs spent by a withdrawal bundle, regardless of its
+    /// status. Returns `None` if the bundle or its spent UTXOs are unknown.
+    pub fn try_get_withdrawal_bundle_spent_value(
+        &self,
+        rotxn: &RoTxn,
+        m6id: M6id,
+    ) -> Result<Option<bitcoin::Amount>, Error> {
+        let Some((bundle_info, _)) =
+            self.withdrawal_bundles.try_get(rotxn, &m6id)?
+        else {
+            return Ok(None);
+        };
+        Ok(bundle_info.spent_value()?)
+    }
+
+    /// Break down the value that the sidechain treasury should hold,
+    /// according to the sidechain state
+    pub fn treasury_breakdown(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<TreasuryBreakdown, Error> {
+        let mut res = TreasuryBreakdown::default();
+        self.utxos
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(outpoint, output)| {
+                if let OutPoint::Deposit(_) = OutPoint::from(outpoint) {
+                    res.deposits = res
+                        .deposits
+                        .checked_add(output.get_value())
+                        .ok_or(AmountOverflowError)?;
+                }
+                if output.content.is_withdrawal() {
+                    res.pending_withdrawals = res
+                        .pending_withdrawals
+                        .checked_add(output.get_value())
+                        .ok_or(AmountOverflowError)?;
+                }
+                Ok::<_, Error>(())
+            })?;
+        self.stxos
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(outpoint, spent_output)| {
+                if let OutPoint::Deposit(_) = OutPoint::from(outpoint) {
+                    res.deposits = res
+                        .deposits
+                        .checked_add(spent_output.output.get_value())
+                        .ok_or(AmountOverflowError)?;
+                }
+                Ok::<_, Error>(())
+            })?;
+        self.shielded_deposits
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(_, value)| {
+                res.deposits = res
+                    .deposits
+                    .checked_add(value)
+                    .ok_or(AmountOverflowError)?;
+                Ok::<_, Error>(())
+            })?;
+        self.withdrawal_bundles
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .for_each(|(_, (bundle_info, bundle_status))| {
+                let total = match bundle_status.latest().value {
+                    WithdrawalBundleStatus::Confirmed => {
+                        &mut res.confirmed_withdrawals
+                    }
+                    WithdrawalBundleStatus::Submitted => {
+                        &mut res.submitted_withdrawals
+                    }
+                    WithdrawalBundleStatus::Dropped
+                    | WithdrawalBundleStatus::Failed
+                    | WithdrawalBundleStatus::Pending
+                    | WithdrawalBundleStatus::SubmittedUnexpected => {
+                        return Ok(());
+                    }
+                };
+                if let Some(value) = bundle_info.spent_value()? {
+                    *total =
+                        total.checked_add(value).ok_or(AmountOverflowError)?;
+                }
+                Ok::<_, Error>(())
+            })?;
+        Ok(res)
+    }
+
+    /// Summarize a withdrawal bundle and its status history
+    fn withdrawal_bundle_history(
+        m6id: M6id,
+        bundle_info: WithdrawalBundleInfo,
+        bundle_status: RollBack<WithdrawalBundleStatus>,
+    ) -> Result<WithdrawalBundleHistory, Error> {
+        let status_history = bundle_status
+            .iter()
+            .map(|status| WithdrawalBundleStatusTransition {
+                status: status.value,
+                height: status.height,
+                main_block_hash: None,
+            })
+            .collect();
+        let (tx, spend_utxos, value, fee) = match bundle_info {
+            WithdrawalBundleInfo::Known(bundle) => {
+                let spent_value = bundle.spend_utxos().values().try_fold(
+                    bitcoin::Amount::ZERO,
+                    |acc, output| {
+                        acc.checked_add(output.get_value())
+                            .ok_or(AmountOverflowError)
+                    },
+                )?;
+                let value = bundle.tx().output.iter().try_fold(
+                    bitcoin::Amount::ZERO,
+                    |acc, txout| {
+                        acc.checked_add(txout.value).ok_or(AmountOverflowError)
+                    },
+                )?;
+                let fee = spent_value
+                    .checked_sub(value)
+                    .ok_or(AmountUnderflowError)?;
+                let spend_utxos =
+                    bundle.spend_utxos().keys().copied().collect();
+                (
+                    Some(bundle.tx().clone()),
+                    Some(spend_utxos),
+                    Some(value),
+                    Some(fee),
+                )
+            }
+            WithdrawalBundleInfo::UnknownConfirmed { spend_utxos } => {
+                (None, Some(spend_utxos.into_keys().collect()), None, None)
+            }
+            WithdrawalBundleInfo::Unknown => (None, None, None, None),
+        };
+        Ok(WithdrawalBundleHistory {
+            m6id,
+            tx,
+            spend_utxos,
+            value,
+            fee,
+            status_history,
+        })
+    }
+
+    /// Get a withdrawal bundle and its status history.
+    /// Mainchain event block hashes are not stored in the state, and are
+    /// unset in the returned status history.
+    pub fn try_get_withdrawal_bundle_history(
+        &self,
+        rotxn: &RoTxn,
+        m6id: M6id,
+    ) -> Result<Option<WithdrawalBundleHistory>, Error> {
+        let Some((bundle_info, bundle_status)) =
+            self.withdrawal_bundles.try_get(rotxn, &m6id)?
+        else {
+            return Ok(None);
+        };
+        Self::withdrawal_bundle_history(m6id, bundle_info, bundle_status)
+            .map(Some)
+    }
+
+    /// Get all withdrawal bundles and their status history, ordered by the
+    /// height at which they were first seen.
+    /// Mainchain event block hashes are not stored in the state, and are
+    /// unset in the returned status history.
+    pub fn get_withdrawal_bundle_histories(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<Vec<WithdrawalBundleHistory>, Error> {
+        let mut res: Vec<_> = self
+            .withdrawal_bundles
+            .iter(rotxn)?
+            .map_err(|err| DbError::from(err).into())
+            .map(|(m6id, (bundle_info, bundle_status))| {
+                Self::withdrawal_bundle_history(
+                    m6id,
+                    bundle_info,
+                    bundle_status,
+                )
+            })
+            .collect()?;
+        res.sort_by_key(|history| {
+            history.status_history.first().map(|status| status.height)
+        });
+        Ok(res)
+    }
+
+    /// Withdrawal bundles that include any of the specified outpoints, along
+    /// with their status history.
+    /// Bundles for each outpoint are ordered by the height at which they
+    /// were created.
+    pub fn get_withdrawal_bundle_inclusions(
+        &self,
+        rotxn: &RoTxn,
+        outpoints: &HashSet<OutPoint>,
+    ) -> Result<HashMap<OutPoint, Vec<WithdrawalBundleInclusion>>, Error> {
+        let mut res = HashMap::<_, Vec<_>>::new();
+        let mut iter = self.withdrawal_bundles.iter(rotxn)?;
+        while let Some((m6id, (bundle_info, bundle_status))) = iter.next()? {
+            let spend_utxos = match &bundle_info {
+                WithdrawalBundleInfo::Known(bundle) => bundle.spend_utxos(),
+                WithdrawalBundleInfo::UnknownConfirmed { spend_utxos } => {
+                    spend_utxos
+                }
+                WithdrawalBundleInfo::Unknown => continue,
+            };
+            let status_history: Vec<_> = bundle_status
+                .iter()
+                .map(|status| WithdrawalBundleStatusUpdate {
+                    status: status.value,
+                    height: status.height,
+                })
+                .collect();
+            let failed =
+                bundle_status.latest().value == WithdrawalBundleStatus::Failed;
+            for outpoint in spend_utxos.keys() {
+                if !outpoints.contains(outpoint) {
+                    continue;
+                }
+                let funds_reinstated = if failed {
+                    let spent_by_bundle = self
+                        .stxos
+                        .try_get(rotxn, &OutPointKey::from(outpoint))?
+                        .is_some_and(|spent_output| {
+                            spent_output.inpoint == InPoint::Withdrawal { m6id }
+                        });
+                    Some(!spent_by_bundle)
+                } else {
+                    None
+                };
+                res.entry(*outpoint).or_default().push(
+                    WithdrawalBundleInclusion {
+                        m6id,
+                        status_history: status_history.clone(),
+                        funds_reinstated,
+                    },
+                );
+            }
+        }
+        for inclusions in res.values_mut() {
+            inclusions.sort_by_key(|inclusion| {
+                inclusion.status_history.first().map(|status| status.height)
+            });
+        }
+        Ok(res)
+    }
+
     pub fn validate_block(
         &self,
         rotxn: &RoTxn,
@@ -627,11 +1508,13 @@ impl State {
 
     /// Returns data that must be archived in order to disconnect to the new
     /// tip.
+    /// Returns `Some(_)` frontier if shielded deposits changed the orchard
+    /// frontier.
     pub fn connect_two_way_peg_data(
         &self,
         rwtxn: &mut RwTxn,
         two_way_peg_data: &TwoWayPegData,
-    ) -> Result<Accumulator, Error> {
+    ) -> Result<(Accumulator, Option<types::orchard::Frontier>), Error> {
         two_way_peg_data::connect(self, rwtxn, two_way_peg_data)
     }
 
@@ -654,12 +1537,18 @@ impl Watchable<()> for State {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
+    use std::collections::{BTreeMap, HashSet};
+
     use crate::{
-        state::State,
+        state::{
+            Error, State, TreasuryBreakdown, WithdrawalBundleInfo,
+            rollback::RollBack,
+        },
         types::{
-            InPoint, OutPoint, OutPointKey, Output, OutputContent, SpentOutput,
-            TransparentAddress,
+            InPoint, Network, OutPoint, OutPointKey, Output, OutputContent,
+            SpentOutput, TransparentAddress, WithdrawalBundle,
+            WithdrawalBundleStatus,
         },
     };
 
@@ -689,7 +1578,7 @@ mod test {
 
     pub fn fresh_state(test_name: &str) -> anyhow::Result<(sneed::Env, State)> {
         let env = temp_env(test_name)?;
-        let state = State::new(&env)?;
+        let state = State::new(&env, false, Network::default())?;
         Ok((env, state))
     }
 
@@ -778,4 +1667,579 @@ mod test {
         );
         Ok(())
     }
+
+    #[test]
+    fn compact_state_mode_is_persisted() -> anyhow::Result<()> {
+        let (env, state) = fresh_state("compact-state-mode")?;
+        anyhow::ensure!(!state.is_compact());
+        drop(state);
+        anyhow::ensure!(matches!(
+            State::new(&env, true, Network::default()),
+            Err(Error::CompactStateMismatch {
+                stored: false,
+                requested: true
+            })
+        ));
+        anyhow::ensure!(
+            !State::new(&env, false, Network::default())?.is_compact()
+        );
+        Ok(())
+    }
+
+    // an output included in a failed bundle and then in a later bundle is
+    // linked to both bundles, and reported as reinstated by the failed one
+    #[test]
+    fn withdrawal_bundle_inclusions() -> anyhow::Result<()> {
+        let (env, state) = fresh_state("withdrawal-bundle-inclusions")?;
+        let outpoint = OutPoint::Regular {
+            txid: [1; 32].into(),
+            vout: 0,
+        };
+        let other_outpoint = OutPoint::Regular {
+            txid: [2; 32].into(),
+            vout: 0,
+        };
+        let output = value_output(TransparentAddress::ALL_ZEROS, 1000);
+        let bundle = |block_height, outpoints: &[OutPoint]| {
+            let spend_utxos: BTreeMap<_, _> = outpoints
+                .iter()
+                .map(|outpoint| (*outpoint, output.clone()))
+                .collect();
+            WithdrawalBundle::new(
+                block_height,
+                bitcoin::Amount::ZERO,
+                spend_utxos,
+                Vec::new(),
+            )
+        };
+        let failed_bundle = bundle(1, &[outpoint, other_outpoint])?;
+        let failed_m6id = failed_bundle.compute_m6id();
+        let submitted_bundle = bundle(5, &[outpoint])?;
+        let submitted_m6id = submitted_bundle.compute_m6id();
+        {
+            let mut rwtxn = env.write_txn()?;
+            let mut failed_status =
+                RollBack::new(WithdrawalBundleStatus::Pending, 1);
+            failed_status
+                .push(WithdrawalBundleStatus::Submitted, 2)
+                .unwrap();
+            failed_status
+                .push(WithdrawalBundleStatus::Failed, 3)
+                .unwrap();
+            state.withdrawal_bundles.put(
+                &mut rwtxn,
+                &failed_m6id,
+                &(WithdrawalBundleInfo::Known(failed_bundle), failed_status),
+            )?;
+            let mut submitted_status =
+                RollBack::new(WithdrawalBundleStatus::Pending, 5);
+            submitted_status
+                .push(WithdrawalBundleStatus::Submitted, 6)
+                .unwrap();
+            state.withdrawal_bundles.put(
+                &mut rwtxn,
+                &submitted_m6id,
+                &(
+                    WithdrawalBundleInfo::Known(submitted_bundle),
+                    submitted_status,
+                ),
+            )?;
+            let spent_output = SpentOutput {
+                output: output.clone(),
+                inpoint: InPoint::Withdrawal {
+                    m6id: submitted_m6id,
+                },
+            };
+            state.stxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(&outpoint),
+                &spent_output,
+            )?;
+            rwtxn.commit()?;
+        }
+        let rotxn = env.read_txn()?;
+        let inclusions = state.get_withdrawal_bundle_inclusions(
+            &rotxn,
+            &HashSet::from([outpoint]),
+        )?;
+        anyhow::ensure!(inclusions.len() == 1);
+        let inclusions = &inclusions[&outpoint];
+        anyhow::ensure!(inclusions.len() == 2);
+        anyhow::ensure!(inclusions[0].m6id == failed_m6id);
+        anyhow::ensure!(inclusions[0].status_history.len() == 3);
+        anyhow::ensure!(inclusions[0].funds_reinstated == Some(true));
+        anyhow::ensure!(inclusions[1].m6id == submitted_m6id);
+        anyhow::ensure!(inclusions[1].funds_reinstated.is_none());
+        Ok(())
+    }
+
+    // the value of a known bundle is the total paid out to mainchain
+    // addresses, and the fee is the remainder of the spent withdrawal outputs
+    #[test]
+    fn withdrawal_bundle_history_value_and_fee() -> anyhow::Result<()> {
+        use bitcoin::hashes::Hash as _;
+
+        let (env, state) = fresh_state("withdrawal-bundle-history")?;
+        let script_pubkey =
+            bitcoin::ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros());
+        let main_address = bitcoin::Address::from_script(
+            &script_pubkey,
+            bitcoin::Network::Regtest,
+        )?
+        .into_unchecked();
+        let outpoint = OutPoint::Regular {
+            txid: [1; 32].into(),
+            vout: 0,
+        };
+        let output = Output {
+            address: TransparentAddress::ALL_ZEROS,
+            content: OutputContent::Withdrawal {
+                value: bitcoin::Amount::from_sat(1000),
+                main_fee: bitcoin::Amount::from_sat(100),
+                main_address,
+            },
+        };
+        let bundle = WithdrawalBundle::new(
+            1,
+            bitcoin::Amount::from_sat(100),
+            BTreeMap::from([(outpoint, output)]),
+            vec![bitcoin::TxOut {
+                value: bitcoin::Amount::from_sat(1000),
+                script_pubkey,
+            }],
+        )?;
+        let m6id = bundle.compute_m6id();
+        {
+            let mut rwtxn = env.write_txn()?;
+            state.withdrawal_bundles.put(
+                &mut rwtxn,
+                &m6id,
+                &(
+                    WithdrawalBundleInfo::Known(bundle),
+                    RollBack::new(WithdrawalBundleStatus::Pending, 1),
+                ),
+            )?;
+            rwtxn.commit()?;
+        }
+        let rotxn = env.read_txn()?;
+        let history = state
+            .try_get_withdrawal_bundle_history(&rotxn, m6id)?
+            .ok_or_else(|| anyhow::anyhow!("missing withdrawal bundle"))?;
+        anyhow::ensure!(history.spend_utxos == Some(vec![outpoint]));
+        anyhow::ensure!(history.value == Some(bitcoin::Amount::from_sat(1000)));
+        anyhow::ensure!(history.fee == Some(bitcoin::Amount::from_sat(100)));
+        anyhow::ensure!(history.status_history.len() == 1);
+        Ok(())
+    }
+
+    // spent and unspent deposits are both reported, regular outputs are not
+    #[test]
+    fn deposit_outpoints() -> anyhow::Result<()> {
+        use bitcoin::hashes::Hash as _;
+        let (env, state) = fresh_state("deposit-outpoints")?;
+        let unspent_deposit = bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        let spent_deposit = bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([2; 32]),
+            vout: 1,
+        };
+        let output = value_output(TransparentAddress::ALL_ZEROS, 1000);
+        {
+            let mut rwtxn = env.write_txn()?;
+            state.utxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(OutPoint::Deposit(unspent_deposit)),
+                &output,
+            )?;
+            state.utxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(OutPoint::Regular {
+                    txid: [3; 32].into(),
+                    vout: 0,
+                }),
+                &output,
+            )?;
+            state.stxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(OutPoint::Deposit(spent_deposit)),
+                &SpentOutput {
+                    output: output.clone(),
+                    inpoint: InPoint::Regular {
+                        txid: [4; 32].into(),
+                        vin: 0,
+                    },
+                },
+            )?;
+            rwtxn.commit()?;
+        }
+        let rotxn = env.read_txn()?;
+        let deposit_outpoints = state.get_deposit_outpoints(&rotxn)?;
+        anyhow::ensure!(
+            deposit_outpoints
+                == HashSet::from([unspent_deposit, spent_deposit])
+        );
+        Ok(())
+    }
+
+    /// A withdrawal can be funded entirely by the value balance of an orchard
+    /// bundle, without any transparent inputs
+    #[test]
+    fn shielded_withdrawal_value_balance() -> anyhow::Result<()> {
+        use bitcoin::hashes::Hash as _;
+        use bytemuck::TransparentWrapper as _;
+        use incrementalmerkletree::{Hashable as _, Level};
+
+        use crate::{
+            authorization,
+            types::{
+                FilledTransaction, PointedOutput, ShieldedAddress, Transaction,
+                orchard::{
+                    Anchor, Builder, BundleFlags, FullViewingKey,
+                    MerkleHashOrchard, MerklePath, Note, Scope,
+                    SpendAuthorizingKey, SpendingKey,
+                },
+            },
+        };
+
+        let (env, state) = fresh_state("shielded-withdrawal-value-balance")?;
+        let sk = SpendingKey::from_bytes([7; 32])
+            .into_option()
+            .ok_or_else(|| anyhow::anyhow!("invalid spending key"))?;
+        let fvk = FullViewingKey::from(&sk);
+        let address = ShieldedAddress(fvk.address_at(0u32, Scope::External));
+        let note = Note::from_deposit(
+            &bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_byte_array([3; 32]),
+                vout: 0,
+            },
+            &address,
+            bitcoin::Amount::from_sat(10_000),
+        )?;
+        // Spend the note from a single-leaf tree
+        let auth_path = std::array::from_fn(|level| {
+            MerkleHashOrchard::empty_root(Level::from(level as u8))
+        });
+        let merkle_path = MerklePath::from_parts(0, auth_path);
+        let anchor = Anchor::wrap(merkle_path.root(note.commitment().0));
+        let mut builder = Builder::new(BundleFlags::ENABLED, true, anchor);
+        builder.add_spend(fvk, note, merkle_path)?;
+        let (bundle, _) = builder
+            .build(rand::rngs::OsRng, None)?
+            .ok_or_else(|| anyhow::anyhow!("empty bundle"))?;
+        let bundle = bundle.create_proof(rand::rngs::OsRng)?;
+        let proof = {
+            let rotxn = env.read_txn()?;
+            state.get_utreexo_proof(
+                &rotxn,
+                std::iter::empty::<&PointedOutput>(),
+            )?
+        };
+        let withdrawal = |value: u64, main_fee: u64| Output {
+            address: TransparentAddress::ALL_ZEROS,
+            content: OutputContent::Withdrawal {
+                value: bitcoin::Amount::from_sat(value),
+                main_fee: bitcoin::Amount::from_sat(main_fee),
+                main_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                    .parse()
+                    .unwrap(),
+            },
+        };
+        let transaction = authorization::sign_orchard(
+            &[SpendAuthorizingKey::from(&sk)],
+            Transaction {
+                inputs: Vec::new(),
+                proof,
+                outputs: vec![withdrawal(6_000, 1_000)],
+                orchard_bundle: Some(bundle),
+            },
+        )?;
+        let mut filled = FilledTransaction {
+            transaction,
+            spent_utxos: Vec::new(),
+        };
+        let fee = state.validate_filled_transaction(&filled)?;
+        anyhow::ensure!(fee == bitcoin::Amount::from_sat(3_000));
+        // The withdrawal value and mainchain fee must not exceed the value
+        // balance
+        filled.transaction.outputs = vec![withdrawal(9_000, 2_000)];
+        let err = state
+            .validate_filled_transaction(&filled)
+            .expect_err("withdrawal exceeds value balance");
+        anyhow::ensure!(matches!(err, Error::NotEnoughValueIn));
+        Ok(())
+    }
+
+    // withdrawal outputs in blocks, including coinbase outputs, are only
+    // checked against the withdrawal policy once it is active
+    #[test]
+    fn block_withdrawal_outputs_are_checked_after_activation()
+    -> anyhow::Result<()> {
+        let dust_withdrawal = Output {
+            address: TransparentAddress::ALL_ZEROS,
+            content: OutputContent::Withdrawal {
+                value: bitcoin::Amount::from_sat(100),
+                main_fee: bitcoin::Amount::from_sat(1_000),
+                main_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                    .parse()
+                    .unwrap(),
+            },
+        };
+        let (_env, state) = fresh_state("withdrawal-policy-inactive")?;
+        anyhow::ensure!(
+            super::withdrawal_policy_activation_height(state.network).is_none()
+        );
+        state.validate_block_withdrawal_outputs(
+            1,
+            std::iter::once(&dust_withdrawal),
+        )?;
+        let env = temp_env("withdrawal-policy-active")?;
+        let state = State::new(&env, false, Network::Regtest)?;
+        let err = state
+            .validate_block_withdrawal_outputs(
+                0,
+                std::iter::once(&dust_withdrawal),
+            )
+            .expect_err("withdrawal policy is active on regtest");
+        anyhow::ensure!(matches!(err, Error::WithdrawalPolicy(_)));
+        Ok(())
+    }
+
+    // withdrawals can be cancelled by spending them, unless they are
+    // included in the pending withdrawal bundle
+    #[test]
+    fn withdrawal_spends_exclude_pending_bundle() -> anyhow::Result<()> {
+        use crate::types::{FilledTransaction, Transaction};
+
+        let (env, state) = fresh_state("withdrawal-spends")?;
+        let withdrawal = Output {
+            address: TransparentAddress::ALL_ZEROS,
+            content: OutputContent::Withdrawal {
+                value: bitcoin::Amount::from_sat(10_000),
+                main_fee: bitcoin::Amount::from_sat(1_000),
+                main_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                    .parse()
+                    .unwrap(),
+            },
+        };
+        let bundled_outpoint = OutPoint::Regular {
+            txid: [1; 32].into(),
+            vout: 0,
+        };
+        let unbundled_outpoint = OutPoint::Regular {
+            txid: [2; 32].into(),
+            vout: 0,
+        };
+        let spend = |outpoint: OutPoint| FilledTransaction {
+            transaction: Transaction {
+                inputs: vec![(outpoint, [0; 32])],
+                proof: Default::default(),
+                outputs: Vec::new(),
+                orchard_bundle: None,
+            },
+            spent_utxos: vec![withdrawal.clone()],
+        };
+        {
+            let rotxn = env.read_txn()?;
+            state.validate_withdrawal_spends(
+                &rotxn,
+                &[spend(bundled_outpoint)],
+            )?;
+        }
+        {
+            let bundle = WithdrawalBundle::new(
+                1,
+                bitcoin::Amount::from_sat(1_000),
+                BTreeMap::from([(bundled_outpoint, withdrawal.clone())]),
+                Vec::new(),
+            )?;
+            let m6id = bundle.compute_m6id();
+            let mut rwtxn = env.write_txn()?;
+            state.withdrawal_bundles.put(
+                &mut rwtxn,
+                &m6id,
+                &(
+                    WithdrawalBundleInfo::Known(bundle),
+                    RollBack::new(WithdrawalBundleStatus::Pending, 1),
+                ),
+            )?;
+            state
+                .pending_withdrawal_bundle
+                .put(&mut rwtxn, &(), &m6id)?;
+            rwtxn.commit()?;
+        }
+        let rotxn = env.read_txn()?;
+        state
+            .validate_withdrawal_spends(&rotxn, &[spend(unbundled_outpoint)])?;
+        let err = state
+            .validate_withdrawal_spends(&rotxn, &[spend(bundled_outpoint)])
+            .expect_err("bundled withdrawal should not be spendable");
+        anyhow::ensure!(matches!(
+            err,
+            Error::WithdrawalBundled { outpoint } if outpoint == bundled_outpoint
+        ));
+        // Blocks may spend bundled withdrawals until the rule is active
+        anyhow::ensure!(
+            super::withdrawal_cancellation_activation_height(state.network)
+                .is_none()
+        );
+        state.validate_block_withdrawal_spends(
+            &rotxn,
+            1,
+            &[spend(bundled_outpoint)],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn treasury_breakdown() -> anyhow::Result<()> {
+        use bitcoin::hashes::Hash as _;
+
+        let (env, state) = fresh_state("treasury-breakdown")?;
+        let withdrawal = Output {
+            address: TransparentAddress::ALL_ZEROS,
+            content: OutputContent::Withdrawal {
+                value: bitcoin::Amount::from_sat(10_000),
+                main_fee: bitcoin::Amount::from_sat(1_000),
+                main_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                    .parse()
+                    .unwrap(),
+            },
+        };
+        {
+            let mut rwtxn = env.write_txn()?;
+            // Unspent deposit: 20,000 sats
+            state.utxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(&OutPoint::Deposit(bitcoin::OutPoint {
+                    txid: bitcoin::Txid::from_byte_array([1; 32]),
+                    vout: 0,
+                })),
+                &value_output(TransparentAddress::ALL_ZEROS, 20_000),
+            )?;
+            // Spent deposit: 30,000 sats
+            state.stxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(&OutPoint::Deposit(bitcoin::OutPoint {
+                    txid: bitcoin::Txid::from_byte_array([2; 32]),
+                    vout: 0,
+                })),
+                &SpentOutput {
+                    output: value_output(TransparentAddress::ALL_ZEROS, 30_000),
+                    inpoint: InPoint::Regular {
+                        txid: [2; 32].into(),
+                        vin: 0,
+                    },
+                },
+            )?;
+            // Shielded deposit: 5,000 sats
+            state.shielded_deposits.put(
+                &mut rwtxn,
+                &bitcoin::OutPoint {
+                    txid: bitcoin::Txid::from_byte_array([3; 32]),
+                    vout: 0,
+                },
+                &bitcoin::Amount::from_sat(5_000),
+            )?;
+            // Unbundled withdrawal: 11,000 sats
+            state.utxos.put(
+                &mut rwtxn,
+                &OutPointKey::from(&OutPoint::Regular {
+                    txid: [4; 32].into(),
+                    vout: 0,
+                }),
+                &withdrawal,
+            )?;
+            // Submitted and confirmed bundles, each spending a withdrawal
+            // of 11,000 sats
+            for (i, status) in [
+                (5u8, WithdrawalBundleStatus::Submitted),
+                (6u8, WithdrawalBundleStatus::Confirmed),
+            ] {
+                let outpoint = OutPoint::Regular {
+                    txid: [i; 32].into(),
+                    vout: 0,
+                };
+                let bundle = WithdrawalBundle::new(
+                    1,
+                    bitcoin::Amount::from_sat(1_000),
+                    BTreeMap::from([(outpoint, withdrawal.clone())]),
+                    Vec::new(),
+                )?;
+                let m6id = bundle.compute_m6id();
+                state.stxos.put(
+                    &mut rwtxn,
+                    &OutPointKey::from(&outpoint),
+                    &SpentOutput {
+                        output: withdrawal.clone(),
+                        inpoint: InPoint::Withdrawal { m6id },
+                    },
+                )?;
+                state.withdrawal_bundles.put(
+                    &mut rwtxn,
+                    &m6id,
+                    &(
+                        WithdrawalBundleInfo::Known(bundle),
+                        RollBack::new(status, 1),
+                    ),
+                )?;
+            }
+            rwtxn.commit()?;
+        }
+        let rotxn = env.read_txn()?;
+        let breakdown = state.treasury_breakdown(&rotxn)?;
+        anyhow::ensure!(
+            breakdown
+                == TreasuryBreakdown {
+                    deposits: bitcoin::Amount::from_sat(55_000),
+                    submitted_withdrawals: bitcoin::Amount::from_sat(11_000),
+                    confirmed_withdrawals: bitcoin::Amount::from_sat(11_000),
+                    pending_withdrawals: bitcoin::Amount::from_sat(11_000),
+                },
+            "unexpected treasury breakdown: {breakdown:?}"
+        );
+        anyhow::ensure!(
+            breakdown.expected_ctip_value()?
+                == bitcoin::Amount::from_sat(44_000)
+        );
+        let sidechain_wealth = state.sidechain_wealth(&rotxn)?;
+        anyhow::ensure!(
+            breakdown.expected_sidechain_wealth()? == sidechain_wealth,
+            "expected sidechain wealth ({}) does not match computed ({})",
+            breakdown.expected_sidechain_wealth()?,
+            sidechain_wealth,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shielded_pool_value_cannot_become_negative() -> anyhow::Result<()> {
+        use bitcoin::{Amount, SignedAmount};
+
+        use crate::state::{error, orchard::apply_value_balance};
+
+        let pool_value = Amount::from_sat(1_000);
+        anyhow::ensure!(
+            apply_value_balance(pool_value, SignedAmount::from_sat(400))?
+                == Amount::from_sat(600)
+        );
+        anyhow::ensure!(
+            apply_value_balance(pool_value, SignedAmount::from_sat(-400))?
+                == Amount::from_sat(1_400)
+        );
+        anyhow::ensure!(
+            apply_value_balance(pool_value, SignedAmount::from_sat(1_000))?
+                == Amount::ZERO
+        );
+        let err =
+            apply_value_balance(pool_value, SignedAmount::from_sat(1_001))
+                .expect_err("pool value should not become negative");
+        anyhow::ensure!(matches!(
+            err,
+            error::Orchard::NegativePoolValue { pool_value: value, value_out }
+                if value == pool_value && value_out == Amount::from_sat(1_001)
+        ));
+        Ok(())
+    }
 }
@@ -1,16 +1,55 @@
 //! Orchard state
 
+use fallible_iterator::FallibleIterator as _;
 use heed::types::{SerdeBincode, Unit};
-use sneed::{DatabaseUnique, RoDatabaseUnique, RwTxn, UnitKey};
+use sneed::{DatabaseUnique, RoDatabaseUnique, RoTxn, RwTxn, UnitKey};
 
 use crate::{
     state::{self, error::Orchard as Error},
     types::{
-        BlockHash, VERSION, Version,
+        AmountOverflowError, BlockHash, Transaction, VERSION, Version,
         orchard::{Anchor, Frontier, MerkleHashOrchard, Nullifier},
     },
 };
 
+/// Net value removed from the shielded pool by the orchard bundles in the
+/// specified transactions
+pub fn value_balance<'a, Txs>(
+    transactions: Txs,
+) -> Result<bitcoin::SignedAmount, AmountOverflowError>
+where
+    Txs: IntoIterator<Item = &'a Transaction>,
+{
+    transactions
+        .into_iter()
+        .filter_map(|transaction| transaction.orchard_bundle.as_ref())
+        .try_fold(bitcoin::SignedAmount::ZERO, |acc, bundle| {
+            acc.checked_add(bundle.value_balance())
+        })
+        .ok_or(AmountOverflowError)
+}
+
+/// Shielded pool value after removing `value_balance` from the pool.
+/// Returns an error if the pool value would become negative.
+pub fn apply_value_balance(
+    pool_value: bitcoin::Amount,
+    value_balance: bitcoin::SignedAmount,
+) -> Result<bitcoin::Amount, Error> {
+    if value_balance.is_positive() {
+        let value_out = value_balance.unsigned_abs();
+        pool_value
+            .checked_sub(value_out)
+            .ok_or(Error::NegativePoolValue {
+                pool_value,
+                value_out,
+            })
+    } else {
+        Ok(pool_value
+            .checked_add(value_balance.unsigned_abs())
+            .ok_or(AmountOverflowError)?)
+    }
+}
+
 #[derive(Clone)]
 pub struct Orchard {
     /// Maps block hashes to historical roots.
@@ -24,12 +63,15 @@ pub struct Orchard {
     historical_roots:
         DatabaseUnique<SerdeBincode<Anchor>, SerdeBincode<Option<BlockHash>>>,
     nullifiers: DatabaseUnique<SerdeBincode<Nullifier>, Unit>,
+    /// Total value of notes in the shielded pool.
+    /// Should always exist
+    pool_value: DatabaseUnique<UnitKey, SerdeBincode<bitcoin::Amount>>,
     /// Version number for this DB
     version: DatabaseUnique<UnitKey, SerdeBincode<Version>>,
 }
 
 impl Orchard {
-    pub const NUM_DBS: u32 = 5;
+    pub const NUM_DBS: u32 = 6;
 
     pub fn new(
         env: &sneed::Env,
@@ -42,6 +84,8 @@ impl Orchard {
             DatabaseUnique::create(env, rwtxn, "orchard_historical_roots")?;
         let nullifiers =
             DatabaseUnique::create(env, rwtxn, "orchard_nullifiers")?;
+        let pool_value =
+            DatabaseUnique::create(env, rwtxn, "orchard_pool_value")?;
         let version =
             DatabaseUnique::create(env, rwtxn, "state_orchard_version")?;
         let res = Self {
@@ -49,23 +93,75 @@ impl Orchard {
             frontier,
             historical_roots,
             nullifiers,
+            pool_value,
             version,
         };
-        if !res.frontier.contains_key(rwtxn, &())? {
-            res.frontier.put(rwtxn, &(), &Frontier::empty())?;
-        }
-        if res.historical_roots.len(rwtxn)? == 0 {
-            let empty_root = Frontier::empty().root().into();
-            res.block_hash_to_root.put(rwtxn, &None, &empty_root)?;
-            res.historical_roots.put(rwtxn, &empty_root, &None)?;
-        }
-        assert_ne!(res.block_hash_to_root.len(rwtxn)?, 0);
+        let () = res.init(rwtxn)?;
         if !res.version.contains_key(rwtxn, &())? {
             res.version.put(rwtxn, &(), &*VERSION)?;
         }
         Ok(res)
     }
 
+    /// Store the empty frontier and root, and an empty pool value, if they
+    /// do not already exist
+    fn init(&self, rwtxn: &mut RwTxn) -> Result<(), state::Error> {
+        if !self.frontier.contains_key(rwtxn, &())? {
+            self.frontier.put(rwtxn, &(), &Frontier::empty())?;
+        }
+        if !self.pool_value.contains_key(rwtxn, &())? {
+            self.pool_value.put(rwtxn, &(), &bitcoin::Amount::ZERO)?;
+        }
+        if self.historical_roots.len(rwtxn)? == 0 {
+            let empty_root = Frontier::empty().root().into();
+            self.block_hash_to_root.put(rwtxn, &None, &empty_root)?;
+            self.historical_roots.put(rwtxn, &empty_root, &None)?;
+        }
+        assert_ne!(self.block_hash_to_root.len(rwtxn)?, 0);
+        Ok(())
+    }
+
+    /// Delete all Orchard state, leaving only the empty frontier and root
+    pub(in crate::state) fn reset(
+        &self,
+        rwtxn: &mut RwTxn,
+    ) -> Result<(), state::Error> {
+        self.block_hash_to_root.clear(rwtxn)?;
+        self.frontier.clear(rwtxn)?;
+        self.historical_roots.clear(rwtxn)?;
+        self.nullifiers.clear(rwtxn)?;
+        self.pool_value.clear(rwtxn)?;
+        self.init(rwtxn)
+    }
+
+    /// Replace all Orchard state with the specified frontier, nullifiers,
+    /// historical roots, and pool value
+    pub(in crate::state) fn load(
+        &self,
+        rwtxn: &mut RwTxn,
+        frontier: &Frontier,
+        nullifiers: &[Nullifier],
+        historical_roots: &[(Anchor, Option<BlockHash>)],
+        pool_value: bitcoin::Amount,
+    ) -> Result<(), Error> {
+        self.block_hash_to_root.clear(rwtxn)?;
+        self.historical_roots.clear(rwtxn)?;
+        self.nullifiers.clear(rwtxn)?;
+        self.frontier.put(rwtxn, &(), frontier)?;
+        self.pool_value.put(rwtxn, &(), &pool_value)?;
+        for (root, block_hash) in historical_roots {
+            // A block's first root precedes any additional roots
+            if !self.block_hash_to_root.contains_key(rwtxn, block_hash)? {
+                self.block_hash_to_root.put(rwtxn, block_hash, root)?;
+            }
+            self.historical_roots.put(rwtxn, root, block_hash)?;
+        }
+        for nullifier in nullifiers {
+            self.nullifiers.put(rwtxn, nullifier, &())?;
+        }
+        Ok(())
+    }
+
     pub fn frontier(
         &self,
     ) -> &RoDatabaseUnique<UnitKey, SerdeBincode<Frontier>> {
@@ -79,12 +175,41 @@ impl Orchard {
         &self.historical_roots
     }
 
+    /// All historical roots, with each block's first root preceding any
+    /// additional roots for the block
+    pub(in crate::state) fn get_historical_roots(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<Vec<(Anchor, Option<BlockHash>)>, state::Error> {
+        let mut res: Vec<_> = self
+            .block_hash_to_root
+            .iter(rotxn)?
+            .map(|(block_hash, root)| Ok((root, block_hash)))
+            .collect()?;
+        let historical_roots: Vec<_> =
+            self.historical_roots.iter(rotxn)?.collect()?;
+        for (root, block_hash) in historical_roots {
+            if self.block_hash_to_root.try_get(rotxn, &block_hash)?
+                != Some(root)
+            {
+                res.push((root, block_hash));
+            }
+        }
+        Ok(res)
+    }
+
     pub fn nullifiers(
         &self,
     ) -> &RoDatabaseUnique<SerdeBincode<Nullifier>, Unit> {
         &self.nullifiers
     }
 
+    pub fn pool_value(
+        &self,
+    ) -> &RoDatabaseUnique<UnitKey, SerdeBincode<bitcoin::Amount>> {
+        &self.pool_value
+    }
+
     pub(in crate::state) fn put_frontier(
         &self,
         rwtxn: &mut RwTxn,
@@ -112,6 +237,59 @@ impl Orchard {
         }
     }
 
+    /// Store an additional historical root for a block, if it is new, eg.
+    /// the root after appending notes minted by shielded deposits.
+    /// The block's first root remains valid.
+    /// Returns `true` if the root is new, `false` otherwise
+    pub(in crate::state) fn put_additional_historical_root(
+        &self,
+        rwtxn: &mut RwTxn,
+        block_hash: BlockHash,
+        root: MerkleHashOrchard,
+    ) -> Result<bool, Error> {
+        let root = Anchor::from(root);
+        if self.historical_roots.contains_key(rwtxn, &root)? {
+            Ok(false)
+        } else {
+            self.historical_roots.put(rwtxn, &root, &Some(block_hash))?;
+            Ok(true)
+        }
+    }
+
+    /// Delete an additional historical root for a block, if it exists.
+    /// The block's first root is not deleted.
+    pub(in crate::state) fn delete_additional_historical_root(
+        &self,
+        rwtxn: &mut RwTxn,
+        block_hash: BlockHash,
+        root: MerkleHashOrchard,
+    ) -> Result<bool, Error> {
+        let root = Anchor::from(root);
+        if self.block_hash_to_root.try_get(rwtxn, &Some(block_hash))?
+            == Some(root)
+            || self.historical_roots.try_get(rwtxn, &root)?
+                != Some(Some(block_hash))
+        {
+            return Ok(false);
+        }
+        self.historical_roots.delete(rwtxn, &root)?;
+        Ok(true)
+    }
+
+    /// Remove `value_balance` from the shielded pool.
+    /// Returns an error if the pool value would become negative.
+    pub(in crate::state) fn apply_value_balance(
+        &self,
+        rwtxn: &mut RwTxn,
+        value_balance: bitcoin::SignedAmount,
+    ) -> Result<(), Error> {
+        let pool_value = self.pool_value.get(rwtxn, &())?;
+        let pool_value = apply_value_balance(pool_value, value_balance)?;
+        self.pool_value
+            .put(rwtxn, &(), &pool_value)
+            .map_err(Error::from)
+    }
+
     pub(in crate::state) fn put_nullifier(
         &self,
         rwtxn: &mut RwTxn,
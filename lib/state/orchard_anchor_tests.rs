@@ -23,9 +23,9 @@ use crate::{
         error::{self, Error},
     },
     types::{
-        AccumulatorDiff, AuthorizedTransaction, Body, Header, OutPoint, Output,
-        OutputContent, PointedOutput, Transaction, TransparentAddress,
-        orchard as o,
+        AccumulatorDiff, AuthorizedTransaction, Body, Header, Network,
+        OutPoint, Output, OutputContent, PointedOutput, Transaction,
+        TransparentAddress, orchard as o,
     },
 };
 
@@ -235,7 +235,7 @@ fn forged_anchor_rejected_in_block() {
         opts.map_size(1024 * 1024 * 1024).max_dbs(State::NUM_DBS);
         unsafe { sneed::Env::open(&opts, &tmp.0) }.unwrap()
     };
-    let state = State::new(&env).unwrap();
+    let state = State::new(&env, false, Network::default()).unwrap();
 
     let attacker_addr = TransparentAddress([0x11; 20]);
     let empty_proof = {
@@ -0,0 +1,477 @@
+//! State snapshots, used to bootstrap a node from a UTXO set instead of
+//! applying every historical block.
+//! Background validation of history below a snapshot is not implemented.
+//! A node started from a snapshot treats blocks up to the snapshot tip as
+//! pruned, so it never fetches their bodies, and relies on the snapshot hash
+//! for their validity.
+
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use fallible_iterator::FallibleIterator as _;
+use serde::{Deserialize, Serialize};
+use sneed::{RoTxn, RwTxn};
+
+use crate::{
+    state::{
+        Error, State, WithdrawalBundleInfo, error::Snapshot as SnapshotError,
+        rollback::RollBack,
+    },
+    types::{
+        Accumulator, BlockHash, Hash, InPoint, M6id, Network, OutPoint,
+        OutPointKey, Output, SnapshotHash, SpentOutput, WithdrawalBundleStatus,
+        orchard::{Anchor, Frontier, Nullifier},
+    },
+};
+
+/// Magic bytes at the start of a snapshot file
+const MAGIC: &[u8; 8] = b"THORSNAP";
+
+/// Snapshot file format version
+pub const FORMAT_VERSION: u32 = 4;
+
+const SIGNET_SNAPSHOT_HASHES: &[SnapshotHash] = &[];
+
+const FORKNET_SNAPSHOT_HASHES: &[SnapshotHash] = &[];
+
+/// Snapshots that can be loaded without explicitly trusting them
+pub const fn trusted_hashes(network: Network) -> &'static [SnapshotHash] {
+    match network {
+        Network::Signet => SIGNET_SNAPSHOT_HASHES,
+        Network::Regtest => &[],
+        Network::Forknet => FORKNET_SNAPSHOT_HASHES,
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> SnapshotHash {
+    let hash: Hash = blake3::hash(bytes).into();
+    SnapshotHash(hash)
+}
+
+/// Everything needed to validate blocks on top of the snapshot tip.
+/// Spent outputs are not included, except for spent deposits and outputs
+/// spent by withdrawal bundles, which are needed to compute sidechain
+/// wealth.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    tip: BlockHash,
+    height: u32,
+    utxos: Vec<(OutPoint, Output)>,
+    stxos: Vec<(OutPoint, SpentOutput)>,
+    utreexo_accumulator: Accumulator,
+    orchard_frontier: Frontier,
+    orchard_nullifiers: Vec<Nullifier>,
+    orchard_historical_roots: Vec<(Anchor, Option<BlockHash>)>,
+    orchard_pool_value: bitcoin::Amount,
+    pending_withdrawal_bundle: Option<M6id>,
+    latest_failed_withdrawal_bundle: Option<RollBack<M6id>>,
+    withdrawal_bundles: Vec<(
+        M6id,
+        (WithdrawalBundleInfo, RollBack<WithdrawalBundleStatus>),
+    )>,
+    deposit_blocks: Vec<(u32, (bitcoin::BlockHash, u32))>,
+    shielded_deposits: Vec<(bitcoin::OutPoint, bitcoin::Amount)>,
+    withdrawal_bundle_event_blocks: Vec<(u32, (bitcoin::BlockHash, u32))>,
+    withdrawal_heights: Vec<(OutPoint, u32)>,
+    /// Creation heights of withdrawal outputs in `stxos`, which are
+    /// restored if a withdrawal bundle fails
+    spent_withdrawal_heights: Vec<(OutPoint, u32)>,
+}
+
+impl Snapshot {
+    pub fn tip(&self) -> BlockHash {
+        self.tip
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn utreexo_accumulator(&self) -> &Accumulator {
+        &self.utreexo_accumulator
+    }
+
+    pub fn orchard_frontier(&self) -> &Frontier {
+        &self.orchard_frontier
+    }
+
+    /// Hash committing to the snapshot contents
+    pub fn hash(&self) -> Result<SnapshotHash, SnapshotError> {
+        let bytes = bincode::serialize(self)?;
+        Ok(hash_bytes(&bytes))
+    }
+
+    /// Write the snapshot, returning the snapshot hash.
+    /// The file consists of magic bytes, the format version, the snapshot
+    /// hash, and the bincode-serialized snapshot.
+    pub fn write<W: Write>(
+        &self,
+        mut writer: W,
+    ) -> Result<SnapshotHash, SnapshotError> {
+        let bytes = bincode::serialize(self)?;
+        let hash = hash_bytes(&bytes);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&hash.0)?;
+        writer.write_all(&bytes)?;
+        writer.flush()?;
+        Ok(hash)
+    }
+
+    /// Read a snapshot, checking it against the hash in the file
+    pub fn read<R: Read>(
+        mut reader: R,
+    ) -> Result<(Self, SnapshotHash), SnapshotError> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+        let mut expected = Hash::default();
+        reader.read_exact(&mut expected)?;
+        let expected = SnapshotHash(expected);
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let computed = hash_bytes(&bytes);
+        if computed != expected {
+            return Err(SnapshotError::HashMismatch { expected, computed });
+        }
+        let snapshot = bincode::deserialize(&bytes)?;
+        Ok((snapshot, computed))
+    }
+}
+
+/// Snapshot file to load
+#[derive(Clone, Debug)]
+pub struct LoadConfig {
+    pub path: PathBuf,
+    /// Trust a snapshot with this hash, in addition to the snapshots that
+    /// are trusted for the network
+    pub trusted_hash: Option<SnapshotHash>,
+}
+
+impl LoadConfig {
+    /// Read the snapshot, checking that it is trusted
+    pub fn read(
+        &self,
+        network: Network,
+    ) -> Result<(Snapshot, SnapshotHash), SnapshotError> {
+        let file = std::fs::File::open(&self.path)?;
+        let (snapshot, hash) = Snapshot::read(std::io::BufReader::new(file))?;
+        if self.trusted_hash != Some(hash)
+            && !trusted_hashes(network).contains(&hash)
+        {
+            return Err(SnapshotError::Untrusted { hash });
+        }
+        Ok((snapshot, hash))
+    }
+}
+
+impl State {
+    /// Create a snapshot of the state at the current tip.
+    /// Compact states do not store the full UTXO set, and cannot be
+    /// snapshotted.
+    pub fn snapshot(&self, rotxn: &RoTxn) -> Result<Snapshot, Error> {
+        if self.is_compact() {
+            return Err(SnapshotError::CompactState.into());
+        }
+        let tip = self.try_get_tip(rotxn)?.ok_or(Error::NoTip)?;
+        let height = self.try_get_height(rotxn)?.ok_or(Error::NoTip)?;
+        let utxos = self
+            .utxos
+            .iter(rotxn)?
+            .map(|(outpoint_key, output)| {
+                Ok((OutPoint::from(outpoint_key), output))
+            })
+            .collect()?;
+        let stxos: Vec<(OutPoint, SpentOutput)> = self
+            .stxos
+            .iter(rotxn)?
+            .map(|(outpoint_key, spent_output)| {
+                Ok((OutPoint::from(outpoint_key), spent_output))
+            })
+            .filter(|(outpoint, spent_output)| {
+                Ok(matches!(outpoint, OutPoint::Deposit(_))
+                    || matches!(
+                        spent_output.inpoint,
+                        InPoint::Withdrawal { .. }
+                    ))
+            })
+            .collect()?;
+        let orchard_frontier = self
+            .orchard
+            .frontier()
+            .try_get(rotxn, &())?
+            .unwrap_or_else(Frontier::empty);
+        let orchard_nullifiers =
+            self.orchard.nullifiers().iter_keys(rotxn)?.collect()?;
+        let orchard_historical_roots =
+            self.orchard.get_historical_roots(rotxn)?;
+        let orchard_pool_value = self
+            .orchard
+            .pool_value()
+            .try_get(rotxn, &())?
+            .unwrap_or(bitcoin::Amount::ZERO);
+        let withdrawal_bundles =
+            self.withdrawal_bundles.iter(rotxn)?.collect()?;
+        let deposit_blocks = self.deposit_blocks.iter(rotxn)?.collect()?;
+        let shielded_deposits =
+            self.shielded_deposits.iter(rotxn)?.collect()?;
+        let withdrawal_bundle_event_blocks =
+            self.withdrawal_bundle_event_blocks.iter(rotxn)?.collect()?;
+        let withdrawal_heights = self
+            .withdrawal_heights
+            .iter(rotxn)?
+            .map(|(outpoint_key, height)| {
+                Ok((OutPoint::from(outpoint_key), height))
+            })
+            .collect()?;
+        let mut spent_withdrawal_heights = Vec::new();
+        for (outpoint, spent_output) in &stxos {
+            if !spent_output.output.content.is_withdrawal() {
+                continue;
+            }
+            if let Some(height) = self
+                .spent_withdrawal_heights
+                .try_get(rotxn, &OutPointKey::from(outpoint))?
+            {
+                spent_withdrawal_heights.push((*outpoint, height));
+            }
+        }
+        Ok(Snapshot {
+            tip,
+            height,
+            utxos,
+            stxos,
+            utreexo_accumulator: self.get_accumulator(rotxn)?,
+            orchard_frontier,
+            orchard_nullifiers,
+            orchard_historical_roots,
+            orchard_pool_value,
+            pending_withdrawal_bundle: self
+                .pending_withdrawal_bundle
+                .try_get(rotxn, &())?,
+            latest_failed_withdrawal_bundle: self
+                .latest_failed_withdrawal_bundle
+                .try_get(rotxn, &())?,
+            withdrawal_bundles,
+            deposit_blocks,
+            shielded_deposits,
+            withdrawal_bundle_event_blocks,
+            withdrawal_heights,
+            spent_withdrawal_heights,
+        })
+    }
+
+    /// Replace the state with a snapshot.
+    /// The state MUST be empty.
+    pub fn load_snapshot(
+        &self,
+        rwtxn: &mut RwTxn,
+        snapshot: &Snapshot,
+    ) -> Result<(), Error> {
+        if let Some(tip) = self.try_get_tip(rwtxn)? {
+            return Err(SnapshotError::StateNotEmpty { tip }.into());
+        }
+        let () = self.reset(rwtxn)?;
+        self.tip.put(rwtxn, &(), &snapshot.tip)?;
+        self.height.put(rwtxn, &(), &snapshot.height)?;
+        for (outpoint, output) in &snapshot.utxos {
+            if !self.stores_utxo(outpoint, output) {
+                continue;
+            }
+            self.utxos
+                .put(rwtxn, &OutPointKey::from(outpoint), output)?;
+        }
+        for (outpoint, spent_output) in &snapshot.stxos {
+            if !self.stores_stxo(outpoint, spent_output) {
+                continue;
+            }
+            self.stxos.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                spent_output,
+            )?;
+        }
+        self.utreexo_accumulator.put(
+            rwtxn,
+            &(),
+            &snapshot.utreexo_accumulator,
+        )?;
+        let () = self.orchard.load(
+            rwtxn,
+            &snapshot.orchard_frontier,
+            &snapshot.orchard_nullifiers,
+            &snapshot.orchard_historical_roots,
+            snapshot.orchard_pool_value,
+        )?;
+        if let Some(m6id) = &snapshot.pending_withdrawal_bundle {
+            self.pending_withdrawal_bundle.put(rwtxn, &(), m6id)?;
+        }
+        if let Some(m6id) = &snapshot.latest_failed_withdrawal_bundle {
+            self.latest_failed_withdrawal_bundle.put(rwtxn, &(), m6id)?;
+        }
+        for (m6id, bundle) in &snapshot.withdrawal_bundles {
+            self.withdrawal_bundles.put(rwtxn, m6id, bundle)?;
+        }
+        for (idx, block) in &snapshot.deposit_blocks {
+            self.deposit_blocks.put(rwtxn, idx, block)?;
+        }
+        for (outpoint, value) in &snapshot.shielded_deposits {
+            self.shielded_deposits.put(rwtxn, outpoint, value)?;
+        }
+        for (idx, block) in &snapshot.withdrawal_bundle_event_blocks {
+            self.withdrawal_bundle_event_blocks.put(rwtxn, idx, block)?;
+        }
+        for (outpoint, height) in &snapshot.withdrawal_heights {
+            self.withdrawal_heights.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                height,
+            )?;
+        }
+        for (outpoint, height) in &snapshot.spent_withdrawal_heights {
+            self.spent_withdrawal_heights.put(
+                rwtxn,
+                &OutPointKey::from(outpoint),
+                height,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash as _;
+
+    use super::{LoadConfig, Snapshot};
+    use crate::{
+        state::{
+            error::Snapshot as SnapshotError,
+            test::{fresh_state, temp_env_path, value_output},
+        },
+        types::{
+            BlockHash, InPoint, Network, OutPoint, OutPointKey, SpentOutput,
+            TransparentAddress,
+        },
+    };
+
+    /// Snapshot a state with a tip, a UTXO and a spent deposit
+    fn test_snapshot() -> anyhow::Result<Snapshot> {
+        let (env, state) = fresh_state("snapshot-source")?;
+        let mut rwtxn = env.write_txn()?;
+        state.tip.put(&mut rwtxn, &(), &BlockHash::from([1; 32]))?;
+        state.height.put(&mut rwtxn, &(), &7)?;
+        let utxo = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([2; 32]),
+            vout: 0,
+        });
+        state.utxos.put(
+            &mut rwtxn,
+            &OutPointKey::from(&utxo),
+            &value_output(TransparentAddress::ALL_ZEROS, 50),
+        )?;
+        let stxo = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([3; 32]),
+            vout: 0,
+        });
+        state.stxos.put(
+            &mut rwtxn,
+            &OutPointKey::from(&stxo),
+            &SpentOutput {
+                output: value_output(TransparentAddress::ALL_ZEROS, 100),
+                inpoint: InPoint::Regular {
+                    txid: [4; 32].into(),
+                    vin: 0,
+                },
+            },
+        )?;
+        let snapshot = state.snapshot(&rwtxn)?;
+        Ok(snapshot)
+    }
+
+    #[test]
+    fn snapshot_round_trip() -> anyhow::Result<()> {
+        let snapshot = test_snapshot()?;
+        let mut bytes = Vec::new();
+        let hash = snapshot.write(&mut bytes)?;
+        anyhow::ensure!(hash == snapshot.hash()?);
+        let (read, read_hash) = Snapshot::read(bytes.as_slice())?;
+        anyhow::ensure!(read_hash == hash);
+        anyhow::ensure!(read.hash()? == hash);
+        // Loading the snapshot reproduces the state it was taken from
+        let (env, state) = fresh_state("snapshot-round-trip")?;
+        let mut rwtxn = env.write_txn()?;
+        state.load_snapshot(&mut rwtxn, &read)?;
+        anyhow::ensure!(state.try_get_tip(&rwtxn)? == Some(snapshot.tip()));
+        anyhow::ensure!(
+            state.try_get_height(&rwtxn)? == Some(snapshot.height())
+        );
+        anyhow::ensure!(
+            state.sidechain_wealth(&rwtxn)? == bitcoin::Amount::from_sat(150)
+        );
+        anyhow::ensure!(state.snapshot(&rwtxn)?.hash()? == hash);
+        // A snapshot cannot be loaded into a non-empty state
+        anyhow::ensure!(state.load_snapshot(&mut rwtxn, &read).is_err_and(
+            |err| matches!(
+                err,
+                crate::state::Error::Snapshot(
+                    SnapshotError::StateNotEmpty { .. }
+                )
+            )
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_is_checked() -> anyhow::Result<()> {
+        let snapshot = test_snapshot()?;
+        let mut bytes = Vec::new();
+        let _hash = snapshot.write(&mut bytes)?;
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        anyhow::ensure!(matches!(
+            Snapshot::read(corrupted.as_slice()),
+            Err(SnapshotError::HashMismatch { .. })
+        ));
+        let mut bad_magic = bytes;
+        bad_magic[0] ^= 1;
+        anyhow::ensure!(matches!(
+            Snapshot::read(bad_magic.as_slice()),
+            Err(SnapshotError::InvalidMagic)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn untrusted_snapshot_is_rejected() -> anyhow::Result<()> {
+        let snapshot = test_snapshot()?;
+        let path = temp_env_path("untrusted-snapshot")?;
+        let hash = snapshot.write(std::fs::File::create(&path)?)?;
+        let untrusted = LoadConfig {
+            path: path.clone(),
+            trusted_hash: None,
+        };
+        anyhow::ensure!(matches!(
+            untrusted.read(Network::Regtest),
+            Err(SnapshotError::Untrusted { hash: untrusted_hash })
+                if untrusted_hash == hash
+        ));
+        let trusted = LoadConfig {
+            path,
+            trusted_hash: Some(hash),
+        };
+        let (_snapshot, trusted_hash) = trusted.read(Network::Regtest)?;
+        anyhow::ensure!(trusted_hash == hash);
+        Ok(())
+    }
+}
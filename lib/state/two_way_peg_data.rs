@@ -7,29 +7,110 @@ use sneed::{RoTxn, RwTxn, db::error::Error as DbError};
 
 use crate::{
     state::{
-        Error, State, WITHDRAWAL_BUNDLE_FAILURE_GAP, WithdrawalBundleInfo,
-        error, rollback::RollBack,
+        Error, MAX_WITHDRAWAL_WAIT, State, WITHDRAWAL_AGE_BOOST_INTERVAL,
+        WITHDRAWAL_BUNDLE_FAILURE_GAP, WithdrawalBundleInfo, error,
+        rollback::RollBack, shielded_deposit_activation_height,
+        withdrawal_priority_activation_height,
     },
     types::{
         Accumulator, AccumulatorDiff, AggregatedWithdrawal,
         AmountOverflowError, InPoint, M6id, OutPoint, OutPointKey, Output,
         OutputContent, PointedOutput, PointedOutputRef, SpentOutput,
         WithdrawalBundle, WithdrawalBundleEvent, WithdrawalBundleEventStatus,
-        WithdrawalBundleStatus, hash,
+        WithdrawalBundleStatus, hash, orchard,
         proto::mainchain::{BlockEvent, TwoWayPegData},
     },
 };
 
+/// Priority of an aggregated withdrawal for inclusion in a bundle.
+/// Variants are ordered from lowest to highest priority.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum WithdrawalPriority {
+    /// Mainchain fee rate in millisats per vbyte of bundle output, boosted
+    /// by the number of blocks waited
+    FeeRate(u128),
+    /// Waited at least [`MAX_WITHDRAWAL_WAIT`] blocks.
+    /// Overdue withdrawals are prioritized by the number of blocks waited.
+    Overdue { age: u32 },
+}
+
+impl WithdrawalPriority {
+    fn new(main_fee: bitcoin::Amount, txout_size: u32, age: u32) -> Self {
+        if age >= MAX_WITHDRAWAL_WAIT {
+            return Self::Overdue { age };
+        }
+        let fee_rate =
+            u128::from(main_fee.to_sat()) * 1000 / u128::from(txout_size);
+        let boost_interval = u128::from(WITHDRAWAL_AGE_BOOST_INTERVAL);
+        Self::FeeRate(
+            fee_rate * (boost_interval + u128::from(age)) / boost_interval,
+        )
+    }
+}
+
+/// Select aggregated withdrawals to include in a bundle, in bundle order.
+/// Each aggregated withdrawal is paired with the height at which its oldest
+/// withdrawal was created.
+/// If `prioritize` is `false`, withdrawals are selected in
+/// `AggregatedWithdrawal` order, as before
+/// [`withdrawal_priority_activation_height`].
+fn select_withdrawals(
+    aggregated_withdrawals: Vec<(AggregatedWithdrawal, u32)>,
+    block_height: u32,
+    prioritize: bool,
+) -> Vec<(AggregatedWithdrawal, bitcoin::ScriptBuf)> {
+    let mut candidates: Vec<_> = aggregated_withdrawals
+        .into_iter()
+        .filter_map(|(aggregated, created_height)| {
+            let script_pubkey =
+                aggregated.main_address.assume_checked_ref().script_pubkey();
+            // Withdrawals with invalid SPKs cannot be included
+            let spk_size = u32::try_from(script_pubkey.len()).ok()?;
+            let txout_size = WithdrawalBundle::txout_size(spk_size)?;
+            let age = block_height.saturating_sub(created_height);
+            let priority = prioritize.then(|| {
+                WithdrawalPriority::new(aggregated.main_fee, txout_size, age)
+            });
+            Some((priority, txout_size, aggregated, script_pubkey))
+        })
+        .collect();
+    // Highest priority first, ties broken by `AggregatedWithdrawal` order
+    candidates.sort_by(|(priority_a, _, a, _), (priority_b, _, b, _)| {
+        priority_b.cmp(priority_a).then_with(|| b.cmp(a))
+    });
+    let mut selected = Vec::new();
+    let mut bundle_txouts_size: u32 = 0;
+    for (_, txout_size, aggregated, script_pubkey) in candidates {
+        let Ok(n_outputs) = u32::try_from(selected.len() + 1) else {
+            break;
+        };
+        if let Some(sum_txout_sizes) =
+            bundle_txouts_size.checked_add(txout_size)
+        {
+            bundle_txouts_size = sum_txout_sizes;
+        } else {
+            break;
+        };
+        if WithdrawalBundle::predict_weight(n_outputs, bundle_txouts_size)
+            .is_none()
+        {
+            break;
+        }
+        selected.push((aggregated, script_pubkey));
+    }
+    selected
+}
+
 fn collect_withdrawal_bundle(
     state: &State,
     rotxn: &RoTxn,
     block_height: u32,
 ) -> Result<Option<WithdrawalBundle>, Error> {
     // Aggregate all outputs by destination.
-    // destination -> (value, mainchain fee, spent_utxos)
+    // destination -> (aggregated withdrawal, oldest creation height)
     let mut address_to_aggregated_withdrawal = HashMap::<
         bitcoin::Address<bitcoin::address::NetworkUnchecked>,
-        AggregatedWithdrawal,
+        (AggregatedWithdrawal, u32),
     >::new();
     let () = state
         .utxos
@@ -43,14 +124,25 @@ fn collect_withdrawal_bundle(
                 main_fee,
             } = output.content
             {
-                let aggregated = address_to_aggregated_withdrawal
-                    .entry(main_address.clone())
-                    .or_insert(AggregatedWithdrawal {
-                        spend_utxos: HashMap::new(),
-                        main_address: main_address.clone(),
-                        value: bitcoin::Amount::ZERO,
-                        main_fee: bitcoin::Amount::ZERO,
-                    });
+                // Withdrawals without a recorded creation height are
+                // treated as new
+                let created_height = state
+                    .withdrawal_heights
+                    .try_get(rotxn, &outpoint)
+                    .map_err(DbError::from)?
+                    .unwrap_or(block_height);
+                let (aggregated, oldest_height) =
+                    address_to_aggregated_withdrawal
+                        .entry(main_address.clone())
+                        .or_insert((
+                            AggregatedWithdrawal {
+                                spend_utxos: HashMap::new(),
+                                main_address: main_address.clone(),
+                                value: bitcoin::Amount::ZERO,
+                                main_fee: bitcoin::Amount::ZERO,
+                            },
+                            created_height,
+                        ));
                 // Add up all values.
                 aggregated.value = aggregated
                     .value
@@ -63,50 +155,29 @@ fn collect_withdrawal_bundle(
                 aggregated
                     .spend_utxos
                     .insert(OutPoint::from(outpoint), output);
+                *oldest_height = (*oldest_height).min(created_height);
             }
             Ok::<_, Error>(())
         })?;
     if address_to_aggregated_withdrawal.is_empty() {
         return Ok(None);
     }
-    let mut aggregated_withdrawals: Vec<_> =
-        address_to_aggregated_withdrawal.into_values().collect();
-    aggregated_withdrawals.sort_by_key(|a| std::cmp::Reverse(a.clone()));
+    let prioritize = withdrawal_priority_activation_height(state.network)
+        .is_some_and(|activation_height| block_height >= activation_height);
+    let selected = select_withdrawals(
+        address_to_aggregated_withdrawal.into_values().collect(),
+        block_height,
+        prioritize,
+    );
     let mut fee = bitcoin::Amount::ZERO;
     let mut spend_utxos = BTreeMap::<OutPoint, Output>::new();
-    let mut bundle_outputs = Vec::new();
-    let mut bundle_txouts_size: u32 = 0;
-    for aggregated in &aggregated_withdrawals {
-        let script_pubkey =
-            aggregated.main_address.assume_checked_ref().script_pubkey();
-        let Ok(n_outputs) = u32::try_from(bundle_outputs.len() + 1) else {
-            break;
-        };
-        let Ok(spk_size) = u32::try_from(script_pubkey.len()) else {
-            // This SPK is invalid, but others might be ok
-            continue;
-        };
-        let Some(txout_size) = WithdrawalBundle::txout_size(spk_size) else {
-            // This SPK is invalid, but others might be ok
-            continue;
-        };
-        if let Some(sum_txout_sizes) =
-            bundle_txouts_size.checked_add(txout_size)
-        {
-            bundle_txouts_size = sum_txout_sizes;
-        } else {
-            break;
-        };
-        if WithdrawalBundle::predict_weight(n_outputs, bundle_txouts_size)
-            .is_none()
-        {
-            break;
-        }
+    let mut bundle_outputs = Vec::with_capacity(selected.len());
+    for (aggregated, script_pubkey) in selected {
         let bundle_output = bitcoin::TxOut {
             value: aggregated.value,
             script_pubkey,
         };
-        spend_utxos.extend(aggregated.spend_utxos.clone());
+        spend_utxos.extend(aggregated.spend_utxos);
         bundle_outputs.push(bundle_output);
         fee += aggregated.main_fee;
     }
@@ -159,6 +230,7 @@ fn connect_withdrawal_bundle_submitted(
                 }
                 .into());
             };
+            state.spend_withdrawal_height(rwtxn, &key)?;
             let spent_output = SpentOutput {
                 output: spend_output.clone(),
                 inpoint: InPoint::Withdrawal { m6id },
@@ -332,6 +404,12 @@ fn connect_withdrawal_bundle_confirmed(
                     .collect()
                     .map_err(DbError::from)?;
                 for (outpoint, output) in &utxos {
+                    if output.content.is_withdrawal() {
+                        state.spend_withdrawal_height(
+                            rwtxn,
+                            &OutPointKey::from(outpoint),
+                        )?;
+                    }
                     let spent_output = SpentOutput {
                         output: output.clone(),
                         inpoint: InPoint::Withdrawal { m6id },
@@ -383,6 +461,7 @@ fn connect_withdrawal_bundle_confirmed(
                             },
                         );
                     }
+                    state.spend_withdrawal_height(rwtxn, &outpoint_key)?;
                     let spent_output = SpentOutput {
                         output: output.clone(),
                         inpoint: InPoint::Withdrawal { m6id },
@@ -453,6 +532,10 @@ fn connect_withdrawal_bundle_failed(
                     .utxos
                     .put(rwtxn, &OutPointKey::from(outpoint), output)
                     .map_err(DbError::from)?;
+                state.unspend_withdrawal_height(
+                    rwtxn,
+                    &OutPointKey::from(outpoint),
+                )?;
                 let utxo_hash = crate::types::hashes::hash_with_scratch_buffer(
                     &PointedOutput {
                         outpoint: *outpoint,
@@ -531,6 +614,28 @@ fn connect_withdrawal_bundle_event(
     }
 }
 
+/// Create a deposit UTXO
+fn connect_transparent_deposit(
+    state: &State,
+    rwtxn: &mut RwTxn,
+    accumulator_diff: &mut AccumulatorDiff,
+    outpoint: bitcoin::OutPoint,
+    output: Output,
+) -> Result<(), Error> {
+    let outpoint = OutPoint::Deposit(outpoint);
+    state
+        .utxos
+        .put(rwtxn, &OutPointKey::from(outpoint), &output)
+        .map_err(DbError::from)?;
+    let utxo_hash =
+        crate::types::hashes::hash_with_scratch_buffer(&PointedOutput {
+            outpoint,
+            output,
+        });
+    accumulator_diff.insert(utxo_hash.into());
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 fn connect_event(
     state: &State,
@@ -539,21 +644,19 @@ fn connect_event(
     accumulator_diff: &mut AccumulatorDiff,
     latest_deposit_block_hash: &mut Option<bitcoin::BlockHash>,
     latest_withdrawal_bundle_event_block_hash: &mut Option<bitcoin::BlockHash>,
+    frontier: &mut orchard::Frontier,
     event_block_hash: bitcoin::BlockHash,
     event: &BlockEvent,
 ) -> Result<(), Error> {
     match event {
         BlockEvent::Deposit(deposit) => {
-            let outpoint = OutPoint::Deposit(deposit.outpoint);
-            let output = deposit.output.clone();
-            state
-                .utxos
-                .put(rwtxn, &OutPointKey::from(outpoint), &output)
-                .map_err(DbError::from)?;
-            let utxo_hash = crate::types::hashes::hash_with_scratch_buffer(
-                &PointedOutput { outpoint, output },
-            );
-            accumulator_diff.insert(utxo_hash.into());
+            let () = connect_transparent_deposit(
+                state,
+                rwtxn,
+                accumulator_diff,
+                deposit.outpoint,
+                deposit.output.clone(),
+            )?;
             *latest_deposit_block_hash = Some(event_block_hash);
         }
         BlockEvent::WithdrawalBundle(withdrawal_bundle_event) => {
@@ -567,21 +670,67 @@ fn connect_event(
             )?;
             *latest_withdrawal_bundle_event_block_hash = Some(event_block_hash);
         }
+        BlockEvent::ShieldedDeposit(deposit) => {
+            *latest_deposit_block_hash = Some(event_block_hash);
+            // Must agree with `State::mints_shielded_deposit`
+            let note = if shielded_deposit_activation_height(state.network)
+                .is_some_and(|activation_height| {
+                    block_height >= activation_height
+                }) {
+                match deposit.note() {
+                    Ok(note) => Some(note),
+                    Err(err) => {
+                        let err = Error::from(err);
+                        tracing::warn!(
+                            %event_block_hash,
+                            "Crediting shielded deposit as a transparent \
+                             deposit: {err:#}"
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let Some(note) = note else {
+                return connect_transparent_deposit(
+                    state,
+                    rwtxn,
+                    accumulator_diff,
+                    deposit.outpoint,
+                    deposit.transparent_output(),
+                );
+            };
+            if !frontier.append(&note.commitment()) {
+                return Err(error::Orchard::AppendCommitment.into());
+            }
+            state
+                .shielded_deposits
+                .put(rwtxn, &deposit.outpoint, &deposit.value)
+                .map_err(DbError::from)?;
+            let value_in =
+                deposit.value.to_signed().map_err(|_| AmountOverflowError)?;
+            let () = state.orchard.apply_value_balance(rwtxn, -value_in)?;
+        }
     }
     Ok(())
 }
 
 /// Returns data that must be archived in order to disconnect to the new
 /// tip.
+/// Returns `Some(_)` frontier if shielded deposits changed the orchard
+/// frontier.
 pub fn connect(
     state: &State,
     rwtxn: &mut RwTxn,
     two_way_peg_data: &TwoWayPegData,
-) -> Result<Accumulator, Error> {
+) -> Result<(Accumulator, Option<orchard::Frontier>), Error> {
     let block_height = state.try_get_height(rwtxn)?.ok_or(Error::NoTip)?;
     tracing::trace!(%block_height, "Connecting 2WPD...");
     let mut accumulator = state.utreexo_accumulator.get(rwtxn, &())?;
     let mut accumulator_diff = AccumulatorDiff::default();
+    let mut frontier = state.orchard.frontier().get(rwtxn, &())?;
+    let body_root = frontier.root();
     let mut latest_deposit_block_hash = None;
     let mut latest_withdrawal_bundle_event_block_hash = None;
     for (event_block_hash, event_block_info) in &two_way_peg_data.block_info {
@@ -593,11 +742,27 @@ pub fn connect(
                 &mut accumulator_diff,
                 &mut latest_deposit_block_hash,
                 &mut latest_withdrawal_bundle_event_block_hash,
+                &mut frontier,
                 *event_block_hash,
                 event,
             )?;
         }
     }
+    // Shielded deposit notes are appended after the block's transactions.
+    // The root after the block's transactions remains a valid anchor, so
+    // that transactions anchored to it are not invalidated.
+    let frontier = if frontier.root() != body_root {
+        let tip = state.try_get_tip(rwtxn)?.ok_or(Error::NoTip)?;
+        let () = state.orchard.put_frontier(rwtxn, &frontier)?;
+        let _: bool = state.orchard.put_additional_historical_root(
+            rwtxn,
+            tip,
+            frontier.root(),
+        )?;
+        Some(frontier)
+    } else {
+        None
+    };
     // Handle deposits.
     if let Some(latest_deposit_block_hash) = latest_deposit_block_hash {
         let deposit_block_seq_idx = state
@@ -675,7 +840,7 @@ pub fn connect(
         .utreexo_accumulator
         .put(rwtxn, &(), &accumulator)
         .map_err(DbError::from)?;
-    Ok(accumulator)
+    Ok((accumulator, frontier))
 }
 
 fn disconnect_withdrawal_bundle_submitted(
@@ -727,6 +892,10 @@ fn disconnect_withdrawal_bundle_submitted(
                         &OutPointKey::from(outpoint),
                         output,
                     )?;
+                    state.unspend_withdrawal_height(
+                        rwtxn,
+                        &OutPointKey::from(outpoint),
+                    )?;
                     let utxo_hash = hash(&PointedOutput {
                         outpoint: *outpoint,
                         output: output.clone(),
@@ -801,6 +970,7 @@ fn disconnect_withdrawal_bundle_confirmed(
                             outpoint: *outpoint,
                         });
                     };
+                    state.unspend_withdrawal_height(rwtxn, &outpoint_key)?;
                     let utxo_hash = hash(&PointedOutputRef {
                         outpoint: *outpoint,
                         output,
@@ -825,6 +995,9 @@ fn disconnect_withdrawal_bundle_confirmed(
                         outpoint: *outpoint,
                     });
                 };
+                if output.content.is_withdrawal() {
+                    state.unspend_withdrawal_height(rwtxn, &outpoint_key)?;
+                }
                 let utxo_hash = crate::types::hashes::hash_with_scratch_buffer(
                     &PointedOutputRef {
                         outpoint: *outpoint,
@@ -900,6 +1073,10 @@ fn disconnect_withdrawal_bundle_failed(
                     }
                     .into());
                 };
+                state.spend_withdrawal_height(
+                    rwtxn,
+                    &OutPointKey::from(outpoint),
+                )?;
                 let utxo_hash = crate::types::hashes::hash_with_scratch_buffer(
                     &PointedOutput {
                         outpoint: *outpoint,
@@ -974,6 +1151,31 @@ fn disconnect_withdrawal_bundle_event(
     }
 }
 
+/// Delete a deposit UTXO
+fn disconnect_transparent_deposit(
+    state: &State,
+    rwtxn: &mut RwTxn,
+    accumulator_diff: &mut AccumulatorDiff,
+    outpoint: bitcoin::OutPoint,
+    output: Output,
+) -> Result<(), Error> {
+    let outpoint = OutPoint::Deposit(outpoint);
+    if !state
+        .utxos
+        .delete(rwtxn, &OutPointKey::from(outpoint))
+        .map_err(DbError::from)?
+    {
+        return Err(error::NoUtxo { outpoint }.into());
+    }
+    let utxo_hash =
+        crate::types::hashes::hash_with_scratch_buffer(&PointedOutput {
+            outpoint,
+            output,
+        });
+    accumulator_diff.remove(utxo_hash.into());
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 fn disconnect_event(
     state: &State,
@@ -987,19 +1189,13 @@ fn disconnect_event(
 ) -> Result<(), Error> {
     match event {
         BlockEvent::Deposit(deposit) => {
-            let outpoint = OutPoint::Deposit(deposit.outpoint);
-            let output = deposit.output.clone();
-            if !state
-                .utxos
-                .delete(rwtxn, &OutPointKey::from(outpoint))
-                .map_err(DbError::from)?
-            {
-                return Err(error::NoUtxo { outpoint }.into());
-            }
-            let utxo_hash = crate::types::hashes::hash_with_scratch_buffer(
-                &PointedOutput { outpoint, output },
-            );
-            accumulator_diff.remove(utxo_hash.into());
+            let () = disconnect_transparent_deposit(
+                state,
+                rwtxn,
+                accumulator_diff,
+                deposit.outpoint,
+                deposit.output.clone(),
+            )?;
             *latest_deposit_block_hash = Some(event_block_hash);
         }
         BlockEvent::WithdrawalBundle(withdrawal_bundle_event) => {
@@ -1012,6 +1208,32 @@ fn disconnect_event(
             )?;
             *latest_withdrawal_bundle_event_block_hash = Some(event_block_hash);
         }
+        BlockEvent::ShieldedDeposit(deposit) => {
+            *latest_deposit_block_hash = Some(event_block_hash);
+            if !state.mints_shielded_deposit(block_height, deposit) {
+                return disconnect_transparent_deposit(
+                    state,
+                    rwtxn,
+                    accumulator_diff,
+                    deposit.outpoint,
+                    deposit.transparent_output(),
+                );
+            }
+            // The orchard frontier is restored from the archive when the
+            // block is disconnected
+            if !state
+                .shielded_deposits
+                .delete(rwtxn, &deposit.outpoint)
+                .map_err(DbError::from)?
+            {
+                return Err(Error::NoShieldedDeposit {
+                    outpoint: deposit.outpoint,
+                });
+            }
+            let value_in =
+                deposit.value.to_signed().map_err(|_| AmountOverflowError)?;
+            let () = state.orchard.apply_value_balance(rwtxn, value_in)?;
+        }
     }
     Ok(())
 }
@@ -1028,6 +1250,21 @@ pub fn disconnect(
     let mut accumulator_diff = AccumulatorDiff::default();
     let mut latest_deposit_block_hash = None;
     let mut latest_withdrawal_bundle_event_block_hash = None;
+    // The frontier is not reverted until the block is disconnected, so its
+    // root is the root after any shielded deposits
+    if two_way_peg_data.block_info.values().any(|block_info| {
+        block_info
+            .shielded_deposits()
+            .any(|deposit| state.mints_shielded_deposit(block_height, deposit))
+    }) {
+        let tip = state.try_get_tip(rwtxn)?.ok_or(Error::NoTip)?;
+        let frontier = state.orchard.frontier().get(rwtxn, &())?;
+        let _: bool = state.orchard.delete_additional_historical_root(
+            rwtxn,
+            tip,
+            frontier.root(),
+        )?;
+    }
     // Restore pending withdrawal bundle
     for (event_block_hash, event_block_info) in
         two_way_peg_data.block_info.iter().rev()
@@ -1123,7 +1360,10 @@ pub fn disconnect(
 
 #[cfg(test)]
 mod test {
-    use std::{collections::BTreeMap, sync::Arc};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+    };
 
     use bitcoin::{
         Network,
@@ -1134,19 +1374,21 @@ mod test {
 
     use crate::{
         state::{
-            State, WithdrawalBundleInfo,
+            MAX_WITHDRAWAL_WAIT, State, WITHDRAWAL_AGE_BOOST_INTERVAL,
+            WithdrawalBundleInfo,
             rollback::RollBack,
-            test::{fresh_state, value_output},
+            test::{fresh_state, temp_env, value_output},
             two_way_peg_data::{
-                collect_withdrawal_bundle, disconnect,
-                disconnect_withdrawal_bundle_failed,
+                collect_withdrawal_bundle, connect_withdrawal_bundle_submitted,
+                disconnect, disconnect_withdrawal_bundle_failed,
+                disconnect_withdrawal_bundle_submitted, select_withdrawals,
             },
         },
         types::{
-            AccumulatorDiff, InPoint, M6id, OutPoint, OutPointKey, Output,
-            OutputContent, TransparentAddress, Txid, WithdrawalBundle,
-            WithdrawalBundleEvent, WithdrawalBundleEventStatus,
-            WithdrawalBundleStatus,
+            AccumulatorDiff, AggregatedWithdrawal, InPoint, M6id, OutPoint,
+            OutPointKey, Output, OutputContent, TransparentAddress, Txid,
+            WithdrawalBundle, WithdrawalBundleEvent,
+            WithdrawalBundleEventStatus, WithdrawalBundleStatus,
             proto::mainchain::{BlockEvent, BlockInfo, TwoWayPegData},
         },
     };
@@ -1211,6 +1453,82 @@ mod test {
         Ok(())
     }
 
+    // spending a withdrawal output in a bundle moves its creation height out
+    // of `withdrawal_heights`, and disconnecting the spend restores it
+    #[test]
+    fn bundle_submission_moves_withdrawal_height() -> anyhow::Result<()> {
+        let (env, state) =
+            fresh_state("bundle_submission_moves_withdrawal_height")?;
+        let outpoint = OutPoint::Regular {
+            txid: Txid::from([1; 32]),
+            vout: 0,
+        };
+        let output = Output {
+            address: TransparentAddress::ALL_ZEROS,
+            content: OutputContent::Withdrawal {
+                value: bitcoin::Amount::from_sat(10_000),
+                main_fee: bitcoin::Amount::from_sat(1_000),
+                main_address: regtest_p2wpkh_address(1),
+            },
+        };
+        let key = OutPointKey::from(&outpoint);
+        let bundle = WithdrawalBundle::new(
+            3,
+            bitcoin::Amount::from_sat(1_000),
+            BTreeMap::from([(outpoint, output.clone())]),
+            Vec::new(),
+        )?;
+        let m6id = bundle.compute_m6id();
+
+        let mut rwtxn = env.write_txn()?;
+        state.utxos.put(&mut rwtxn, &key, &output)?;
+        state.withdrawal_heights.put(&mut rwtxn, &key, &2)?;
+        state.withdrawal_bundles.put(
+            &mut rwtxn,
+            &m6id,
+            &(
+                WithdrawalBundleInfo::Known(bundle),
+                RollBack::new(WithdrawalBundleStatus::Pending, 3),
+            ),
+        )?;
+        state
+            .pending_withdrawal_bundle
+            .put(&mut rwtxn, &(), &m6id)?;
+        let mut accumulator_diff = AccumulatorDiff::default();
+        connect_withdrawal_bundle_submitted(
+            &state,
+            &mut rwtxn,
+            4,
+            &mut accumulator_diff,
+            &bitcoin::BlockHash::from_byte_array([9; 32]),
+            m6id,
+        )?;
+        anyhow::ensure!(
+            state.withdrawal_heights.try_get(&rwtxn, &key)?.is_none()
+        );
+        anyhow::ensure!(
+            state.spent_withdrawal_heights.try_get(&rwtxn, &key)? == Some(2)
+        );
+
+        disconnect_withdrawal_bundle_submitted(
+            &state,
+            &mut rwtxn,
+            4,
+            &mut accumulator_diff,
+            m6id,
+        )?;
+        anyhow::ensure!(
+            state.withdrawal_heights.try_get(&rwtxn, &key)? == Some(2)
+        );
+        anyhow::ensure!(
+            state
+                .spent_withdrawal_heights
+                .try_get(&rwtxn, &key)?
+                .is_none()
+        );
+        Ok(())
+    }
+
     // disconnecting a withdrawal bundle event must remove its
     // withdrawal_bundle_event_blocks record, not a deposit_blocks record that
     // happens to share the same sequence index
@@ -1381,6 +1699,231 @@ mod test {
         Ok(())
     }
 
+    fn indexed_p2wpkh_address(
+        idx: u32,
+    ) -> bitcoin::Address<bitcoin::address::NetworkUnchecked> {
+        let mut hash = [0_u8; 20];
+        hash[..4].copy_from_slice(&idx.to_be_bytes());
+        let script_pubkey = bitcoin::ScriptBuf::new_p2wpkh(
+            &bitcoin::WPubkeyHash::from_byte_array(hash),
+        );
+        bitcoin::Address::from_script(&script_pubkey, Network::Regtest)
+            .expect("P2WPKH script should have an address")
+            .into_unchecked()
+    }
+
+    fn aggregated_withdrawal(
+        main_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
+        main_fee: u64,
+    ) -> AggregatedWithdrawal {
+        AggregatedWithdrawal {
+            spend_utxos: HashMap::new(),
+            main_address,
+            value: bitcoin::Amount::from_sat(100_000),
+            main_fee: bitcoin::Amount::from_sat(main_fee),
+        }
+    }
+
+    /// Number of high-fee withdrawals created in each simulated round.
+    /// More P2WPKH withdrawals than fit in a single bundle.
+    const FLOOD_SIZE: u32 = 4_000;
+
+    /// Mainchain fee for flooding withdrawals
+    const FLOOD_MAIN_FEE: u64 = 10_000;
+
+    // fresh withdrawals are selected by fee rate, so a low-fee withdrawal is
+    // left out of a full bundle
+    #[test]
+    fn select_withdrawals_prioritizes_fee_rate() -> anyhow::Result<()> {
+        let block_height = 1_000;
+        let low_fee_address = indexed_p2wpkh_address(0);
+        let high_fee_address = indexed_p2wpkh_address(1);
+        let mut candidates = vec![
+            (
+                aggregated_withdrawal(low_fee_address.clone(), 31),
+                block_height,
+            ),
+            (
+                aggregated_withdrawal(
+                    high_fee_address.clone(),
+                    2 * FLOOD_MAIN_FEE,
+                ),
+                block_height,
+            ),
+        ];
+        candidates.extend((2..FLOOD_SIZE + 2).map(|idx| {
+            (
+                aggregated_withdrawal(
+                    indexed_p2wpkh_address(idx),
+                    FLOOD_MAIN_FEE,
+                ),
+                block_height,
+            )
+        }));
+        let selected = select_withdrawals(candidates, block_height, true);
+        anyhow::ensure!(
+            selected.len() < FLOOD_SIZE as usize,
+            "expected a full bundle, selected {} withdrawals",
+            selected.len()
+        );
+        anyhow::ensure!(selected[0].0.main_address == high_fee_address);
+        anyhow::ensure!(
+            !selected
+                .iter()
+                .any(|(aggregated, _)| aggregated.main_address
+                    == low_fee_address)
+        );
+        Ok(())
+    }
+
+    // priority is by fee per output byte, boosted by age, and overdue
+    // withdrawals come first, oldest first
+    #[test]
+    fn select_withdrawals_orders_by_boosted_fee_rate() -> anyhow::Result<()> {
+        let block_height = 2 * MAX_WITHDRAWAL_WAIT;
+        let p2wsh_address = {
+            let script_pubkey = bitcoin::ScriptBuf::new_p2wsh(
+                &bitcoin::WScriptHash::from_byte_array([1; 32]),
+            );
+            bitcoin::Address::from_script(&script_pubkey, Network::Regtest)?
+                .into_unchecked()
+        };
+        let candidates = vec![
+            // 2000 sats for a 31 vB output
+            (
+                aggregated_withdrawal(indexed_p2wpkh_address(0), 2_000),
+                block_height,
+            ),
+            // 2000 sats for a 43 vB output
+            (
+                aggregated_withdrawal(p2wsh_address.clone(), 2_000),
+                block_height,
+            ),
+            // 1000 sats for a 31 vB output, boosted 3x by age
+            (
+                aggregated_withdrawal(indexed_p2wpkh_address(1), 1_000),
+                block_height - 2 * WITHDRAWAL_AGE_BOOST_INTERVAL,
+            ),
+            // Overdue
+            (
+                aggregated_withdrawal(indexed_p2wpkh_address(2), 31),
+                block_height - MAX_WITHDRAWAL_WAIT,
+            ),
+            // Overdue for longer
+            (
+                aggregated_withdrawal(indexed_p2wpkh_address(3), 31),
+                block_height - MAX_WITHDRAWAL_WAIT - 1,
+            ),
+        ];
+        let selected: Vec<_> =
+            select_withdrawals(candidates, block_height, true)
+                .into_iter()
+                .map(|(aggregated, _)| aggregated.main_address)
+                .collect();
+        let expected = vec![
+            indexed_p2wpkh_address(3),
+            indexed_p2wpkh_address(2),
+            indexed_p2wpkh_address(1),
+            indexed_p2wpkh_address(0),
+            p2wsh_address,
+        ];
+        anyhow::ensure!(selected == expected, "unexpected order: {selected:?}");
+        Ok(())
+    }
+
+    // before activation, withdrawals are selected in `AggregatedWithdrawal`
+    // order, regardless of fee rate and age
+    #[test]
+    fn select_withdrawals_before_activation_ignores_priority()
+    -> anyhow::Result<()> {
+        let block_height = MAX_WITHDRAWAL_WAIT + 1;
+        let candidates: Vec<_> = (0..4)
+            .map(|idx| {
+                (
+                    aggregated_withdrawal(
+                        indexed_p2wpkh_address(idx),
+                        1_000 * u64::from(idx + 1),
+                    ),
+                    block_height - idx * WITHDRAWAL_AGE_BOOST_INTERVAL,
+                )
+            })
+            .collect();
+        let mut expected: Vec<_> = candidates
+            .iter()
+            .map(|(aggregated, _)| aggregated.clone())
+            .collect();
+        expected.sort_by(|a, b| b.cmp(a));
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|aggregated| aggregated.main_address)
+            .collect();
+        let selected: Vec<_> =
+            select_withdrawals(candidates, block_height, false)
+                .into_iter()
+                .map(|(aggregated, _)| aggregated.main_address)
+                .collect();
+        anyhow::ensure!(selected == expected, "unexpected order: {selected:?}");
+        Ok(())
+    }
+
+    // simulate rounds of bundle creation, in which more high-fee withdrawals
+    // are created each round than can be included in a bundle. A low-fee
+    // withdrawal must be included once it is overdue.
+    #[test]
+    fn select_withdrawals_does_not_starve_low_fee_withdrawal()
+    -> anyhow::Result<()> {
+        let low_fee_address = indexed_p2wpkh_address(0);
+        let mut pending = HashMap::new();
+        pending.insert(
+            low_fee_address.clone(),
+            (aggregated_withdrawal(low_fee_address.clone(), 31), 0),
+        );
+        let mut next_idx = 1;
+        let max_rounds = MAX_WITHDRAWAL_WAIT / WITHDRAWAL_AGE_BOOST_INTERVAL;
+        let mut included_at = None;
+        for round in 0..=max_rounds {
+            let block_height = round * WITHDRAWAL_AGE_BOOST_INTERVAL;
+            for idx in next_idx..next_idx + FLOOD_SIZE {
+                let main_address = indexed_p2wpkh_address(idx);
+                pending.insert(
+                    main_address.clone(),
+                    (
+                        aggregated_withdrawal(main_address, FLOOD_MAIN_FEE),
+                        block_height,
+                    ),
+                );
+            }
+            next_idx += FLOOD_SIZE;
+            let selected = select_withdrawals(
+                pending.values().cloned().collect(),
+                block_height,
+                true,
+            );
+            anyhow::ensure!(
+                selected.len() < pending.len(),
+                "expected a full bundle at height {block_height}"
+            );
+            for (aggregated, _) in selected {
+                pending.remove(&aggregated.main_address);
+            }
+            if !pending.contains_key(&low_fee_address) {
+                included_at = Some(block_height);
+                break;
+            }
+        }
+        let Some(included_at) = included_at else {
+            anyhow::bail!(
+                "low-fee withdrawal not included after {max_rounds} rounds"
+            )
+        };
+        // Not included until overdue
+        anyhow::ensure!(
+            included_at == MAX_WITHDRAWAL_WAIT,
+            "low-fee withdrawal included at height {included_at}"
+        );
+        Ok(())
+    }
+
     // connecting a deposit then disconnecting it on a reorg must round-trip
     #[test]
     fn deposit_reorg_round_trips() -> anyhow::Result<()> {
@@ -1465,4 +2008,281 @@ mod test {
 
         Ok(())
     }
+
+    // a shielded deposit appends its note commitment to the frontier,
+    // replaces the block's historical root, and counts towards wealth
+    #[test]
+    fn shielded_deposit_mints_note() -> anyhow::Result<()> {
+        use crate::types::{
+            Body, Header, ShieldedAddress,
+            orchard::{Anchor, FullViewingKey, Scope, SpendingKey},
+            proto::mainchain::ShieldedDeposit,
+        };
+
+        let env = temp_env("shielded_deposit_mints_note")?;
+        let state = State::new(&env, false, crate::types::Network::Regtest)?;
+        let empty_body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let merkle_root = empty_body.compute_merkle_root();
+        let main1 = bitcoin::BlockHash::from_byte_array([11; 32]);
+        let header = Header {
+            merkle_root,
+            prev_side_hash: None,
+            prev_main_hash: main1,
+            roots: Vec::new(),
+        };
+        let sk = SpendingKey::from_bytes([7; 32])
+            .into_option()
+            .ok_or_else(|| anyhow::anyhow!("invalid spending key"))?;
+        let address = ShieldedAddress(
+            FullViewingKey::from(&sk).address_at(0u32, Scope::External),
+        );
+        let deposit = ShieldedDeposit {
+            tx_index: 0,
+            outpoint: bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_byte_array([3; 32]),
+                vout: 1,
+            },
+            address,
+            value: bitcoin::Amount::from_sat(1000),
+        };
+        let deposit_twpd = {
+            let mut block_info = LinkedHashMap::new();
+            block_info.insert(
+                main1,
+                BlockInfo {
+                    bmm_commitment: None,
+                    events: vec![BlockEvent::ShieldedDeposit(deposit.clone())],
+                },
+            );
+            TwoWayPegData { block_info }
+        };
+        let mut rwtxn = env.write_txn()?;
+        let body_frontier = state.orchard.frontier().get(&rwtxn, &())?;
+        state.apply_block(&mut rwtxn, &header, &empty_body)?;
+        let (_, frontier) =
+            state.connect_two_way_peg_data(&mut rwtxn, &deposit_twpd)?;
+        let frontier = frontier
+            .ok_or_else(|| anyhow::anyhow!("expected frontier to change"))?;
+        let mut expected_frontier = body_frontier;
+        anyhow::ensure!(
+            expected_frontier.append(&deposit.note()?.commitment())
+        );
+        anyhow::ensure!(frontier.root() == expected_frontier.root());
+        let root = Anchor::from(frontier.root());
+        anyhow::ensure!(
+            state.orchard.historical_roots().try_get(&rwtxn, &root)?
+                == Some(Some(header.hash()))
+        );
+        anyhow::ensure!(state.sidechain_wealth(&rwtxn)? == deposit.value);
+        anyhow::ensure!(
+            state.get_shielded_pool_value(&rwtxn)? == deposit.value
+        );
+        anyhow::ensure!(state.deposit_blocks.last(&rwtxn)?.is_some());
+
+        state.disconnect_two_way_peg_data(&mut rwtxn, &deposit_twpd)?;
+        anyhow::ensure!(
+            !state
+                .shielded_deposits
+                .contains_key(&rwtxn, &deposit.outpoint)?
+        );
+        anyhow::ensure!(state.deposit_blocks.last(&rwtxn)?.is_none());
+        anyhow::ensure!(
+            state.get_shielded_pool_value(&rwtxn)? == bitcoin::Amount::ZERO
+        );
+        anyhow::ensure!(
+            !state
+                .orchard
+                .historical_roots()
+                .contains_key(&rwtxn, &root)?
+        );
+        Ok(())
+    }
+
+    // before activation, a shielded deposit is credited as a transparent
+    // deposit UTXO instead of minting a note
+    #[test]
+    fn shielded_deposit_before_activation_is_transparent() -> anyhow::Result<()>
+    {
+        use crate::types::{
+            Body, Header, ShieldedAddress,
+            orchard::{FullViewingKey, Scope, SpendingKey},
+            proto::mainchain::ShieldedDeposit,
+        };
+
+        let (env, state) =
+            fresh_state("shielded_deposit_before_activation_is_transparent")?;
+        let empty_body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let main1 = bitcoin::BlockHash::from_byte_array([11; 32]);
+        let header = Header {
+            merkle_root: empty_body.compute_merkle_root(),
+            prev_side_hash: None,
+            prev_main_hash: main1,
+            roots: Vec::new(),
+        };
+        let sk = SpendingKey::from_bytes([7; 32])
+            .into_option()
+            .ok_or_else(|| anyhow::anyhow!("invalid spending key"))?;
+        let deposit = ShieldedDeposit {
+            tx_index: 0,
+            outpoint: bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_byte_array([3; 32]),
+                vout: 1,
+            },
+            address: ShieldedAddress(
+                FullViewingKey::from(&sk).address_at(0u32, Scope::External),
+            ),
+            value: bitcoin::Amount::from_sat(1000),
+        };
+        let deposit_twpd = {
+            let mut block_info = LinkedHashMap::new();
+            block_info.insert(
+                main1,
+                BlockInfo {
+                    bmm_commitment: None,
+                    events: vec![BlockEvent::ShieldedDeposit(deposit.clone())],
+                },
+            );
+            TwoWayPegData { block_info }
+        };
+        let key = OutPointKey::from(&OutPoint::Deposit(deposit.outpoint));
+        let mut rwtxn = env.write_txn()?;
+        state.apply_block(&mut rwtxn, &header, &empty_body)?;
+        anyhow::ensure!(!state.mints_shielded_deposit(1, &deposit));
+        let (_, frontier) =
+            state.connect_two_way_peg_data(&mut rwtxn, &deposit_twpd)?;
+        anyhow::ensure!(frontier.is_none());
+        anyhow::ensure!(
+            state.utxos.try_get(&rwtxn, &key)?
+                == Some(value_output(TransparentAddress::ALL_ZEROS, 1000))
+        );
+        anyhow::ensure!(
+            !state
+                .shielded_deposits
+                .contains_key(&rwtxn, &deposit.outpoint)?
+        );
+        anyhow::ensure!(state.sidechain_wealth(&rwtxn)? == deposit.value);
+        anyhow::ensure!(
+            state.get_shielded_pool_value(&rwtxn)? == bitcoin::Amount::ZERO
+        );
+
+        state.disconnect_two_way_peg_data(&mut rwtxn, &deposit_twpd)?;
+        anyhow::ensure!(!state.utxos.contains_key(&rwtxn, &key)?);
+        anyhow::ensure!(state.deposit_blocks.last(&rwtxn)?.is_none());
+        Ok(())
+    }
+
+    // transactions anchored to the root after a block's transactions remain
+    // valid after the block's shielded deposits are connected
+    #[test]
+    fn shielded_deposit_keeps_block_root() -> anyhow::Result<()> {
+        use crate::types::{
+            Body, Header, ShieldedAddress,
+            orchard::{Anchor, FullViewingKey, Scope, SpendingKey},
+            proto::mainchain::ShieldedDeposit,
+        };
+
+        let env = temp_env("shielded_deposit_keeps_block_root")?;
+        let state = State::new(&env, false, crate::types::Network::Regtest)?;
+        let empty_body = Body {
+            coinbase: Vec::new(),
+            transactions: Vec::new(),
+            authorizations: Vec::new(),
+        };
+        let main1 = bitcoin::BlockHash::from_byte_array([11; 32]);
+        let header = Header {
+            merkle_root: empty_body.compute_merkle_root(),
+            prev_side_hash: None,
+            prev_main_hash: main1,
+            roots: Vec::new(),
+        };
+        let sk = SpendingKey::from_bytes([7; 32])
+            .into_option()
+            .ok_or_else(|| anyhow::anyhow!("invalid spending key"))?;
+        let address = ShieldedAddress(
+            FullViewingKey::from(&sk).address_at(0u32, Scope::External),
+        );
+        let deposit = |vout| ShieldedDeposit {
+            tx_index: 0,
+            outpoint: bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_byte_array([3; 32]),
+                vout,
+            },
+            address,
+            value: bitcoin::Amount::from_sat(1000),
+        };
+        let deposit_twpd = {
+            let mut block_info = LinkedHashMap::new();
+            block_info.insert(
+                main1,
+                BlockInfo {
+                    bmm_commitment: None,
+                    events: vec![BlockEvent::ShieldedDeposit(deposit(1))],
+                },
+            );
+            TwoWayPegData { block_info }
+        };
+        let mut rwtxn = env.write_txn()?;
+        state.apply_block(&mut rwtxn, &header, &empty_body)?;
+        // Append a note commitment as if the block's transactions created a
+        // note
+        let mut body_frontier = state.orchard.frontier().get(&rwtxn, &())?;
+        anyhow::ensure!(body_frontier.append(&deposit(0).note()?.commitment()));
+        state.orchard.put_frontier(&mut rwtxn, &body_frontier)?;
+        anyhow::ensure!(state.orchard.put_historical_root(
+            &mut rwtxn,
+            header.hash(),
+            body_frontier.root()
+        )?);
+        let body_root = Anchor::from(body_frontier.root());
+
+        let (_, frontier) =
+            state.connect_two_way_peg_data(&mut rwtxn, &deposit_twpd)?;
+        let frontier = frontier
+            .ok_or_else(|| anyhow::anyhow!("expected frontier to change"))?;
+        let deposit_root = Anchor::from(frontier.root());
+        anyhow::ensure!(deposit_root != body_root);
+        for root in [body_root, deposit_root] {
+            anyhow::ensure!(
+                state.orchard.historical_roots().try_get(&rwtxn, &root)?
+                    == Some(Some(header.hash()))
+            );
+        }
+        // The block's first root is exported first, so that it is restored
+        // when a snapshot is loaded
+        let historical_roots = state.orchard.get_historical_roots(&rwtxn)?;
+        let position = |root| {
+            historical_roots
+                .iter()
+                .position(|(historical_root, _)| *historical_root == root)
+        };
+        anyhow::ensure!(matches!(
+            (position(body_root), position(deposit_root)),
+            (Some(body_position), Some(deposit_position))
+                if body_position < deposit_position
+        ));
+
+        state.disconnect_two_way_peg_data(&mut rwtxn, &deposit_twpd)?;
+        anyhow::ensure!(
+            state
+                .orchard
+                .historical_roots()
+                .try_get(&rwtxn, &body_root)?
+                == Some(Some(header.hash()))
+        );
+        anyhow::ensure!(
+            !state
+                .orchard
+                .historical_roots()
+                .contains_key(&rwtxn, &deposit_root)?
+        );
+        Ok(())
+    }
 }
@@ -13,6 +13,14 @@ use utoipa::ToSchema;
 
 use crate::types::THIS_SIDECHAIN;
 
+/// Format an encoded address with `s{sidechain_number}_` prefix and a
+/// checksum postfix
+fn format_for_deposit(address: &str) -> String {
+    let prefix = format!("s{THIS_SIDECHAIN}_{address}_");
+    let prefix_digest = sha256::Hash::hash(prefix.as_bytes()).to_byte_array();
+    format!("{prefix}{}", hex::encode(&prefix_digest[..3]))
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum TransparentAddressParseError {
     #[error("bs58 error")]
@@ -36,10 +44,7 @@ impl TransparentAddress {
 
     /// Format with `s{sidechain_number}_` prefix and a checksum postfix
     pub fn format_for_deposit(&self) -> String {
-        let prefix = format!("s{}_{}_", THIS_SIDECHAIN, self.as_base58());
-        let prefix_digest =
-            sha256::Hash::hash(prefix.as_bytes()).to_byte_array();
-        format!("{prefix}{}", hex::encode(&prefix_digest[..3]))
+        format_for_deposit(&self.as_base58())
     }
 }
 
@@ -159,6 +164,12 @@ impl ShieldedAddress {
         }
         Ok(res)
     }
+
+    /// Format with `s{sidechain_number}_` prefix and a checksum postfix.
+    /// Deposits to this address mint a shielded note.
+    pub fn format_for_deposit(&self) -> String {
+        format_for_deposit(&self.bech32m_encode())
+    }
 }
 
 impl std::fmt::Display for ShieldedAddress {
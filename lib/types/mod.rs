@@ -19,10 +19,12 @@ pub mod orchard;
 pub mod proto;
 pub mod schema;
 mod transaction;
+pub mod withdrawal_policy;
 
 pub use address::{Address, ShieldedAddress, TransparentAddress};
 pub use hashes::{
-    BlockHash, Hash, M6id, MerkleRoot, Txid, hash, hash_with_scratch_buffer,
+    BlockHash, Hash, M6id, MerkleRoot, SnapshotHash, Txid, hash,
+    hash_with_scratch_buffer,
 };
 pub use transaction::{
     AuthorizedTransaction, Body, Content as OutputContent, FilledTransaction,
@@ -148,7 +150,10 @@ pub enum WithdrawalBundleEventStatus {
     Submitted,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema,
+)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum WithdrawalBundleStatus {
     Confirmed,
     /// Formerly pending bundle
@@ -168,6 +173,86 @@ pub struct WithdrawalBundleEvent {
     pub status: WithdrawalBundleEventStatus,
 }
 
+/// A withdrawal bundle status, and the sidechain height at which the bundle
+/// entered that status
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema,
+)]
+pub struct WithdrawalBundleStatusUpdate {
+    pub status: WithdrawalBundleStatus,
+    pub height: u32,
+}
+
+/// A withdrawal bundle status update, and the mainchain block containing the
+/// withdrawal bundle event that caused it
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema,
+)]
+pub struct WithdrawalBundleStatusTransition {
+    pub status: WithdrawalBundleStatus,
+    /// Sidechain height at which the status was applied
+    pub height: u32,
+    /// `None` for statuses that are not caused by mainchain events, or if
+    /// the event block is unknown
+    #[schema(value_type = Option<schema::BitcoinBlockHash>)]
+    pub main_block_hash: Option<bitcoin::BlockHash>,
+}
+
+/// A withdrawal bundle, and its full status history
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+pub struct WithdrawalBundleHistory {
+    pub m6id: M6id,
+    /// Mainchain transaction, if the bundle is known
+    #[schema(value_type = Option<schema::BitcoinTransaction>)]
+    pub tx: Option<bitcoin::Transaction>,
+    /// Sidechain outpoints spent by the bundle, if known
+    pub spend_utxos: Option<Vec<transaction::OutPoint>>,
+    /// Total value paid out to mainchain addresses, if the bundle is known
+    #[serde(
+        rename = "value_sats",
+        with = "bitcoin::amount::serde::as_sat::opt"
+    )]
+    #[schema(value_type = Option<u64>)]
+    pub value: Option<bitcoin::Amount>,
+    /// Mainchain fee, if the bundle is known
+    #[serde(rename = "fee_sats", with = "bitcoin::amount::serde::as_sat::opt")]
+    #[schema(value_type = Option<u64>)]
+    pub fee: Option<bitcoin::Amount>,
+    /// Status transitions, earliest first
+    pub status_history: Vec<WithdrawalBundleStatusTransition>,
+}
+
+/// A withdrawal bundle that includes a withdrawal output
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+pub struct WithdrawalBundleInclusion {
+    pub m6id: M6id,
+    /// Status updates, earliest first
+    pub status_history: Vec<WithdrawalBundleStatusUpdate>,
+    /// If the bundle failed, `true` if the withdrawal output is no longer
+    /// spent by the bundle, so that the funds can be included in a later
+    /// bundle
+    pub funds_reinstated: Option<bool>,
+}
+
+/// A deposit that has been credited on the sidechain
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+pub struct DepositInfo {
+    #[schema(value_type = schema::BitcoinOutPoint)]
+    pub main_outpoint: bitcoin::OutPoint,
+    #[serde(rename = "value_sats", with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub value: bitcoin::Amount,
+    pub address: TransparentAddress,
+    /// Mainchain block that included the deposit
+    #[schema(value_type = schema::BitcoinBlockHash)]
+    pub main_block_hash: bitcoin::BlockHash,
+    pub main_height: u32,
+    /// Sidechain height at which the deposit was credited
+    pub height: u32,
+    /// Input that spent the deposit output, if it has been spent
+    pub spent_by: Option<InPoint>,
+}
+
 pub static OP_DRIVECHAIN_SCRIPT: LazyLock<bitcoin::ScriptBuf> =
     LazyLock::new(|| {
         let mut script = bitcoin::ScriptBuf::new();
@@ -639,6 +724,41 @@ pub struct Tip {
     pub main_block_hash: bitcoin::BlockHash,
 }
 
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema,
+)]
+pub enum ChainTipStatus {
+    /// Tip of the active chain
+    Active,
+    /// All bodies are available, but the branch is not active
+    ValidFork,
+    /// No bodies are available for blocks in the branch
+    HeadersOnly,
+    /// The tip, or one of its ancestors, has been invalidated
+    Invalid,
+    /// Some, but not all, bodies are available for blocks in the branch
+    MissingBody,
+}
+
+/// A block with no known successors, or the tip of the active chain
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema,
+)]
+pub struct ChainTip {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    /// Number of blocks between the tip and the active chain.
+    /// `0` for the active tip.
+    pub branch_len: u32,
+    /// Best mainchain block that BMM-verifies the tip, if any
+    #[schema(value_type = Option<schema::BitcoinBlockHash>)]
+    pub main_block_hash: Option<bitcoin::BlockHash>,
+    /// Total mainchain work at the best BMM verification, if any
+    #[schema(value_type = Option<String>)]
+    pub total_work: Option<bitcoin::Work>,
+    pub status: ChainTipStatus,
+}
+
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 #[cfg_attr(
     feature = "clap",
@@ -652,6 +772,19 @@ pub enum Network {
     Forknet,
 }
 
+impl Network {
+    /// The mainchain network that the sidechain network runs on
+    pub fn mainchain_network(&self) -> bitcoin::Network {
+        match self {
+            Self::Signet => bitcoin::Network::Signet,
+            Self::Regtest => bitcoin::Network::Regtest,
+            // Forknet is a fork of Bitcoin mainnet, and uses mainnet
+            // addresses
+            Self::Forknet => bitcoin::Network::Bitcoin,
+        }
+    }
+}
+
 /// Semver-compatible version
 #[derive(
     BorshSerialize,
@@ -665,6 +798,7 @@ pub enum Network {
     PartialEq,
     PartialOrd,
     Serialize,
+    ToSchema,
 )]
 pub struct Version {
     pub major: u64,
@@ -707,6 +841,27 @@ pub struct Block {
     pub body: Body,
 }
 
+/// A block header, along with its height, BMM inclusions and transaction
+/// count
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct HeaderInfo {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    pub header: Header,
+    /// Mainchain blocks that BMM-verify the block
+    #[schema(value_type = Vec<schema::BitcoinBlockHash>)]
+    pub bmm_inclusions: Vec<bitcoin::BlockHash>,
+    /// Number of transactions, not including the coinbase.
+    /// `None` if the body is not available.
+    pub n_transactions: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct BlockInfo {
+    pub header_info: HeaderInfo,
+    pub body: Body,
+}
+
 #[cfg(test)]
 mod withdrawal_bundle_order_regression {
     use super::*;
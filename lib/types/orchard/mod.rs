@@ -1341,11 +1341,78 @@ struct NoteRepr {
     rseed: RandomSeedRepr,
 }
 
+/// Errors when deriving the note minted by a shielded deposit
+#[derive(Debug, Error)]
+#[error("no valid note for shielded deposit {outpoint}")]
+pub struct DepositNoteError {
+    pub outpoint: bitcoin::OutPoint,
+}
+
 #[derive(Clone, Copy, Debug, TransparentWrapper)]
 #[repr(transparent)]
 pub struct Note(orchard::Note);
 
 impl Note {
+    /// Key derivation context for shielded deposit note `rho`
+    const DEPOSIT_RHO_CONTEXT: &str = "thunder-orchard shielded deposit rho";
+
+    /// Key derivation context for shielded deposit note `rseed`
+    const DEPOSIT_RSEED_CONTEXT: &str =
+        "thunder-orchard shielded deposit rseed";
+
+    /// Number of candidate seeds to try when deriving a shielded deposit
+    /// note
+    const DEPOSIT_RSEED_ATTEMPTS: u32 = 256;
+
+    /// Deterministically derive the note minted by a shielded deposit.
+    /// The note randomness is derived from the deposit outpoint, which is
+    /// unique, so that every node mints the same note commitment.
+    /// The recipient and value are public on the mainchain, so the note does
+    /// not need to be encrypted.
+    /// Returns an error if no valid note can be derived, in which case the
+    /// deposit is invalid.
+    pub fn from_deposit(
+        outpoint: &bitcoin::OutPoint,
+        recipient: &Address,
+        value: bitcoin::Amount,
+    ) -> Result<Self, DepositNoteError> {
+        let outpoint_bytes = bitcoin::consensus::serialize(outpoint);
+        let mut rho_bytes =
+            blake3::derive_key(Self::DEPOSIT_RHO_CONTEXT, &outpoint_bytes);
+        // Clear the top bits, so that rho is a canonical Pallas base field
+        // element
+        rho_bytes[31] &= 0b0011_1111;
+        let rho = orchard::note::Rho::from_bytes(&rho_bytes)
+            .into_option()
+            .ok_or(DepositNoteError {
+                outpoint: *outpoint,
+            })?;
+        // Invalid seeds are negligibly rare, and are skipped
+        (0..Self::DEPOSIT_RSEED_ATTEMPTS)
+            .find_map(|counter| {
+                let rseed_bytes: [u8; 32] =
+                    blake3::Hasher::new_derive_key(Self::DEPOSIT_RSEED_CONTEXT)
+                        .update(&outpoint_bytes)
+                        .update(&counter.to_le_bytes())
+                        .finalize()
+                        .into();
+                let rseed =
+                    orchard::note::RandomSeed::from_bytes(rseed_bytes, &rho)
+                        .into_option()?;
+                orchard::Note::from_parts(
+                    recipient.0,
+                    NoteValue::from_raw(value.to_sat()),
+                    rho,
+                    rseed,
+                )
+                .into_option()
+            })
+            .map(Self)
+            .ok_or(DepositNoteError {
+                outpoint: *outpoint,
+            })
+    }
+
     pub fn value(&self) -> bitcoin::Amount {
         bitcoin::Amount::from_sat(self.0.value().inner())
     }
@@ -1353,6 +1420,11 @@ impl Note {
     pub fn nullifier(&self, fvk: &FullViewingKey) -> Nullifier {
         Nullifier::wrap(self.0.nullifier(fvk))
     }
+
+    /// Extracted note commitment, appended to the note commitment tree
+    pub fn commitment(&self) -> ExtractedNoteCommitment {
+        ExtractedNoteCommitment(self.0.commitment().into())
+    }
 }
 
 impl<'de> Deserialize<'de> for Note {
@@ -266,7 +266,10 @@ pub mod mainchain {
     use thiserror::Error;
 
     use super::common::{ConsensusHex, ReverseHex};
-    use crate::types::{M6id, Output, OutputContent, THIS_SIDECHAIN};
+    use crate::types::{
+        Address, M6id, Output, OutputContent, ShieldedAddress, THIS_SIDECHAIN,
+        TransparentAddress,
+    };
 
     pub mod generated {
         tonic::include_proto!("cusf.mainchain.v1");
@@ -414,13 +417,21 @@ pub mod mainchain {
         }
     }
 
-    impl TryFrom<generated::deposit::Output> for Output {
+    /// Destination and value of a deposit output
+    enum DepositOutput {
+        Transparent(Output),
+        Shielded {
+            address: ShieldedAddress,
+            value: bitcoin::Amount,
+        },
+    }
+
+    impl TryFrom<generated::deposit::Output> for DepositOutput {
         type Error = super::Error;
 
         fn try_from(
             output: generated::deposit::Output,
         ) -> Result<Self, Self::Error> {
-            use crate::types::TransparentAddress;
             let generated::deposit::Output {
                 address,
                 value_sats,
@@ -447,17 +458,19 @@ pub mod mainchain {
                                 address_bytes = hex::encode(address_bytes),
                                 "Ignoring invalid deposit address"
                             );
-                            break 'address TransparentAddress::ALL_ZEROS;
+                            break 'address Address::Transparent(
+                                TransparentAddress::ALL_ZEROS,
+                            );
                         }
                     };
-                match TransparentAddress::from_str(address_utf8) {
+                match Address::from_str(address_utf8) {
                     Ok(address) => address,
                     Err(_) => {
                         tracing::warn!(
                             address_utf8,
                             "Ignoring invalid deposit address"
                         );
-                        TransparentAddress::ALL_ZEROS
+                        Address::Transparent(TransparentAddress::ALL_ZEROS)
                     }
                 }
             };
@@ -468,10 +481,14 @@ pub mod mainchain {
                     )
                 })
                 .map(bitcoin::Amount::from_sat)?;
-            Ok(Self {
-                address,
-                content: OutputContent::Value(value),
-            })
+            let res = match address {
+                Address::Shielded(address) => Self::Shielded { address, value },
+                Address::Transparent(address) => Self::Transparent(Output {
+                    address,
+                    content: OutputContent::Value(value),
+                }),
+            };
+            Ok(res)
         }
     }
 
@@ -488,6 +505,14 @@ pub mod mainchain {
         pub network: Network,
     }
 
+    /// The sidechain's treasury UTXO on the mainchain
+    #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    pub struct Ctip {
+        pub outpoint: OutPoint,
+        pub value: bitcoin::Amount,
+        pub sequence_number: u64,
+    }
+
     #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
     pub struct Deposit {
         /// Position of this transaction within the block that included it
@@ -496,35 +521,42 @@ pub mod mainchain {
         pub output: Output,
     }
 
-    impl TryFrom<generated::Deposit> for Deposit {
-        type Error = super::Error;
+    /// A deposit to a shielded address.
+    /// From the shielded deposit activation height, shielded deposits mint a
+    /// note, instead of creating a UTXO.
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    pub struct ShieldedDeposit {
+        /// Position of this transaction within the block that included it
+        pub tx_index: u64,
+        pub outpoint: OutPoint,
+        pub address: ShieldedAddress,
+        pub value: bitcoin::Amount,
+    }
 
-        fn try_from(deposit: generated::Deposit) -> Result<Self, Self::Error> {
-            let generated::Deposit {
-                sequence_number,
-                outpoint,
-                output,
-            } = deposit;
-            let sequence_number = sequence_number.ok_or_else(|| {
-                super::Error::missing_field::<generated::Deposit>(
-                    "sequence_number",
-                )
-            })?;
-            let Some(outpoint) = outpoint else {
-                return Err(super::Error::missing_field::<generated::Deposit>(
-                    "outpoint",
-                ));
-            };
-            let Some(output) = output else {
-                return Err(super::Error::missing_field::<generated::Deposit>(
-                    "output",
-                ));
-            };
-            Ok(Self {
-                tx_index: sequence_number,
-                outpoint: outpoint.try_into()?,
-                output: output.try_into()?,
-            })
+    impl ShieldedDeposit {
+        /// The deposit UTXO credited by this deposit, if it does not mint a
+        /// note
+        pub fn transparent_output(&self) -> Output {
+            Output {
+                address: TransparentAddress::ALL_ZEROS,
+                content: OutputContent::Value(self.value),
+            }
+        }
+
+        /// The note minted by this deposit.
+        /// Returns an error if the deposit is invalid, in which case it is
+        /// credited as a transparent UTXO.
+        pub fn note(
+            &self,
+        ) -> Result<
+            crate::types::orchard::Note,
+            crate::types::orchard::DepositNoteError,
+        > {
+            crate::types::orchard::Note::from_deposit(
+                &self.outpoint,
+                &self.address,
+                self.value,
+            )
         }
     }
 
@@ -593,6 +625,51 @@ pub mod mainchain {
     pub enum BlockEvent {
         Deposit(Deposit),
         WithdrawalBundle(crate::types::WithdrawalBundleEvent),
+        ShieldedDeposit(ShieldedDeposit),
+    }
+
+    impl TryFrom<generated::Deposit> for BlockEvent {
+        type Error = super::Error;
+
+        fn try_from(deposit: generated::Deposit) -> Result<Self, Self::Error> {
+            let generated::Deposit {
+                sequence_number,
+                outpoint,
+                output,
+            } = deposit;
+            let sequence_number = sequence_number.ok_or_else(|| {
+                super::Error::missing_field::<generated::Deposit>(
+                    "sequence_number",
+                )
+            })?;
+            let Some(outpoint) = outpoint else {
+                return Err(super::Error::missing_field::<generated::Deposit>(
+                    "outpoint",
+                ));
+            };
+            let Some(output) = output else {
+                return Err(super::Error::missing_field::<generated::Deposit>(
+                    "output",
+                ));
+            };
+            let outpoint = outpoint.try_into()?;
+            let res = match output.try_into()? {
+                DepositOutput::Transparent(output) => Self::Deposit(Deposit {
+                    tx_index: sequence_number,
+                    outpoint,
+                    output,
+                }),
+                DepositOutput::Shielded { address, value } => {
+                    Self::ShieldedDeposit(ShieldedDeposit {
+                        tx_index: sequence_number,
+                        outpoint,
+                        address,
+                        value,
+                    })
+                }
+            };
+            Ok(res)
+        }
     }
 
     impl From<Deposit> for BlockEvent {
@@ -615,9 +692,7 @@ pub mod mainchain {
         ) -> Result<Self, Self::Error> {
             use generated::block_info::event::Event;
             match event {
-                Event::Deposit(deposit) => {
-                    Ok(BlockEvent::Deposit(deposit.try_into()?))
-                }
+                Event::Deposit(deposit) => deposit.try_into(),
                 Event::WithdrawalBundle(bundle_event) => {
                     Ok(BlockEvent::WithdrawalBundle(bundle_event.try_into()?))
                 }
@@ -649,14 +724,27 @@ pub mod mainchain {
         pub fn deposits(&self) -> impl DoubleEndedIterator<Item = &Deposit> {
             self.events.iter().filter_map(|event| match event {
                 BlockEvent::Deposit(deposit) => Some(deposit),
-                BlockEvent::WithdrawalBundle(_) => None,
+                BlockEvent::WithdrawalBundle(_)
+                | BlockEvent::ShieldedDeposit(_) => None,
             })
         }
 
         pub fn into_deposits(self) -> impl DoubleEndedIterator<Item = Deposit> {
             self.events.into_iter().filter_map(|event| match event {
                 BlockEvent::Deposit(deposit) => Some(deposit),
-                BlockEvent::WithdrawalBundle(_) => None,
+                BlockEvent::WithdrawalBundle(_)
+                | BlockEvent::ShieldedDeposit(_) => None,
+            })
+        }
+
+        pub fn shielded_deposits(
+            &self,
+        ) -> impl DoubleEndedIterator<Item = &ShieldedDeposit> {
+            self.events.iter().filter_map(|event| match event {
+                BlockEvent::ShieldedDeposit(deposit) => Some(deposit),
+                BlockEvent::Deposit(_) | BlockEvent::WithdrawalBundle(_) => {
+                    None
+                }
             })
         }
 
@@ -668,7 +756,7 @@ pub mod mainchain {
                 BlockEvent::WithdrawalBundle(bundle_event) => {
                     Some(bundle_event)
                 }
-                BlockEvent::Deposit(_) => None,
+                BlockEvent::Deposit(_) | BlockEvent::ShieldedDeposit(_) => None,
             })
         }
     }
@@ -1131,6 +1219,36 @@ pub mod mainchain {
             (&block_header_info).try_into()
         }
 
+        /// Get the sidechain's treasury UTXO, if it exists
+        pub async fn get_ctip(&mut self) -> Result<Option<Ctip>, super::Error> {
+            let request = generated::GetCtipRequest {
+                sidechain_number: Some(THIS_SIDECHAIN as u32),
+            };
+            let generated::GetCtipResponse { ctip } =
+                self.0.get_ctip(request).await?.into_inner();
+            let Some(generated::get_ctip_response::Ctip {
+                txid,
+                vout,
+                value,
+                sequence_number,
+            }) = ctip
+            else {
+                return Ok(None);
+            };
+            let txid = txid
+                .ok_or_else(|| {
+                    super::Error::missing_field::<
+                        generated::get_ctip_response::Ctip,
+                    >("txid")
+                })?
+                .decode::<generated::get_ctip_response::Ctip, _>("txid")?;
+            Ok(Some(Ctip {
+                outpoint: OutPoint { txid, vout },
+                value: bitcoin::Amount::from_sat(value),
+                sequence_number,
+            }))
+        }
+
         pub async fn get_two_way_peg_data(
             &mut self,
             start_block_hash: Option<BlockHash>,
@@ -1230,7 +1348,7 @@ pub mod mainchain {
 
         pub async fn create_deposit_tx(
             &mut self,
-            address: crate::types::TransparentAddress,
+            address: Address,
             value_sats: u64,
             fee_sats: u64,
         ) -> Result<Txid, super::Error> {
@@ -0,0 +1,195 @@
+//! Policy checks for withdrawal destinations.
+//! Withdrawals that fail these checks could never be paid out on the
+//! mainchain, so they are rejected by the wallet and the mempool, and by
+//! consensus from an activation height.
+
+use bitcoin::{AddressType, address::NetworkUnchecked};
+use thiserror::Error;
+
+use crate::types::{Network, Output, OutputContent, WithdrawalBundle};
+
+/// Minimum fee rate for a withdrawal bundle to be relayed on the mainchain
+const MIN_RELAY_FEE_RATE: bitcoin::FeeRate = bitcoin::FeeRate::BROADCAST_MIN;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(
+        "withdrawal value ({value}) to `{address}` is below the mainchain dust threshold ({dust_threshold})"
+    )]
+    Dust {
+        address: String,
+        value: bitcoin::Amount,
+        dust_threshold: bitcoin::Amount,
+    },
+    #[error(
+        "withdrawal mainchain fee ({main_fee}) to `{address}` is below the minimum mainchain fee for the output ({min_main_fee})"
+    )]
+    MainFeeTooLow {
+        address: String,
+        main_fee: bitcoin::Amount,
+        min_main_fee: bitcoin::Amount,
+    },
+    #[error(
+        "withdrawal address `{address}` does not have a standard script type"
+    )]
+    NonstandardScript { address: String },
+    #[error(
+        "withdrawal address `{address}` is not valid for mainchain network `{network}`"
+    )]
+    WrongNetwork {
+        address: String,
+        network: bitcoin::Network,
+    },
+}
+
+/// Minimum mainchain fee for a withdrawal output with the specified
+/// scriptPubKey, so that the output pays for its own size in the bundle.
+/// Returns `None` if the scriptPubKey is too large.
+pub fn min_main_fee(
+    script_pubkey: &bitcoin::Script,
+) -> Option<bitcoin::Amount> {
+    let spk_size = u32::try_from(script_pubkey.len()).ok()?;
+    let txout_size = WithdrawalBundle::txout_size(spk_size)?;
+    MIN_RELAY_FEE_RATE.fee_vb(txout_size as u64)
+}
+
+/// Check that a withdrawal can be paid out on the mainchain
+pub fn check(
+    network: Network,
+    main_address: &bitcoin::Address<NetworkUnchecked>,
+    value: bitcoin::Amount,
+    main_fee: bitcoin::Amount,
+) -> Result<(), Error> {
+    let address = main_address.assume_checked_ref().to_string();
+    let main_network = network.mainchain_network();
+    if !main_address.is_valid_for_network(main_network) {
+        return Err(Error::WrongNetwork {
+            address,
+            network: main_network,
+        });
+    }
+    let main_address = main_address.assume_checked_ref();
+    match main_address.address_type() {
+        Some(
+            AddressType::P2pkh
+            | AddressType::P2sh
+            | AddressType::P2wpkh
+            | AddressType::P2wsh
+            | AddressType::P2tr,
+        ) => (),
+        _ => return Err(Error::NonstandardScript { address }),
+    }
+    let script_pubkey = main_address.script_pubkey();
+    let dust_threshold = script_pubkey.minimal_non_dust();
+    if value < dust_threshold {
+        return Err(Error::Dust {
+            address,
+            value,
+            dust_threshold,
+        });
+    }
+    let Some(min_main_fee) = min_main_fee(&script_pubkey) else {
+        return Err(Error::NonstandardScript { address });
+    };
+    if main_fee < min_main_fee {
+        return Err(Error::MainFeeTooLow {
+            address,
+            main_fee,
+            min_main_fee,
+        });
+    }
+    Ok(())
+}
+
+/// Check an output. Outputs that are not withdrawals always pass.
+pub fn check_output(network: Network, output: &Output) -> Result<(), Error> {
+    match &output.content {
+        OutputContent::Value(_) => Ok(()),
+        OutputContent::Withdrawal {
+            value,
+            main_fee,
+            main_address,
+        } => check(network, main_address, *value, *main_fee),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, check};
+    use crate::types::Network;
+
+    fn address(
+        s: &str,
+    ) -> bitcoin::Address<bitcoin::address::NetworkUnchecked> {
+        s.parse().unwrap()
+    }
+
+    const SIGNET_P2WPKH: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    #[test]
+    fn accepts_standard_withdrawal() {
+        let res = check(
+            Network::Signet,
+            &address(SIGNET_P2WPKH),
+            bitcoin::Amount::from_sat(10_000),
+            bitcoin::Amount::from_sat(1_000),
+        );
+        assert!(res.is_ok(), "{res:?}");
+    }
+
+    #[test]
+    fn rejects_wrong_network() {
+        let res = check(
+            Network::Signet,
+            &address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            bitcoin::Amount::from_sat(10_000),
+            bitcoin::Amount::from_sat(1_000),
+        );
+        assert!(matches!(res, Err(Error::WrongNetwork { .. })), "{res:?}");
+    }
+
+    #[test]
+    fn rejects_nonstandard_script() {
+        // Segwit v1 program that is not 32 bytes long
+        let res = check(
+            Network::Signet,
+            &address("tb1pqqqsyqcyq5rqwzqfpg9scrgwpu8qfrvj"),
+            bitcoin::Amount::from_sat(10_000),
+            bitcoin::Amount::from_sat(1_000),
+        );
+        assert!(
+            matches!(res, Err(Error::NonstandardScript { .. })),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn rejects_dust() {
+        let res = check(
+            Network::Signet,
+            &address(SIGNET_P2WPKH),
+            bitcoin::Amount::from_sat(100),
+            bitcoin::Amount::from_sat(1_000),
+        );
+        assert!(matches!(res, Err(Error::Dust { .. })), "{res:?}");
+    }
+
+    #[test]
+    fn rejects_low_main_fee() {
+        // A P2WPKH txout is 31 vbytes
+        let res = check(
+            Network::Signet,
+            &address(SIGNET_P2WPKH),
+            bitcoin::Amount::from_sat(10_000),
+            bitcoin::Amount::from_sat(30),
+        );
+        assert!(matches!(res, Err(Error::MainFeeTooLow { .. })), "{res:?}");
+        let res = check(
+            Network::Signet,
+            &address(SIGNET_P2WPKH),
+            bitcoin::Amount::from_sat(10_000),
+            bitcoin::Amount::from_sat(31),
+        );
+        assert!(res.is_ok(), "{res:?}");
+    }
+}
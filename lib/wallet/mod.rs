@@ -29,10 +29,12 @@ use sneed::{
 use tokio_stream::{StreamMap, wrappers::WatchStream};
 
 use crate::{
-    authorization,
+    authorization, migration,
     types::{
-        Accumulator, AmountOverflowError, AmountUnderflowError, BlockHash,
-        Body, Header, PointedOutput, Txid, UtreexoError, VERSION, Version,
+        Accumulator, Address, AmountOverflowError, AmountUnderflowError,
+        BlockHash, Body, Header, Network, PointedOutput, Txid, UtreexoError,
+        Version, WithdrawalBundleInclusion, proto::mainchain::ShieldedDeposit,
+        withdrawal_policy,
     },
     util::Watchable,
 };
@@ -87,6 +89,37 @@ impl Balance {
     }
 }
 
+/// A withdrawal output created by the wallet, and the withdrawal bundles
+/// that have included it
+#[derive(Clone, Debug, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct WalletWithdrawal {
+    pub output: PointedOutput,
+    /// `false` if the withdrawal output is in an unconfirmed transaction
+    pub confirmed: bool,
+    /// Withdrawal bundles that have included the output, earliest first
+    pub bundles: Vec<WithdrawalBundleInclusion>,
+}
+
+/// A deposit transaction created by the wallet
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize, utoipa::ToSchema)]
+pub struct WalletDeposit {
+    /// Mainchain deposit transaction ID
+    #[schema(value_type = String)]
+    pub txid: bitcoin::Txid,
+    /// Transparent or shielded deposit address
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    #[schema(value_type = String)]
+    pub address: Address,
+    #[serde(rename = "value_sats", with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub value: bitcoin::Amount,
+    /// Mainchain fee
+    #[serde(rename = "fee_sats", with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub fee: bitcoin::Amount,
+}
+
 #[allow(clippy::duplicated_attributes)]
 #[derive(Debug, thiserror::Error, transitive::Transitive)]
 #[transitive(
@@ -130,6 +163,8 @@ pub enum Error {
     DbWrite(#[from] RwTxnError),
     #[error("io error")]
     Io(#[from] std::io::Error),
+    #[error("migration error")]
+    Migration(#[from] migration::Error),
     #[error("no index for address {address}")]
     NoIndex { address: TransparentAddress },
     #[error(
@@ -138,6 +173,8 @@ pub enum Error {
     NoSeed,
     #[error("not enough funds")]
     NotEnoughFunds,
+    #[error("utxo {outpoint} is not a withdrawal")]
+    NotAWithdrawal { outpoint: OutPoint },
     #[error("utxo does not exist")]
     NoUtxo,
     #[error("Orchard balance error")]
@@ -162,6 +199,8 @@ pub enum Error {
     SeedAlreadyExists,
     #[error(transparent)]
     Utreexo(#[from] UtreexoError),
+    #[error(transparent)]
+    WithdrawalPolicy(#[from] withdrawal_policy::Error),
     #[error("zip32 error")]
     Zip32(#[from] ::orchard::zip32::Error),
 }
@@ -227,6 +266,11 @@ pub struct Wallet {
     // for [T; 64], use heed's `Bytes`
     // TODO: Don't store the seed in plaintext.
     seed: DatabaseUnique<U8, Bytes>,
+    /// Deposits created by the wallet, keyed by mainchain txid
+    deposits: DatabaseUnique<
+        SerdeBincode<bitcoin::Txid>,
+        SerdeBincode<WalletDeposit>,
+    >,
     /// Map each address to it's index
     address_to_index:
         DatabaseUnique<SerdeBincode<TransparentAddress>, U32<BigEndian>>,
@@ -265,13 +309,33 @@ pub struct Wallet {
     /// Block that the wallet was last synced to.
     /// May be empty, if there is no tip yet
     tip: DatabaseUnique<UnitKey, SerdeBincode<BlockHash>>,
+    /// Used to check withdrawal destinations
+    network: Network,
     _version: DatabaseUnique<UnitKey, SerdeBincode<Version>>,
 }
 
 impl Wallet {
-    pub const NUM_DBS: u32 = ShardTreeDb::<WalletEnv>::NUM_DBS + 16;
-
-    pub fn new(path: &Path) -> Result<Self, Error> {
+    pub const NUM_DBS: u32 = ShardTreeDb::<WalletEnv>::NUM_DBS + 17;
+
+    /// Schema version of the wallet DBs
+    pub const SCHEMA_VERSION: Version = Version {
+        major: 0,
+        minor: 16,
+        patch: 0,
+    };
+
+    pub const SCHEMA: migration::Schema = migration::Schema {
+        version_db: "version",
+        version: Self::SCHEMA_VERSION,
+        min_version: Version {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        },
+        steps: &[],
+    };
+
+    fn open_env(path: &Path) -> Result<Env, Error> {
         std::fs::create_dir_all(path)?;
         let env = {
             use heed::EnvFlags;
@@ -304,8 +368,31 @@ impl Wallet {
             unsafe { Env::open(&env_open_options, path) }
                 .map_err(EnvError::from)?
         };
+        Ok(env)
+    }
+
+    /// Report pending migrations for the wallet at the specified path,
+    /// without modifying it
+    pub fn migration_dry_run(path: &Path) -> Result<migration::Report, Error> {
+        let env = Self::open_env(path)?;
+        let report = migration::migrate(
+            &env,
+            &[Self::SCHEMA],
+            migration::Config { dry_run: true },
+        )?;
+        Ok(report)
+    }
+
+    pub fn new(path: &Path, network: Network) -> Result<Self, Error> {
+        let env = Self::open_env(path)?;
+        let _: migration::Report = migration::migrate(
+            &env,
+            &[Self::SCHEMA],
+            migration::Config::default(),
+        )?;
         let mut rwtxn = env.write_txn()?;
         let seed_db = DatabaseUnique::create(&env, &mut rwtxn, "seed")?;
+        let deposits = DatabaseUnique::create(&env, &mut rwtxn, "deposits")?;
         let address_to_index =
             DatabaseUnique::create(&env, &mut rwtxn, "address_to_index")?;
         let index_to_address =
@@ -348,14 +435,16 @@ impl Wallet {
         let stxos_unconfirmed =
             DatabaseUnique::create(&env, &mut rwtxn, "stxos_unconfirmed")?;
         let tip = DatabaseUnique::create(&env, &mut rwtxn, "tip")?;
-        let version = DatabaseUnique::create(&env, &mut rwtxn, "version")?;
+        let version =
+            DatabaseUnique::create(&env, &mut rwtxn, Self::SCHEMA.version_db)?;
         if version.try_get(&rwtxn, &())?.is_none() {
-            version.put(&mut rwtxn, &(), &*VERSION)?;
+            version.put(&mut rwtxn, &(), &Self::SCHEMA_VERSION)?;
         }
         rwtxn.commit()?;
         Ok(Self {
             env,
             seed: seed_db,
+            deposits,
             address_to_index,
             index_to_address,
             orchard_address_to_index,
@@ -371,6 +460,7 @@ impl Wallet {
             stxos,
             stxos_unconfirmed,
             tip,
+            network,
             _version: version,
         })
     }
@@ -698,6 +788,12 @@ impl Wallet {
             value = %value.display_dynamic(),
             "Creating withdrawal"
         );
+        let () = withdrawal_policy::check(
+            self.network,
+            &main_address,
+            value,
+            main_fee,
+        )?;
         let mut rwtxn = self.env.write_txn()?;
         let (total, coins) = self.select_transparent_coins(
             &rwtxn,
@@ -744,6 +840,53 @@ impl Wallet {
         })
     }
 
+    /// Cancel a withdrawal that has not been included in a bundle, by
+    /// spending the withdrawal output back to a transparent address.
+    /// The fee is paid from the withdrawal output.
+    pub fn create_withdrawal_cancellation(
+        &self,
+        accumulator: &Accumulator,
+        outpoint: OutPoint,
+        fee: bitcoin::Amount,
+    ) -> Result<Transaction, Error> {
+        tracing::trace!(
+            accumulator = %accumulator.0,
+            fee = %fee.display_dynamic(),
+            %outpoint,
+            "Creating withdrawal cancellation"
+        );
+        let mut rwtxn = self.env.write_txn()?;
+        let output = self
+            .utxos
+            .try_get(&rwtxn, &outpoint)?
+            .ok_or(Error::NoUtxo)?;
+        if !output.content.is_withdrawal() {
+            return Err(Error::NotAWithdrawal { outpoint });
+        }
+        let value = output
+            .get_value()
+            .checked_sub(fee)
+            .ok_or(Error::NotEnoughFunds)?;
+        let utxo_hash =
+            crate::types::hashes::hash_with_scratch_buffer(&PointedOutput {
+                outpoint,
+                output,
+            });
+        let input_utxo_hashes: Vec<BitcoinNodeHash> = vec![(&utxo_hash).into()];
+        let proof = accumulator.prove(&input_utxo_hashes)?;
+        let outputs = vec![Output {
+            address: self.get_new_transparent_address(&mut rwtxn)?,
+            content: OutputContent::Value(value),
+        }];
+        rwtxn.commit()?;
+        Ok(Transaction {
+            inputs: vec![(outpoint, utxo_hash)],
+            proof,
+            outputs,
+            orchard_bundle: None,
+        })
+    }
+
     pub fn create_transaction(
         &self,
         accumulator: &Accumulator,
@@ -1006,22 +1149,21 @@ impl Wallet {
         Ok(tx)
     }
 
-    /// Create a transaction that unshields the specified amount.
+    /// Create a transaction that spends shielded notes to fund a single
+    /// transparent output.
     /// Fees are paid from shielded notes.
-    pub fn create_unshield_transaction(
+    fn create_unshield_transaction_with_output(
         &self,
+        mut rwtxn: RwTxn<'_>,
         accumulator: &Accumulator,
-        value: bitcoin::Amount,
+        output: Output,
         fee: bitcoin::Amount,
     ) -> Result<Transaction, Error> {
-        let mut rwtxn = self.env.write_txn()?;
         let inputs = Vec::new();
         let input_utxo_hashes = Vec::<BitcoinNodeHash>::new();
         let utreexo_proof = accumulator.prove(&input_utxo_hashes)?;
-        let outputs = vec![Output {
-            address: self.get_new_transparent_address(&mut rwtxn)?,
-            content: OutputContent::Value(value),
-        }];
+        let value = output.get_value();
+        let outputs = vec![output];
         let shielded_addr = self.get_new_orchard_address(&mut rwtxn)?;
         let orchard_spending_key = self.get_orchard_spending_key(&rwtxn)?;
         let (rwtxn, value_in, anchor, coins) = self.select_shielded_coins(
@@ -1065,6 +1207,70 @@ impl Wallet {
         Ok(res)
     }
 
+    /// Create a transaction that unshields the specified amount.
+    /// Fees are paid from shielded notes.
+    pub fn create_unshield_transaction(
+        &self,
+        accumulator: &Accumulator,
+        value: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<Transaction, Error> {
+        let mut rwtxn = self.env.write_txn()?;
+        let output = Output {
+            address: self.get_new_transparent_address(&mut rwtxn)?,
+            content: OutputContent::Value(value),
+        };
+        self.create_unshield_transaction_with_output(
+            rwtxn,
+            accumulator,
+            output,
+            fee,
+        )
+    }
+
+    /// Create a withdrawal that is funded by shielded notes, in a single
+    /// transaction.
+    /// The withdrawal value, mainchain fee, and sidechain fee are paid from
+    /// shielded notes.
+    pub fn create_shielded_withdrawal(
+        &self,
+        accumulator: &Accumulator,
+        main_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
+        value: bitcoin::Amount,
+        main_fee: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<Transaction, Error> {
+        tracing::trace!(
+            accumulator = %accumulator.0,
+            fee = %fee.display_dynamic(),
+            ?main_address,
+            main_fee = %main_fee.display_dynamic(),
+            value = %value.display_dynamic(),
+            "Creating shielded withdrawal"
+        );
+        let () = withdrawal_policy::check(
+            self.network,
+            &main_address,
+            value,
+            main_fee,
+        )?;
+        let mut rwtxn = self.env.write_txn()?;
+        let output = Output {
+            address: self.get_new_transparent_address(&mut rwtxn)?,
+            content: OutputContent::Withdrawal {
+                value,
+                main_fee,
+                main_address,
+            },
+        };
+        self.create_unshield_transaction_with_output(
+            rwtxn,
+            accumulator,
+            output,
+            fee,
+        )
+    }
+
     pub fn delete_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
         let mut rwtxn = self.env.write_txn()?;
         for outpoint in outpoints {
@@ -1330,13 +1536,15 @@ impl Wallet {
         )
     }
 
-    /// Connects ONLY the orchard effects from a block.
+    /// Connects ONLY the orchard effects from a block, including notes
+    /// minted by shielded deposits in the block's two-way peg data.
     /// Updates the wallet tip.
     pub fn connect_orchard_block<'a>(
         &self,
         mut rwtxn: RwTxn<'a>,
         header: &Header,
         body: &Body,
+        shielded_deposits: &[ShieldedDeposit],
     ) -> Result<RwTxn<'a>, Error> {
         assert_eq!(self.try_get_tip(&rwtxn)?, header.prev_side_hash);
         assert_eq!(body.compute_merkle_root(), header.merkle_root);
@@ -1363,6 +1571,33 @@ impl Wallet {
                 )?;
             }
         }
+        // Shielded deposit notes are appended after the block's transactions.
+        // Invalid deposits are not credited.
+        for deposit in shielded_deposits {
+            let Ok(note) = deposit.note() else {
+                continue;
+            };
+            let retention = if self
+                .orchard_address_to_index
+                .contains_key(&rwtxn, &deposit.address)?
+            {
+                let position = shard_tree
+                    .max_leaf_position(None)?
+                    .map_or_else(|| 0.into(), |pos| pos + 1);
+                self.orchard_notes.put(
+                    &mut rwtxn,
+                    &note.nullifier(&fvk),
+                    &(note, orchard::PositionWrapper(position)),
+                )?;
+                incrementalmerkletree::Retention::Marked
+            } else {
+                incrementalmerkletree::Retention::Ephemeral
+            };
+            let () = shard_tree.append(
+                orchard::MerkleHashOrchard::from_cmx(&note.commitment().0),
+                retention,
+            )?;
+        }
         let block_hash = header.hash();
         let () = self.put_tip(&mut rwtxn, &block_hash)?;
         let checkpoint_id = orchard::shardtree_db::CheckpointId {
@@ -1375,7 +1610,8 @@ impl Wallet {
         Ok(rwtxn)
     }
 
-    /// Disconnects ONLY the orchard effects from a block.
+    /// Disconnects ONLY the orchard effects from a block, including notes
+    /// minted by shielded deposits in the block's two-way peg data.
     /// Does not delete memos.
     /// Updates the wallet tip.
     pub fn disconnect_orchard_block<'a>(
@@ -1383,12 +1619,20 @@ impl Wallet {
         mut rwtxn: RwTxn<'a>,
         header: &Header,
         body: &Body,
+        shielded_deposits: &[ShieldedDeposit],
     ) -> Result<RwTxn<'a>, Error> {
         assert_eq!(self.try_get_tip(&rwtxn)?, Some(header.hash()));
         assert_eq!(body.compute_merkle_root(), header.merkle_root);
         let fvk = self.get_orchard_full_viewing_key(&rwtxn)?;
         let ivks = self.get_orchard_incoming_viewing_keys(&rwtxn)?;
         let ovks = self.get_orchard_outgoing_viewing_keys(&rwtxn)?;
+        for deposit in shielded_deposits.iter().rev() {
+            let Ok(note) = deposit.note() else {
+                continue;
+            };
+            let nullifier = note.nullifier(&fvk);
+            let _: bool = self.orchard_notes.delete(&mut rwtxn, &nullifier)?;
+        }
         for tx in body.transactions.iter().rev() {
             let Some(orchard_bundle) = tx.orchard_bundle.as_ref() else {
                 continue;
@@ -1564,6 +1808,26 @@ impl Wallet {
         Ok(balance)
     }
 
+    /// Record a deposit created by the wallet
+    pub fn put_deposit(&self, deposit: &WalletDeposit) -> Result<(), Error> {
+        let mut rwtxn = self.env.write_txn()?;
+        self.deposits.put(&mut rwtxn, &deposit.txid, deposit)?;
+        rwtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_deposits(
+        &self,
+        rotxn: &RoTxn,
+    ) -> Result<Vec<WalletDeposit>, Error> {
+        let deposits = self
+            .deposits
+            .iter(rotxn)?
+            .map(|(_, deposit)| Ok(deposit))
+            .collect()?;
+        Ok(deposits)
+    }
+
     pub fn get_stxos(
         &self,
         rotxn: &RoTxn,
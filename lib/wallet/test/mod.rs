@@ -1,4 +1,4 @@
-use crate::wallet::Wallet;
+use crate::{types::Network, wallet::Wallet};
 
 #[test]
 fn test_get_or_generate_last_address() -> anyhow::Result<()> {
@@ -13,7 +13,7 @@ fn test_get_or_generate_last_address() -> anyhow::Result<()> {
         let _unused = std::fs::remove_dir_all(&test_dir);
     }
 
-    let wallet = Wallet::new(&test_dir)?;
+    let wallet = Wallet::new(&test_dir, Network::default())?;
 
     // Seed must be set before we can generate addresses
     assert!(!wallet.has_seed()?);
@@ -1,23 +1,35 @@
 //! RPC API
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
 use jsonrpsee::{core::RpcResult, proc_macros::rpc};
 use l2l_openapi::open_api;
 use serde::{Deserialize, Serialize};
 use thunder_orchard::{
-    net::Peer,
+    net::{NodePublicKey, Peer},
+    node::{
+        treasury_audit::Report as TreasuryAuditReport,
+        verify::Report as VerifyChainReport,
+    },
     types::{
-        BlockHash, MerkleRoot, OutPoint, Output, OutputContent, PointedOutput,
-        ShieldedAddress, SpentOutput, Transaction, TransparentAddress, Txid,
-        WithdrawalBundle, schema as thunder_orchard_schema,
+        BlockHash, BlockInfo, ChainTip, DepositInfo, HeaderInfo, M6id,
+        MerkleRoot, OutPoint, Output, OutputContent, PointedOutput,
+        ShieldedAddress, SnapshotHash, SpentOutput, Transaction,
+        TransparentAddress, Txid, WithdrawalBundle, WithdrawalBundleHistory,
+        WithdrawalBundleStatus, schema as thunder_orchard_schema,
     },
-    wallet::Balance,
+    wallet::{Balance, WalletDeposit, WalletWithdrawal},
 };
 use utoipa::ToSchema;
 
 mod schema;
 
+/// Maximum number of blocks that can be requested with `get_blocks`
+pub const MAX_GET_BLOCKS_COUNT: u32 = 100;
+
+/// Maximum number of headers that can be requested with `get_headers`
+pub const MAX_GET_HEADERS_COUNT: u32 = 2000;
+
 #[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct GetTransactionResponse {
     pub tx: Transaction,
@@ -32,11 +44,28 @@ pub struct GetTransactionResponse {
 ])]
 #[rpc(client, server)]
 pub trait Rpc {
+    /// Reconcile the sidechain treasury UTXO on the mainchain with the
+    /// deposits and withdrawals recorded in the sidechain state, and report
+    /// any discrepancy
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "audit_treasury")]
+    async fn audit_treasury(&self) -> RpcResult<TreasuryAuditReport>;
+
     /// Get balance in sats
     #[open_api_method(output_schema(ToSchema))]
     #[method(name = "balance")]
     async fn balance(&self) -> RpcResult<Balance>;
 
+    /// Cancel a withdrawal that has not been included in a withdrawal
+    /// bundle, returning the funds to a transparent address.
+    /// The fee is paid from the withdrawal.
+    #[method(name = "cancel_withdrawal")]
+    async fn cancel_withdrawal(
+        &self,
+        outpoint: OutPoint,
+        fee_sats: u64,
+    ) -> RpcResult<Txid>;
+
     /// Connect to a peer
     #[open_api_method(output_schema(ToSchema))]
     #[method(name = "connect_peer")]
@@ -58,6 +87,40 @@ pub trait Rpc {
         fee_sats: u64,
     ) -> RpcResult<bitcoin::Txid>;
 
+    /// Deposit to a shielded address.
+    /// The deposit is minted into a shielded note.
+    #[open_api_method(output_schema(PartialSchema = "schema::BitcoinTxid"))]
+    #[method(name = "create_shielded_deposit")]
+    async fn create_shielded_deposit(
+        &self,
+        address: ShieldedAddress,
+        value_sats: u64,
+        fee_sats: u64,
+    ) -> RpcResult<bitcoin::Txid>;
+
+    /// Write a snapshot of the state at the current tip to a file.
+    /// Returns the snapshot hash.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "dump_state_snapshot")]
+    async fn dump_state_snapshot(
+        &self,
+        #[open_api_method_arg(schema(PartialSchema = "schema::PathBuf"))]
+        path: PathBuf,
+    ) -> RpcResult<SnapshotHash>;
+
+    /// Write blocks in the current chain, from height `from` to height `to`
+    /// inclusive, to a block file.
+    /// Returns the number of blocks written.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "export_blocks")]
+    async fn export_blocks(
+        &self,
+        #[open_api_method_arg(schema(PartialSchema = "schema::PathBuf"))]
+        path: PathBuf,
+        from: u32,
+        to: u32,
+    ) -> RpcResult<u32>;
+
     /// Delete peer from known_peers DB.
     /// Connections to the peer are not terminated.
     #[method(name = "forget_peer")]
@@ -76,6 +139,13 @@ pub trait Rpc {
         address: TransparentAddress,
     ) -> RpcResult<String>;
 
+    /// Format a shielded deposit address
+    #[method(name = "format_shielded_deposit_address")]
+    async fn format_shielded_deposit_address(
+        &self,
+        address: ShieldedAddress,
+    ) -> RpcResult<String>;
+
     /// Generate a mnemonic seed phrase
     #[method(name = "generate_mnemonic")]
     async fn generate_mnemonic(&self) -> RpcResult<String>;
@@ -87,6 +157,26 @@ pub trait Rpc {
         block_hash: thunder_orchard::types::BlockHash,
     ) -> RpcResult<Option<thunder_orchard::types::Block>>;
 
+    /// Get the hash of the block at the specified height in the current
+    /// chain, if it exists
+    #[open_api_method(output_schema(
+        PartialSchema = "schema::Optional<thunder_orchard::types::BlockHash>"
+    ))]
+    #[method(name = "get_block_hash")]
+    async fn get_block_hash(
+        &self,
+        height: u32,
+    ) -> RpcResult<Option<thunder_orchard::types::BlockHash>>;
+
+    /// Get up to `count` blocks in the current chain, starting at the
+    /// specified height. `count` must not exceed `MAX_GET_BLOCKS_COUNT`.
+    #[method(name = "get_blocks")]
+    async fn get_blocks(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> RpcResult<Vec<BlockInfo>>;
+
     /// Get mainchain blocks that commit to a specified block hash
     #[open_api_method(output_schema(
         PartialSchema = "thunder_orchard_schema::BitcoinBlockHash"
@@ -115,6 +205,28 @@ pub trait Rpc {
         &self,
     ) -> RpcResult<Option<thunder_orchard::types::BlockHash>>;
 
+    /// List all known chain tips, including the tip of the active chain
+    /// and the tips of any competing forks
+    #[method(name = "get_chain_tips")]
+    async fn get_chain_tips(&self) -> RpcResult<Vec<ChainTip>>;
+
+    /// Get the header with the specified block hash, along with its height,
+    /// BMM inclusions and transaction count, if it exists
+    #[method(name = "get_header")]
+    async fn get_header(
+        &self,
+        block_hash: thunder_orchard::types::BlockHash,
+    ) -> RpcResult<Option<HeaderInfo>>;
+
+    /// Get up to `count` headers in the current chain, starting at the
+    /// specified height. `count` must not exceed `MAX_GET_HEADERS_COUNT`.
+    #[method(name = "get_headers")]
+    async fn get_headers(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> RpcResult<Vec<HeaderInfo>>;
+
     /// Get a new shielded address
     #[method(name = "get_new_shielded_address")]
     async fn get_new_shielded_address(&self) -> RpcResult<ShieldedAddress>;
@@ -166,17 +278,86 @@ pub trait Rpc {
         &self,
     ) -> RpcResult<Vec<PointedOutput>>;
 
+    /// Get a withdrawal bundle, along with the sidechain outpoints that it
+    /// spends, its total value and fee, and every status transition with the
+    /// mainchain block that caused it
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "get_withdrawal_bundle")]
+    async fn get_withdrawal_bundle(
+        &self,
+        m6id: M6id,
+    ) -> RpcResult<Option<WithdrawalBundleHistory>>;
+
     /// Get the current block count
     #[method(name = "getblockcount")]
     async fn getblockcount(&self) -> RpcResult<u32>;
 
+    /// Read blocks from a block file, and validate and connect them as if
+    /// they were received from a peer.
+    /// Returns the number of blocks imported.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "import_blocks")]
+    async fn import_blocks(
+        &self,
+        #[open_api_method_arg(schema(PartialSchema = "schema::PathBuf"))]
+        path: PathBuf,
+    ) -> RpcResult<u32>;
+
+    /// Mark a block and its descendants as invalid, disconnecting them if
+    /// they are in the current chain. The block remains invalid across
+    /// restarts, until it is reconsidered.
+    /// Returns the new tip.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "invalidate_block")]
+    async fn invalidate_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> RpcResult<Option<BlockHash>>;
+
     /// Get the height of the latest failed withdrawal bundle
     #[method(name = "latest_failed_withdrawal_bundle_height")]
     async fn latest_failed_withdrawal_bundle_height(
         &self,
     ) -> RpcResult<Option<u32>>;
 
-    /// List peers
+    /// Start from a state snapshot, instead of syncing the full history.
+    /// The state must be empty. The snapshot must be trusted for the
+    /// network, or match `trusted_hash`. If the snapshot tip header has not
+    /// been synced yet, the snapshot is applied once it is available.
+    /// Blocks below the snapshot tip are never downloaded or validated.
+    /// Returns the snapshot hash.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "load_state_snapshot")]
+    async fn load_state_snapshot(
+        &self,
+        #[open_api_method_arg(schema(PartialSchema = "schema::PathBuf"))]
+        path: PathBuf,
+        trusted_hash: Option<SnapshotHash>,
+    ) -> RpcResult<SnapshotHash>;
+
+    /// List deposits that have been credited on the sidechain, in the order
+    /// that they were credited.
+    /// If `address` is set, only deposits to this address are listed.
+    /// If `start_main_height` or `end_main_height` are set, only deposits
+    /// included in mainchain blocks within the (inclusive) height range are
+    /// listed.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "list_deposits")]
+    async fn list_deposits(
+        &self,
+        address: Option<TransparentAddress>,
+        start_main_height: Option<u32>,
+        end_main_height: Option<u32>,
+    ) -> RpcResult<Vec<DepositInfo>>;
+
+    /// List deposits created by the wallet that have not yet been credited
+    /// on the sidechain
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "list_pending_deposits")]
+    async fn list_pending_deposits(&self) -> RpcResult<Vec<WalletDeposit>>;
+
+    /// List peers, along with their advertised tip and version,
+    /// latency, traffic, and pending requests
     #[method(name = "list_peers")]
     async fn list_peers(&self) -> RpcResult<Vec<Peer>>;
 
@@ -184,11 +365,36 @@ pub trait Rpc {
     #[method(name = "list_utxos")]
     async fn list_utxos(&self) -> RpcResult<Vec<PointedOutput>>;
 
+    /// List withdrawal bundles, ordered by the height at which they were
+    /// first seen.
+    /// If `status` is set, only bundles with this latest status are listed.
+    /// If `start_height` or `end_height` are set, only bundles with a status
+    /// transition within the (inclusive) height range are listed.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "list_withdrawal_bundles")]
+    async fn list_withdrawal_bundles(
+        &self,
+        status: Option<WithdrawalBundleStatus>,
+        start_height: Option<u32>,
+        end_height: Option<u32>,
+    ) -> RpcResult<Vec<WithdrawalBundleHistory>>;
+
+    /// List withdrawals created by the wallet, along with the withdrawal
+    /// bundles that have included them and their status history
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "list_withdrawals")]
+    async fn list_withdrawals(&self) -> RpcResult<Vec<WalletWithdrawal>>;
+
     /// Attempt to mine a sidechain block
     #[open_api_method(output_schema(ToSchema))]
     #[method(name = "mine")]
     async fn mine(&self, fee: Option<u64>) -> RpcResult<()>;
 
+    /// Get the public key identifying this node to peers
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "node_public_key")]
+    async fn node_public_key(&self) -> RpcResult<NodePublicKey>;
+
     /// Get OpenAPI schema
     #[open_api_method(output_schema(PartialSchema = "schema::OpenApi"))]
     #[method(name = "openapi_schema")]
@@ -201,6 +407,23 @@ pub trait Rpc {
         &self,
     ) -> RpcResult<Option<WithdrawalBundle>>;
 
+    /// Remove invalidity from a block, and from its ancestors and
+    /// descendants, and re-org to the best valid chain.
+    /// Returns the new tip.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "reconsider_block")]
+    async fn reconsider_block(
+        &self,
+        block_hash: BlockHash,
+    ) -> RpcResult<Option<BlockHash>>;
+
+    /// Wipe the state and mempool, and rebuild them by re-applying the
+    /// current chain from stored blocks.
+    /// Returns the re-applied tip once the reindex is complete.
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "reindex")]
+    async fn reindex(&self) -> RpcResult<Option<BlockHash>>;
+
     /// Remove a tx from the mempool
     #[open_api_method(output_schema(ToSchema))]
     #[method(name = "remove_from_mempool")]
@@ -215,6 +438,10 @@ pub trait Rpc {
     #[method(name = "shield")]
     async fn shield(&self, value_sats: u64, fee_sats: u64) -> RpcResult<Txid>;
 
+    /// Get the total value of notes in the shielded pool
+    #[method(name = "shielded_pool_value")]
+    async fn shielded_pool_value_sats(&self) -> RpcResult<u64>;
+
     /// Transfer shielded funds to the specified address
     #[method(name = "shielded_transfer")]
     async fn shielded_transfer(
@@ -224,6 +451,22 @@ pub trait Rpc {
         fee_sats: u64,
     ) -> RpcResult<Txid>;
 
+    /// Initiate a withdrawal to the specified mainchain address, paid from
+    /// shielded funds in a single transaction
+    #[method(name = "shielded_withdraw")]
+    async fn shielded_withdraw(
+        &self,
+        #[open_api_method_arg(schema(
+            PartialSchema = "thunder_orchard::types::schema::BitcoinAddr"
+        ))]
+        mainchain_address: bitcoin::Address<
+            bitcoin::address::NetworkUnchecked,
+        >,
+        amount_sats: u64,
+        fee_sats: u64,
+        mainchain_fee_sats: u64,
+    ) -> RpcResult<Txid>;
+
     /// Get total sidechain wealth
     #[method(name = "sidechain_wealth")]
     async fn sidechain_wealth_sats(&self) -> RpcResult<u64>;
@@ -246,6 +489,12 @@ pub trait Rpc {
     async fn unshield(&self, value_sats: u64, fee_sats: u64)
     -> RpcResult<Txid>;
 
+    /// Verify the last `depth` blocks of the current chain against stored
+    /// blocks, and check UTXO set invariants
+    #[open_api_method(output_schema(ToSchema))]
+    #[method(name = "verify_chain")]
+    async fn verify_chain(&self, depth: u32) -> RpcResult<VerifyChainReport>;
+
     /// Initiate a withdrawal to the specified mainchain address
     #[method(name = "withdraw")]
     async fn withdraw(
@@ -31,6 +31,21 @@ impl PartialSchema for OpenApi {
     }
 }
 
+pub struct PathBuf;
+
+impl PartialSchema for PathBuf {
+    fn schema() -> RefOr<Schema> {
+        let obj = utoipa::openapi::Object::with_type(openapi::Type::String);
+        RefOr::T(Schema::Object(obj))
+    }
+}
+
+impl ToSchema for PathBuf {
+    fn name() -> std::borrow::Cow<'static, str> {
+        std::borrow::Cow::Borrowed("path.PathBuf")
+    }
+}
+
 /// Optional `T`
 pub struct Optional<T>(PhantomData<T>);
 